@@ -4,15 +4,22 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::hint::black_box;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 // use std::hint::black_box;
 use criterion::{ criterion_group, criterion_main, Criterion };
 use cri_archive_lib::cpk::compress::layla::{LaylaDecompressor, LaylaDecompressorCursor};
 use cri_archive_lib::cpk::encrypt::p5r::P5RDecryptor;
+// used by the commented out table decryption benchmark
+#[allow(unused_imports)]
 use cri_archive_lib::cpk::encrypt::table::TableDecryptor;
 use cri_archive_lib::cpk::file::CpkFile;
 use cri_archive_lib::cpk::free_list::FreeList;
+use cri_archive_lib::cpk::header::TableContainer;
+use cri_archive_lib::cpk::positional::CpkPositionalReader;
 use cri_archive_lib::cpk::reader::CpkReader;
+use cri_archive_lib::cpk::toc::CpkToc;
+use cri_archive_lib::cpk::writer::{CpkBuilder, CpkBuilderFile};
+use cri_archive_lib::schema::view::TableView;
 
 fn read_compressed_layla_3d_model() -> Result<Vec<u8>, Box<dyn Error>> {
     let layla_table = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/Compressed3dModel.crilayla";
@@ -51,7 +58,7 @@ fn benchmark_layla_read_13(cursor: &mut LaylaDecompressorCursor, model_data: &[u
 }
 
 fn benchmark_layla_decompress(model_data: &[u8], allocator: &mut FreeList) {
-    let _ = LaylaDecompressor::decompress(&model_data, allocator);
+    let _ = LaylaDecompressor::decompress(model_data, allocator);
 }
 
 fn decrypt_table_little_init() -> Result<Vec<u8>, Box<dyn Error>> {
@@ -84,6 +91,91 @@ fn extract_joker_persona5_exclusive(reader: &mut CpkReader<BufReader<File>, P5RD
     Ok(())
 }
 
+const PARALLEL_THREADS: usize = 4;
+
+/// Write a CPK with 256 stored 64 KB files to the temp directory
+fn build_parallel_sample() -> Result<std::path::PathBuf, Box<dyn Error>> {
+    let path = std::env::temp_dir().join("cri-archive-lib-parallel-bench.cpk");
+    let mut builder = CpkBuilder::new();
+    for i in 0..0x100u32 {
+        let data = (0..0x10000u32).map(|j| (j.wrapping_mul(i + 1) >> 3) as u8).collect();
+        builder.add_file(CpkBuilderFile::new("DATA", &format!("{:03}.bin", i), data));
+    }
+    builder.write(&mut std::io::BufWriter::new(File::create(&path)?))?;
+    Ok(path)
+}
+
+fn extract_all_in_parallel<F: Fn(&CpkFile) + Sync>(files: &[CpkFile], extract: F) {
+    std::thread::scope(|scope| {
+        for chunk in files.chunks(files.len().div_ceil(PARALLEL_THREADS)) {
+            let extract = &extract;
+            scope.spawn(move || chunk.iter().for_each(extract));
+        }
+    });
+}
+
+fn parallel_extraction_benchmark(c: &mut Criterion) {
+    let path = build_parallel_sample().unwrap();
+    let mut reader = CpkReader::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+    let files = reader.get_files().unwrap();
+    // CpkReader needs exclusive access to its stream, so threads take turns
+    let locked = std::sync::Mutex::new(reader);
+    let positional = CpkPositionalReader::new(File::open(&path).unwrap()).unwrap();
+    let mut group = c.benchmark_group("Parallel Extraction");
+    group.bench_function("CpkReader (Mutex)", |b| b
+        .iter(|| extract_all_in_parallel(&files, |f| {
+            black_box(locked.lock().unwrap().extract_to(f, &mut std::io::sink()).unwrap());
+        })));
+    group.bench_function("CpkPositionalReader", |b| b
+        .iter(|| extract_all_in_parallel(positional.get_files(), |f| {
+            black_box(positional.extract_to(f, &mut std::io::sink()).unwrap());
+        })));
+    group.finish();
+}
+
+/// CPK with 0x4000 small files spread over 64 directories
+fn build_toc_sample() -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut builder = CpkBuilder::new();
+    for i in 0..0x4000u32 {
+        let directory = format!("DATA/{:02}", i % 64);
+        builder.add_file(CpkBuilderFile::new(&directory, &format!("{:05}.bin", i), i.to_le_bytes().to_vec()));
+    }
+    let mut out = Cursor::new(vec![]);
+    builder.write(&mut out)?;
+    Ok(out.into_inner())
+}
+
+/// Read the same columns as CpkToc::read_toc through a TableView
+fn read_toc_view(cpk: &[u8]) -> Result<u64, Box<dyn Error>> {
+    let mut handle = Cursor::new(cpk);
+    let header = CpkToc::read_header(&mut handle, 0)?;
+    handle.seek(SeekFrom::Start(header.toc_offset().unwrap()))?;
+    let toc = TableContainer::new(&mut handle)?;
+    let view = TableView::new(&toc)?;
+    let columns = ["DirName", "FileName", "FileOffset", "FileSize", "ExtractSize", "UserString"]
+        .map(|c| view.column_index(c).unwrap());
+    let mut total = 0;
+    for row in 0..view.row_count() {
+        total += view.get_str_at(row, columns[0]).map_or(0, |s| s.len()) as u64;
+        total += view.get_str_at(row, columns[1]).map_or(0, |s| s.len()) as u64;
+        total += view.get_u64_at(row, columns[2]).unwrap();
+        total += view.get_u32_at(row, columns[3]).unwrap() as u64;
+        total += view.get_u32_at(row, columns[4]).unwrap() as u64;
+        total += view.get_str_at(row, columns[5]).map_or(0, |s| s.len()) as u64;
+    }
+    Ok(total)
+}
+
+fn toc_benchmark(c: &mut Criterion) {
+    let cpk = build_toc_sample().unwrap();
+    let mut group = c.benchmark_group("CPK TOC");
+    group.bench_function("CpkReader::get_files", |b| b
+        .iter(|| black_box(CpkReader::new(Cursor::new(cpk.as_slice())).unwrap().get_files().unwrap().len())));
+    group.bench_function("TableView", |b| b
+        .iter(|| black_box(read_toc_view(&cpk).unwrap())));
+    group.finish();
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     // let model_data = read_compressed_layla_3d_model().unwrap();
     // let mut allocator = FreeList::new();
@@ -158,5 +250,5 @@ fn criterion_benchmark(c: &mut Criterion) {
             .iter(|| black_box(extract_joker_persona5_exclusive(&mut reader, joker_persona_5))));
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(benches, toc_benchmark, parallel_extraction_benchmark, criterion_benchmark);
 criterion_main!(benches);
\ No newline at end of file
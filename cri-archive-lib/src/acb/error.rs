@@ -3,6 +3,11 @@ use std::fmt::{Debug, Display, Formatter};
 
 #[derive(Debug)]
 pub enum AcbError {
+    /// Data column points outside the table
+    InvalidData,
+    /// No decoder for the waveform's encoding, or its feature isn't enabled
+    #[cfg(any(feature = "adx", feature = "hca"))]
+    UnsupportedEncodeType(crate::acb::reader::AcbEncodeType),
 }
 
 impl Error for AcbError {}
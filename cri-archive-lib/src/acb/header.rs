@@ -1,10 +1,11 @@
 use std::collections::HashMap;
-use std::error::Error;
+use crate::error::Result;
 use std::io::Cursor;
 use std::ptr::NonNull;
+use crate::acb::error::AcbError;
 use crate::schema::columns::Column;
 use crate::schema::header::TableHeader;
-use crate::schema::rows::{Row, RowValue};
+use crate::schema::rows::{DataValue, Row, RowValue};
 use crate::schema::strings::{StringPool, StringPoolFast};
 
 #[derive(Debug)]
@@ -19,14 +20,14 @@ pub(crate) struct HighTable<S: StringPool> {
 }
 
 impl HighTable<StringPoolFast> {
-    pub fn new(alloc: &[u8]) -> Result<Self, Box<dyn Error>> {
+    pub fn new(alloc: &[u8]) -> Result<Self> {
         let header = TableHeader::new(alloc);
         let mut cursor = Cursor::new(alloc);
         cursor.set_position(crate::schema::header::HEADER_SIZE as u64);
         let columns = Column::new_list(&mut cursor, &header)?;
         let str_raw = &alloc[header.string_pool_offset() as usize..header.data_pool_offset() as usize];
         let rows = Row::new_list(&mut cursor, &header, columns.as_ref())?;
-        let strings = unsafe { StringPoolFast::new_borrowed(&str_raw, &header)? };
+        let strings = unsafe { StringPoolFast::new_borrowed(str_raw, &header)? };
         let alloc = unsafe { NonNull::new_unchecked(&raw const *alloc as _) };
 
 
@@ -40,11 +41,21 @@ impl HighTable<StringPoolFast> {
         );
         Ok(Self { alloc, header, columns, strings, rows, indices })
     }
+
+    /// Nested table stored in a data column of the first row
+    pub fn get_table(&self, name: &str) -> Result<Option<Self>> {
+        match self.get_value_header(name) {
+            Some(RowValue::Data(data)) if !data.is_none() && data.get_length() != 0 => {
+                let table = self.get_pool_data(data).ok_or(AcbError::InvalidData)?;
+                Ok(Some(HighTable::new(table)?))
+            },
+            _ => Ok(None)
+        }
+    }
 }
 
 impl<S: StringPool> HighTable<S> {
     pub fn get_header(&self) -> &TableHeader { &self.header }
-    pub fn get_columns(&self) -> &[Column] { self.columns.as_ref() }
     pub fn get_strings(&self) -> &S { &self.strings }
     pub fn get_rows(&self) -> &[Row] { &self.rows }
     pub fn get_slice(&self) -> &[u8] { unsafe { self.alloc.as_ref() } }
@@ -55,4 +66,47 @@ impl<S: StringPool> HighTable<S> {
     pub fn get_value<'a>(&'a self, row: &'a Row, name: &'a str) -> Option<&'a RowValue> {
         self.indices.get(&name).map(|i| &row[*i])
     }
+    /// Same as get_value, using the column's default value for columns without row storage
+    pub fn get_value_or_default<'a>(&'a self, row: &'a Row, name: &str) -> Option<&'a RowValue> {
+        let index = *self.indices.get(name)?;
+        match &row[index] {
+            RowValue::None => self.columns[index].get_default_value(),
+            value => Some(value)
+        }
+    }
+    /// Unsigned integer value of any width
+    pub fn get_uint(&self, row: &Row, name: &str) -> Option<u32> {
+        match self.get_value_or_default(row, name)? {
+            RowValue::Byte(v) => Some(*v as u32),
+            RowValue::UInt16(v) => Some(*v as u32),
+            RowValue::UInt32(v) => Some(*v),
+            _ => None
+        }
+    }
+    /// Floating point value of either width
+    pub fn get_float(&self, row: &Row, name: &str) -> Option<f32> {
+        match self.get_value_or_default(row, name)? {
+            RowValue::Single(v) => Some(*v),
+            RowValue::Double(v) => Some(*v as f32),
+            _ => None
+        }
+    }
+    pub fn get_str(&self, row: &Row, name: &str) -> Option<&str> {
+        match self.get_value_or_default(row, name)? {
+            RowValue::String(offset) => self.strings.get_string(*offset),
+            _ => None
+        }
+    }
+    /// Slice of the data pool referenced by a Data value, None if it lies outside the table
+    pub fn get_pool_data(&self, data: &DataValue) -> Option<&[u8]> {
+        let start = (self.header.data_pool_offset() as usize).checked_add(data.get_offset() as usize)?;
+        self.get_slice().get(start..start.checked_add(data.get_length() as usize)?)
+    }
+    /// Contents of a data column, None if the data lies outside the table
+    pub fn get_data(&self, row: &Row, name: &str) -> Option<&[u8]> {
+        match self.get_value_or_default(row, name)? {
+            RowValue::Data(data) if !data.is_none() => self.get_pool_data(data),
+            _ => None
+        }
+    }
 }
\ No newline at end of file
@@ -1,16 +1,77 @@
 use std::collections::HashMap;
-use std::error::Error;
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use crate::error::Result;
+#[cfg(any(feature = "awb", feature = "adx", feature = "hca"))]
+use crate::acb::error::AcbError;
 use crate::acb::header::HighTable;
-use crate::schema::rows::RowValue;
+#[cfg(feature = "adx")]
+use crate::adx::{decoder::AdxDecoder, key::AdxKey};
+#[cfg(feature = "awb")]
+use crate::awb::reader::AwbReader;
+#[cfg(feature = "hca")]
+use crate::hca::decoder::HcaDecoder;
+use crate::schema::rows::{Row, RowValue};
 use crate::schema::strings::{StringPool, StringPoolFast};
 
 type Table = HighTable<StringPoolFast>;
 
-#[derive(Debug)]
+/// Encoding of a waveform, EncodeType in the Waveform table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcbEncodeType {
+    Adx,
+    Hca,
+    HcaMx,
+    Other(u8)
+}
+
+impl From<u8> for AcbEncodeType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Adx,
+            2 => Self::Hca,
+            6 => Self::HcaMx,
+            v => Self::Other(v)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Waveform {
+    /// Index into the AWB, which is the memory AWB (AcbReader::get_awb) unless streaming is set
     awb_index: usize,
-    size: usize
+    streaming: bool,
+    encode_type: AcbEncodeType,
+    channels: u8,
+    sampling_rate: u16,
+    num_samples: u32,
+    loop_flag: bool,
+    /// From the WaveformExtensionData table, in samples
+    loop_start: Option<u32>,
+    loop_end: Option<u32>
+}
+
+impl Waveform {
+    pub fn awb_index(&self) -> usize { self.awb_index }
+    pub fn streaming(&self) -> bool { self.streaming }
+    pub fn encode_type(&self) -> AcbEncodeType { self.encode_type }
+    pub fn channels(&self) -> u8 { self.channels }
+    pub fn sampling_rate(&self) -> u16 { self.sampling_rate }
+    pub fn num_samples(&self) -> u32 { self.num_samples }
+    pub fn loop_flag(&self) -> bool { self.loop_flag }
+    pub fn loop_start(&self) -> Option<u32> { self.loop_start }
+    pub fn loop_end(&self) -> Option<u32> { self.loop_end }
+
+    /// Decode the waveform's data, as read from its AWB, into interleaved 16-bit samples. `key` is
+    /// the game's keycode and `subkey` the AWB's, both are ignored for unencrypted data.
+    #[cfg(any(feature = "adx", feature = "hca"))]
+    pub fn decode(&self, data: &[u8], key: u64, subkey: u16) -> Result<Vec<i16>> {
+        match self.encode_type {
+            #[cfg(feature = "adx")]
+            AcbEncodeType::Adx => AdxDecoder::new_with_key(data, AdxKey::from_keycode(key, subkey))?.decode_all(data),
+            #[cfg(feature = "hca")]
+            AcbEncodeType::Hca | AcbEncodeType::HcaMx => HcaDecoder::new_with_key(data, key, subkey)?.decode_all(data),
+            t => Err(AcbError::UnsupportedEncodeType(t).into())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -20,76 +81,77 @@ pub struct Cue<'a> {
     waveforms: Vec<Waveform>,
 }
 
+impl Cue<'_> {
+    pub fn name(&self) -> &str { self.name }
+    pub fn id(&self) -> u32 { self.id }
+    /// Waveforms played by the cue, in the order they're referenced
+    pub fn waveforms(&self) -> &[Waveform] { &self.waveforms }
+}
+
 #[derive(Debug)]
 pub struct AcbReader {
-    // raw stream
+    /// Bytes of the ACB, which every table points into
     stream: Vec<u8>,
     // archive tables
     header: Table,
     cue_tbl: Option<Table>,
-    cue_name_tbl: Option<Table>,
     waveform_tbl: Option<Table>,
+    waveform_extension_tbl: Option<Table>,
+    synth_tbl: Option<Table>,
     sequence_tbl: Option<Table>,
+    block_sequence_tbl: Option<Table>,
+    block_tbl: Option<Table>,
+    track_tbl: Option<Table>,
+    /// TrackEventTable, or CommandTable in older ACBs
+    track_event_tbl: Option<Table>,
 
-    cue_name_to_index: HashMap<&'static str, usize>,
+    cue_name_to_index: HashMap<String, usize>,
+    /// CueNameTable isn't in the same order as CueTable, so names are looked up by CueIndex
+    cue_index_to_name: HashMap<usize, String>,
     cue_id_to_index: HashMap<u32, usize>,
 }
 
-impl AcbReader {
-    fn build_cue_names(cue_name: &Table) -> Result<Vec<&'static str>, Box<dyn Error>> {
-        let mut cue_names = Vec::with_capacity(cue_name.get_rows().len());
-        for row in cue_name.get_rows() {
-            if let Some(name) = cue_name.get_value(row, "CueName").and_then(|n| match n {
-                RowValue::String(str) => cue_name.get_strings().get_string(*str),
-                _ => None
-            }) {
-                cue_names.push(unsafe { std::str::from_utf8_unchecked(
-                    std::slice::from_raw_parts(name.as_ptr(), name.len())) });
-            }
-        }
-        Ok(cue_names)
-    }
+// Cue and Synth reference types
+const REFERENCE_WAVEFORM: u32 = 1;
+const REFERENCE_SYNTH: u32 = 2;
+const REFERENCE_SEQUENCE: u32 = 3;
+const REFERENCE_BLOCK_SEQUENCE: u32 = 8;
 
-    fn get_table(header: &Table, name: &str) -> Result<Option<Table>, Box<dyn Error>> {
-        let value = header.get_value_header(name);
-        if value.is_none() {
-            return Ok(None);
-        }
-        match value.unwrap() {
-            RowValue::Data(data) => {
-                let file_offset = (header.get_header().data_pool_offset() + data.get_offset()) as usize;
-                Ok(Some(HighTable::new(
-                    &header.get_slice()[file_offset..])?))
-            },
-            _ => Ok(None)
-        }
-    }
+// Track event commands that play a (type, index) reference
+const COMMAND_NOTE_ON: u16 = 2000;
+const COMMAND_NOTE_ON_WITH_NO: u16 = 2003;
 
-    pub fn new(stream: Vec<u8>) -> Result<Self, Box<dyn Error>> {
-        // let cursor = Cursor::new(stream.as_slice());
+const NO_INDEX: u32 = 0xffff;
+
+impl AcbReader {
+    pub fn new(stream: Vec<u8>) -> Result<Self> {
         let header = HighTable::new(stream.as_slice())?;
-        let cue_tbl = Self::get_table(&header, "CueTable")?;
-        let cue_name_tbl = Self::get_table(&header, "CueNameTable")?;
-        let waveform_tbl = Self::get_table(&header, "WaveformTable")?;
-        let sequence_tbl = Self::get_table(&header, "SequenceTable")?;
+        let cue_tbl = header.get_table("CueTable")?;
+        let cue_name_tbl = header.get_table("CueNameTable")?;
+        let waveform_tbl = header.get_table("WaveformTable")?;
+        let waveform_extension_tbl = header.get_table("WaveformExtensionDataTable")?;
+        let synth_tbl = header.get_table("SynthTable")?;
+        let sequence_tbl = header.get_table("SequenceTable")?;
+        let block_sequence_tbl = header.get_table("BlockSequenceTable")?;
+        let block_tbl = header.get_table("BlockTable")?;
+        let track_tbl = header.get_table("TrackTable")?;
+        let track_event_tbl = match header.get_table("TrackEventTable")? {
+            Some(tbl) => Some(tbl),
+            None => header.get_table("CommandTable")?
+        };
 
-        let cue_name_to_index = match &cue_name_tbl {
-            Some(tbl) => {
-                let mut out = HashMap::new();
-                for row in tbl.get_rows() {
-                    if let Some(RowValue::String(name)) = tbl.get_value(row, "CueName") {
-                        if let Some(name) = tbl.get_strings().get_string(*name) {
-                            if let Some(RowValue::UInt16(index)) = tbl.get_value(row, "CueIndex") {
-                                out.insert(unsafe { std::str::from_utf8_unchecked(
-                                    std::slice::from_raw_parts(name.as_ptr(), name.len())) }, *index as usize);
-                            }
-                        }
-                    }
+        let mut cue_name_to_index = HashMap::new();
+        let mut cue_index_to_name = HashMap::new();
+        if let Some(tbl) = &cue_name_tbl {
+            for row in tbl.get_rows() {
+                if let Some(RowValue::String(name)) = tbl.get_value(row, "CueName")
+                    && let Some(name) = tbl.get_strings().get_string(*name)
+                    && let Some(RowValue::UInt16(index)) = tbl.get_value(row, "CueIndex") {
+                    cue_name_to_index.insert(name.to_owned(), *index as usize);
+                    cue_index_to_name.insert(*index as usize, name.to_owned());
                 }
-                out
-            },
-            None => HashMap::new()
-        };
+            }
+        }
 
         let cue_id_to_index = match &cue_tbl {
             Some(tbl) => {
@@ -109,11 +171,17 @@ impl AcbReader {
 
             header,
             cue_tbl,
-            cue_name_tbl,
             waveform_tbl,
+            waveform_extension_tbl,
+            synth_tbl,
             sequence_tbl,
+            block_sequence_tbl,
+            block_tbl,
+            track_tbl,
+            track_event_tbl,
 
             cue_name_to_index,
+            cue_index_to_name,
             cue_id_to_index
         })
     }
@@ -126,19 +194,33 @@ impl AcbReader {
         })
     }
 
-    pub fn get_cue_by_name<'a>(&self, cue: &'a str) -> Option<Cue<'a>> {
-        if self.cue_tbl.is_none() {
-            return None;
+    /// AWB containing the waveforms that are stored in memory, as opposed to streamed from a
+    /// separate AWB file
+    #[cfg(feature = "awb")]
+    pub fn get_awb(&self) -> Result<Option<AwbReader<&[u8]>>> {
+        let head = &self.header;
+        match head.get_value_header("AwbFile") {
+            Some(RowValue::Data(data)) if !data.is_none() && data.get_length() != 0 => {
+                let start = (head.get_header().data_pool_offset() as usize).checked_add(data.get_offset() as usize)
+                    .ok_or(AcbError::InvalidData)?;
+                let end = start.checked_add(data.get_length() as usize).ok_or(AcbError::InvalidData)?;
+                let awb = self.stream.get(start..end).ok_or(AcbError::InvalidData)?;
+                Ok(Some(AwbReader::new(awb)?))
+            },
+            _ => Ok(None)
         }
+    }
+
+    pub fn get_cue_by_name<'a>(&self, cue: &'a str) -> Option<Cue<'a>> {
+        let cue_tbl = self.cue_tbl.as_ref()?;
         self.cue_name_to_index.get(cue).and_then(|index| {
-            let cue_tbl = self.cue_tbl.as_ref().unwrap();
-            let cue_row = &cue_tbl.get_rows()[*index];
+            let cue_row = cue_tbl.get_rows().get(*index)?;
             match cue_tbl.get_value(cue_row, "CueId") {
                 Some(RowValue::UInt32(cue_id)) => {
                     Some(Cue {
                         name: cue,
                         id: *cue_id,
-                        waveforms: vec![]
+                        waveforms: self.get_cue_waveforms(*index)
                     })
                 },
                 _ => None
@@ -146,52 +228,257 @@ impl AcbReader {
         })
     }
 
+    /// Cues that aren't listed in the CueNameTable have an empty name
     pub fn get_cue_by_id(&self, id: u32) -> Option<Cue<'_>> {
-        self.cue_id_to_index.get(&id).and_then(|index| {
-            let cue_name_tbl = self.cue_name_tbl.as_ref().unwrap();
-            let cue_row = &cue_name_tbl.get_rows()[*index];
-            cue_name_tbl.get_value(cue_row, "CueName").and_then(|v| match v {
-                RowValue::String(str) => Some((cue_name_tbl, *str)),
-                _ => None
-            })
-        }).and_then(|(tbl, row)| {
-            tbl.get_strings().get_string(row).map(|name| {
-                Cue {
-                    name,
-                    id,
-                    waveforms: vec![]
+        let index = *self.cue_id_to_index.get(&id)?;
+        Some(Cue {
+            name: self.cue_index_to_name.get(&index).map_or("", |n| n.as_str()),
+            id,
+            waveforms: self.get_cue_waveforms(index)
+        })
+    }
+
+    fn get_row(table: &Option<Table>, index: u32) -> Option<(&Table, &Row)> {
+        let table = table.as_ref()?;
+        table.get_rows().get(index as usize).map(|row| (table, row))
+    }
+
+    /// Big endian u16 array from a data column
+    fn get_u16_list(table: &Table, row: &Row, name: &str) -> Vec<u32> {
+        table.get_data(row, name).map_or(vec![], |data| data.chunks_exact(2)
+            .map(|v| u16::from_be_bytes([v[0], v[1]]) as u32).collect())
+    }
+
+    /// Resolve the cue at index in the Cue table through ReferenceType and ReferenceIndex
+    fn get_cue_waveforms(&self, index: usize) -> Vec<Waveform> {
+        let mut out = vec![];
+        if let Some((cue_tbl, row)) = Self::get_row(&self.cue_tbl, index as u32)
+            && let Some(ref_type) = cue_tbl.get_uint(row, "ReferenceType")
+            && let Some(ref_index) = cue_tbl.get_uint(row, "ReferenceIndex") {
+            self.collect_waveforms(ref_type, ref_index, &mut vec![], &mut out);
+        }
+        out
+    }
+
+    /// Walk the Synth/Sequence graph depth first. References that are already being visited are
+    /// skipped, since they would otherwise recurse forever.
+    fn collect_waveforms(&self, ref_type: u32, index: u32, visiting: &mut Vec<(u32, u32)>, out: &mut Vec<Waveform>) {
+        if visiting.contains(&(ref_type, index)) {
+            return;
+        }
+        visiting.push((ref_type, index));
+        match ref_type {
+            REFERENCE_WAVEFORM => out.extend(self.get_waveform(index)),
+            REFERENCE_SYNTH => if let Some((synth_tbl, row)) = Self::get_row(&self.synth_tbl, index) {
+                let items = Self::get_u16_list(synth_tbl, row, "ReferenceItems");
+                for item in items.chunks_exact(2) {
+                    self.collect_waveforms(item[0], item[1], visiting, out);
+                }
+            },
+            REFERENCE_SEQUENCE => if let Some((sequence_tbl, row)) = Self::get_row(&self.sequence_tbl, index) {
+                self.collect_tracks(sequence_tbl, row, visiting, out);
+            },
+            REFERENCE_BLOCK_SEQUENCE => if let Some((block_sequence_tbl, row)) = Self::get_row(&self.block_sequence_tbl, index) {
+                self.collect_tracks(block_sequence_tbl, row, visiting, out);
+                for block in Self::get_u16_list(block_sequence_tbl, row, "BlockIndex") {
+                    if let Some((block_tbl, row)) = Self::get_row(&self.block_tbl, block) {
+                        self.collect_tracks(block_tbl, row, visiting, out);
+                    }
                 }
-            })
+            },
+            _ => ()
+        }
+        visiting.pop();
+    }
+
+    /// Follow the tracks listed in TrackIndex, limited to NumTracks
+    fn collect_tracks(&self, table: &Table, row: &Row, visiting: &mut Vec<(u32, u32)>, out: &mut Vec<Waveform>) {
+        let mut tracks = Self::get_u16_list(table, row, "TrackIndex");
+        if let Some(count) = table.get_uint(row, "NumTracks") {
+            tracks.truncate(count as usize);
+        }
+        for track in tracks {
+            let Some((track_tbl, track_row)) = Self::get_row(&self.track_tbl, track) else { continue };
+            let Some(event) = track_tbl.get_uint(track_row, "EventIndex").filter(|e| *e != NO_INDEX) else { continue };
+            let Some((event_tbl, event_row)) = Self::get_row(&self.track_event_tbl, event) else { continue };
+            let Some(mut commands) = event_tbl.get_data(event_row, "Command") else { continue };
+            // Commands are a big endian u16 code, u8 size and size bytes of parameters
+            while commands.len() >= 3 {
+                let code = u16::from_be_bytes([commands[0], commands[1]]);
+                let size = commands[2] as usize;
+                let Some(params) = commands.get(3..3 + size) else { break };
+                if matches!(code, COMMAND_NOTE_ON | COMMAND_NOTE_ON_WITH_NO) && params.len() >= 4 {
+                    let ref_type = u16::from_be_bytes([params[0], params[1]]) as u32;
+                    let ref_index = u16::from_be_bytes([params[2], params[3]]) as u32;
+                    self.collect_waveforms(ref_type, ref_index, visiting, out);
+                }
+                commands = &commands[3 + size..];
+            }
+        }
+    }
+
+    fn get_waveform(&self, index: u32) -> Option<Waveform> {
+        let (tbl, row) = Self::get_row(&self.waveform_tbl, index)?;
+        let streaming = tbl.get_uint(row, "Streaming").unwrap_or(0) != 0;
+        // Older ACBs only have an Id column, newer ones have separate IDs for each AWB
+        let awb_index = match tbl.get_uint(row, "Id") {
+            Some(id) => id,
+            None => match streaming {
+                true => tbl.get_uint(row, "StreamAwbId").filter(|i| *i != NO_INDEX)
+                    .or_else(|| tbl.get_uint(row, "MemoryAwbId"))?,
+                false => tbl.get_uint(row, "MemoryAwbId")?
+            }
+        };
+        let extension = tbl.get_uint(row, "ExtensionData").filter(|e| *e != NO_INDEX)
+            .and_then(|e| Self::get_row(&self.waveform_extension_tbl, e));
+        let get_loop = |name| extension.and_then(|(tbl, row)| tbl.get_uint(row, name));
+        Some(Waveform {
+            awb_index: awb_index as usize,
+            streaming,
+            encode_type: AcbEncodeType::from(tbl.get_uint(row, "EncodeType").unwrap_or(0) as u8),
+            channels: tbl.get_uint(row, "NumChannels").unwrap_or(0) as u8,
+            sampling_rate: tbl.get_uint(row, "SamplingRate").unwrap_or(0) as u16,
+            num_samples: tbl.get_uint(row, "NumSamples").unwrap_or(0),
+            loop_flag: tbl.get_uint(row, "LoopFlag").unwrap_or(0) != 0,
+            loop_start: get_loop("LoopStart"),
+            loop_end: get_loop("LoopEnd")
         })
     }
 
     pub fn get_all_cue_names(&self) -> Vec<&str> {
-        self.cue_name_to_index.keys().map(|v| *v).collect()
+        self.cue_name_to_index.keys().map(|v| v.as_str()).collect()
     }
 
     pub fn get_all_cue_ids(&self) -> Vec<u32> {
-        self.cue_id_to_index.keys().map(|v| *v).collect()
+        self.cue_id_to_index.keys().copied().collect()
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use crate::acb::reader::AcbReader;
+    use crate::acb::reader::{AcbEncodeType, AcbReader, Cue};
+    use crate::fixtures::sample_acb;
 
     #[test]
-    fn read_metaphor_battle_voice() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/Metaphor/base_cpk/EN/sound/battle/character/bp01.acb";
-        if !std::fs::exists(sample_path)? {
-            return Ok(());
-        }
-        let reader = AcbReader::new(std::fs::read(sample_path)?)?;
-        assert_eq!(reader.get_name(), Some("bp01"));
-        let cue = reader.get_cue_by_name("v_bp_bp01_034_1_c001");
-        assert_eq!(cue.is_some(), true);
+    fn read_sample_acb() -> Result<(), Box<dyn Error>> {
+        let reader = AcbReader::new(sample_acb()?)?;
+        assert_eq!(reader.get_name(), Some("sample"));
+        let cue = reader.get_cue_by_name("sequence");
+        assert!(cue.is_some());
         let cue = cue.unwrap();
-        assert_eq!(cue.name, "v_bp_bp01_034_1_c001");
-        assert_eq!(cue.id, 34);
+        assert_eq!(cue.name, "sequence");
+        assert_eq!(cue.id, 30);
+        assert!(reader.get_cue_by_name("missing").is_none());
+        // CueNameTable is sorted by name rather than following the Cue table
+        let names: Vec<_> = [10, 20, 30].into_iter().map(|id| reader.get_cue_by_id(id).unwrap().name).collect();
+        assert_eq!(names, vec!["direct", "synth", "sequence"]);
+        assert!(reader.get_cue_by_id(40).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn read_untrusted_cue_index() -> Result<(), Box<dyn Error>> {
+        use crate::fixtures::{build_table, TableValue};
+        use crate::schema::columns::ColumnType;
+        use crate::schema::rows::RowValue;
+        let cues = build_table("Cue", &[("CueId", ColumnType::UInt32)],
+            vec![vec![TableValue::Value(RowValue::UInt32(7))]])?;
+        let cue_names = build_table("CueName", &[("CueName", ColumnType::String), ("CueIndex", ColumnType::UInt16)],
+            vec![vec![TableValue::String("past_end".to_owned()), TableValue::Value(RowValue::UInt16(5))]])?;
+        let header = build_table("Header", &[("CueTable", ColumnType::Data), ("CueNameTable", ColumnType::Data)],
+            vec![vec![TableValue::Data(cues), TableValue::Data(cue_names)]])?;
+        let reader = AcbReader::new(header)?;
+        assert!(reader.get_cue_by_name("past_end").is_none());
+        let cue = reader.get_cue_by_id(7).unwrap();
+        assert_eq!(cue.name(), "");
+        assert!(cue.waveforms().is_empty());
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn read_untrusted_table_offset() -> Result<(), Box<dyn Error>> {
+        use crate::acb::error::AcbError;
+        use crate::error::CriError;
+        use crate::fixtures::{build_table, TableValue};
+        use crate::schema::columns::ColumnType;
+        use crate::schema::rows::{DataValue, RowValue};
+        let header = build_table("Header", &[("CueTable", ColumnType::Data)],
+            vec![vec![TableValue::Value(RowValue::Data(DataValue::new(u32::MAX - 4, 0x10)))]])?;
+        assert!(matches!(AcbReader::new(header), Err(CriError::Acb(AcbError::InvalidData))));
+        Ok(())
+    }
+
+    #[cfg(feature = "awb")]
+    #[test]
+    fn read_embedded_awb() -> Result<(), Box<dyn Error>> {
+        use crate::acb::error::AcbError;
+        use crate::awb::reader::tests::build_afs2;
+        use crate::error::CriError;
+        use crate::schema::columns::ColumnType;
+        use crate::schema::rows::{DataValue, RowValue};
+        use crate::schema::writer::{TableColumn, TableWriter};
+        let awb = build_afs2(&[(0, b"in memory waveform"), (1, &[1; 0x30])], 0x20, 0x55aa);
+        let mut header = TableWriter::new("Header");
+        header.add_column(TableColumn::row("Name", ColumnType::String));
+        header.add_column(TableColumn::row("AwbFile", ColumnType::Data));
+        let row = vec![RowValue::String(header.add_string("embedded")), RowValue::Data(header.add_data(&awb))];
+        header.add_row(row);
+        let reader = AcbReader::new(header.to_bytes()?)?;
+        assert_eq!(reader.get_name(), Some("embedded"));
+        let awb = reader.get_awb()?.unwrap();
+        assert_eq!(awb.subkey(), 0x55aa);
+        assert_eq!(awb.get_by_cue_id(0), Some(&b"in memory waveform"[..]));
+        assert_eq!(awb.get_by_index(1), Some(&[1; 0x30][..]));
+        let mut header = TableWriter::new("Header");
+        header.add_column(TableColumn::row("AwbFile", ColumnType::Data));
+        header.add_row(vec![RowValue::Data(DataValue::new(u32::MAX - 4, 0x10))]);
+        let reader = AcbReader::new(header.to_bytes()?)?;
+        assert!(matches!(reader.get_awb(), Err(CriError::Acb(AcbError::InvalidData))));
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_cue_waveforms() -> Result<(), Box<dyn Error>> {
+        let reader = AcbReader::new(sample_acb()?)?;
+        let awb_indices = |cue: Cue| cue.waveforms().iter().map(|w| w.awb_index()).collect::<Vec<_>>();
+        let direct = reader.get_cue_by_name("direct").unwrap();
+        assert_eq!(direct.id(), 10);
+        let waveform = &direct.waveforms()[0];
+        assert_eq!(waveform.awb_index(), 0);
+        assert!(!waveform.streaming());
+        assert_eq!(waveform.encode_type(), AcbEncodeType::Hca);
+        assert_eq!(waveform.channels(), 2);
+        assert_eq!(waveform.sampling_rate(), 48000);
+        assert_eq!(waveform.num_samples(), 1000);
+        assert!(waveform.loop_flag());
+        assert_eq!((waveform.loop_start(), waveform.loop_end()), (Some(100), Some(900)));
+        assert_eq!(awb_indices(direct), vec![0]);
+        let synth = reader.get_cue_by_id(20).unwrap();
+        assert_eq!(synth.name(), "synth");
+        let streamed = &synth.waveforms()[1];
+        assert!(streamed.streaming());
+        assert_eq!(streamed.encode_type(), AcbEncodeType::Adx);
+        assert_eq!(streamed.loop_start(), None);
+        assert_eq!(awb_indices(synth), vec![1, 5]);
+        assert_eq!(awb_indices(reader.get_cue_by_name("sequence").unwrap()), vec![0, 5]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "adx")]
+    fn decode_adx_waveform() -> Result<(), Box<dyn Error>> {
+        use crate::adx::decoder::{tests::encode_adx, AdxDecoder};
+        use crate::adx::key::AdxKey;
+        let reader = AcbReader::new(sample_acb()?)?;
+        let cue = reader.get_cue_by_id(20).unwrap();
+        let waveform = &cue.waveforms()[1];
+        let source: Vec<i16> = (0..500).map(|i| ((i as f64 * 0.02).sin() * 8000.) as i16).collect();
+        let plain = encode_adx(&source, 1, 32000, None, None);
+        let expected = AdxDecoder::new(&plain)?.decode_all(&plain)?;
+        assert_eq!(waveform.decode(&plain, 0, 0)?, expected);
+        let encrypted = encode_adx(&source, 1, 32000, Some((9, AdxKey::from_keycode(0xCAFE, 0x1234))), None);
+        assert_eq!(waveform.decode(&encrypted, 0xCAFE, 0x1234)?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! # ACF Reader
+//!
+//! An ACF holds the project wide settings shared by a game's ACBs, as tables nested in the data
+//! columns of its header table. Cues refer to categories, AISAC controls and DSP buses by index
+//! into these tables.
+
+use crate::error::Result;
+use crate::acb::header::HighTable;
+use crate::schema::rows::Row;
+use crate::schema::strings::StringPoolFast;
+
+type Table = HighTable<StringPoolFast>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcfCategory {
+    index: usize,
+    name: String,
+    group: u32,
+    volume: f32
+}
+
+impl AcfCategory {
+    /// Index referenced by cues
+    pub fn index(&self) -> usize { self.index }
+    pub fn name(&self) -> &str { &self.name }
+    /// Category group, a cue plays at most one category per group
+    pub fn group(&self) -> u32 { self.group }
+    pub fn volume(&self) -> f32 { self.volume }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcfAisacControl {
+    id: u32,
+    name: String
+}
+
+impl AcfAisacControl {
+    /// Id set by the game to drive the control
+    pub fn id(&self) -> u32 { self.id }
+    pub fn name(&self) -> &str { &self.name }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcfBus {
+    index: usize,
+    name: String,
+    volume: f32
+}
+
+impl AcfBus {
+    pub fn index(&self) -> usize { self.index }
+    pub fn name(&self) -> &str { &self.name }
+    pub fn volume(&self) -> f32 { self.volume }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcfDspSetting {
+    name: String,
+    bus_indexes: Vec<u16>
+}
+
+impl AcfDspSetting {
+    pub fn name(&self) -> &str { &self.name }
+    /// Buses used by the setting, indexes into AcfReader::buses
+    pub fn bus_indexes(&self) -> &[u16] { &self.bus_indexes }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcfGlobalAisac {
+    index: usize,
+    name: String
+}
+
+impl AcfGlobalAisac {
+    pub fn index(&self) -> usize { self.index }
+    pub fn name(&self) -> &str { &self.name }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcfGameVariable {
+    index: usize,
+    name: String,
+    value: f32
+}
+
+impl AcfGameVariable {
+    pub fn index(&self) -> usize { self.index }
+    pub fn name(&self) -> &str { &self.name }
+    /// Initial value
+    pub fn value(&self) -> f32 { self.value }
+}
+
+#[derive(Debug)]
+pub struct AcfReader {
+    name: Option<String>,
+    version: u32,
+    categories: Vec<AcfCategory>,
+    aisac_controls: Vec<AcfAisacControl>,
+    buses: Vec<AcfBus>,
+    dsp_settings: Vec<AcfDspSetting>,
+    global_aisacs: Vec<AcfGlobalAisac>,
+    game_variables: Vec<AcfGameVariable>
+}
+
+impl AcfReader {
+    /// Rows of a nested table, skipping rows without a name
+    fn read_named<T>(header: &Table, names: &[&str], f: impl Fn(&Table, usize, &Row, String) -> T)
+        -> Result<Vec<T>> {
+        // older tools named some tables differently
+        let Some(table) = names.iter().find_map(|n| header.get_table(n).transpose()).transpose()? else {
+            return Ok(vec![]);
+        };
+        Ok(table.get_rows().iter().enumerate()
+            .filter_map(|(i, row)| table.get_str(row, "Name").map(|name| f(&table, i, row, name.to_owned())))
+            .collect())
+    }
+
+    pub fn new(stream: &[u8]) -> Result<Self> {
+        let header = HighTable::new(stream)?;
+        let row = header.get_rows().first();
+        let name = row.and_then(|r| header.get_str(r, "Name")).map(str::to_owned);
+        let version = row.and_then(|r| header.get_uint(r, "Version")).unwrap_or(0);
+        let categories = Self::read_named(&header, &["CategoryTable"], |tbl, index, row, name| AcfCategory {
+            index, name,
+            group: tbl.get_uint(row, "GroupNo").unwrap_or(0),
+            volume: tbl.get_float(row, "Volume").unwrap_or(1.)
+        })?;
+        let aisac_controls = Self::read_named(&header, &["AisacControlNameTable"], |tbl, index, row, name| AcfAisacControl {
+            id: tbl.get_uint(row, "Id").unwrap_or(index as u32), name
+        })?;
+        let buses = Self::read_named(&header, &["DspBusTable", "BusTable"], |tbl, index, row, name| AcfBus {
+            index, name,
+            volume: tbl.get_float(row, "Volume").unwrap_or(1.)
+        })?;
+        let dsp_settings = Self::read_named(&header, &["DspSettingTable"], |tbl, _, row, name| AcfDspSetting {
+            name,
+            bus_indexes: tbl.get_data(row, "BusIndexes").unwrap_or_default()
+                .chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect()
+        })?;
+        let global_aisacs = Self::read_named(&header, &["GlobalAisacReferenceTable"], |_, index, _, name| AcfGlobalAisac {
+            index, name
+        })?;
+        let game_variables = Self::read_named(&header, &["GameVariableTable"], |tbl, index, row, name| AcfGameVariable {
+            index, name,
+            value: tbl.get_float(row, "Value").unwrap_or(0.)
+        })?;
+        Ok(Self { name, version, categories, aisac_controls, buses, dsp_settings, global_aisacs, game_variables })
+    }
+
+    pub fn get_name(&self) -> Option<&str> { self.name.as_deref() }
+    pub fn get_version(&self) -> u32 { self.version }
+    pub fn categories(&self) -> &[AcfCategory] { &self.categories }
+    pub fn aisac_controls(&self) -> &[AcfAisacControl] { &self.aisac_controls }
+    pub fn buses(&self) -> &[AcfBus] { &self.buses }
+    pub fn dsp_settings(&self) -> &[AcfDspSetting] { &self.dsp_settings }
+    pub fn global_aisacs(&self) -> &[AcfGlobalAisac] { &self.global_aisacs }
+    pub fn game_variables(&self) -> &[AcfGameVariable] { &self.game_variables }
+
+    pub fn get_category_by_name(&self, name: &str) -> Option<&AcfCategory> {
+        self.categories.iter().find(|c| c.name == name)
+    }
+    pub fn get_aisac_control_by_name(&self, name: &str) -> Option<&AcfAisacControl> {
+        self.aisac_controls.iter().find(|c| c.name == name)
+    }
+    pub fn get_bus_by_name(&self, name: &str) -> Option<&AcfBus> {
+        self.buses.iter().find(|b| b.name == name)
+    }
+    pub fn get_dsp_setting_by_name(&self, name: &str) -> Option<&AcfDspSetting> {
+        self.dsp_settings.iter().find(|d| d.name == name)
+    }
+    pub fn get_global_aisac_by_name(&self, name: &str) -> Option<&AcfGlobalAisac> {
+        self.global_aisacs.iter().find(|a| a.name == name)
+    }
+    pub fn get_game_variable_by_name(&self, name: &str) -> Option<&AcfGameVariable> {
+        self.game_variables.iter().find(|v| v.name == name)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::acf::reader::AcfReader;
+    use crate::fixtures::sample_acf;
+
+    #[test]
+    fn read_sample_acf() -> Result<(), Box<dyn Error>> {
+        let reader = AcfReader::new(&sample_acf()?)?;
+        assert_eq!(reader.get_name(), Some("sound"));
+        assert_eq!(reader.get_version(), 0x01300000);
+        let voice = reader.get_category_by_name("voice").unwrap();
+        assert_eq!((voice.index(), voice.group(), voice.volume()), (1, 1, 1.));
+        assert_eq!(reader.get_aisac_control_by_name("Distance").map(|c| c.id()), Some(3));
+        let bus = reader.get_bus_by_name("BUS1").unwrap();
+        assert_eq!((bus.index(), bus.volume()), (1, 0.5));
+        assert_eq!(reader.get_dsp_setting_by_name("DspBusSetting_0").unwrap().bus_indexes(), &[0, 1]);
+        assert_eq!(reader.get_game_variable_by_name("Speed").map(|v| v.value()), Some(0.25));
+        assert!(reader.global_aisacs().is_empty());
+        assert!(reader.get_category_by_name("se").is_none());
+        for category in reader.categories() {
+            assert_eq!(reader.get_category_by_name(category.name()).map(|c| c.index()), Some(category.index()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+//! # ADX Decoder
+//!
+//! Audio data is a sequence of frames, alternating between channels. A frame is a 2 byte scale
+//! followed by 4-bit samples, high nibble first. Each sample is predicted from the previous two:
+//!
+//! ```text
+//! sample = nibble * scale + ((coef1 * history1 + coef2 * history2) >> 12)
+//! ```
+//!
+//! A scale with its top bit set marks the end of the stream.
+
+use crate::error::Result;
+use std::f64::consts::{PI, SQRT_2};
+use crate::adx::error::AdxError;
+use crate::adx::header::{AdxEncoding, AdxHeader};
+use crate::adx::key::AdxKey;
+
+/// Coefficients for the fixed encoding, selected by the top 3 bits of the scale
+static FIXED_COEFFICIENTS: [[i32; 2]; 4] = [[0, 0], [0x0F00, 0], [0x1CC0, -0x0D00], [0x1880, -0x0DC0]];
+
+/// Prediction coefficients of the standard and exponential encodings, a 2nd order highpass filter
+pub(crate) fn standard_coefficients(highpass_frequency: u16, sample_rate: u32) -> [i32; 2] {
+    let z = (2. * PI * highpass_frequency as f64 / sample_rate as f64).cos();
+    let a = SQRT_2 - z;
+    let b = SQRT_2 - 1.;
+    let c = (a - ((a + b) * (a - b)).sqrt()) / b;
+    [(c * 8192.).floor() as i32, (c * c * -4096.).floor() as i32]
+}
+
+#[derive(Debug)]
+pub struct AdxDecoder {
+    header: AdxHeader,
+    coefficients: [i32; 2],
+    key: Option<AdxKey>,
+    xor: u16,
+    history: Vec<[i32; 2]>
+}
+
+impl AdxDecoder {
+    /// Create a decoder from the start of an unencrypted ADX file, which must contain the whole
+    /// header
+    pub fn new(data: &[u8]) -> Result<Self> {
+        let header = AdxHeader::parse(data)?;
+        if header.encryption() != 0 {
+            return Err(AdxError::KeyRequired(header.encryption()).into());
+        }
+        Ok(Self::with_header(header, None))
+    }
+
+    /// Create a decoder for a file that may be encrypted. The key is ignored for unencrypted files.
+    pub fn new_with_key(data: &[u8], key: AdxKey) -> Result<Self> {
+        let header = AdxHeader::parse(data)?;
+        let key = (header.encryption() != 0).then_some(key);
+        Ok(Self::with_header(header, key))
+    }
+
+    fn with_header(header: AdxHeader, key: Option<AdxKey>) -> Self {
+        let coefficients = match header.encoding() {
+            AdxEncoding::Standard | AdxEncoding::Exponential =>
+                standard_coefficients(header.highpass_frequency(), header.sample_rate()),
+            // picked per frame
+            AdxEncoding::Fixed => [0, 0]
+        };
+        let history = vec![[0, 0]; header.channels() as usize];
+        let xor = key.map_or(0, |k| k.start());
+        Self { header, coefficients, key, xor, history }
+    }
+
+    pub fn header(&self) -> &AdxHeader { &self.header }
+
+    /// Offset of a frame (covering all channels) from the start of the file
+    pub fn frame_offset(&self, index: u32) -> u64 {
+        self.header.data_offset() as u64 + index as u64 * self.frame_group_size() as u64
+    }
+
+    /// Bytes taken by one frame of every channel
+    fn frame_group_size(&self) -> usize {
+        self.header.frame_size() as usize * self.header.channels() as usize
+    }
+
+    /// Clear the sample history and restart the key, for decoding from the start again
+    pub fn reset(&mut self) {
+        self.history.fill([0, 0]);
+        self.xor = self.key.map_or(0, |k| k.start());
+    }
+
+    fn scale_and_coefficients(&mut self, raw: u16) -> Result<(i32, [i32; 2]), AdxError> {
+        let raw = match self.key {
+            Some(key) => {
+                let raw = raw ^ self.xor;
+                self.xor = key.next(self.xor);
+                raw
+            },
+            None => raw
+        };
+        if raw & 0x8000 != 0 {
+            return Ok((0, self.coefficients));
+        }
+        Ok(match self.header.encoding() {
+            AdxEncoding::Standard => ((raw & 0x1fff) as i32 + 1, self.coefficients),
+            AdxEncoding::Fixed => {
+                let coefficients = *FIXED_COEFFICIENTS.get((raw >> 13) as usize).ok_or(AdxError::InvalidFrameData)?;
+                ((raw & 0x1fff) as i32 + 1, coefficients)
+            },
+            AdxEncoding::Exponential => {
+                let shift = 12u16.checked_sub(raw).ok_or(AdxError::InvalidFrameData)?;
+                (1 << shift, self.coefficients)
+            }
+        })
+    }
+
+    /// Decode one frame for each channel, appending the samples interleaved
+    pub fn decode_frame(&mut self, frames: &[u8], out: &mut Vec<i16>) -> Result<()> {
+        let frame_size = self.header.frame_size() as usize;
+        let channels = self.header.channels() as usize;
+        let frames = frames.get(..self.frame_group_size()).ok_or(AdxError::Truncated)?;
+        let samples = self.header.samples_per_frame();
+        let start = out.len();
+        out.resize(start + samples * channels, 0);
+        for (channel, frame) in frames.chunks_exact(frame_size).enumerate() {
+            let (scale, [coef1, coef2]) = self.scale_and_coefficients(u16::from_be_bytes([frame[0], frame[1]]))?;
+            let [mut history1, mut history2] = self.history[channel];
+            let nibbles = frame[2..].iter().flat_map(|b| [(*b as i8) >> 4, ((*b << 4) as i8) >> 4]);
+            for (i, nibble) in nibbles.enumerate() {
+                let prediction = (coef1 * history1 + coef2 * history2) >> 12;
+                let sample = (nibble as i32 * scale + prediction).clamp(i16::MIN as i32, i16::MAX as i32);
+                out[start + i * channels + channel] = sample as i16;
+                history2 = history1;
+                history1 = sample;
+            }
+            self.history[channel] = [history1, history2];
+        }
+        Ok(())
+    }
+
+    /// Decode a whole ADX file into interleaved 16-bit samples. `data` is the same data the decoder
+    /// was created from.
+    pub fn decode_all(&mut self, data: &[u8]) -> Result<Vec<i16>> {
+        self.reset();
+        let channels = self.header.channels() as usize;
+        // the header's sample count isn't trusted further than the frames that fit in data
+        let frames = self.header.frame_count().min(data.len()
+            .saturating_sub(self.header.data_offset() as usize) / self.frame_group_size().max(1));
+        let mut pcm = Vec::with_capacity(frames.saturating_mul(self.header.samples_per_frame() * channels));
+        for i in 0..self.header.frame_count() as u32 {
+            let frames = data.get(self.frame_offset(i) as usize..).ok_or(AdxError::Truncated)?;
+            self.decode_frame(frames, &mut pcm)?;
+        }
+        pcm.truncate(self.header.sample_count() as usize * channels);
+        Ok(pcm)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::adx::decoder::{standard_coefficients, AdxDecoder};
+    use crate::adx::error::AdxError;
+    use crate::adx::header::tests::build_header;
+    use crate::adx::key::AdxKey;
+    use crate::error::CriError;
+
+    /// Encode interleaved samples as a standard ADX with 18 byte frames. Scales are picked from
+    /// the source, which is good enough for smooth signals.
+    pub(crate) fn encode_adx(samples: &[i16], channels: u8, sample_rate: u32, key: Option<(u8, AdxKey)>,
+                             loop_samples: Option<(u32, u32)>) -> Vec<u8> {
+        let channels = channels as usize;
+        let count = samples.len() / channels;
+        let mut out = build_header(channels as u8, sample_rate, count as u32, key.map_or(0, |k| k.0), loop_samples);
+        let [coef1, coef2] = standard_coefficients(500, sample_rate);
+        let mut history = vec![[0i32, 0i32]; channels];
+        let mut xor = key.map_or(0, |k| k.1.start());
+        for frame in 0..count.div_ceil(32) {
+            for (channel, history) in history.iter_mut().enumerate() {
+                // hold the last sample to pad the final frame
+                let source: Vec<i32> = (frame * 32..frame * 32 + 32)
+                    .map(|i| samples[i.min(count - 1) * channels + channel] as i32).collect();
+                let [mut h1, mut h2] = *history;
+                let mut peak = 0;
+                for (i, s) in source.iter().enumerate() {
+                    let (p1, p2) = match i { 0 => (h1, h2), 1 => (source[0], h1), _ => (source[i - 1], source[i - 2]) };
+                    peak = peak.max((s - ((coef1 * p1 + coef2 * p2) >> 12)).abs());
+                }
+                let scale = (peak / 7 + 1).min(0x2000);
+                let mut stored = (scale - 1) as u16;
+                if let Some((_, key)) = key {
+                    stored ^= xor;
+                    xor = key.next(xor);
+                }
+                out.extend(stored.to_be_bytes());
+                let mut nibbles = vec![];
+                for s in source {
+                    let prediction = (coef1 * h1 + coef2 * h2) >> 12;
+                    let nibble = ((s - prediction) as f64 / scale as f64).round().clamp(-8., 7.) as i32;
+                    let decoded = (nibble * scale + prediction).clamp(-0x8000, 0x7fff);
+                    nibbles.push(nibble as u8 & 0xf);
+                    h2 = h1;
+                    h1 = decoded;
+                }
+                out.extend(nibbles.chunks(2).map(|n| n[0] << 4 | n[1]));
+                *history = [h1, h2];
+            }
+        }
+        out
+    }
+
+    fn sine(count: usize, channels: usize) -> Vec<i16> {
+        (0..count * channels)
+            .map(|i| ((i / channels) as f64 * (0.01 + 0.02 * (i % channels) as f64)).sin() * 12000.)
+            .map(|s| s as i16).collect()
+    }
+
+    #[test]
+    fn decode_round_trip() -> Result<(), Box<dyn Error>> {
+        let source = sine(1000, 2);
+        let adx = encode_adx(&source, 2, 44100, None, Some((100, 900)));
+        let mut decoder = AdxDecoder::new(&adx)?;
+        let pcm = decoder.decode_all(&adx)?;
+        assert_eq!(pcm.len(), source.len());
+        let error = pcm.iter().zip(&source).map(|(a, b)| (*a as i32 - *b as i32).abs()).max().unwrap();
+        assert!(error < 300, "{error}");
+        // decoding again gives the same result
+        assert_eq!(decoder.decode_all(&adx)?, pcm);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_encrypted() -> Result<(), Box<dyn Error>> {
+        let source = sine(500, 1);
+        let expected = AdxDecoder::new(&encode_adx(&source, 1, 32000, None, None))?.decode_all(&encode_adx(&source, 1, 32000, None, None))?;
+        for (key_type, key) in [(8, AdxKey::from_key_string("karaage")), (9, AdxKey::from_keycode(0x30DBE1AB, 0x4d2))] {
+            let adx = encode_adx(&source, 1, 32000, Some((key_type, key)), None);
+            let error = AdxDecoder::new(&adx).unwrap_err();
+            assert!(matches!(error, CriError::Adx(AdxError::KeyRequired(t)) if t == key_type));
+            assert_eq!(AdxDecoder::new_with_key(&adx, key)?.decode_all(&adx)?, expected);
+            let wrong = AdxDecoder::new_with_key(&adx, AdxKey::from_keycode(1, 0))?.decode_all(&adx)?;
+            assert_ne!(wrong, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decode_fixed_coefficients() -> Result<(), Box<dyn Error>> {
+        let mut adx = build_header(1, 22050, 32, 0, None);
+        adx[4] = 2;
+        // coefficient set 2, scale 10
+        adx.extend(((2u16 << 13) | 9).to_be_bytes());
+        adx.extend([0x7f, 0x80, 0x11]);
+        adx.resize(adx.len() + 13, 0);
+        let pcm = AdxDecoder::new(&adx)?.decode_all(&adx)?;
+        let (mut h1, mut h2) = (0, 0);
+        let nibbles = [7, -1, -8, 0, 1, 1];
+        for (i, sample) in pcm.iter().enumerate() {
+            let expected = nibbles.get(i).copied().unwrap_or(0) * 10 + ((0x1CC0 * h1 - 0x0D00 * h2) >> 12);
+            assert_eq!(*sample as i32, expected);
+            (h2, h1) = (h1, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decode_exponential() -> Result<(), Box<dyn Error>> {
+        let mut adx = build_header(1, 22050, 32, 0, None);
+        adx[4] = 4;
+        // scale is 1 << (12 - 9)
+        adx.extend(9u16.to_be_bytes());
+        adx.extend([0x7f, 0x80, 0x11]);
+        adx.resize(adx.len() + 13, 0);
+        let mut decoder = AdxDecoder::new(&adx)?;
+        let pcm = decoder.decode_all(&adx)?;
+        // same highpass filter as the standard encoding
+        let [coef1, coef2] = standard_coefficients(decoder.header().highpass_frequency(), 22050);
+        let (mut h1, mut h2) = (0, 0);
+        let nibbles = [7, -1, -8, 0, 1, 1];
+        for (i, sample) in pcm.iter().enumerate() {
+            let expected = nibbles.get(i).copied().unwrap_or(0) * 8 + ((coef1 * h1 + coef2 * h2) >> 12);
+            assert_eq!(*sample as i32, expected);
+            (h2, h1) = (h1, expected);
+        }
+        assert!(pcm[6..].iter().any(|s| *s != 0));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_truncated() -> Result<(), Box<dyn Error>> {
+        let adx = encode_adx(&sine(100, 1), 1, 32000, None, None);
+        let mut decoder = AdxDecoder::new(&adx)?;
+        let error = decoder.decode_all(&adx[..adx.len() - 1]).unwrap_err();
+        assert!(matches!(error, CriError::Adx(AdxError::Truncated)));
+        // sample count far past the end of the data
+        let adx = build_header(1, 32000, u32::MAX, 0, None);
+        let error = AdxDecoder::new(&adx)?.decode_all(&adx).unwrap_err();
+        assert!(matches!(error, CriError::Adx(AdxError::Truncated)));
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+#[derive(Debug)]
+pub enum AdxError {
+    /// Data doesn't start with 0x8000
+    InvalidMagic,
+    /// Header or frame extends past the end of the data
+    Truncated,
+    /// "(c)CRI" isn't right before the audio data
+    InvalidCopyright,
+    /// Only fixed (2), standard (3) and exponential (4) encodings are supported, AHX isn't
+    UnsupportedEncoding(u8),
+    /// Only 4-bit samples exist
+    UnsupportedBitDepth(u8),
+    /// Only versions 3, 4 and 5 are supported
+    UnsupportedVersion(u8),
+    /// Only key types 8 and 9 exist
+    UnsupportedEncryption(u8),
+    /// File is encrypted with this key type, see `AdxDecoder::new_with_key`
+    KeyRequired(u8),
+    /// Header field is outside of its valid range
+    InvalidValue(&'static str),
+    /// Frame's scale can't be used with the file's encoding
+    InvalidFrameData,
+}
+
+impl Error for AdxError {}
+impl Display for AdxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Debug>::fmt(self, f)
+    }
+}
@@ -0,0 +1,238 @@
+//! # ADX Header
+//!
+//! All values are big endian.
+//!
+//! ```text
+//! 0x0: 0x8000
+//! 0x2: copyright offset (u16), audio data starts 4 bytes after it, right after "(c)CRI"
+//! 0x4: encoding (u8), frame size (u8), bits per sample (u8), channels (u8)
+//! 0x8: sample rate (u32)
+//! 0xc: sample count (u32)
+//! 0x10: highpass frequency (u16), version (u8), encryption (u8)
+//! ```
+//!
+//! Loop points follow, at 0x14 in version 3 and after the channels' initial history (at 0x18) in
+//! version 4. Each is a u32 loop flag at +0x4, then start sample, start byte, end sample and end
+//! byte. Version 5 files don't loop.
+
+use crate::adx::error::AdxError;
+use crate::utils::endianness::BigEndian;
+use crate::utils::slice::FromSlice;
+
+const ADX_MAGIC: u16 = 0x8000;
+const COPYRIGHT: &[u8; 6] = b"(c)CRI";
+const LOOP_SIZE: usize = 0x18;
+
+/// How each frame's scale and prediction coefficients are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdxEncoding {
+    /// Coefficients picked per frame from a fixed set
+    Fixed,
+    /// Coefficients derived from the highpass frequency
+    Standard,
+    /// Coefficients derived from the highpass frequency and a power of 2 scale
+    Exponential
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdxLoop {
+    start_sample: u32,
+    start_byte: u32,
+    end_sample: u32,
+    end_byte: u32
+}
+
+impl AdxLoop {
+    pub fn start_sample(&self) -> u32 { self.start_sample }
+    pub fn start_byte(&self) -> u32 { self.start_byte }
+    /// Exclusive
+    pub fn end_sample(&self) -> u32 { self.end_sample }
+    pub fn end_byte(&self) -> u32 { self.end_byte }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdxHeader {
+    data_offset: u16,
+    encoding: AdxEncoding,
+    frame_size: u8,
+    channels: u8,
+    sample_rate: u32,
+    sample_count: u32,
+    highpass_frequency: u16,
+    version: u8,
+    encryption: u8,
+    loop_info: Option<AdxLoop>
+}
+
+impl AdxHeader {
+    pub fn is_adx(data: &[u8]) -> bool {
+        data.len() >= 2 && u16::from_slice::<BigEndian>(data, 0) == ADX_MAGIC
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, AdxError> {
+        if data.len() < 0x14 {
+            return Err(AdxError::Truncated);
+        }
+        if !Self::is_adx(data) {
+            return Err(AdxError::InvalidMagic);
+        }
+        let data_offset = u16::from_slice::<BigEndian>(data, 2).checked_add(4)
+            .ok_or(AdxError::InvalidValue("data_offset"))?;
+        let header = data.get(..data_offset as usize).ok_or(AdxError::Truncated)?;
+        if data_offset < 0x14 + 6 || &header[data_offset as usize - 6..] != COPYRIGHT {
+            return Err(AdxError::InvalidCopyright);
+        }
+        let encoding = match header[4] {
+            2 => AdxEncoding::Fixed,
+            3 => AdxEncoding::Standard,
+            4 => AdxEncoding::Exponential,
+            e => return Err(AdxError::UnsupportedEncoding(e))
+        };
+        let frame_size = header[5];
+        if header[6] != 4 {
+            return Err(AdxError::UnsupportedBitDepth(header[6]));
+        }
+        let channels = header[7];
+        let sample_rate = u32::from_slice::<BigEndian>(header, 8);
+        let sample_count = u32::from_slice::<BigEndian>(header, 0xc);
+        let highpass_frequency = u16::from_slice::<BigEndian>(header, 0x10);
+        let version = header[0x12];
+        let encryption = header[0x13];
+        if frame_size < 3 {
+            return Err(AdxError::InvalidValue("frame_size"));
+        }
+        if channels == 0 {
+            return Err(AdxError::InvalidValue("channels"));
+        }
+        if sample_rate == 0 {
+            return Err(AdxError::InvalidValue("sample_rate"));
+        }
+        if !matches!(encryption, 0 | 8 | 9) {
+            return Err(AdxError::UnsupportedEncryption(encryption));
+        }
+        let loop_offset = match version {
+            3 => Some(0x14),
+            // initial history, at least 8 bytes
+            4 => Some(0x18 + (4 * channels as usize).max(8)),
+            5 => None,
+            v => return Err(AdxError::UnsupportedVersion(v))
+        };
+        // loop points are only there if the header has room for them
+        let loop_info = loop_offset
+            .filter(|o| o + LOOP_SIZE <= data_offset as usize - 6)
+            .filter(|o| u32::from_slice::<BigEndian>(header, o + 4) != 0)
+            .map(|o| AdxLoop {
+                start_sample: u32::from_slice::<BigEndian>(header, o + 8),
+                start_byte: u32::from_slice::<BigEndian>(header, o + 0xc),
+                end_sample: u32::from_slice::<BigEndian>(header, o + 0x10),
+                end_byte: u32::from_slice::<BigEndian>(header, o + 0x14)
+            });
+        if let Some(l) = &loop_info && l.start_sample > l.end_sample {
+            return Err(AdxError::InvalidValue("loop"));
+        }
+        Ok(Self {
+            data_offset, encoding, frame_size, channels, sample_rate, sample_count, highpass_frequency,
+            version, encryption, loop_info
+        })
+    }
+
+    /// Offset of the first frame
+    pub fn data_offset(&self) -> u16 { self.data_offset }
+    pub fn encoding(&self) -> AdxEncoding { self.encoding }
+    /// Size of a frame for a single channel, including its 2 byte scale
+    pub fn frame_size(&self) -> u8 { self.frame_size }
+    pub fn channels(&self) -> u8 { self.channels }
+    pub fn sample_rate(&self) -> u32 { self.sample_rate }
+    /// Samples per channel
+    pub fn sample_count(&self) -> u32 { self.sample_count }
+    pub fn highpass_frequency(&self) -> u16 { self.highpass_frequency }
+    pub fn version(&self) -> u8 { self.version }
+    /// Key type, 0 if the file isn't encrypted
+    pub fn encryption(&self) -> u8 { self.encryption }
+    pub fn loop_info(&self) -> Option<&AdxLoop> { self.loop_info.as_ref() }
+
+    pub fn samples_per_frame(&self) -> usize {
+        (self.frame_size as usize - 2) * 2
+    }
+
+    /// Number of frames per channel
+    pub fn frame_count(&self) -> usize {
+        (self.sample_count as usize).div_ceil(self.samples_per_frame())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::adx::error::AdxError;
+    use crate::adx::header::{AdxEncoding, AdxHeader};
+
+    /// Version 4 header with room for loop points
+    pub(crate) fn build_header(channels: u8, sample_rate: u32, sample_count: u32, encryption: u8,
+                               loop_samples: Option<(u32, u32)>) -> Vec<u8> {
+        let history = (4 * channels as usize).max(8);
+        let data_offset = (0x18 + history + 0x18 + 6).next_multiple_of(4);
+        let mut out = vec![0x80, 0];
+        out.extend((data_offset as u16 - 4).to_be_bytes());
+        out.extend([3, 18, 4, channels]);
+        out.extend(sample_rate.to_be_bytes());
+        out.extend(sample_count.to_be_bytes());
+        out.extend(500u16.to_be_bytes());
+        out.extend([4, encryption, 0, 0, 0, 0]);
+        out.resize(0x18 + history, 0);
+        let (flag, (start, end)) = (loop_samples.is_some() as u32, loop_samples.unwrap_or_default());
+        let frame_bytes = |s: u32| data_offset as u32 + s / 32 * 18 * channels as u32;
+        for value in [0, flag, start, frame_bytes(start), end, frame_bytes(end)] {
+            out.extend(value.to_be_bytes());
+        }
+        out.resize(data_offset - 6, 0);
+        out.extend(b"(c)CRI");
+        out
+    }
+
+    #[test]
+    fn parse_header() -> Result<(), Box<dyn Error>> {
+        let data = build_header(2, 44100, 1000, 9, Some((100, 900)));
+        let header = AdxHeader::parse(&data)?;
+        assert_eq!(header.data_offset() as usize, data.len());
+        assert_eq!(header.encoding(), AdxEncoding::Standard);
+        assert_eq!(header.frame_size(), 18);
+        assert_eq!(header.channels(), 2);
+        assert_eq!(header.sample_rate(), 44100);
+        assert_eq!(header.sample_count(), 1000);
+        assert_eq!(header.highpass_frequency(), 500);
+        assert_eq!(header.version(), 4);
+        assert_eq!(header.encryption(), 9);
+        assert_eq!(header.samples_per_frame(), 32);
+        assert_eq!(header.frame_count(), 32);
+        let l = header.loop_info().unwrap();
+        assert_eq!((l.start_sample(), l.end_sample()), (100, 900));
+        assert_eq!(l.start_byte(), data.len() as u32 + 3 * 36);
+        assert!(AdxHeader::parse(&build_header(1, 44100, 1000, 0, None))?.loop_info().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid() -> Result<(), Box<dyn Error>> {
+        let error = |data: &[u8]| AdxHeader::parse(data).unwrap_err();
+        let data = build_header(1, 22050, 64, 0, None);
+        assert!(matches!(error(&data[..0x10]), AdxError::Truncated));
+        assert!(matches!(error(&data[..data.len() - 1]), AdxError::Truncated));
+        let mut bad = data.clone();
+        bad[0] = 0;
+        assert!(matches!(error(&bad), AdxError::InvalidMagic));
+        let mut bad = data.clone();
+        *bad.last_mut().unwrap() = b'X';
+        assert!(matches!(error(&bad), AdxError::InvalidCopyright));
+        let mut bad = data.clone();
+        bad[4] = 0x10;
+        assert!(matches!(error(&bad), AdxError::UnsupportedEncoding(0x10)));
+        let mut bad = data.clone();
+        bad[0x13] = 1;
+        assert!(matches!(error(&bad), AdxError::UnsupportedEncryption(1)));
+        let mut bad = data.clone();
+        bad[0x12] = 6;
+        assert!(matches!(error(&bad), AdxError::UnsupportedVersion(6)));
+        Ok(())
+    }
+}
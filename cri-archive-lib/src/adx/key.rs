@@ -0,0 +1,110 @@
+//! # ADX Encryption
+//!
+//! Encrypted ADX files XOR each frame's scale with a 15-bit value from a linear congruential
+//! generator, `xor = (xor * mult + add) & 0x7FFF`, stepped once per frame in file order. The
+//! generator's start, multiplier and increment come from the key:
+//!
+//! - **Type 8**: Derived from a key string, through a table of primes
+//! - **Type 9**: Derived from a 64-bit keycode, the same one used by the game's HCA files
+
+/// First 1024 primes above 0x4000, used to derive type 8 keys
+static KEY8_PRIMES: [u16; 0x400] = key8_primes();
+
+const fn key8_primes() -> [u16; 0x400] {
+    let mut primes = [0; 0x400];
+    let mut count = 0;
+    let mut candidate = 0x4000u32;
+    while count < primes.len() {
+        let mut divisor = 2;
+        while divisor * divisor <= candidate && !candidate.is_multiple_of(divisor) {
+            divisor += 1;
+        }
+        if divisor * divisor > candidate {
+            primes[count] = candidate as u16;
+            count += 1;
+        }
+        candidate += 1;
+    }
+    primes
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdxKey {
+    start: u16,
+    mult: u16,
+    add: u16
+}
+
+impl AdxKey {
+    pub fn new(start: u16, mult: u16, add: u16) -> Self {
+        Self { start, mult, add }
+    }
+
+    /// Type 8 key from its key string. An empty string gives an all zero key.
+    pub fn from_key_string(key: &str) -> Self {
+        if key.is_empty() {
+            return Self::new(0, 0, 0);
+        }
+        let mut values = [KEY8_PRIMES[0x100], KEY8_PRIMES[0x200], KEY8_PRIMES[0x300]];
+        for c in key.bytes() {
+            // CRI's library indexes with a signed char
+            let prime = KEY8_PRIMES[(c as i8 as i32 + 0x80) as usize] as u32;
+            for value in &mut values {
+                *value = KEY8_PRIMES[(*value as u32 * prime % 0x400) as usize];
+            }
+        }
+        Self::new(values[0], values[1], values[2])
+    }
+
+    /// Type 9 key from a 64-bit keycode. `subkey` comes from the AWB the file is stored in, and is
+    /// 0 for standalone files.
+    pub fn from_keycode(keycode: u64, subkey: u16) -> Self {
+        let mut keycode = match subkey {
+            0 => keycode,
+            s => keycode.wrapping_mul(((s as u64) << 16) | ((!s) as u64 + 2))
+        };
+        // the encoder doesn't accept 0
+        keycode = keycode.saturating_sub(1);
+        Self::new(
+            ((keycode >> 27) & 0x7fff) as u16,
+            (((keycode >> 12) & 0x7ffc) | 1) as u16,
+            (((keycode << 1) & 0x7fff) | 1) as u16
+        )
+    }
+
+    pub fn start(&self) -> u16 { self.start }
+    pub fn mult(&self) -> u16 { self.mult }
+    pub fn add(&self) -> u16 { self.add }
+
+    /// Value to XOR with the scale of the next frame
+    pub(crate) fn next(&self, xor: u16) -> u16 {
+        (xor as u32 * self.mult as u32 + self.add as u32) as u16 & 0x7fff
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::adx::key::{AdxKey, KEY8_PRIMES};
+
+    #[test]
+    fn key_string() -> Result<(), Box<dyn Error>> {
+        assert_eq!(KEY8_PRIMES[0], 0x401B);
+        // Okami, Killer7, Phantasy Star Universe
+        assert_eq!(AdxKey::from_key_string("karaage"), AdxKey::new(0x49e1, 0x4a57, 0x553d));
+        assert_eq!(AdxKey::from_key_string("GHM"), AdxKey::new(0x50fb, 0x5803, 0x5701));
+        assert_eq!(AdxKey::from_key_string("3x5k62bg9ptbwy"), AdxKey::new(0x5deb, 0x5f27, 0x673f));
+        assert_eq!(AdxKey::from_key_string(""), AdxKey::new(0, 0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn keycode() -> Result<(), Box<dyn Error>> {
+        let key = AdxKey::from_keycode(0x100_0000_0001, 0);
+        assert_eq!((key.start(), key.mult(), key.add()), (0x2000, 0x0001, 0x0001));
+        assert_eq!(AdxKey::from_keycode(0, 0), AdxKey::new(0, 1, 1));
+        assert_ne!(AdxKey::from_keycode(12345, 0x55aa), AdxKey::from_keycode(12345, 0));
+        assert_eq!(key.next(0x7fff), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+#[derive(Debug)]
+pub enum AwbError {
+    /// Data doesn't start with AFS2
+    InvalidMagic,
+    /// Header or tables extend past the end of the data
+    Truncated,
+    /// Offsets can only be 2, 4 or 8 bytes
+    UnsupportedOffsetSize(u8),
+    /// Cue IDs can only be 2 or 4 bytes
+    UnsupportedIdSize(u16),
+    /// Entry at this index lies outside the data
+    InvalidOffset(usize),
+}
+
+impl Error for AwbError {}
+impl Display for AwbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Debug>::fmt(self, f)
+    }
+}
@@ -0,0 +1,232 @@
+//! # AWB (AFS2) Reader
+//!
+//! AWB files contain the waveforms played by an ACB's cues. They're either stored next to the ACB
+//! (for streamed waveforms) or embedded in the ACB's `AwbFile` column (for in-memory waveforms).
+//!
+//! ```text
+//! 0x0: "AFS2"
+//! 0x4: version (u8), offset size (u8), cue ID size (u16)
+//! 0x8: entry count (u32)
+//! 0xc: alignment (u16), HCA subkey (u16)
+//! 0x10: cue IDs (entry count * cue ID size)
+//!       offsets ((entry count + 1) * offset size)
+//! ```
+//!
+//! All values are little endian. Each offset is the end of the previous entry, so an entry starts
+//! at its offset rounded up to the alignment, and ends at the next offset.
+
+use crate::error::Result;
+use crate::awb::error::AwbError;
+use crate::utils::endianness::LittleEndian;
+use crate::utils::slice::FromSlice;
+
+static AFS2_MAGIC: [u8; 4] = *b"AFS2";
+const HEADER_SIZE: usize = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AwbEntry {
+    cue_id: u32,
+    offset: u64,
+    size: u64
+}
+
+impl AwbEntry {
+    /// ID used by the ACB's Waveform table to refer to this entry
+    pub fn cue_id(&self) -> u32 { self.cue_id }
+    /// Absolute offset of the entry's data
+    pub fn offset(&self) -> u64 { self.offset }
+    pub fn size(&self) -> u64 { self.size }
+}
+
+#[derive(Debug)]
+pub struct AwbReader<D: AsRef<[u8]> = Vec<u8>> {
+    data: D,
+    version: u8,
+    offset_size: u8,
+    id_size: u16,
+    alignment: u16,
+    subkey: u16,
+    entries: Vec<AwbEntry>
+}
+
+impl<D: AsRef<[u8]>> AwbReader<D> {
+    pub fn new(data: D) -> Result<Self> {
+        let raw = data.as_ref();
+        if raw.len() < HEADER_SIZE {
+            return Err(AwbError::Truncated.into());
+        }
+        if raw[..4] != AFS2_MAGIC {
+            return Err(AwbError::InvalidMagic.into());
+        }
+        let version = raw[4];
+        let offset_size = raw[5];
+        let id_size = u16::from_slice::<LittleEndian>(raw, 6);
+        let count = u32::from_slice::<LittleEndian>(raw, 8) as usize;
+        let alignment = u16::from_slice::<LittleEndian>(raw, 0xc);
+        let subkey = u16::from_slice::<LittleEndian>(raw, 0xe);
+        if !matches!(offset_size, 2 | 4 | 8) {
+            return Err(AwbError::UnsupportedOffsetSize(offset_size).into());
+        }
+        if !matches!(id_size, 2 | 4) {
+            return Err(AwbError::UnsupportedIdSize(id_size).into());
+        }
+        let offsets_start = count.checked_mul(id_size as usize)
+            .and_then(|s| s.checked_add(HEADER_SIZE)).ok_or(AwbError::Truncated)?;
+        let tables_end = count.checked_add(1)
+            .and_then(|c| c.checked_mul(offset_size as usize))
+            .and_then(|s| s.checked_add(offsets_start)).ok_or(AwbError::Truncated)?;
+        if tables_end > raw.len() {
+            return Err(AwbError::Truncated.into());
+        }
+        let get_id = |i: usize| {
+            let ofs = HEADER_SIZE + i * id_size as usize;
+            match id_size {
+                2 => u16::from_slice::<LittleEndian>(raw, ofs) as u32,
+                _ => u32::from_slice::<LittleEndian>(raw, ofs)
+            }
+        };
+        let get_offset = |i: usize| {
+            let ofs = offsets_start + i * offset_size as usize;
+            match offset_size {
+                2 => u16::from_slice::<LittleEndian>(raw, ofs) as u64,
+                4 => u32::from_slice::<LittleEndian>(raw, ofs) as u64,
+                _ => u64::from_slice::<LittleEndian>(raw, ofs)
+            }
+        };
+        let align = (alignment as u64).max(1);
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = get_offset(i).checked_next_multiple_of(align)
+                .ok_or(AwbError::InvalidOffset(i))?;
+            let end = get_offset(i + 1);
+            if end > raw.len() as u64 {
+                return Err(AwbError::InvalidOffset(i).into());
+            }
+            // empty entries may end before their aligned start
+            entries.push(AwbEntry { cue_id: get_id(i), offset, size: end.saturating_sub(offset) });
+        }
+        Ok(Self { data, version, offset_size, id_size, alignment, subkey, entries })
+    }
+
+    pub fn version(&self) -> u8 { self.version }
+    pub fn offset_size(&self) -> u8 { self.offset_size }
+    pub fn id_size(&self) -> u16 { self.id_size }
+    pub fn alignment(&self) -> u16 { self.alignment }
+    /// Mixed into the HCA key for waveforms in this AWB
+    pub fn subkey(&self) -> u16 { self.subkey }
+    pub fn entries(&self) -> &[AwbEntry] { &self.entries }
+    pub fn len(&self) -> usize { self.entries.len() }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    pub fn get_entry(&self, index: usize) -> Option<&AwbEntry> {
+        self.entries.get(index)
+    }
+
+    pub fn find_by_cue_id(&self, cue_id: u32) -> Option<&AwbEntry> {
+        self.entries.iter().find(|e| e.cue_id == cue_id)
+    }
+
+    pub fn get_data(&self, entry: &AwbEntry) -> &[u8] {
+        let data = self.data.as_ref();
+        let start = (entry.offset as usize).min(data.len());
+        &data[start..start + (entry.size as usize).min(data.len() - start)]
+    }
+
+    /// Data of the entry at index, in the order they're stored
+    pub fn get_by_index(&self, index: usize) -> Option<&[u8]> {
+        self.get_entry(index).map(|e| self.get_data(e))
+    }
+
+    pub fn get_by_cue_id(&self, cue_id: u32) -> Option<&[u8]> {
+        self.find_by_cue_id(cue_id).map(|e| self.get_data(e))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::awb::error::AwbError;
+    use crate::awb::reader::AwbReader;
+    use crate::error::CriError;
+
+    /// Build a version 2 AFS2 archive with 4 byte offsets
+    pub(crate) fn build_afs2(files: &[(u16, &[u8])], alignment: u16, subkey: u16) -> Vec<u8> {
+        let mut out = b"AFS2".to_vec();
+        out.extend([2, 4]);
+        out.extend(2u16.to_le_bytes());
+        out.extend((files.len() as u32).to_le_bytes());
+        out.extend(alignment.to_le_bytes());
+        out.extend(subkey.to_le_bytes());
+        for (id, _) in files {
+            out.extend(id.to_le_bytes());
+        }
+        let mut offset = out.len() + (files.len() + 1) * 4;
+        let mut data = vec![];
+        for (_, file) in files {
+            out.extend((offset as u32).to_le_bytes());
+            let start = offset.next_multiple_of(alignment as usize);
+            data.resize(data.len() + start - offset, 0);
+            data.extend(*file);
+            offset = start + file.len();
+        }
+        out.extend((offset as u32).to_le_bytes());
+        out.extend(data);
+        out
+    }
+
+    #[test]
+    fn read_entries() -> Result<(), Box<dyn Error>> {
+        let files: [(u16, &[u8]); 3] = [(5, b"first waveform"), (2, b""), (9, &[0xff; 0x45])];
+        let awb = AwbReader::new(build_afs2(&files, 0x20, 0x1234))?;
+        assert_eq!(awb.version(), 2);
+        assert_eq!(awb.offset_size(), 4);
+        assert_eq!(awb.id_size(), 2);
+        assert_eq!(awb.alignment(), 0x20);
+        assert_eq!(awb.subkey(), 0x1234);
+        assert_eq!(awb.len(), 3);
+        for (entry, (id, data)) in awb.entries().iter().zip(files) {
+            assert_eq!(entry.offset() % 0x20, 0);
+            assert_eq!(entry.cue_id(), id as u32);
+            assert_eq!(awb.get_data(entry), data);
+        }
+        assert_eq!(awb.get_by_index(0), Some(&b"first waveform"[..]));
+        assert_eq!(awb.get_by_cue_id(9), Some(&[0xff; 0x45][..]));
+        assert_eq!(awb.get_by_cue_id(2), Some(&[][..]));
+        assert!(awb.get_by_cue_id(3).is_none());
+        assert!(awb.get_by_index(3).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn read_borrowed() -> Result<(), Box<dyn Error>> {
+        let data = build_afs2(&[(0, b"borrowed")], 1, 0);
+        let awb = AwbReader::new(data.as_slice())?;
+        assert_eq!(awb.get_by_index(0), Some(&b"borrowed"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid() -> Result<(), Box<dyn Error>> {
+        let data = build_afs2(&[(0, b"some waveform data")], 0x20, 0);
+        let error = |data: &[u8]| match AwbReader::new(data).unwrap_err() {
+            CriError::Awb(e) => e,
+            e => panic!("{}", e)
+        };
+        assert!(matches!(error(&data[..0xc]), AwbError::Truncated));
+        assert!(matches!(error(&data[..0x18]), AwbError::Truncated));
+        assert!(matches!(error(&data[..data.len() - 1]), AwbError::InvalidOffset(0)));
+        let mut bad = data.clone();
+        bad[0] = b'B';
+        assert!(matches!(error(&bad), AwbError::InvalidMagic));
+        let mut bad = data.clone();
+        bad[5] = 3;
+        assert!(matches!(error(&bad), AwbError::UnsupportedOffsetSize(3)));
+        // aligning the offset would overflow
+        let mut bad = build_afs2(&[(0, b"")], 0x20, 0).into_iter().take(0x12).collect::<Vec<u8>>();
+        bad[5] = 8;
+        bad.extend(u64::MAX.to_le_bytes());
+        bad.extend(0u64.to_le_bytes());
+        assert!(matches!(error(&bad), AwbError::InvalidOffset(0)));
+        Ok(())
+    }
+}
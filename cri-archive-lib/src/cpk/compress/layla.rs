@@ -43,6 +43,8 @@
 //! If the max value is returned, we read next number of bits in fib sequence, up to 8 bits. Then
 //! read 8s until max value no longer returned.
 
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
 use crate::cpk::free_list::{FreeList, FreeListNode};
 use crate::from_slice;
 use crate::utils::slice::FromSlice;
@@ -56,7 +58,7 @@ static BIT_MASK: [u32; 16] = [
 ];
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct LaylaHeader {
     magic: u64,
     uncompressed_size: u32,
@@ -67,6 +69,35 @@ impl LaylaHeader {
     pub fn from_stream(file: &[u8]) -> &Self {
         unsafe { &*(file.as_ptr() as *const Self) }
     }
+
+    /// Read and validate the header of untrusted input. The uncompressed data must fit inside the
+    /// input and the decompressed size must be reachable from the size of the bitstream.
+    pub fn parse(file: &[u8]) -> Result<Self, LaylaError> {
+        if file.len() < size_of::<Self>() {
+            return Err(LaylaError::TruncatedHeader(file.len()));
+        }
+        let header = Self {
+            magic: from_slice!(file, u64, LittleEndian),
+            uncompressed_size: from_slice!(file, u32, LittleEndian, 0x8),
+            uncompressed_header_offset: from_slice!(file, u32, LittleEndian, 0xc)
+        };
+        if header.magic != LAYLA_HEADER_MAGIC {
+            return Err(LaylaError::InvalidMagic);
+        }
+        let data_len = (file.len() - size_of::<Self>()) as u64;
+        if header.uncompressed_header_offset as u64 + LaylaDecompressor::UNCOMPRESSED_DATA_SIZE as u64 > data_len {
+            return Err(LaylaError::InvalidUncompressedOffset(header.uncompressed_header_offset));
+        }
+        // The longest copy command encodes 255 bytes in 8 bits
+        if header.uncompressed_size as u64 > (header.uncompressed_header_offset as u64 + 1) * 0x100 {
+            return Err(LaylaError::InvalidUncompressedSize(header.uncompressed_size));
+        }
+        Ok(header)
+    }
+
+    pub fn uncompressed_size(&self) -> u32 { self.uncompressed_size }
+
+    pub fn uncompressed_header_offset(&self) -> u32 { self.uncompressed_header_offset }
 }
 
 #[derive(Debug)]
@@ -203,6 +234,38 @@ impl LaylaDecompressorCursor {
     }
 }
 
+/// Bounds checked equivalent of `LaylaDecompressorCursor`, reading backwards from the end of the
+/// bitstream and failing instead of reading past its start.
+#[derive(Debug)]
+pub(crate) struct LaylaCheckedCursor<'a> {
+    cdata: &'a [u8],
+    position: usize,
+    bits_left: usize
+}
+
+impl<'a> LaylaCheckedCursor<'a> {
+    pub fn new(cdata: &'a [u8]) -> Self {
+        Self { cdata, position: cdata.len(), bits_left: 0 }
+    }
+
+    /// Read up to 8 bits
+    #[inline]
+    pub fn read(&mut self, mut bits: usize) -> Result<u32, LaylaError> {
+        let mut res = 0;
+        while bits > 0 {
+            if self.bits_left == 0 {
+                self.position = self.position.checked_sub(1).ok_or(LaylaError::TruncatedStream)?;
+                self.bits_left = 8;
+            }
+            let bit_round = bits.min(self.bits_left);
+            self.bits_left -= bit_round;
+            res = (res << bit_round) | ((self.cdata[self.position] as u32 >> self.bits_left) & BIT_MASK[bit_round]);
+            bits -= bit_round;
+        }
+        Ok(res)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct LaylaDecompressorImpl<'a> {
     header: &'a LaylaHeader,
@@ -313,9 +376,11 @@ impl LaylaDecompressor {
     const UNCOMPRESSED_DATA_SIZE: usize = 0x100;
 
     pub fn is_compressed(input: &[u8]) -> bool {
-        from_slice!(input, u64, LittleEndian) == LAYLA_HEADER_MAGIC
+        input.len() >= size_of::<LaylaHeader>() && from_slice!(input, u64, LittleEndian) == LAYLA_HEADER_MAGIC
     }
 
+    /// Fast path for trusted input, corrupt streams can read and write out of bounds.
+    /// Use try_decompress for untrusted input.
     pub fn decompress(input: &[u8], free_list: &mut FreeList) -> FreeListNode {
         let header = LaylaHeader::from_stream(input);
         let mut result = free_list.allocate(header.uncompressed_size as usize + Self::UNCOMPRESSED_DATA_SIZE);
@@ -325,15 +390,320 @@ impl LaylaDecompressor {
         dcmp_impl.decompress();
         result
     }
+
+    /// Size of the input after decompression
+    pub fn get_decompressed_size(input: &[u8]) -> usize {
+        LaylaHeader::from_stream(input).uncompressed_size as usize + Self::UNCOMPRESSED_DATA_SIZE
+    }
+
+    /// Decompress untrusted input, validating the header and every command in the bitstream.
+    pub fn try_decompress(input: &[u8]) -> Result<Vec<u8>, LaylaError> {
+        let mut output = vec![];
+        Self::try_decompress_into(input, &mut output)?;
+        Ok(output)
+    }
+
+    /// Same as try_decompress, reusing the output buffer. On error, the contents of the buffer are
+    /// unspecified.
+    pub fn try_decompress_into(input: &[u8], output: &mut Vec<u8>) -> Result<(), LaylaError> {
+        let header = LaylaHeader::parse(input)?;
+        output.clear();
+        output.resize(header.uncompressed_size as usize + Self::UNCOMPRESSED_DATA_SIZE, 0);
+        Self::try_decompress_checked(&header, input, output)
+    }
+
+    /// Same as try_decompress, allocating the output from a free list like decompress
+    pub fn try_decompress_with(input: &[u8], free_list: &mut FreeList) -> Result<FreeListNode, LaylaError> {
+        let header = LaylaHeader::parse(input)?;
+        let mut output = free_list.allocate(header.uncompressed_size as usize + Self::UNCOMPRESSED_DATA_SIZE);
+        Self::try_decompress_checked(&header, input, output.as_mut_slice())?;
+        Ok(output)
+    }
+
+    /// Output must be exactly the decompressed size of a header returned by LaylaHeader::parse
+    fn try_decompress_checked(header: &LaylaHeader, input: &[u8], output: &mut [u8]) -> Result<(), LaylaError> {
+        let data = &input[size_of::<LaylaHeader>()..];
+        let uncmp_offset = header.uncompressed_header_offset as usize;
+        output[..Self::UNCOMPRESSED_DATA_SIZE]
+            .copy_from_slice(&data[uncmp_offset..uncmp_offset + Self::UNCOMPRESSED_DATA_SIZE]);
+        let mut cursor = LaylaCheckedCursor::new(&data[..uncmp_offset]);
+        let mut remaining = header.uncompressed_size as usize;
+        while remaining > 0 {
+            // index of the next byte to write, going backwards
+            let pwrite = Self::UNCOMPRESSED_DATA_SIZE + remaining - 1;
+            if cursor.read(1)? == 0 {
+                output[pwrite] = cursor.read(8)? as u8;
+                remaining -= 1;
+                continue;
+            }
+            let offset = cursor.read(13)? as usize + LaylaDecompressorImpl::MIN_COPY_LENGTH;
+            let mut length = LaylaDecompressorImpl::MIN_COPY_LENGTH;
+            for bits in [2, 3, 5] {
+                let this_level = cursor.read(bits)?;
+                length += this_level as usize;
+                if this_level != BIT_MASK[bits] { break; }
+                if bits == 5 {
+                    loop {
+                        let this_level = cursor.read(8)?;
+                        length += this_level as usize;
+                        if this_level != u8::MAX as u32 { break; }
+                    }
+                }
+            }
+            if length > remaining {
+                return Err(LaylaError::CopyPastStart(length));
+            }
+            if pwrite + offset >= output.len() {
+                return Err(LaylaError::InvalidBackReference(pwrite, offset));
+            }
+            // copies may overlap the bytes they write, so this has to go backwards as well
+            for i in (pwrite + 1 - length..=pwrite).rev() {
+                output[i] = output[i + offset];
+            }
+            remaining -= length;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum LaylaError {
+    /// CRILAYLA stores the first 0x100 bytes uncompressed, so smaller inputs can't be compressed
+    InputTooSmall(usize),
+    /// Input is smaller than the 0x10 byte header
+    TruncatedHeader(usize),
+    InvalidMagic,
+    /// Uncompressed data offset points outside the input
+    InvalidUncompressedOffset(u32),
+    /// Decompressed size can't be produced from a bitstream of this size
+    InvalidUncompressedSize(u32),
+    /// Bitstream ended before the output was filled
+    TruncatedStream,
+    /// Copy source (write position, offset) lies past the end of the output
+    InvalidBackReference(usize, usize),
+    /// Copy of the given length would write before the start of the compressed area
+    CopyPastStart(usize),
+}
+
+impl Error for LaylaError {}
+
+impl Display for LaylaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Debug>::fmt(self, f)
+    }
+}
+
+/// How much effort the compressor spends looking for matches. Higher levels give smaller output
+/// at the cost of compression speed. Decompression speed is unaffected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LaylaCompressionLevel {
+    /// Greedy matching, only checking the most recent candidates
+    Fast,
+    /// Lazy matching with a moderate search depth
+    #[default]
+    Normal,
+    /// Lazy matching with a deep search
+    Best
+}
+
+impl LaylaCompressionLevel {
+    fn max_chain(&self) -> usize {
+        match self {
+            Self::Fast => 4,
+            Self::Normal => 48,
+            Self::Best => 1024
+        }
+    }
+
+    fn lazy(&self) -> bool {
+        *self != Self::Fast
+    }
+}
+
+/// Bitstream writer for the compressor. Bits are written MSB first in the order that
+/// `LaylaDecompressorCursor` reads them, then reversed since the decompressor reads backwards.
+#[derive(Debug)]
+struct LaylaCompressorCursor {
+    out: Vec<u8>,
+    pending: u64,
+    bits: usize
+}
+
+impl LaylaCompressorCursor {
+    fn new(capacity: usize) -> Self {
+        Self { out: Vec::with_capacity(capacity), pending: 0, bits: 0 }
+    }
+
+    #[inline]
+    fn write(&mut self, value: u32, bits: usize) {
+        self.pending = (self.pending << bits) | (value & BIT_MASK_U32[bits]) as u64;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.out.push((self.pending >> self.bits) as u8);
+        }
+    }
+
+    fn write_length(&mut self, length: usize) {
+        // Variable length is the same fibonacci-like sequence read by LaylaDecompressorImpl
+        let mut length = length - LaylaDecompressorImpl::MIN_COPY_LENGTH;
+        for bits in [2, 3, 5] {
+            let max = BIT_MASK[bits] as usize;
+            self.write(length.min(max) as u32, bits);
+            if length < max { return; }
+            length -= max;
+        }
+        loop {
+            let this_level = length.min(u8::MAX as usize);
+            self.write(this_level as u32, 8);
+            if this_level != u8::MAX as usize { return; }
+            length -= this_level;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits != 0 {
+            // pad remaining low bits with zero
+            self.out.push((self.pending << (8 - self.bits)) as u8);
+        }
+        self.out.reverse();
+        self.out
+    }
+}
+
+static BIT_MASK_U32: [u32; 17] = [
+    0x0000, 0x0001, 0x0003, 0x0007, 0x000f, 0x001f, 0x003f, 0x007f,
+    0x00ff, 0x01ff, 0x03ff, 0x07ff, 0x0fff, 0x1fff, 0x3fff, 0x7fff, 0xffff
+];
+
+/// Hash chain match finder. Operates over the reversed input, since CRILAYLA is decompressed
+/// from the end of the file: back references in the reversed input point towards the end of the
+/// original file, which is what the decompressor has already written.
+#[derive(Debug)]
+struct LaylaMatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    max_chain: usize
+}
+
+impl<'a> LaylaMatchFinder<'a> {
+    const HASH_BITS: usize = 15;
+    const NO_ENTRY: u32 = u32::MAX;
+    // Copy offset is stored as 13 bits, starting from MIN_COPY_LENGTH
+    const MIN_DISTANCE: usize = LaylaDecompressorImpl::MIN_COPY_LENGTH;
+    const MAX_DISTANCE: usize = (1 << 13) - 1 + Self::MIN_DISTANCE;
+
+    fn new(data: &'a [u8], max_chain: usize) -> Self {
+        Self { data, head: vec![Self::NO_ENTRY; 1 << Self::HASH_BITS],
+            prev: vec![Self::NO_ENTRY; data.len()], max_chain }
+    }
+
+    #[inline]
+    fn hash(&self, pos: usize) -> usize {
+        let v = (self.data[pos] as u32) << 16 | (self.data[pos + 1] as u32) << 8 | self.data[pos + 2] as u32;
+        (v.wrapping_mul(0x9E3779B1) >> (32 - Self::HASH_BITS)) as usize
+    }
+
+    #[inline]
+    fn insert(&mut self, pos: usize) {
+        if pos + LaylaDecompressorImpl::MIN_COPY_LENGTH > self.data.len() { return; }
+        let hash = self.hash(pos);
+        self.prev[pos] = self.head[hash];
+        self.head[hash] = pos as u32;
+    }
+
+    /// Returns the longest match at pos as (distance, length), before pos is inserted
+    fn find(&self, pos: usize) -> Option<(usize, usize)> {
+        let max_length = self.data.len() - pos;
+        if max_length < LaylaDecompressorImpl::MIN_COPY_LENGTH { return None; }
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain = 0;
+        while candidate != Self::NO_ENTRY && chain < self.max_chain {
+            let cand = candidate as usize;
+            let distance = pos - cand;
+            if distance > Self::MAX_DISTANCE { break; }
+            candidate = self.prev[cand];
+            if distance < Self::MIN_DISTANCE { continue; }
+            chain += 1;
+            let best_length = best.map_or(LaylaDecompressorImpl::MIN_COPY_LENGTH - 1, |b| b.1);
+            // quick reject: the byte that would make this match longer must agree
+            if self.data[cand + best_length.min(max_length - 1)] != self.data[pos + best_length.min(max_length - 1)] {
+                continue;
+            }
+            let length = self.data[cand..].iter().zip(&self.data[pos..pos + max_length])
+                .take_while(|(a, b)| a == b).count();
+            if length > best_length {
+                best = Some((distance, length));
+                if length == max_length { break; }
+            }
+        }
+        best
+    }
+}
+
+#[derive(Debug)]
+pub struct LaylaCompressor;
+
+impl LaylaCompressor {
+    /// Compress the input into a CRILAYLA stream. The input must be at least 0x100 bytes long.
+    pub fn compress(input: &[u8], level: LaylaCompressionLevel) -> Result<Vec<u8>, LaylaError> {
+        let prefix_size = LaylaDecompressor::UNCOMPRESSED_DATA_SIZE;
+        if input.len() < prefix_size {
+            return Err(LaylaError::InputTooSmall(input.len()));
+        }
+        let data: Vec<u8> = input[prefix_size..].iter().rev().copied().collect();
+        let mut finder = LaylaMatchFinder::new(&data, level.max_chain());
+        let mut cursor = LaylaCompressorCursor::new(data.len() / 2);
+        let mut pos = 0;
+        let mut next_match = None;
+        while pos < data.len() {
+            let current = next_match.take().or_else(|| finder.find(pos));
+            finder.insert(pos);
+            let Some((distance, length)) = current else {
+                cursor.write(0, 1);
+                cursor.write(data[pos] as u32, 8);
+                pos += 1;
+                continue;
+            };
+            // Lazy matching: prefer a literal if the next position has a longer match
+            if level.lazy() && pos + 1 < data.len() {
+                let lookahead = finder.find(pos + 1);
+                if lookahead.is_some_and(|(_, l)| l > length) {
+                    cursor.write(0, 1);
+                    cursor.write(data[pos] as u32, 8);
+                    next_match = lookahead;
+                    pos += 1;
+                    continue;
+                }
+            }
+            cursor.write(1, 1);
+            cursor.write((distance - LaylaMatchFinder::MIN_DISTANCE) as u32, 13);
+            cursor.write_length(length);
+            for i in pos + 1..pos + length {
+                finder.insert(i);
+            }
+            pos += length;
+        }
+        let compressed = cursor.finish();
+        let mut out = Vec::with_capacity(size_of::<LaylaHeader>() + compressed.len() + prefix_size);
+        out.extend_from_slice(&LAYLA_HEADER_MAGIC.to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        out.extend_from_slice(&compressed);
+        out.extend_from_slice(&input[..prefix_size]);
+        Ok(out)
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use std::fs::File;
-    use std::io::Read;
-    use crate::cpk::compress::layla::{LaylaDecompressor, LaylaDecompressorCursor};
+    use crate::cpk::compress::layla::{LaylaCompressionLevel, LaylaCompressor, LaylaDecompressor,
+        LaylaDecompressorCursor, LaylaError};
     use crate::cpk::free_list::FreeList;
+    use crate::fixtures::{model, noise};
 
     #[test]
     fn cursor_read_stream_1bit() -> Result<(), Box<dyn Error>> {
@@ -410,19 +780,159 @@ pub mod tests {
     }
 
     #[test]
-    fn layla_read_test() -> Result<(), Box<dyn Error>> {
-        let layla_table = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/Compressed3dModel.crilayla";
-        let expected = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/Uncompressed3DModel.dff";
-        if !std::fs::exists(layla_table)? || !std::fs::exists(expected)? {
-            return Ok(());
-        }
-        let mut layla_data = vec![];
-        File::open(layla_table)?.read_to_end(&mut layla_data)?;
+    fn layla_read_model() -> Result<(), Box<dyn Error>> {
+        let model = model(0x8000);
+        let compressed = LaylaCompressor::compress(&model, LaylaCompressionLevel::Best)?;
+        assert!(compressed.len() < model.len());
         let mut allocator = FreeList::new();
-        let result = LaylaDecompressor::decompress(&layla_data, &mut allocator);
-        let mut expected_data = vec![];
-        File::open(expected)?.read_to_end(&mut expected_data)?;
-        assert_eq!(&result, &expected_data);
+        let result = LaylaDecompressor::decompress(&compressed, &mut allocator);
+        assert_eq!(&result, &model);
         Ok(())
     }
-}
\ No newline at end of file
+
+    const LEVELS: [LaylaCompressionLevel; 3] = [
+        LaylaCompressionLevel::Fast, LaylaCompressionLevel::Normal, LaylaCompressionLevel::Best
+    ];
+
+    fn round_trip(input: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut allocator = FreeList::new();
+        for level in LEVELS {
+            let compressed = LaylaCompressor::compress(input, level)?;
+            assert!(LaylaDecompressor::is_compressed(&compressed));
+            let result: Vec<u8> = LaylaDecompressor::decompress(&compressed, &mut allocator).into();
+            assert_eq!(result, input, "round trip failed for {:?}", level);
+            assert_eq!(LaylaDecompressor::try_decompress(&compressed)?, input, "checked round trip failed for {:?}", level);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn layla_compress_round_trip_text() -> Result<(), Box<dyn Error>> {
+        let text = "In the lonely night, the stars are shining bright. Take your time, take your heart. "
+            .repeat(0x100);
+        round_trip(text.as_bytes())
+    }
+
+    #[test]
+    fn layla_compress_round_trip_zeros() -> Result<(), Box<dyn Error>> {
+        let input = vec![0u8; 0x10000];
+        round_trip(&input)?;
+        let compressed = LaylaCompressor::compress(&input, LaylaCompressionLevel::Best)?;
+        assert!(compressed.len() < input.len() / 8);
+        Ok(())
+    }
+
+    #[test]
+    fn layla_compress_round_trip_noise() -> Result<(), Box<dyn Error>> {
+        round_trip(&noise(0x4321))
+    }
+
+    #[test]
+    fn layla_compress_round_trip_mixed() -> Result<(), Box<dyn Error>> {
+        let mut input = noise(0x800);
+        for i in 0..0x40 {
+            let start = (i * 0x1f) % 0x700;
+            input.extend_from_within(start..start + 0x40 + i);
+            input.extend(noise(i));
+        }
+        round_trip(&input)
+    }
+
+    #[test]
+    fn layla_compress_boundary_sizes() -> Result<(), Box<dyn Error>> {
+        round_trip(&noise(0x100))?;
+        round_trip(&noise(0x101))?;
+        round_trip(&[0x55; 0x103])
+    }
+
+    #[test]
+    fn layla_decompress_into_buffer() -> Result<(), Box<dyn Error>> {
+        let input = "Sometimes, being a hero means being a villain.".repeat(0x40).into_bytes();
+        let compressed = LaylaCompressor::compress(&input, LaylaCompressionLevel::Normal)?;
+        assert_eq!(LaylaDecompressor::get_decompressed_size(&compressed), input.len());
+        // buffer is reused and resized, regardless of previous contents
+        let mut buffer = vec![0xff; 0x10];
+        LaylaDecompressor::try_decompress_into(&compressed, &mut buffer)?;
+        assert_eq!(buffer, input);
+        LaylaDecompressor::try_decompress_into(&compressed, &mut buffer)?;
+        assert_eq!(buffer, input);
+        let mut free_list = FreeList::new();
+        assert_eq!(LaylaDecompressor::try_decompress_with(&compressed, &mut free_list)?, input);
+        Ok(())
+    }
+
+    #[test]
+    fn layla_compress_too_small() -> Result<(), Box<dyn Error>> {
+        assert!(matches!(LaylaCompressor::compress(&[0; 0xff], LaylaCompressionLevel::Normal),
+            Err(LaylaError::InputTooSmall(0xff))));
+        Ok(())
+    }
+
+    #[test]
+    fn layla_checked_header() -> Result<(), Box<dyn Error>> {
+        let input = noise(0x400);
+        let compressed = LaylaCompressor::compress(&input, LaylaCompressionLevel::Normal)?;
+        assert!(matches!(LaylaDecompressor::try_decompress(&compressed[..0xf]),
+            Err(LaylaError::TruncatedHeader(0xf))));
+        let mut bad_magic = compressed.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(LaylaDecompressor::try_decompress(&bad_magic), Err(LaylaError::InvalidMagic)));
+        // uncompressed data is at the end, so cutting off the end moves it out of bounds
+        assert!(matches!(LaylaDecompressor::try_decompress(&compressed[..compressed.len() - 1]),
+            Err(LaylaError::InvalidUncompressedOffset(_))));
+        let mut bad_size = compressed.clone();
+        bad_size[0x8..0xc].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(LaylaDecompressor::try_decompress(&bad_size),
+            Err(LaylaError::InvalidUncompressedSize(u32::MAX))));
+        Ok(())
+    }
+
+    #[test]
+    fn layla_checked_truncated_stream() -> Result<(), Box<dyn Error>> {
+        let input = noise(0x400);
+        let mut compressed = LaylaCompressor::compress(&input, LaylaCompressionLevel::Normal)?;
+        // drop the first byte of the bitstream, which is read last
+        let offset = u32::from_le_bytes(compressed[0xc..0x10].try_into()?);
+        compressed.remove(0x10);
+        compressed[0xc..0x10].copy_from_slice(&(offset - 1).to_le_bytes());
+        assert!(matches!(LaylaDecompressor::try_decompress(&compressed), Err(LaylaError::TruncatedStream)));
+        Ok(())
+    }
+
+    #[test]
+    fn layla_checked_invalid_copies() -> Result<(), Box<dyn Error>> {
+        // Bitstream is read from the end: copy flag, 13 bit offset (+3), 2 bit length (+3)
+        let stream = [0x00, 0x00, 0xff, 0xff];
+        let build = |uncompressed_size: u32, stream: &[u8]| {
+            let mut data = b"CRILAYLA".to_vec();
+            data.extend(uncompressed_size.to_le_bytes());
+            data.extend((stream.len() as u32).to_le_bytes());
+            data.extend(stream);
+            data.extend([0u8; 0x100]);
+            data
+        };
+        // offset + 3 lands past the end of an 8 byte output
+        assert!(matches!(LaylaDecompressor::try_decompress(&build(8, &stream)),
+            Err(LaylaError::InvalidBackReference(0x107, 0x2002))));
+        // offset 0 (+3), length 3 into a 2 byte output
+        let stream = [0x00, 0x00, 0x00, 0b1000_0000];
+        assert!(matches!(LaylaDecompressor::try_decompress(&build(2, &stream)),
+            Err(LaylaError::CopyPastStart(3))));
+        Ok(())
+    }
+
+    #[test]
+    fn layla_checked_garbage() -> Result<(), Box<dyn Error>> {
+        // corrupting valid streams must never panic
+        let input = "Trickster, the time has come. ".repeat(0x40).into_bytes();
+        let compressed = LaylaCompressor::compress(&input, LaylaCompressionLevel::Best)?;
+        let mut output = vec![];
+        for (i, byte) in noise(0x400).into_iter().enumerate() {
+            let mut corrupted = compressed.clone();
+            let position = 0x10 + (i * 7) % (corrupted.len() - 0x10);
+            corrupted[position] ^= byte | 1;
+            let _ = LaylaDecompressor::try_decompress_into(&corrupted, &mut output);
+        }
+        Ok(())
+    }
+}
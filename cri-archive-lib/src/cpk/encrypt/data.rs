@@ -1,3 +1,5 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
 use crate::cpk::file::CpkFile;
 
 pub trait FileDecryptor {
@@ -9,6 +11,78 @@ pub trait FileDecryptor {
     fn decrypt_in_place(input: &mut [u8]);
 }
 
+/// Inverse of a `FileDecryptor`, used by `CpkBuilder` to write archives that games can read
+pub trait FileEncryptor {
+    /// User string that marks files as encrypted, if the decryptor detects them from it
+    const USER_STRING: Option<&'static str>;
+
+    /// Encrypts the input by overwriting it
+    fn encrypt_in_place(input: &mut [u8]);
+}
+
+/// Object-safe counterpart of `FileDecryptor`, so that a reader's decryption can be picked at
+/// runtime with `Box<dyn DynFileDecryptor>`. Every `FileDecryptor` implements it.
+pub trait DynFileDecryptor: Debug + Send + Sync {
+    /// Check if a given file is encrypted, from the file info or the start of its data
+    fn is_encrypted(&self, file: &CpkFile, stream: &[u8]) -> bool;
+
+    /// Decrypts a file that is_encrypted returned true for, by overwriting it
+    fn decrypt_in_place(&self, file: &CpkFile, input: &mut [u8]);
+}
+
+impl<T: FileDecryptor + Debug + Send + Sync> DynFileDecryptor for T {
+    fn is_encrypted(&self, file: &CpkFile, stream: &[u8]) -> bool {
+        T::is_encrypted(file, stream)
+    }
+
+    fn decrypt_in_place(&self, _: &CpkFile, input: &mut [u8]) {
+        T::decrypt_in_place(input)
+    }
+}
+
+impl DynFileDecryptor for Box<dyn DynFileDecryptor> {
+    fn is_encrypted(&self, file: &CpkFile, stream: &[u8]) -> bool {
+        (**self).is_encrypted(file, stream)
+    }
+
+    fn decrypt_in_place(&self, file: &CpkFile, input: &mut [u8]) {
+        (**self).decrypt_in_place(file, input)
+    }
+}
+
+/// Decryption used by the CPK readers: the reader's `FileDecryptor` type, unless a decryptor was
+/// picked at runtime with `new_with_decryptor`
+#[derive(Debug)]
+pub(crate) struct Decryption<E> {
+    runtime: Option<Box<dyn DynFileDecryptor>>,
+    decryption: PhantomData<fn() -> E>
+}
+
+impl<E: FileDecryptor> Decryption<E> {
+    pub(crate) fn new() -> Self {
+        Self { runtime: None, decryption: PhantomData }
+    }
+
+    pub(crate) fn new_runtime<D: DynFileDecryptor + 'static>(decryptor: D) -> Self {
+        Self { runtime: Some(Box::new(decryptor)), decryption: PhantomData }
+    }
+
+    pub(crate) fn is_encrypted(&self, file: &CpkFile, stream: &[u8]) -> bool {
+        match &self.runtime {
+            Some(decryptor) => decryptor.is_encrypted(file, stream),
+            None => E::is_encrypted(file, stream)
+        }
+    }
+
+    pub(crate) fn decrypt_in_place(&self, file: &CpkFile, input: &mut [u8]) {
+        match &self.runtime {
+            Some(decryptor) => decryptor.decrypt_in_place(file, input),
+            None => E::decrypt_in_place(input)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct DummyDecryptor;
 
 impl FileDecryptor for DummyDecryptor {
@@ -17,4 +91,127 @@ impl FileDecryptor for DummyDecryptor {
     }
 
     fn decrypt_in_place(_: &mut [u8]) {}
-}
\ No newline at end of file
+}
+
+/// Decryptors tried in order. Each file is decrypted by the first decryptor that considers it
+/// encrypted, so archives mixing several schemes can be read.
+#[derive(Debug, Default)]
+pub struct DecryptorChain(Vec<Box<dyn DynFileDecryptor>>);
+
+impl DecryptorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every decryptor enabled through features. They detect their own files, from the user
+    /// string (P5R) or the data (table encryption).
+    pub fn auto() -> Self {
+        let mut chain = Self::new();
+        #[cfg(feature = "cpk_encryption_p5r")]
+        chain.push(Box::new(crate::cpk::encrypt::p5r::P5RDecryptor));
+        #[cfg(feature = "cpk_encryption_table")]
+        chain.push(Box::new(crate::cpk::encrypt::table::TableFileDecryptor));
+        chain
+    }
+
+    /// Chain from a comma separated list of names, see `names`. Returns None for unknown or
+    /// disabled decryptors.
+    pub fn from_name(names: &str) -> Option<Self> {
+        let mut chain = Self::new();
+        for name in names.split(',').map(|n| n.trim().to_ascii_lowercase()) {
+            match name.as_str() {
+                "none" => (),
+                "auto" => chain.0.extend(Self::auto().0),
+                #[cfg(feature = "cpk_encryption_p5r")]
+                "p5r" => chain.push(Box::new(crate::cpk::encrypt::p5r::P5RDecryptor)),
+                #[cfg(feature = "cpk_encryption_table")]
+                "table" => chain.push(Box::new(crate::cpk::encrypt::table::TableFileDecryptor)),
+                _ => return None
+            }
+        }
+        Some(chain)
+    }
+
+    /// Names accepted by from_name
+    pub fn names() -> Vec<&'static str> {
+        let mut names = vec!["none", "auto"];
+        #[cfg(feature = "cpk_encryption_p5r")]
+        names.push("p5r");
+        #[cfg(feature = "cpk_encryption_table")]
+        names.push("table");
+        names
+    }
+
+    pub fn push(&mut self, decryptor: Box<dyn DynFileDecryptor>) {
+        self.0.push(decryptor);
+    }
+
+    pub fn with<D: DynFileDecryptor + 'static>(mut self, decryptor: D) -> Self {
+        self.push(Box::new(decryptor));
+        self
+    }
+
+    pub fn len(&self) -> usize { self.0.len() }
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+impl DynFileDecryptor for DecryptorChain {
+    fn is_encrypted(&self, file: &CpkFile, stream: &[u8]) -> bool {
+        self.0.iter().any(|d| d.is_encrypted(file, stream))
+    }
+
+    fn decrypt_in_place(&self, file: &CpkFile, input: &mut [u8]) {
+        if let Some(decryptor) = self.0.iter().find(|d| d.is_encrypted(file, input)) {
+            decryptor.decrypt_in_place(file, input);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::cpk::encrypt::data::{DecryptorChain, DummyDecryptor, DynFileDecryptor, FileDecryptor};
+    use crate::cpk::file::CpkFile;
+
+    /// XORs files whose user string is "XOR"
+    #[derive(Debug, Default)]
+    pub(crate) struct XorDecryptor;
+
+    impl FileDecryptor for XorDecryptor {
+        fn is_encrypted(file: &CpkFile, _: &[u8]) -> bool { file.user_string() == "XOR" }
+        fn decrypt_in_place(input: &mut [u8]) {
+            input.iter_mut().for_each(|b| *b ^= 0xff);
+        }
+    }
+
+    #[test]
+    fn chain_decryptors() -> Result<(), Box<dyn Error>> {
+        let xor = CpkFile::new("", "", 0, 4, 4, "XOR");
+        let plain = CpkFile::new("", "", 0, 4, 4, "");
+        let chain = DecryptorChain::new().with(DummyDecryptor).with(XorDecryptor);
+        assert_eq!(chain.len(), 2);
+        assert!(chain.is_encrypted(&xor, &[0; 4]));
+        assert!(!chain.is_encrypted(&plain, &[0; 4]));
+        let mut data = [0, 1, 2, 3];
+        chain.decrypt_in_place(&xor, &mut data);
+        assert_eq!(data, [0xff, 0xfe, 0xfd, 0xfc]);
+        chain.decrypt_in_place(&plain, &mut data);
+        assert_eq!(data, [0xff, 0xfe, 0xfd, 0xfc]);
+        let boxed: Box<dyn DynFileDecryptor> = Box::new(chain);
+        assert!(boxed.is_encrypted(&xor, &[]));
+        Ok(())
+    }
+
+    #[test]
+    fn decryptor_from_name() -> Result<(), Box<dyn Error>> {
+        assert!(DecryptorChain::from_name("none").unwrap().is_empty());
+        assert_eq!(DecryptorChain::from_name("auto").unwrap().len(), DecryptorChain::auto().len());
+        assert!(DecryptorChain::from_name("unknown").is_none());
+        for name in DecryptorChain::names() {
+            assert!(DecryptorChain::from_name(&name.to_uppercase()).is_some());
+        }
+        #[cfg(all(feature = "cpk_encryption_p5r", feature = "cpk_encryption_table"))]
+        assert_eq!(DecryptorChain::from_name("p5r, table").unwrap().len(), 2);
+        Ok(())
+    }
+}
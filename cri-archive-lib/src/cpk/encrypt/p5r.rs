@@ -22,14 +22,14 @@ use core::arch::x86_64::{
 use core::arch::aarch64::{ uint8x16_t, vld1q_u8, vst1q_u8, veorq_u8 };
 
 use std::ptr::{ read_unaligned, write_unaligned };
-use crate::cpk::encrypt::data::FileDecryptor;
+use crate::cpk::encrypt::data::{FileDecryptor, FileEncryptor};
 use crate::cpk::file::CpkFile;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct P5RDecryptor;
 
 impl FileDecryptor for P5RDecryptor {
-    fn is_encrypted(file: &CpkFile, _stream: &[u8]) -> bool { file.user_string() == "CRI_CFATTR:ENCRYPT" }
+    fn is_encrypted(file: &CpkFile, _stream: &[u8]) -> bool { file.user_string() == Self::USER_STRING }
     fn decrypt_in_place(input: &mut [u8]) {
         // Files shorter than 0x820 can't be "decrypted".
         // They aren't "encrypted" to begin with, even if they are marked with ENCRYPT user string
@@ -48,7 +48,19 @@ impl FileDecryptor for P5RDecryptor {
     }
 }
 
+/// Encrypts files for P5R. The XOR is its own inverse, so this re-applies it and marks the file with
+/// the user string that `P5RDecryptor` looks for.
+#[derive(Debug, Default)]
+pub struct P5REncryptor;
+
+impl FileEncryptor for P5REncryptor {
+    const USER_STRING: Option<&'static str> = Some(P5RDecryptor::USER_STRING);
+    fn encrypt_in_place(input: &mut [u8]) { P5RDecryptor::decrypt_in_place(input) }
+}
+
 impl P5RDecryptor {
+    pub const USER_STRING: &'static str = "CRI_CFATTR:ENCRYPT";
+
     // Offset of encrypted data.
     pub(crate) const ENCRYPTED_DATA_OFFSET: usize = 0x20;
 
@@ -126,7 +138,8 @@ impl P5RDecryptor {
 pub mod tests {
     use std::error::Error;
     use std::ops::{Deref, DerefMut};
-    use crate::cpk::encrypt::p5r::P5RDecryptor;
+    use crate::cpk::encrypt::data::{FileDecryptor, FileEncryptor};
+    use crate::cpk::encrypt::p5r::{P5RDecryptor, P5REncryptor};
 
     // #[repr(align(8))]
     #[derive(Debug, Clone)]
@@ -167,4 +180,22 @@ pub mod tests {
         assert_eq!(&*encrypted_u64, &*values);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn p5r_round_trip() -> Result<(), Box<dyn Error>> {
+        let plain: Vec<u8> = (0..0x1000u32).map(|i| (i * 13 + i / 7) as u8).collect();
+        let mut data = plain.clone();
+        P5REncryptor::encrypt_in_place(&mut data);
+        assert_eq!(data[..0x20], plain[..0x20]);
+        assert_ne!(data[0x20..0x420], plain[0x20..0x420]);
+        assert_eq!(data[0x420..], plain[0x420..]);
+        P5RDecryptor::decrypt_in_place(&mut data);
+        assert_eq!(data, plain);
+        // too short to be encrypted
+        let mut short = plain[..0x820].to_vec();
+        P5REncryptor::encrypt_in_place(&mut short);
+        assert_eq!(short, plain[..0x820]);
+        assert_eq!(P5REncryptor::USER_STRING, Some("CRI_CFATTR:ENCRYPT"));
+        Ok(())
+    }
+}
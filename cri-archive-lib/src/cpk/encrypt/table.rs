@@ -10,8 +10,10 @@ use core::arch::x86_64::{
 };
 #[cfg(target_arch = "aarch64")]
 use core::arch::aarch64::{ uint8x16_t, vld1q_s8, vdupq_n_s8, vmulq_s8, vst1q_s8, veorq_s8 };
+#[cfg(not(feature = "dangerous"))]
 use std::error::Error;
-use std::ptr::copy_nonoverlapping;
+use crate::cpk::encrypt::data::{FileDecryptor, FileEncryptor};
+use crate::cpk::file::CpkFile;
 use crate::from_slice;
 use crate::utils::slice::FromSlice;
 use crate::utils::endianness::NativeEndian;
@@ -21,6 +23,37 @@ use crate::utils::intrinsics::{ multiply_bytes_avx, multiply_bytes_sse };
 #[derive(Debug)]
 pub struct TableDecryptor;
 
+/// Decrypts files stored as encrypted @UTF tables, detected from their first 4 bytes
+#[derive(Debug, Default)]
+pub struct TableFileDecryptor;
+
+impl FileDecryptor for TableFileDecryptor {
+    fn is_encrypted(_: &CpkFile, stream: &[u8]) -> bool { TableDecryptor::is_encrypted(stream) }
+    fn decrypt_in_place(input: &mut [u8]) { TableDecryptor::decrypt_utf_in_place(input) }
+}
+
+/// Encrypts @UTF tables. The keystream doesn't depend on the data, so this applies the same XOR as
+/// `TableDecryptor`.
+#[derive(Debug, Default)]
+pub struct TableEncryptor;
+
+impl TableEncryptor {
+    pub fn encrypt_utf(input: &[u8]) -> Vec<u8> {
+        let mut result = input.to_vec();
+        Self::encrypt_utf_in_place(&mut result);
+        result
+    }
+
+    pub fn encrypt_utf_in_place(input: &mut [u8]) {
+        TableDecryptor::decrypt_utf_in_place(input)
+    }
+}
+
+impl FileEncryptor for TableEncryptor {
+    const USER_STRING: Option<&'static str> = None;
+    fn encrypt_in_place(input: &mut [u8]) { Self::encrypt_utf_in_place(input) }
+}
+
 impl TableDecryptor {
     #[cfg(target_endian = "little")]
     const ENCRYPT_MAGIC: u32 = 0xF5F39E1F;
@@ -32,7 +65,7 @@ impl TableDecryptor {
             from_slice!(bytes, u32, NativeEndian) == Self::ENCRYPT_MAGIC
         }
         #[cfg(not(feature = "dangerous"))] {
-            Self::is_encrypted_non_dangerous(bytes).map_or(false, |v| v == Self::ENCRYPT_MAGIC)
+            Self::is_encrypted_non_dangerous(bytes).is_ok_and(|v| v == Self::ENCRYPT_MAGIC)
         }
     }
 
@@ -42,8 +75,7 @@ impl TableDecryptor {
     }
 
     pub fn decrypt_utf(input: &[u8]) -> Vec<u8> {
-        let mut result = Vec::with_capacity(input.len());
-        unsafe { copy_nonoverlapping(input.as_ptr(), result.as_mut_ptr(), result.len()) };
+        let mut result = input.to_vec();
         Self::decrypt_utf_in_place(&mut result);
         result
     }
@@ -160,8 +192,8 @@ impl TableDecryptor {
 
     #[inline(always)]
     fn decrypt_in_place_u8(input: &mut [u8], start: usize, mut xor: i8) {
-        for i in start..input.len() {
-            input[i] ^= xor as u8;
+        for b in input.iter_mut().skip(start) {
+            *b ^= xor as u8;
             xor = xor.wrapping_mul(21);
         }
     }
@@ -170,41 +202,35 @@ impl TableDecryptor {
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use std::fs::File;
-    use std::io::{BufReader, Read};
-    use crate::cpk::encrypt::table::TableDecryptor;
+    use crate::cpk::encrypt::table::{TableDecryptor, TableEncryptor};
+    use crate::fixtures::sample_acb;
+
+    /// Byte at a time keystream, as a reference for the vectorized paths
+    fn reference_xor(input: &mut [u8]) {
+        let mut xor = 95u8;
+        for b in input {
+            *b ^= xor;
+            xor = xor.wrapping_mul(21);
+        }
+    }
 
     #[test]
     fn is_table_encrypted() -> Result<(), Box<dyn Error>> {
-        let decrypted = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/DecyptedTable.@utf";
-        let encrypted = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/EncryptedTable.@utf";
-        if !std::fs::exists(encrypted)? || !std::fs::exists(decrypted)? {
-            return Ok(());
-        }
-        let mut encrypt_handle = BufReader::new(File::open(encrypted)?);
-        let mut encrypt_data = vec![];
-        encrypt_handle.read_to_end(&mut encrypt_data)?;
-        assert_eq!(TableDecryptor::is_encrypted(&encrypt_data), true);
-        let mut decrypt_handle = BufReader::new(File::open(decrypted)?);
-        let mut decrypt_data = vec![];
-        decrypt_handle.read_to_end(&mut decrypt_data)?;
-        assert_eq!(TableDecryptor::is_encrypted(&decrypt_data), false);
+        let decrypt_data = sample_acb()?;
+        let mut encrypt_data = decrypt_data.clone();
+        reference_xor(&mut encrypt_data);
+        assert!(TableDecryptor::is_encrypted(&encrypt_data));
+        assert!(!TableDecryptor::is_encrypted(&decrypt_data));
+        assert!(!TableDecryptor::is_encrypted(&encrypt_data[1..]));
         Ok(())
     }
 
     #[test]
     fn can_decrypt_table() -> Result<(), Box<dyn Error>> {
-        let decrypted = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/DecyptedTable.@utf";
-        let encrypted = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/EncryptedTable.@utf";
-        if !std::fs::exists(encrypted)? || !std::fs::exists(decrypted)? {
-            return Ok(());
-        }
-        let mut encrypt_handle = BufReader::new(File::open(encrypted)?);
-        let mut encrypt_data = vec![];
-        encrypt_handle.read_to_end(&mut encrypt_data)?;
-        let mut decrypt_handle = BufReader::new(File::open(decrypted)?);
-        let mut decrypt_data = vec![];
-        decrypt_handle.read_to_end(&mut decrypt_data)?;
+        // odd length, so every path also goes through its byte-wise tail
+        let decrypt_data = [sample_acb()?.as_slice(), b"tail"].concat()[..0x3f3].to_vec();
+        let mut encrypt_data = decrypt_data.clone();
+        reference_xor(&mut encrypt_data);
         let mut encrypt_avx2 = encrypt_data.clone();
         let mut encrypt_sse3 = encrypt_data.clone();
         let mut encrypt_u64 = encrypt_data.clone();
@@ -216,4 +242,23 @@ pub mod tests {
         assert_eq!(&encrypt_u64, &decrypt_data);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn table_round_trip() -> Result<(), Box<dyn Error>> {
+        // odd length to cover the byte-wise tail
+        let plain: Vec<u8> = b"@UTF".iter().copied().chain((0..0x1235u32).map(|i| (i * 5 + i / 11) as u8)).collect();
+        let encrypted = TableEncryptor::encrypt_utf(&plain);
+        assert_eq!(encrypted.len(), plain.len());
+        assert!(TableDecryptor::is_encrypted(&encrypted));
+        assert!(!TableDecryptor::is_encrypted(&plain));
+        assert_eq!(encrypted[0], b'@' ^ 95);
+        assert_eq!(encrypted[1], b'U' ^ 95u8.wrapping_mul(21));
+        assert_eq!(TableDecryptor::decrypt_utf(&encrypted), plain);
+        let mut in_place = plain.clone();
+        TableEncryptor::encrypt_utf_in_place(&mut in_place);
+        assert_eq!(in_place, encrypted);
+        TableDecryptor::decrypt_utf_in_place(&mut in_place);
+        assert_eq!(in_place, plain);
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
+use std::fmt::{Display, Formatter};
 use std::ptr::NonNull;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CpkFile {
     /// Directory in which the file is contained. DirName in CRI Table
     directory: NonNull<str>,
@@ -15,6 +16,16 @@ pub struct CpkFile {
     /// String some developers attach to provide more info on file, e.g. encrypt this file.
     /// UserString in CRI Table
     user_string: NonNull<str>,
+    /// Numeric ID of the file. ID in CRI Table, or from the ITOC for ID addressed CPKs
+    id: Option<u32>,
+    /// Last modified time of the file when it was packed. UpdateDateTime in ETOC
+    update_date_time: Option<u64>,
+    /// Directory the file was packed from. LocalDir in ETOC
+    local_dir: Option<NonNull<str>>,
+    /// Path of the group containing this file, from the GTOC
+    group: Option<NonNull<str>>,
+    /// Name of the file's attribute within its group, from the GTOC
+    attribute: Option<NonNull<str>>,
 }
 
 impl CpkFile {
@@ -24,14 +35,76 @@ impl CpkFile {
     pub fn file_size(&self) -> u32 { self.file_size }
     pub fn extract_size(&self) -> u32 { self.extract_size }
     pub fn user_string(&self) -> &str { unsafe { self.user_string.as_ref() } }
+    pub fn id(&self) -> Option<u32> { self.id }
+    pub fn update_date_time(&self) -> Option<CpkDateTime> { self.update_date_time.map(CpkDateTime::from) }
+    pub fn local_dir(&self) -> Option<&str> { self.local_dir.map(|v| unsafe { v.as_ref() }) }
+    pub fn group(&self) -> Option<&str> { self.group.map(|v| unsafe { v.as_ref() }) }
+    pub fn attribute(&self) -> Option<&str> { self.attribute.map(|v| unsafe { v.as_ref() }) }
 
     pub fn new(directory: &str, file_name: &str, file_offset: u64, file_size: u32,
                extract_size: u32, user_string: &str) -> Self {
         let directory = unsafe { NonNull::new_unchecked(&raw const *directory as *mut str) };
         let file_name = unsafe { NonNull::new_unchecked(&raw const *file_name as *mut str) };
         let user_string = unsafe { NonNull::new_unchecked(&raw const *user_string as *mut str) };
-        Self { directory, file_name, file_offset, file_size, extract_size, user_string }
+        Self { directory, file_name, file_offset, file_size, extract_size, user_string, id: None,
+            update_date_time: None, local_dir: None, group: None, attribute: None }
+    }
+
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub(crate) fn set_id(&mut self, id: u32) {
+        self.id = Some(id);
+    }
+
+    pub(crate) fn set_update_date_time(&mut self, value: u64) {
+        self.update_date_time = Some(value);
+    }
+
+    pub(crate) fn set_local_dir(&mut self, value: &str) {
+        self.local_dir = Some(unsafe { NonNull::new_unchecked(&raw const *value as *mut str) });
+    }
+
+    pub(crate) fn set_group(&mut self, group: &str, attribute: Option<&str>) {
+        self.group = Some(unsafe { NonNull::new_unchecked(&raw const *group as *mut str) });
+        self.attribute = attribute.map(|v| unsafe { NonNull::new_unchecked(&raw const *v as *mut str) });
     }
 }
 
-unsafe impl Send for CpkFile {}
\ No newline at end of file
+unsafe impl Send for CpkFile {}
+unsafe impl Sync for CpkFile {}
+
+/// Timestamp stored in UpdateDateTime, packed as
+/// `year << 48 | month << 40 | day << 32 | hour << 24 | minute << 16 | second << 8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CpkDateTime(u64);
+
+impl CpkDateTime {
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+        Self((year as u64) << 48 | (month as u64) << 40 | (day as u64) << 32
+            | (hour as u64) << 24 | (minute as u64) << 16 | (second as u64) << 8)
+    }
+
+    pub fn year(&self) -> u16 { (self.0 >> 48) as u16 }
+    pub fn month(&self) -> u8 { (self.0 >> 40) as u8 }
+    pub fn day(&self) -> u8 { (self.0 >> 32) as u8 }
+    pub fn hour(&self) -> u8 { (self.0 >> 24) as u8 }
+    pub fn minute(&self) -> u8 { (self.0 >> 16) as u8 }
+    pub fn second(&self) -> u8 { (self.0 >> 8) as u8 }
+    pub fn raw(&self) -> u64 { self.0 }
+}
+
+impl From<u64> for CpkDateTime {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl Display for CpkDateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year(), self.month(), self.day(),
+            self.hour(), self.minute(), self.second())
+    }
+}
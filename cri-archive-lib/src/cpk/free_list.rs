@@ -127,7 +127,7 @@ impl FreeList {
         if bit != 0 {
             unsafe {
                 let diff = len.min(8 - bit);
-                *byte = *byte | ((Self::bit_mask_u8(diff) << bit) as u8);
+                *byte |= (Self::bit_mask_u8(diff) << bit) as u8;
                 byte = byte.add(1);
                 len -= diff;
             }
@@ -143,7 +143,7 @@ impl FreeList {
         let bit = len & 7;
         if bit != 0 {
             unsafe {
-                *byte = *byte | (Self::bit_mask_u8(bit) as u8);
+                *byte |= Self::bit_mask_u8(bit) as u8;
             }
         }
     }
@@ -157,7 +157,7 @@ impl FreeList {
         if bit != 0 {
             unsafe {
                 let diff = len.min(8 - bit);
-                *byte = *byte & !((Self::bit_mask_u8(diff) << bit) as u8);
+                *byte &= !((Self::bit_mask_u8(diff) << bit) as u8);
                 byte = byte.add(1);
                 len -= diff;
             }
@@ -173,7 +173,7 @@ impl FreeList {
         let bit = len & 7;
         if bit != 0 {
             unsafe {
-                *byte = *byte & !(Self::bit_mask_u8(bit) as u8);
+                *byte &= !(Self::bit_mask_u8(bit) as u8);
             }
         }
     }
@@ -203,9 +203,15 @@ impl FreeList {
     }
 }
 
+impl Default for FreeList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for FreeList {
     fn drop(&mut self) {
-        if self.slab != std::ptr::null_mut() {
+        if !self.slab.is_null() {
             unsafe { std::alloc::dealloc(self.slab, Self::get_layout_temp()) }
         }
     }
@@ -263,12 +269,7 @@ impl From<&mut FreeListNode> for &mut [u8] {
 
 impl From<FreeListNode> for Vec<u8> {
     fn from(value: FreeListNode) -> Self {
-        let mut out = Vec::with_capacity(value.size);
-        unsafe {
-            out.set_len(out.capacity());
-            std::ptr::copy_nonoverlapping(value.ptr, out.as_mut_ptr(), out.len());
-        }
-        out
+        value.as_slice().to_vec()
     }
 }
 
@@ -1,11 +1,12 @@
-use std::error::Error;
 use std::io::{Cursor, Read, Seek};
 use std::mem::MaybeUninit;
 use crate::cpk::encrypt::table::TableDecryptor;
+use crate::cpk::file::CpkDateTime;
+use crate::error::{CriError, Result};
 use crate::from_slice;
 use crate::schema::columns::Column;
 use crate::schema::header::TableHeader;
-use crate::schema::rows::Row;
+use crate::schema::rows::{DataValue, Row, RowValue};
 use crate::schema::strings::{StringPool, StringPoolFast};
 use crate::utils::slice::FromSlice;
 use crate::utils::endianness::NativeEndian;
@@ -14,13 +15,13 @@ use crate::utils::endianness::NativeEndian;
 pub struct TableContainer;
 
 impl TableContainer {
-    pub fn new<R: Read + Seek>(stream: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new<R: Read + Seek>(stream: &mut R) -> Result<Vec<u8>> {
         let mut table_header: MaybeUninit<[u8; 0x10]> = MaybeUninit::uninit();
         stream.read_exact(unsafe { table_header.assume_init_mut() })?;
         let table_header = unsafe { table_header.assume_init() };
         let size = from_slice!(&table_header, u32, NativeEndian, 0x8) as usize;
-        let mut table = Vec::with_capacity(size);
-        unsafe { table.set_len(table.capacity()) };
+        let mut table = vec![0; size];
         stream.read_exact(&mut table)?;
         if TableDecryptor::is_encrypted(&table) {
             TableDecryptor::decrypt_utf_in_place(&mut table);
@@ -33,7 +34,6 @@ impl TableContainer {
 pub(crate) struct HighTable<S: StringPool> {
     #[allow(dead_code)]
     alloc: Vec<u8>,
-    #[allow(dead_code)]
     header: TableHeader,
     columns: Vec<Column>,
     strings: S,
@@ -41,14 +41,18 @@ pub(crate) struct HighTable<S: StringPool> {
 }
 
 impl HighTable<StringPoolFast> {
-    pub fn new(alloc: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+    pub fn new(alloc: Vec<u8>) -> Result<Self> {
+        if alloc.len() < crate::schema::header::HEADER_SIZE {
+            return Err(CriError::malformed(alloc.len() as u64, "table is smaller than its header"));
+        }
         let header = TableHeader::new(&alloc);
         let mut cursor = Cursor::new(alloc.as_slice());
         cursor.set_position(crate::schema::header::HEADER_SIZE as u64);
         let columns = Column::new_list(&mut cursor, &header)?;
-        let str_raw = &alloc[header.string_pool_offset() as usize..header.data_pool_offset() as usize];
+        let str_raw = alloc.get(header.string_pool_offset() as usize..header.data_pool_offset() as usize)
+            .ok_or(CriError::malformed(header.string_pool_offset() as u64, "string pool lies outside the table"))?;
         let rows = Row::new_list(&mut cursor, &header, columns.as_ref())?;
-        let strings = unsafe { StringPoolFast::new_borrowed(&str_raw, &header)? };
+        let strings = unsafe { StringPoolFast::new_borrowed(str_raw, &header)? };
         Ok(Self { alloc, header, columns, strings, rows })
     }
 }
@@ -57,6 +61,208 @@ impl<S: StringPool> HighTable<S> {
     pub fn get_columns(&self) -> &[Column] { self.columns.as_ref() }
     pub fn get_strings(&self) -> &S { &self.strings }
     pub fn get_rows(&self) -> &[Row] { &self.rows }
+    /// Get the slice of the data pool referenced by a Data column value
+    pub fn get_data(&self, data: &DataValue) -> Option<&[u8]> {
+        let start = (self.header.data_pool_offset() + data.get_offset()) as usize;
+        self.alloc.get(start..start + data.get_length() as usize)
+    }
     #[allow(dead_code)]
     pub fn get_alloc(&self) -> &[u8] { &self.alloc }
-}
\ No newline at end of file
+}
+
+/// Well-known columns of the CPK header table. Columns that are missing or have no value are None.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpkHeaderInfo {
+    /// Time the archive was built
+    update_date_time: Option<CpkDateTime>,
+    /// Size of the archive
+    file_size: Option<u64>,
+    content_offset: Option<u64>,
+    content_size: Option<u64>,
+    toc_offset: Option<u64>,
+    toc_size: Option<u64>,
+    toc_crc: Option<u32>,
+    htoc_offset: Option<u64>,
+    htoc_size: Option<u64>,
+    etoc_offset: Option<u64>,
+    etoc_size: Option<u64>,
+    itoc_offset: Option<u64>,
+    itoc_size: Option<u64>,
+    itoc_crc: Option<u32>,
+    gtoc_offset: Option<u64>,
+    gtoc_size: Option<u64>,
+    gtoc_crc: Option<u32>,
+    hgtoc_offset: Option<u64>,
+    hgtoc_size: Option<u64>,
+    /// Total size of files as stored in the archive
+    enabled_packed_size: Option<u64>,
+    /// Total size of files after extraction
+    enabled_data_size: Option<u64>,
+    total_data_size: Option<u64>,
+    tocs: Option<u32>,
+    files: Option<u32>,
+    groups: Option<u32>,
+    attrs: Option<u32>,
+    total_files: Option<u32>,
+    directories: Option<u32>,
+    updates: Option<u32>,
+    version: Option<u16>,
+    revision: Option<u16>,
+    /// Alignment of files in the content area
+    align: Option<u16>,
+    sorted: Option<u16>,
+    enable_file_name: Option<u16>,
+    eid: Option<u16>,
+    /// 0: ID only (ITOC), 1: file names (TOC), 2: file names and IDs, 3: file names and groups
+    cpk_mode: Option<u32>,
+    /// Tool used to build the archive
+    tvers: Option<String>,
+    comment: Option<String>,
+    codec: Option<u32>,
+    dpk_itoc: Option<u32>,
+    enable_toc_crc: Option<u16>,
+    enable_file_crc: Option<u16>,
+    crc_mode: Option<u32>,
+    crc_table: Option<Vec<u8>>,
+    encryption_enabled: Option<u32>
+}
+
+impl CpkHeaderInfo {
+    pub(crate) fn new<S: StringPool>(table: &HighTable<S>) -> Self {
+        let mut info = Self::default();
+        let Some(row) = table.get_rows().first() else { return info };
+        for (col, value) in table.get_columns().iter().zip(row.iter()) {
+            let value = match value {
+                RowValue::None => match col.get_default_value() {
+                    Some(v) => v,
+                    None => continue
+                },
+                v => v
+            };
+            let Some(name) = table.get_strings().get_string(col.get_string_offset()) else { continue };
+            match name {
+                "UpdateDateTime" => info.update_date_time = Self::get_integer(value).map(CpkDateTime::from),
+                "FileSize" => info.file_size = Self::get_integer(value),
+                "ContentOffset" => info.content_offset = Self::get_integer(value),
+                "ContentSize" => info.content_size = Self::get_integer(value),
+                "TocOffset" => info.toc_offset = Self::get_integer(value),
+                "TocSize" => info.toc_size = Self::get_integer(value),
+                "TocCrc" => info.toc_crc = Self::get_integer(value).map(|v| v as u32),
+                "HtocOffset" => info.htoc_offset = Self::get_integer(value),
+                "HtocSize" => info.htoc_size = Self::get_integer(value),
+                "EtocOffset" => info.etoc_offset = Self::get_integer(value),
+                "EtocSize" => info.etoc_size = Self::get_integer(value),
+                "ItocOffset" => info.itoc_offset = Self::get_integer(value),
+                "ItocSize" => info.itoc_size = Self::get_integer(value),
+                "ItocCrc" => info.itoc_crc = Self::get_integer(value).map(|v| v as u32),
+                "GtocOffset" => info.gtoc_offset = Self::get_integer(value),
+                "GtocSize" => info.gtoc_size = Self::get_integer(value),
+                "GtocCrc" => info.gtoc_crc = Self::get_integer(value).map(|v| v as u32),
+                "HgtocOffset" => info.hgtoc_offset = Self::get_integer(value),
+                "HgtocSize" => info.hgtoc_size = Self::get_integer(value),
+                "EnabledPackedSize" => info.enabled_packed_size = Self::get_integer(value),
+                "EnabledDataSize" => info.enabled_data_size = Self::get_integer(value),
+                "TotalDataSize" => info.total_data_size = Self::get_integer(value),
+                "Tocs" => info.tocs = Self::get_integer(value).map(|v| v as u32),
+                "Files" => info.files = Self::get_integer(value).map(|v| v as u32),
+                "Groups" => info.groups = Self::get_integer(value).map(|v| v as u32),
+                "Attrs" => info.attrs = Self::get_integer(value).map(|v| v as u32),
+                "TotalFiles" => info.total_files = Self::get_integer(value).map(|v| v as u32),
+                "Directories" => info.directories = Self::get_integer(value).map(|v| v as u32),
+                "Updates" => info.updates = Self::get_integer(value).map(|v| v as u32),
+                "Version" => info.version = Self::get_integer(value).map(|v| v as u16),
+                "Revision" => info.revision = Self::get_integer(value).map(|v| v as u16),
+                "Align" => info.align = Self::get_integer(value).map(|v| v as u16),
+                "Sorted" => info.sorted = Self::get_integer(value).map(|v| v as u16),
+                "EnableFileName" => info.enable_file_name = Self::get_integer(value).map(|v| v as u16),
+                "EID" => info.eid = Self::get_integer(value).map(|v| v as u16),
+                "CpkMode" => info.cpk_mode = Self::get_integer(value).map(|v| v as u32),
+                "Tvers" => info.tvers = Self::get_string(table, value),
+                "Comment" => info.comment = Self::get_string(table, value),
+                "Codec" => info.codec = Self::get_integer(value).map(|v| v as u32),
+                "DpkItoc" => info.dpk_itoc = Self::get_integer(value).map(|v| v as u32),
+                "EnableTocCrc" => info.enable_toc_crc = Self::get_integer(value).map(|v| v as u16),
+                "EnableFileCrc" => info.enable_file_crc = Self::get_integer(value).map(|v| v as u16),
+                "CrcMode" => info.crc_mode = Self::get_integer(value).map(|v| v as u32),
+                "CrcTable" => info.crc_table = Self::get_data(table, value),
+                "EncryptionEnabled" => info.encryption_enabled = Self::get_integer(value).map(|v| v as u32),
+                _ => ()
+            }
+        }
+        info
+    }
+
+    fn get_integer(value: &RowValue) -> Option<u64> {
+        match value {
+            RowValue::Byte(v) => Some(*v as u64),
+            RowValue::SByte(v) => Some(*v as u64),
+            RowValue::UInt16(v) => Some(*v as u64),
+            RowValue::Int16(v) => Some(*v as u64),
+            RowValue::UInt32(v) => Some(*v as u64),
+            RowValue::Int32(v) => Some(*v as u64),
+            RowValue::UInt64(v) => Some(*v),
+            RowValue::Int64(v) => Some(*v as u64),
+            _ => None
+        }
+    }
+
+    fn get_string<S: StringPool>(table: &HighTable<S>, value: &RowValue) -> Option<String> {
+        match value {
+            RowValue::String(ofs) => table.get_strings().get_string(*ofs).map(|s| s.to_owned()),
+            _ => None
+        }
+    }
+
+    fn get_data<S: StringPool>(table: &HighTable<S>, value: &RowValue) -> Option<Vec<u8>> {
+        match value {
+            RowValue::Data(data) if !data.is_none() => table.get_data(data).map(|d| d.to_vec()),
+            _ => None
+        }
+    }
+
+    pub fn update_date_time(&self) -> Option<CpkDateTime> { self.update_date_time }
+    pub fn file_size(&self) -> Option<u64> { self.file_size }
+    pub fn content_offset(&self) -> Option<u64> { self.content_offset }
+    pub fn content_size(&self) -> Option<u64> { self.content_size }
+    pub fn toc_offset(&self) -> Option<u64> { self.toc_offset }
+    pub fn toc_size(&self) -> Option<u64> { self.toc_size }
+    pub fn toc_crc(&self) -> Option<u32> { self.toc_crc }
+    pub fn htoc_offset(&self) -> Option<u64> { self.htoc_offset }
+    pub fn htoc_size(&self) -> Option<u64> { self.htoc_size }
+    pub fn etoc_offset(&self) -> Option<u64> { self.etoc_offset }
+    pub fn etoc_size(&self) -> Option<u64> { self.etoc_size }
+    pub fn itoc_offset(&self) -> Option<u64> { self.itoc_offset }
+    pub fn itoc_size(&self) -> Option<u64> { self.itoc_size }
+    pub fn itoc_crc(&self) -> Option<u32> { self.itoc_crc }
+    pub fn gtoc_offset(&self) -> Option<u64> { self.gtoc_offset }
+    pub fn gtoc_size(&self) -> Option<u64> { self.gtoc_size }
+    pub fn gtoc_crc(&self) -> Option<u32> { self.gtoc_crc }
+    pub fn hgtoc_offset(&self) -> Option<u64> { self.hgtoc_offset }
+    pub fn hgtoc_size(&self) -> Option<u64> { self.hgtoc_size }
+    pub fn enabled_packed_size(&self) -> Option<u64> { self.enabled_packed_size }
+    pub fn enabled_data_size(&self) -> Option<u64> { self.enabled_data_size }
+    pub fn total_data_size(&self) -> Option<u64> { self.total_data_size }
+    pub fn tocs(&self) -> Option<u32> { self.tocs }
+    pub fn files(&self) -> Option<u32> { self.files }
+    pub fn groups(&self) -> Option<u32> { self.groups }
+    pub fn attrs(&self) -> Option<u32> { self.attrs }
+    pub fn total_files(&self) -> Option<u32> { self.total_files }
+    pub fn directories(&self) -> Option<u32> { self.directories }
+    pub fn updates(&self) -> Option<u32> { self.updates }
+    pub fn version(&self) -> Option<u16> { self.version }
+    pub fn revision(&self) -> Option<u16> { self.revision }
+    pub fn align(&self) -> Option<u16> { self.align }
+    pub fn sorted(&self) -> Option<u16> { self.sorted }
+    pub fn enable_file_name(&self) -> Option<u16> { self.enable_file_name }
+    pub fn eid(&self) -> Option<u16> { self.eid }
+    pub fn cpk_mode(&self) -> Option<u32> { self.cpk_mode }
+    pub fn tvers(&self) -> Option<&str> { self.tvers.as_deref() }
+    pub fn comment(&self) -> Option<&str> { self.comment.as_deref() }
+    pub fn codec(&self) -> Option<u32> { self.codec }
+    pub fn dpk_itoc(&self) -> Option<u32> { self.dpk_itoc }
+    pub fn enable_toc_crc(&self) -> Option<u16> { self.enable_toc_crc }
+    pub fn enable_file_crc(&self) -> Option<u16> { self.enable_file_crc }
+    pub fn crc_mode(&self) -> Option<u32> { self.crc_mode }
+    pub fn crc_table(&self) -> Option<&[u8]> { self.crc_table.as_deref() }
+    pub fn encryption_enabled(&self) -> Option<u32> { self.encryption_enabled }
+}
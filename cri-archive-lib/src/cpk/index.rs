@@ -0,0 +1,253 @@
+//! # CPK Path Index
+//!
+//! Lookup of `CpkFile` entries by path. Paths are normalized before comparison: backslashes
+//! become forward slashes, empty and `.` components are removed and matching is case-insensitive,
+//! so `model\Character//0001/c0001_002_00.gmd` finds `MODEL/CHARACTER/0001/C0001_002_00.GMD`.
+//!
+//! Glob patterns support `?` (any character except `/`), `*` (any number of characters except
+//! `/`), `**` (any number of characters, including `/`) and `[abc]`/`[a-z]`/`[!abc]` classes.
+
+use std::collections::{BTreeMap, HashMap};
+use crate::cpk::file::CpkFile;
+
+#[derive(Debug, Default)]
+pub struct CpkPathIndex {
+    files: Vec<CpkFile>,
+    /// normalized path -> index into files
+    paths: HashMap<String, usize>,
+    /// normalized directory -> (directory as written in the TOC, files directly in the directory)
+    directories: BTreeMap<String, (String, Vec<usize>)>
+}
+
+impl CpkPathIndex {
+    pub fn new(files: &[CpkFile]) -> Self {
+        let mut index = Self::default();
+        for file in files {
+            // ID only files can't be found by path
+            if file.file_name().is_empty() { continue; }
+            let directory = Self::normalize(file.directory());
+            // register parent directories so that they show up in subdirectory listings
+            let mut parent = directory.as_str();
+            let mut original = file.directory().trim_matches(['/', '\\']);
+            while !index.directories.contains_key(parent) {
+                index.directories.insert(parent.to_owned(), (original.to_owned(), vec![]));
+                if parent.is_empty() { break; }
+                parent = parent.rfind('/').map_or("", |i| &parent[..i]);
+                original = original.rfind(['/', '\\']).map_or("", |i| &original[..i]);
+            }
+            let path = Self::join(&directory, &Self::normalize(file.file_name()));
+            if index.paths.contains_key(&path) { continue; }
+            index.paths.insert(path, index.files.len());
+            index.directories.get_mut(&directory).unwrap().1.push(index.files.len());
+            index.files.push(file.clone());
+        }
+        index
+    }
+
+    /// Normalize a path for lookup
+    pub fn normalize(path: &str) -> String {
+        let mut out = String::with_capacity(path.len());
+        for part in path.split(['/', '\\']).filter(|p| !p.is_empty() && *p != ".") {
+            if !out.is_empty() {
+                out.push('/');
+            }
+            out.push_str(&part.to_lowercase());
+        }
+        out
+    }
+
+    fn join(directory: &str, file_name: &str) -> String {
+        match directory {
+            "" => file_name.to_owned(),
+            d => format!("{}/{}", d, file_name)
+        }
+    }
+
+    pub fn files(&self) -> &[CpkFile] { &self.files }
+
+    pub fn len(&self) -> usize { self.files.len() }
+
+    pub fn is_empty(&self) -> bool { self.files.is_empty() }
+
+    pub fn find_file(&self, path: &str) -> Option<&CpkFile> {
+        self.paths.get(&Self::normalize(path)).map(|i| &self.files[*i])
+    }
+
+    /// Files directly inside the directory, in TOC order. Use "" for the root directory.
+    pub fn list_directory(&self, directory: &str) -> Vec<&CpkFile> {
+        self.directories.get(&Self::normalize(directory))
+            .map_or(vec![], |(_, files)| files.iter().map(|i| &self.files[*i]).collect())
+    }
+
+    /// Full paths of directories directly inside the directory, sorted by name
+    pub fn list_subdirectories(&self, directory: &str) -> Vec<&str> {
+        let directory = Self::normalize(directory);
+        self.directories.iter()
+            .filter(|(path, _)| !path.is_empty() && match path.rfind('/') {
+                Some(i) => path[..i] == directory,
+                None => directory.is_empty()
+            })
+            .map(|(_, (original, _))| original.as_str())
+            .collect()
+    }
+
+    /// Files whose path matches the glob pattern, in TOC order
+    pub fn glob(&self, pattern: &str) -> Vec<&CpkFile> {
+        let pattern: Vec<char> = Self::normalize(pattern).chars().collect();
+        let mut matches: Vec<usize> = self.paths.iter()
+            .filter(|(path, _)| Self::glob_match(&pattern, &path.chars().collect::<Vec<_>>()))
+            .map(|(_, i)| *i)
+            .collect();
+        matches.sort_unstable();
+        matches.into_iter().map(|i| &self.files[i]).collect()
+    }
+
+    fn glob_match(pattern: &[char], path: &[char]) -> bool {
+        // each (pattern, path) position is only evaluated once, since backtracking over several
+        // stars would otherwise take exponential time
+        let mut memo = vec![None; (pattern.len() + 1) * (path.len() + 1)];
+        Self::glob_match_at(pattern, path, 0, 0, &mut memo)
+    }
+
+    fn glob_match_at(pattern: &[char], path: &[char], p: usize, s: usize, memo: &mut [Option<bool>]) -> bool {
+        let key = p * (path.len() + 1) + s;
+        if let Some(result) = memo[key] {
+            return result;
+        }
+        let rest = &path[s..];
+        let result = match pattern.get(p) {
+            None => rest.is_empty(),
+            Some('*') if pattern.get(p + 1) == Some(&'*') => {
+                // "**/" also matches no directories at all
+                (pattern.get(p + 2) == Some(&'/') && Self::glob_match_at(pattern, path, p + 3, s, memo))
+                    || (s..=path.len()).any(|i| Self::glob_match_at(pattern, path, p + 2, i, memo))
+            },
+            Some('*') => {
+                let limit = rest.iter().position(|c| *c == '/').unwrap_or(rest.len());
+                (s..=s + limit).any(|i| Self::glob_match_at(pattern, path, p + 1, i, memo))
+            },
+            Some('?') => rest.first().is_some_and(|c| *c != '/')
+                && Self::glob_match_at(pattern, path, p + 1, s + 1, memo),
+            Some('[') => match pattern.iter().skip(p + 2).position(|c| *c == ']').map(|i| i + p + 2) {
+                None => rest.first() == Some(&'[') && Self::glob_match_at(pattern, path, p + 1, s + 1, memo),
+                Some(end) => match rest.first() {
+                    None => false,
+                    Some(c) => {
+                        let (negate, class) = match pattern[p + 1] {
+                            '!' | '^' => (true, &pattern[p + 2..end]),
+                            _ => (false, &pattern[p + 1..end])
+                        };
+                        let mut found = false;
+                        let mut i = 0;
+                        while i < class.len() {
+                            if i + 2 < class.len() && class[i + 1] == '-' {
+                                found |= class[i] <= *c && *c <= class[i + 2];
+                                i += 3;
+                            } else {
+                                found |= class[i] == *c;
+                                i += 1;
+                            }
+                        }
+                        found != negate && *c != '/' && Self::glob_match_at(pattern, path, end + 1, s + 1, memo)
+                    }
+                }
+            },
+            Some(c) => rest.first() == Some(c) && Self::glob_match_at(pattern, path, p + 1, s + 1, memo)
+        };
+        memo[key] = Some(result);
+        result
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::cpk::file::CpkFile;
+    use crate::cpk::index::CpkPathIndex;
+
+    fn sample_files() -> Vec<CpkFile> {
+        [
+            ("", "init.bin"),
+            ("MODEL/CHARACTER/0001", "C0001_001_00.GMD"),
+            ("MODEL/CHARACTER/0001", "C0001_002_00.GMD"),
+            ("MODEL/CHARACTER/0002", "C0002_001_00.GMD"),
+            ("MODEL/FIELD", "F001.GFS"),
+            ("SOUND", "bgm.acb"),
+            ("SOUND", "bgm.awb"),
+        ].iter().enumerate()
+            .map(|(i, (dir, name))| CpkFile::new(dir, name, i as u64 * 0x800, 0x10, 0x10, "<NULL>"))
+            .collect()
+    }
+
+    fn names(files: Vec<&CpkFile>) -> Vec<&str> {
+        files.iter().map(|f| f.file_name()).collect()
+    }
+
+    #[test]
+    fn normalize_paths() -> Result<(), Box<dyn Error>> {
+        assert_eq!(CpkPathIndex::normalize("MODEL/CHARACTER/0001/C0001_002_00.GMD"),
+            "model/character/0001/c0001_002_00.gmd");
+        assert_eq!(CpkPathIndex::normalize("\\\\model\\.\\Character//0001/"), "model/character/0001");
+        assert_eq!(CpkPathIndex::normalize(""), "");
+        Ok(())
+    }
+
+    #[test]
+    fn find_file() -> Result<(), Box<dyn Error>> {
+        let files = sample_files();
+        let index = CpkPathIndex::new(&files);
+        assert_eq!(index.len(), files.len());
+        let file = index.find_file("MODEL/CHARACTER/0001/C0001_002_00.GMD").unwrap();
+        assert_eq!(file.file_offset(), 0x1000);
+        let file = index.find_file("model\\character\\0001\\c0001_002_00.gmd").unwrap();
+        assert_eq!(file.file_offset(), 0x1000);
+        assert_eq!(index.find_file("/init.bin").unwrap().file_offset(), 0);
+        assert!(index.find_file("MODEL/CHARACTER/0001").is_none());
+        assert!(index.find_file("SOUND/se.acb").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn list_directories() -> Result<(), Box<dyn Error>> {
+        let files = sample_files();
+        let index = CpkPathIndex::new(&files);
+        assert_eq!(names(index.list_directory("")), vec!["init.bin"]);
+        assert_eq!(names(index.list_directory("sound/")), vec!["bgm.acb", "bgm.awb"]);
+        assert!(index.list_directory("MODEL").is_empty());
+        assert_eq!(index.list_subdirectories(""), vec!["MODEL", "SOUND"]);
+        assert_eq!(index.list_subdirectories("model"), vec!["MODEL/CHARACTER", "MODEL/FIELD"]);
+        assert_eq!(index.list_subdirectories("MODEL/CHARACTER"),
+            vec!["MODEL/CHARACTER/0001", "MODEL/CHARACTER/0002"]);
+        Ok(())
+    }
+
+    #[test]
+    fn glob_files() -> Result<(), Box<dyn Error>> {
+        let files = sample_files();
+        let index = CpkPathIndex::new(&files);
+        assert_eq!(names(index.glob("SOUND/*")), vec!["bgm.acb", "bgm.awb"]);
+        assert_eq!(names(index.glob("sound/*.ac?")), vec!["bgm.acb"]);
+        assert_eq!(names(index.glob("MODEL/*")), Vec::<&str>::new());
+        assert_eq!(names(index.glob("MODEL/**/*.GMD")),
+            vec!["C0001_001_00.GMD", "C0001_002_00.GMD", "C0002_001_00.GMD"]);
+        assert_eq!(names(index.glob("**/C000[2-9]_*")), vec!["C0002_001_00.GMD"]);
+        assert_eq!(names(index.glob("**/c0001_00[!1]_00.gmd")), vec!["C0001_002_00.GMD"]);
+        assert_eq!(index.glob("**").len(), files.len());
+        assert_eq!(names(index.glob("*")), vec!["init.bin"]);
+        assert_eq!(names(index.glob("[")), Vec::<&str>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn glob_many_stars() -> Result<(), Box<dyn Error>> {
+        // CpkFile borrows its name
+        let name = "a".repeat(100);
+        let files = vec![CpkFile::new("", &name, 0, 0x10, 0x10, "<NULL>")];
+        let index = CpkPathIndex::new(&files);
+        // exponential with plain backtracking, so these would hang without memoization
+        assert!(index.glob(&format!("{}b", "*a".repeat(20))).is_empty());
+        assert!(index.glob(&format!("{}b", "**a".repeat(20))).is_empty());
+        assert_eq!(index.glob(&"*a".repeat(20)).len(), 1);
+        Ok(())
+    }
+}
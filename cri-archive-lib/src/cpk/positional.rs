@@ -0,0 +1,303 @@
+//! # CPK Positional Reader
+//!
+//! Reads files with positional reads (`pread` on Unix, `ReadFile` with an offset on Windows) so
+//! that there's no shared cursor to seek. Unlike `CpkReader`, extracting only needs `&self`
+//! without any locking, and the reader is `Sync` whenever the source is.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use crate::cpk::compress::layla::LaylaDecompressor;
+use crate::cpk::encrypt::data::{Decryption, DummyDecryptor, DynFileDecryptor, FileDecryptor};
+use crate::cpk::file::CpkFile;
+use crate::cpk::header::CpkHeaderInfo;
+use crate::cpk::index::CpkPathIndex;
+use crate::cpk::toc::CpkToc;
+use crate::error::Result;
+
+/// Source that can be read at any offset through a shared reference
+pub trait ReadAt {
+    /// Fill the buffer with the data at offset, failing if there's not enough data
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()>;
+}
+
+/// Only Unix and Windows have positional reads
+#[cfg(any(unix, windows))]
+impl ReadAt for std::fs::File {
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        // seek_read moves the file cursor, but no other code here depends on it
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(self, buf, offset) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        let data = usize::try_from(offset).ok()
+            .and_then(|o| self.get(o..)).and_then(|d| d.get(..buf.len()))
+            .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.as_slice().read_exact_at(buf, offset)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        (**self).read_exact_at(buf, offset)
+    }
+}
+
+/// Adapts a ReadAt source into Read + Seek for parsing the tables of contents
+struct ReadAtCursor<'a, R: ReadAt> {
+    source: &'a R,
+    position: u64
+}
+
+impl<R: ReadAt> Read for ReadAtCursor<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // table readers always know the size of what they read, so reads are all or nothing
+        self.source.read_exact_at(buf, self.position)?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+impl<R: ReadAt> Seek for ReadAtCursor<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(p) => p,
+            SeekFrom::Current(p) => self.position.checked_add_signed(p)
+                .ok_or(std::io::Error::from(std::io::ErrorKind::InvalidInput))?,
+            SeekFrom::End(_) => return Err(std::io::ErrorKind::Unsupported.into())
+        };
+        Ok(self.position)
+    }
+}
+
+#[derive(Debug)]
+pub struct CpkPositionalReader<R: ReadAt, E: FileDecryptor = DummyDecryptor> {
+    source: R,
+    toc: CpkToc,
+    decryptor: Decryption<E>
+}
+
+impl<R: ReadAt> CpkPositionalReader<R> {
+    pub fn new(source: R) -> Result<Self> {
+        Self::new_with_encryption(source)
+    }
+
+    /// Create a reader using a decryptor picked at runtime, e.g. a `DecryptorChain`
+    pub fn new_with_decryptor(source: R, decryptor: impl DynFileDecryptor + 'static) -> Result<Self> {
+        Self::new_with(source, Decryption::new_runtime(decryptor))
+    }
+}
+
+impl<R: ReadAt, E: FileDecryptor> CpkPositionalReader<R, E> {
+    const STREAM_CHUNK_SIZE: usize = 0x10000;
+
+    pub fn new_with_encryption(source: R) -> Result<Self> {
+        Self::new_with(source, Decryption::new())
+    }
+
+    fn new_with(source: R, decryptor: Decryption<E>) -> Result<Self> {
+        let toc = CpkToc::new(&mut ReadAtCursor { source: &source, position: 0 }, 0)?;
+        Ok(Self { source, toc, decryptor })
+    }
+
+    pub fn toc(&self) -> &CpkToc { &self.toc }
+
+    pub fn header(&self) -> &CpkHeaderInfo { self.toc.header() }
+
+    pub fn index(&self) -> &CpkPathIndex { self.toc.index() }
+
+    pub fn get_files(&self) -> &[CpkFile] { self.toc.files() }
+
+    /// Find a file by its path. Paths are case-insensitive and may use either slash.
+    pub fn find_file(&self, path: &str) -> Option<&CpkFile> {
+        self.toc.find_file(path)
+    }
+
+    /// Files directly inside a directory
+    pub fn list_directory(&self, directory: &str) -> Vec<&CpkFile> {
+        self.toc.list_directory(directory)
+    }
+
+    /// Files matching a glob pattern, see `CpkPathIndex`
+    pub fn glob(&self, pattern: &str) -> Vec<&CpkFile> {
+        self.toc.glob(pattern)
+    }
+
+    fn offset_of(&self, file: &CpkFile) -> u64 {
+        self.toc.content_offset() + file.file_offset()
+    }
+
+    /// Read, decrypt and decompress a file
+    pub fn extract_file(&self, file: &CpkFile) -> Result<Vec<u8>> {
+        let mut data = vec![0; file.file_size() as usize];
+        self.source.read_exact_at(&mut data, self.offset_of(file))?;
+        if self.decryptor.is_encrypted(file, &data) {
+            self.decryptor.decrypt_in_place(file, &mut data);
+        }
+        Ok(match LaylaDecompressor::is_compressed(&data) {
+            true => {
+                let mut out = vec![];
+                LaylaDecompressor::try_decompress_into(&data, &mut out)?;
+                out
+            },
+            false => data
+        })
+    }
+
+    /// Extract a file into a writer, returning the number of bytes written. Files that are
+    /// stored as-is are streamed in chunks without reading the whole file into memory.
+    #[inline]
+    pub fn extract_to<W: Write>(&self, file: &CpkFile, out: &mut W) -> Result<u64> {
+        self.extract_to_with_buffer(file, out, &mut vec![])
+    }
+
+    /// Same as extract_to, decompressing CRILAYLA compressed files into the provided buffer so
+    /// that it can be reused between files.
+    pub fn extract_to_with_buffer<W: Write>(&self, file: &CpkFile, out: &mut W, buffer: &mut Vec<u8>)
+        -> Result<u64> {
+        let offset = self.offset_of(file);
+        let size = file.file_size() as usize;
+        let mut chunk = vec![0; size.min(Self::STREAM_CHUNK_SIZE)];
+        self.source.read_exact_at(&mut chunk, offset)?;
+        if self.decryptor.is_encrypted(file, &chunk) || LaylaDecompressor::is_compressed(&chunk) {
+            let start = chunk.len();
+            chunk.resize(size, 0);
+            self.source.read_exact_at(&mut chunk[start..], offset + start as u64)?;
+            if self.decryptor.is_encrypted(file, &chunk) {
+                self.decryptor.decrypt_in_place(file, &mut chunk);
+            }
+            let data = match LaylaDecompressor::is_compressed(&chunk) {
+                true => {
+                    LaylaDecompressor::try_decompress_into(&chunk, buffer)?;
+                    buffer.as_slice()
+                },
+                false => chunk.as_slice()
+            };
+            out.write_all(data)?;
+            return Ok(data.len() as u64);
+        }
+        out.write_all(&chunk)?;
+        let mut read = chunk.len();
+        while read < size {
+            let length = (size - read).min(Self::STREAM_CHUNK_SIZE);
+            self.source.read_exact_at(&mut chunk[..length], offset + read as u64)?;
+            out.write_all(&chunk[..length])?;
+            read += length;
+        }
+        Ok(size as u64)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use crate::cpk::compress::layla::LaylaCompressionLevel;
+    use crate::cpk::encrypt::p5r::P5RDecryptor;
+    use crate::cpk::positional::{CpkPositionalReader, ReadAt};
+    use crate::cpk::reader::CpkReader;
+    use crate::cpk::writer::{CpkBuilder, CpkBuilderFile};
+    use crate::fixtures::sample_data;
+
+    fn build_sample() -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.set_compression(Some(LaylaCompressionLevel::Fast));
+        builder.add_file(CpkBuilderFile::new("", "empty.bin", vec![]));
+        builder.add_file(CpkBuilderFile::new("DATA", "large.bin", (0..0x2345f).map(|i: u32| (i % 251) as u8).collect()));
+        builder.add_file(CpkBuilderFile::new("DATA", "text.txt", "Looking cool, Joker! ".repeat(0x100).into_bytes()));
+        builder.add_file(CpkBuilderFile::new("DATA", "encrypted.bin", sample_data(0x900, 5))
+            .with_user_string("CRI_CFATTR:ENCRYPT"));
+        for i in 0..0x20 {
+            builder.add_file(CpkBuilderFile::new("MANY", &format!("{:02}.bin", i), sample_data(0x40 + i, i as u8)));
+        }
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        Ok(cpk.into_inner())
+    }
+
+    #[test]
+    fn read_exact_at_slice() -> Result<(), Box<dyn Error>> {
+        let data: Vec<u8> = (0..0x10).collect();
+        let mut buf = [0; 4];
+        data.read_exact_at(&mut buf, 0xc)?;
+        assert_eq!(buf, [0xc, 0xd, 0xe, 0xf]);
+        assert!(data.read_exact_at(&mut buf, 0xd).is_err());
+        assert!(data.read_exact_at(&mut buf, u64::MAX).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn matches_stream_reader() -> Result<(), Box<dyn Error>> {
+        let cpk = build_sample()?;
+        let reader = CpkPositionalReader::<_, P5RDecryptor>::new_with_encryption(cpk.as_slice())?;
+        let mut stream = CpkReader::<_, P5RDecryptor>::new_with_encryption(Cursor::new(cpk.clone()))?;
+        let files = stream.get_files()?;
+        assert_eq!(reader.get_files().len(), files.len());
+        assert_eq!(reader.header(), stream.header().unwrap());
+        let mut buffer = vec![];
+        for (a, b) in reader.get_files().iter().zip(&files) {
+            let expected = stream.extract_file(b)?.to_vec();
+            assert_eq!(reader.extract_file(a)?, expected);
+            let mut out = vec![];
+            assert_eq!(reader.extract_to_with_buffer(a, &mut out, &mut buffer)?, expected.len() as u64);
+            assert_eq!(out, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(unix, windows))]
+    fn extract_in_parallel_from_file() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("cri-archive-lib-positional-{}.cpk", std::process::id()));
+        std::fs::write(&path, build_sample()?)?;
+        let reader = CpkPositionalReader::new(Arc::new(std::fs::File::open(&path)?))?;
+        let files = reader.glob("MANY/*");
+        assert_eq!(files.len(), 0x20);
+        std::thread::scope(|scope| {
+            for chunk in files.chunks(5) {
+                let reader = &reader;
+                scope.spawn(move || {
+                    for file in chunk {
+                        let i = file.file_name()[..2].parse::<usize>().unwrap();
+                        assert_eq!(reader.extract_file(file).unwrap(), sample_data(0x40 + i, i as u8));
+                    }
+                });
+            }
+        });
+        drop(reader);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
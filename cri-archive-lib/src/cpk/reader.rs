@@ -1,25 +1,27 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
-use std::io::{Read, Seek, SeekFrom};
-use std::marker::PhantomData;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
 use crate::cpk::compress::layla::LaylaDecompressor;
-use crate::cpk::encrypt::data::{DummyDecryptor, FileDecryptor};
+use crate::cpk::encrypt::data::{Decryption, DummyDecryptor, DynFileDecryptor, FileDecryptor};
 use crate::cpk::file::CpkFile;
 use crate::cpk::free_list::{FreeList, FreeListNode};
-use crate::cpk::header::{HighTable, TableContainer};
-use crate::schema::columns::{Column, ColumnFlag};
-use crate::schema::rows::{Row, RowValue};
-use crate::schema::strings::{ StringPool, StringPoolFast };
+use crate::cpk::header::CpkHeaderInfo;
+use crate::cpk::index::CpkPathIndex;
+use crate::cpk::toc::CpkToc;
+use crate::error::Result;
 
 #[derive(Debug)]
 pub enum CpkReaderError {
     MissingTocOffset,
     MissingContentOffset,
-    NoFileName,
-    NoFileSize,
-    NoExtractSize,
+    /// String offset doesn't point into the table's string pool
+    InvalidString(u32),
+    InvalidItoc,
+    InvalidGtoc,
     GetFilesNotCalled,
+    /// File data lies outside of the archive
+    FileOutOfBounds,
 }
 
 impl Error for CpkReaderError {}
@@ -34,10 +36,9 @@ impl Display for CpkReaderError {
 pub struct CpkReader<R: Read + Seek, E: FileDecryptor = DummyDecryptor> {
     stream: R,
     start_pos: u64,
-    content_ofs: u64,
-    toc_table: Option<HighTable<StringPoolFast>>,
+    toc: Option<CpkToc>,
     free_list: FreeList,
-    decryption: PhantomData<E>,
+    decryptor: Decryption<E>,
     lock: AtomicBool
 }
 
@@ -45,18 +46,25 @@ unsafe impl<R: Read + Seek, E: FileDecryptor> Send for CpkReader<R, E> {}
 unsafe impl<R: Read + Seek, E: FileDecryptor> Sync for CpkReader<R, E> {}
 
 impl<R: Read + Seek> CpkReader<R> {
-    pub fn new(stream: R) -> Result<Self, Box<dyn Error>> {
+    pub fn new(stream: R) -> Result<Self> {
         Self::new_with_encryption(stream)
     }
+
+    /// Create a reader using a decryptor picked at runtime, e.g. a `DecryptorChain`
+    pub fn new_with_decryptor(stream: R, decryptor: impl DynFileDecryptor + 'static) -> Result<Self> {
+        Self::new_with(stream, Decryption::new_runtime(decryptor))
+    }
 }
 
 impl<R: Read + Seek, E: FileDecryptor> CpkReader<R, E> {
-    const DEFAULT_OFFSET: u64 = u64::MAX;
+    pub fn new_with_encryption(stream: R) -> Result<Self> {
+        Self::new_with(stream, Decryption::new())
+    }
 
-    pub fn new_with_encryption(mut stream: R) -> Result<Self, Box<dyn Error>> {
+    fn new_with(mut stream: R, decryptor: Decryption<E>) -> Result<Self> {
         let start_pos = stream.stream_position()?;
-        Ok(Self { stream, start_pos, content_ofs: Self::DEFAULT_OFFSET, toc_table: None,
-            free_list: FreeList::new(), decryption: PhantomData::<E>, lock: AtomicBool::new(false) })
+        Ok(Self { stream, start_pos, toc: None, free_list: FreeList::new(), decryptor,
+            lock: AtomicBool::new(false) })
     }
 
     #[inline]
@@ -69,276 +77,195 @@ impl<R: Read + Seek, E: FileDecryptor> CpkReader<R, E> {
         self.lock.store(false, Ordering::Release);
     }
 
-    pub fn get_files(&mut self) -> Result<Vec<CpkFile>, Box<dyn Error>> {
-        self.stream.seek(SeekFrom::Start(self.start_pos))?;
-        // Read CPK table to get offset to TOC and Content
-        let cpk_table = HighTable::<StringPoolFast>::new(
-            TableContainer::new(&mut self.stream)?)?;
-        let cpk_strs = cpk_table.get_strings();
-        let mut toc_offset= Self::DEFAULT_OFFSET;
-        for (col, row) in cpk_table.get_columns().iter()
-            .zip(cpk_table.get_rows()[0].iter()) {
-            if toc_offset != Self::DEFAULT_OFFSET && self.content_ofs != Self::DEFAULT_OFFSET { break; }
-            if col.get_value().get_flags().contains(ColumnFlag::NAME | ColumnFlag::ROW_STORAGE) {
-                if let RowValue::UInt64(v) = row {
-                    if let Some(str) = cpk_strs.get_string(col.get_string_offset()) {
-                        match str {
-                            "TocOffset" => toc_offset = *v,
-                            // cache content offset for extract_file calls
-                            "ContentOffset" => self.content_ofs = *v,
-                            _ => ()
-                        }
-                    }
-                }
-            }
-        }
-        if toc_offset == Self::DEFAULT_OFFSET { return Err(Box::new(CpkReaderError::MissingTocOffset)) }
-        if self.content_ofs == Self::DEFAULT_OFFSET { return Err(Box::new(CpkReaderError::MissingContentOffset)) }
-        // In some CPKs offsets are relative to TOC as opposed to ContentOffset in header.
-        // This happens when TOC address is before ContentOffset.
-        if toc_offset < self.content_ofs {
-            self.content_ofs = toc_offset;
-        }
-        // Read and cache TOC table
-        self.stream.seek(SeekFrom::Start(toc_offset))?;
-        self.toc_table = Some(HighTable::<StringPoolFast>::new(
-            TableContainer::new(&mut self.stream)?)?);
-        let toc_table = self.toc_table.as_mut().unwrap();
-        let toc_str = toc_table.get_strings();
-        let toc_indices = TocTableIndices::new(toc_str, toc_table.get_columns());
-        let toc_col = toc_table.get_columns();
-        let files = toc_table.get_rows();
-        let mut out = Vec::with_capacity(files.len());
-        for file in files {
-            let directory_name = file.cpk_get_directory_name(
-                &toc_col[toc_indices.dir_name], toc_str, toc_indices.dir_name)?;
-            let file_name = file.cpk_get_file_name(toc_str, toc_indices.file_name)?;
-            let file_offset = file.cpk_get_file_offset(toc_indices.file_offset)?;
-            let file_size = file.cpk_get_file_size(toc_indices.file_size)?;
-            let extract_size = file.cpk_get_extract_size(toc_indices.extract_size)?;
-            let user_string = file.cpk_get_user_string(
-                &toc_col[toc_indices.user_string], toc_str, toc_indices.user_string)?;
-            out.push(CpkFile::new(directory_name, file_name, file_offset, file_size, extract_size, user_string))
-        }
-        Ok(out)
+    /// Read the CPK header table
+    pub fn read_header(&mut self) -> Result<CpkHeaderInfo> {
+        CpkToc::read_header(&mut self.stream, self.start_pos)
     }
 
-    #[inline]
-    pub fn extract_file(&self, file: &CpkFile) -> Result<FreeListNode, Box<dyn Error>> {
-        unsafe { &mut *(&raw const *self as *mut Self) }.extract_file_inner(file)
+    /// Tables of contents read by the last call to get_files
+    pub fn toc(&self) -> Option<&CpkToc> {
+        self.toc.as_ref()
     }
 
-    fn extract_file_inner(&mut self, file: &CpkFile) -> Result<FreeListNode, Box<dyn Error>> {
-        if self.content_ofs == Self::DEFAULT_OFFSET { return Err(Box::new(CpkReaderError::GetFilesNotCalled)) }
-        self.acquire();
-        self.stream.seek(SeekFrom::Start(self.content_ofs + file.file_offset()))?;
-        let mut out = self.free_list.allocate(file.file_size() as usize);
-        self.stream.read_exact(out.as_mut_slice())?;
-        self.unacquire();
-        if E::is_encrypted(file, out.as_slice()) {
-            E::decrypt_in_place(out.as_mut_slice());
-        }
-        Ok(match LaylaDecompressor::is_compressed(out.as_slice()) {
-            true => LaylaDecompressor::decompress(out.as_slice(), &mut self.free_list),
-            false => out
-        })
+    /// Header read by the last call to get_files
+    pub fn header(&self) -> Option<&CpkHeaderInfo> {
+        self.toc.as_ref().map(|t| t.header())
     }
-}
 
-impl Row {
-    pub(crate) fn cpk_get_file_name<'a, S: StringPool>(&'a self, string_pool: &'a S, col_index: usize)
-        -> Result<&'a str, Box<dyn Error>> {
-        match self[col_index] {
-            RowValue::String(ofs) => string_pool.get_string(ofs).map_or(
-                Err(Box::new(CpkReaderError::NoFileName)), |v| Ok(v)),
-            _ => Err(Box::new(CpkReaderError::NoFileName))
-        }
+    /// Path index built by the last call to get_files
+    pub fn index(&self) -> Option<&CpkPathIndex> {
+        self.toc.as_ref().map(|t| t.index())
     }
 
-    fn cpk_get_u32_value(&self, col_index: usize) -> Result<u32, Box<dyn Error>> {
-        match self[col_index] {
-            RowValue::UInt32(size) => Ok(size),
-            _ => Err(Box::new(CpkReaderError::NoFileName))
-        }
+    /// Find a file by its path. Paths are case-insensitive and may use either slash.
+    /// Returns None if get_files hasn't been called.
+    pub fn find_file(&self, path: &str) -> Option<&CpkFile> {
+        self.toc.as_ref().and_then(|t| t.find_file(path))
     }
 
-    pub(crate) fn cpk_get_file_size(&self, col_index: usize)
-        -> Result<u32, Box<dyn Error>> {
-        self.cpk_get_u32_value(col_index)
+    /// Files directly inside a directory
+    pub fn list_directory(&self, directory: &str) -> Vec<&CpkFile> {
+        self.toc.as_ref().map_or(vec![], |t| t.list_directory(directory))
     }
 
-    pub(crate) fn cpk_get_extract_size(&self, col_index: usize)
-        -> Result<u32, Box<dyn Error>> {
-        self.cpk_get_u32_value(col_index)
+    /// Files matching a glob pattern, see `CpkPathIndex`
+    pub fn glob(&self, pattern: &str) -> Vec<&CpkFile> {
+        self.toc.as_ref().map_or(vec![], |t| t.glob(pattern))
     }
 
-    pub(crate) fn cpk_get_file_offset(&self, col_index: usize) -> Result<u64, Box<dyn Error>> {
-        match self[col_index] {
-            RowValue::UInt64(size) => Ok(size),
-            _ => Err(Box::new(CpkReaderError::NoFileName))
-        }
+    pub fn get_files(&mut self) -> Result<Vec<CpkFile>> {
+        let toc = self.toc.insert(CpkToc::new(&mut self.stream, self.start_pos)?);
+        Ok(toc.files().to_vec())
     }
 
-    pub(crate) fn cpk_get_string_may_default<'a, S: StringPool>(&'a self, column: &Column,
-        string_pool: &'a S, col_index: usize) -> Result<&'a str, Box<dyn Error>> {
-        if let RowValue::String(ofs) = self[col_index] {
-            if let Some(str) = string_pool.get_string(ofs) {
-                return Ok(str);
-            }
-        } else if let RowValue::None = self[col_index] {
-            if let Some(def) = column.get_default_value() {
-                if let RowValue::String(ofs) = *def {
-                    if let Some(str) = string_pool.get_string(ofs) {
-                        return Ok(str);
-                    }
-                }
-            }
-        }
-        Err(Box::new(CpkReaderError::NoFileName))
+    /// Offset that file offsets are relative to, cached by get_files
+    fn content_offset(&self) -> Result<u64, CpkReaderError> {
+        self.toc.as_ref().map(|t| t.content_offset()).ok_or(CpkReaderError::GetFilesNotCalled)
+    }
+
+    #[inline]
+    pub fn extract_file(&self, file: &CpkFile) -> Result<FreeListNode> {
+        unsafe { &mut *(&raw const *self as *mut Self) }.extract_file_inner(file)
     }
 
-    pub(crate) fn cpk_get_directory_name<'a, S: StringPool>(&'a self, column: &Column,
-        string_pool: &'a S, col_index: usize) -> Result<&'a str, Box<dyn Error>> {
-        self.cpk_get_string_may_default(column, string_pool, col_index)
+    /// Extract a file into a writer, returning the number of bytes written. Files that are
+    /// stored as-is are streamed in chunks without reading the whole file into memory.
+    /// Use `CpkPositionalReader` to extract from several threads.
+    #[inline]
+    pub fn extract_to<W: Write>(&mut self, file: &CpkFile, out: &mut W) -> Result<u64> {
+        self.extract_to_with_buffer(file, out, &mut vec![])
     }
 
-    pub(crate) fn cpk_get_user_string<'a, S: StringPool>(&'a self, column: &Column,
-        string_pool: &'a S, col_index: usize) -> Result<&'a str, Box<dyn Error>> {
-        self.cpk_get_string_may_default(column, string_pool, col_index)
+    /// Same as extract_to, decompressing CRILAYLA compressed files into the provided buffer so
+    /// that it can be reused between files.
+    pub fn extract_to_with_buffer<W: Write>(&mut self, file: &CpkFile, out: &mut W, buffer: &mut Vec<u8>)
+        -> Result<u64> {
+        let offset = self.content_offset()? + file.file_offset();
+        let size = file.file_size() as usize;
+        let mut chunk = vec![0; size.min(Self::STREAM_CHUNK_SIZE)];
+        self.read_at(offset, &mut chunk)?;
+        // Only files that are stored as-is can be streamed, otherwise the whole file is needed
+        if self.decryptor.is_encrypted(file, &chunk) || LaylaDecompressor::is_compressed(&chunk) {
+            let start = chunk.len();
+            chunk.resize(size, 0);
+            self.read_at(offset + start as u64, &mut chunk[start..])?;
+            if self.decryptor.is_encrypted(file, &chunk) {
+                self.decryptor.decrypt_in_place(file, &mut chunk);
+            }
+            let data = match LaylaDecompressor::is_compressed(&chunk) {
+                true => {
+                    LaylaDecompressor::try_decompress_into(&chunk, buffer)?;
+                    buffer.as_slice()
+                },
+                false => chunk.as_slice()
+            };
+            out.write_all(data)?;
+            return Ok(data.len() as u64);
+        }
+        out.write_all(&chunk)?;
+        let mut written = chunk.len();
+        while written < size {
+            let length = (size - written).min(Self::STREAM_CHUNK_SIZE);
+            self.read_at(offset + written as u64, &mut chunk[..length])?;
+            out.write_all(&chunk[..length])?;
+            written += length;
+        }
+        Ok(size as u64)
     }
-}
 
-#[derive(Debug)]
-struct TocTableIndices {
-    dir_name: usize,
-    file_name: usize,
-    file_size: usize,
-    extract_size: usize,
-    file_offset: usize,
-    user_string: usize
-}
+    const STREAM_CHUNK_SIZE: usize = 0x10000;
 
-impl TocTableIndices {
-    pub(crate) fn new<S: StringPool>(pool: &S, cols: &[Column]) -> Self {
-        let mut inst = Self {
-            dir_name: usize::MAX,
-            file_name: usize::MAX,
-            file_size: usize::MAX,
-            extract_size: usize::MAX,
-            file_offset: usize::MAX,
-            user_string: usize::MAX
-        };
-        for (i, c) in cols.iter().enumerate() {
-            if let Some(s) = pool.get_string(c.get_string_offset()) {
-                match s {
-                    "DirName" => inst.dir_name = i,
-                    "FileName" => inst.file_name = i,
-                    "FileSize" => inst.file_size = i,
-                    "ExtractSize" => inst.extract_size = i,
-                    "FileOffset" => inst.file_offset = i,
-                    "UserString" => inst.user_string = i,
-                    _ => ()
-                }
-            }
+    /// Reads from an absolute offset in the stream
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.stream.seek(SeekFrom::Start(offset))?;
+        Ok(self.stream.read_exact(buf)?)
+    }
+
+    fn extract_file_inner(&mut self, file: &CpkFile) -> Result<FreeListNode> {
+        let content_ofs = self.content_offset()?;
+        self.acquire();
+        let mut out = self.free_list.allocate(file.file_size() as usize);
+        let result = self.stream.seek(SeekFrom::Start(content_ofs + file.file_offset()))
+            .and_then(|_| self.stream.read_exact(out.as_mut_slice()));
+        self.unacquire();
+        result?;
+        if self.decryptor.is_encrypted(file, out.as_slice()) {
+            self.decryptor.decrypt_in_place(file, out.as_mut_slice());
         }
-        inst
+        Ok(match LaylaDecompressor::is_compressed(out.as_slice()) {
+            true => LaylaDecompressor::try_decompress_with(out.as_slice(), &mut self.free_list)?,
+            false => out
+        })
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use std::collections::HashMap;
     use std::error::Error;
-    use std::fs::File;
-    use std::io::BufReader;
+    use std::io::Cursor;
+    use crate::cpk::compress::layla::LaylaCompressionLevel;
     use crate::cpk::encrypt::p5r::P5RDecryptor;
+    use crate::cpk::encrypt::table::TableDecryptor;
+    use crate::cpk::file::CpkFile;
     use crate::cpk::reader::CpkReader;
-
-    #[test]
-    fn get_files_basic_table() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/SampleData.cpk";
-        if !std::fs::exists(sample_path)? {
-            return Ok(());
+    use crate::cpk::writer::{CpkBuilder, CpkBuilderFile};
+    use crate::error::CriError;
+    use crate::fixtures::{p5r_cpk, p5r_cpk_model, sample_cpk, sample_cpk_files, shift_jis_cpk, shift_jis_cpk_files};
+    use crate::schema::columns::ColumnType;
+    use crate::schema::rows::RowValue;
+    use crate::schema::writer::{TableColumn, TableWriter};
+
+    fn check_sample_files(files: &[CpkFile]) {
+        let expected = sample_cpk_files();
+        assert_eq!(files.len(), expected.len());
+        for (file, (name, data)) in files.iter().zip(expected.iter()) {
+            assert_eq!(file.directory(), "");
+            assert_eq!(file.file_name(), *name);
+            assert_eq!(file.extract_size() as usize, data.len());
+            assert_eq!(file.user_string(), "<NULL>");
+            assert_eq!(file.file_offset() % CpkBuilder::DEFAULT_ALIGNMENT as u64, 0);
         }
-        let mut reader = CpkReader::new(BufReader::new(File::open(sample_path)?))?;
-        let files = reader.get_files()?;
-        assert_eq!(files[0].directory(), "");
-        assert_eq!(files[0].file_name(), "Audio-NoCompression.flac");
-        assert_eq!(files[0].file_offset(), 0);
         assert_eq!(files[0].file_size(), 48431);
-        assert_eq!(files[0].extract_size(), 48431);
-        assert_eq!(files[0].user_string(), "<NULL>");
-        assert_eq!(files[1].directory(), "");
-        assert_eq!(files[1].file_name(), "Image-NoCompression.jpg");
-        assert_eq!(files[1].file_offset(), 48640);
+        assert_eq!(files[1].file_offset(), files[0].file_offset() + 0xc000);
         assert_eq!(files[1].file_size(), 120719);
-        assert_eq!(files[1].extract_size(), 120719);
-        assert_eq!(files[1].user_string(), "<NULL>");
-        assert_eq!(files[2].directory(), "");
-        assert_eq!(files[2].file_name(), "Text-Compressed.txt");
-        assert_eq!(files[2].file_offset(), 169472);
-        assert_eq!(files[2].file_size(), 2484);
+        assert!(files[2].file_size() < files[2].extract_size());
         assert_eq!(files[2].extract_size(), 3592);
-        assert_eq!(files[2].user_string(), "<NULL>");
+    }
+
+    #[test]
+    fn get_files_basic_table() -> Result<(), Box<dyn Error>> {
+        let mut reader = CpkReader::new(Cursor::new(sample_cpk(false)?))?;
+        let files = reader.get_files()?;
+        check_sample_files(&files);
         Ok(())
     }
 
     #[test]
     fn get_files_encrypted_table() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/SampleData-Encrypted.cpk";
-        if !std::fs::exists(sample_path)? {
-            return Ok(());
-        }
-        let mut reader = CpkReader::new(BufReader::new(File::open(sample_path)?))?;
+        let cpk = sample_cpk(true)?;
+        assert!(TableDecryptor::is_encrypted(&cpk[0x10..]));
+        assert!(TableDecryptor::is_encrypted(&cpk[0x810..]));
+        let mut reader = CpkReader::new(Cursor::new(cpk))?;
         let files = reader.get_files()?;
-        assert_eq!(files[0].directory(), "");
-        assert_eq!(files[0].file_name(), "Audio-NoCompression.flac");
-        assert_eq!(files[0].file_offset(), 0);
-        assert_eq!(files[0].file_size(), 48431);
-        assert_eq!(files[0].extract_size(), 48431);
-        assert_eq!(files[0].user_string(), "<NULL>");
-        assert_eq!(files[1].directory(), "");
-        assert_eq!(files[1].file_name(), "Image-NoCompression.jpg");
-        assert_eq!(files[1].file_offset(), 48640);
-        assert_eq!(files[1].file_size(), 120719);
-        assert_eq!(files[1].extract_size(), 120719);
-        assert_eq!(files[1].user_string(), "<NULL>");
-        assert_eq!(files[2].directory(), "");
-        assert_eq!(files[2].file_name(), "Text-Compressed.txt");
-        assert_eq!(files[2].file_offset(), 169472);
-        assert_eq!(files[2].file_size(), 2484);
-        assert_eq!(files[2].extract_size(), 3592);
-        assert_eq!(files[2].user_string(), "<NULL>");
+        check_sample_files(&files);
         Ok(())
     }
 
     #[test]
     fn extract_sample_image_uncompressed() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/SampleData-Encrypted.cpk";
-        let expected_path = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/SampleData/Image-NoCompression.jpg";
-        if !std::fs::exists(sample_path)? || !std::fs::exists(expected_path)? {
-            return Ok(());
-        }
-        let mut reader = CpkReader::new(BufReader::new(File::open(sample_path)?))?;
+        let mut reader = CpkReader::new(Cursor::new(sample_cpk(true)?))?;
         let files = reader.get_files()?;
         let img = reader.extract_file(&files[1])?; // Uncompressed Image
-        let expected = std::fs::read(expected_path)?;
-        assert_eq!(img, expected);
+        assert_eq!(img, sample_cpk_files()[1].1);
         Ok(())
     }
 
     #[test]
     fn extract_sample_text_compressed() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/SampleData-Encrypted.cpk";
-        let expected_path = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/SampleData/Text-Compressed.txt";
-        if !std::fs::exists(sample_path)? || !std::fs::exists(expected_path)? {
-            return Ok(());
-        }
-        let mut reader = CpkReader::new(BufReader::new(File::open(sample_path)?))?;
+        let mut reader = CpkReader::new(Cursor::new(sample_cpk(true)?))?;
         let files = reader.get_files()?;
         let text = reader.extract_file(&files[2])?; // Compressed Text
-        let expected = std::fs::read(expected_path)?;
-        assert_eq!(text, expected);
+        assert_eq!(text, sample_cpk_files()[2].1);
         Ok(())
     }
 
@@ -347,47 +274,447 @@ pub mod tests {
 
     #[test]
     fn get_files_p5r() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/SteamLibrary/steamapps/common/P5R/CPK/BASE.CPK";
-        if !std::fs::exists(sample_path)? {
-            return Ok(());
-        }
-        let mut reader = CpkReader::new(BufReader::new(File::open(sample_path)?))?;
-        let _files = reader.get_files()?;
-        // for (i, file) in files.iter().enumerate() {
-        //     println!("{}: path: {}/{}, size: 0x{:x}/0x{:x}, ofs: 0x{:x}, user: {}", i, file.directory(), file.file_name(), file.file_size(), file.extract_size(), file.file_offset(), file.user_string());
-        // }
+        let mut reader = CpkReader::new(Cursor::new(p5r_cpk()?))?;
+        let files = reader.get_files()?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].directory(), "MODEL/CHARACTER/0001");
+        assert_eq!(files[0].user_string(), "CRI_CFATTR:ENCRYPT");
+        // large enough for the XOR to apply
+        assert!(files[0].file_size() > 0x820);
+        assert!(files[0].file_size() < files[0].extract_size());
+        assert_eq!(files[1].file_name(), "empty.bin");
         Ok(())
     }
 
     #[test]
     fn extract_p5r_c0001_002_00() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/SteamLibrary/steamapps/common/P5R/CPK/BASE.CPK";
-        let expected_path = "D:/PERSONA5ROYAL/BASE.CPK/MODEL/CHARACTER/0001/C0001_002_00.GMD";
-        if !std::fs::exists(sample_path)? || !std::fs::exists(expected_path)? {
-            return Ok(());
+        let (path, expected) = p5r_cpk_model();
+        let mut reader = CpkReader::<_, P5RDecryptor>::new_with_encryption(Cursor::new(p5r_cpk()?))?;
+        reader.get_files()?;
+        let joker_persona_5 = reader.find_file(path).unwrap();
+        let joker_persona_5 = reader.extract_file(joker_persona_5)?;
+        assert_eq!(joker_persona_5, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn get_files_p4g() -> Result<(), Box<dyn Error>> {
+        let mut reader = CpkReader::new(Cursor::new(shift_jis_cpk()?))?;
+        let files = reader.get_files()?;
+        let mut expected = shift_jis_cpk_files();
+        expected.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        assert_eq!(files.len(), expected.len());
+        for (file, (directory, file_name, data)) in files.iter().zip(expected) {
+            assert_eq!(file.directory(), directory);
+            assert_eq!(file.file_name(), file_name);
+            assert_eq!(reader.extract_file(file)?, data);
+        }
+        Ok(())
+    }
+
+    const ITOC_CONTENT_OFFSET: u64 = 0x1000;
+    const ITOC_ALIGN: u16 = 0x20;
+
+    fn write_chunk(out: &mut Vec<u8>, offset: u64, magic: &[u8; 4], table: &[u8]) {
+        out.resize(offset as usize, 0);
+        out.extend_from_slice(magic);
+        out.extend_from_slice(&0xffu32.to_le_bytes());
+        out.extend_from_slice(&(table.len() as u64).to_le_bytes());
+        out.extend_from_slice(table);
+    }
+
+    /// Sizes table for ITOC DataL/DataH
+    fn itoc_sizes(files: &[(u32, Vec<u8>)], large: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+        let size_type = if large { ColumnType::UInt32 } else { ColumnType::UInt16 };
+        let mut table = TableWriter::new("CpkItocL");
+        table.add_column(TableColumn::row("ID", ColumnType::UInt16));
+        table.add_column(TableColumn::row("FileSize", size_type));
+        table.add_column(TableColumn::row("ExtractSize", size_type));
+        for (id, data) in files.iter().filter(|(_, d)| (d.len() >= 0x10000) == large) {
+            let size = match large {
+                true => RowValue::UInt32(data.len() as u32),
+                false => RowValue::UInt16(data.len() as u16)
+            };
+            table.add_row(vec![RowValue::UInt16(*id as u16), size.clone(), size]);
+        }
+        Ok(table.to_bytes()?)
+    }
+
+    /// Build an ID addressed CPK. Files are stored in ID order from ITOC_CONTENT_OFFSET.
+    /// If named is set, a TOC is also written for files with a name. GTOC is written after the
+    /// content.
+    fn build_itoc_cpk(files: &[(u32, Vec<u8>)], named: &[(u32, &str)], gtoc: Option<Vec<u8>>)
+        -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut sorted = files.to_vec();
+        sorted.sort_by_key(|f| f.0);
+        let mut offsets = vec![];
+        let mut offset = ITOC_CONTENT_OFFSET;
+        for (_, data) in &sorted {
+            offsets.push(offset);
+            offset = (offset + data.len() as u64).next_multiple_of(ITOC_ALIGN as u64);
+        }
+        let mut itoc = TableWriter::new("CpkItocInfo");
+        itoc.add_column(TableColumn::row("FilesL", ColumnType::UInt32));
+        itoc.add_column(TableColumn::row("FilesH", ColumnType::UInt32));
+        itoc.add_column(TableColumn::row("DataL", ColumnType::Data));
+        itoc.add_column(TableColumn::row("DataH", ColumnType::Data));
+        let data_l = itoc.add_data(&itoc_sizes(files, false)?);
+        let data_h = itoc.add_data(&itoc_sizes(files, true)?);
+        let large = files.iter().filter(|(_, d)| d.len() >= 0x10000).count() as u32;
+        itoc.add_row(vec![RowValue::UInt32(files.len() as u32 - large), RowValue::UInt32(large),
+            RowValue::Data(data_l), RowValue::Data(data_h)]);
+        let itoc = itoc.to_bytes()?;
+        let itoc_offset = 0x800;
+        let toc_offset = itoc_offset + 0x400;
+
+        let mut header = TableWriter::new("CpkHeader");
+        header.add_column(TableColumn::row("ContentOffset", ColumnType::UInt64));
+        header.add_column(TableColumn::row("TocOffset", ColumnType::UInt64));
+        header.add_column(TableColumn::row("ItocOffset", ColumnType::UInt64));
+        header.add_column(TableColumn::row("Align", ColumnType::UInt16));
+        header.add_column(TableColumn::row("CpkMode", ColumnType::UInt32));
+        header.add_column(TableColumn::row("GtocOffset", ColumnType::UInt64));
+        let gtoc_offset = offset.next_multiple_of(0x800);
+        header.add_row(vec![RowValue::UInt64(ITOC_CONTENT_OFFSET),
+            RowValue::UInt64(if named.is_empty() { 0 } else { toc_offset }),
+            RowValue::UInt64(itoc_offset), RowValue::UInt16(ITOC_ALIGN),
+            RowValue::UInt32(if named.is_empty() { 0 } else { 2 }),
+            RowValue::UInt64(if gtoc.is_some() { gtoc_offset } else { 0 })]);
+
+        let mut out = vec![];
+        write_chunk(&mut out, 0, b"CPK ", &header.to_bytes()?);
+        write_chunk(&mut out, itoc_offset, b"ITOC", &itoc);
+        if !named.is_empty() {
+            let mut toc = TableWriter::new("CpkTocInfo");
+            for (name, ctype) in [("DirName", ColumnType::String), ("FileName", ColumnType::String),
+                ("FileSize", ColumnType::UInt32), ("ExtractSize", ColumnType::UInt32),
+                ("FileOffset", ColumnType::UInt64), ("ID", ColumnType::UInt32),
+                ("UserString", ColumnType::String)] {
+                toc.add_column(TableColumn::row(name, ctype));
+            }
+            for (id, name) in named {
+                let index = sorted.iter().position(|f| f.0 == *id).unwrap();
+                let size = RowValue::UInt32(sorted[index].1.len() as u32);
+                let row = vec![RowValue::String(toc.add_string("DATA")),
+                    RowValue::String(toc.add_string(name)), size.clone(), size,
+                    // TOC is before the content, so offsets are relative to it
+                    RowValue::UInt64(offsets[index] - toc_offset),
+                    RowValue::UInt32(*id), RowValue::String(0)];
+                toc.add_row(row);
+            }
+            write_chunk(&mut out, toc_offset, b"TOC ", &toc.to_bytes()?);
+        }
+        for ((_, data), offset) in sorted.iter().zip(offsets) {
+            out.resize(offset as usize, 0);
+            out.extend_from_slice(data);
+        }
+        if let Some(gtoc) = gtoc {
+            write_chunk(&mut out, gtoc_offset, b"GTOC", &gtoc);
+        }
+        Ok(out)
+    }
+
+    /// CPK with a TOC holding one row of the given columns. String values are the column's name.
+    fn build_toc_only_cpk(columns: &[(&str, RowValue)]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut header = TableWriter::new("CpkHeader");
+        header.add_column(TableColumn::row("ContentOffset", ColumnType::UInt64));
+        header.add_column(TableColumn::row("TocOffset", ColumnType::UInt64));
+        header.add_row(vec![RowValue::UInt64(0x1000), RowValue::UInt64(0x800)]);
+        let mut toc = TableWriter::new("CpkTocInfo");
+        let mut row = vec![];
+        for (name, value) in columns {
+            toc.add_column(TableColumn::row(name, value.get_type().unwrap()));
+            row.push(match value {
+                RowValue::String(_) => RowValue::String(toc.add_string(name)),
+                v => v.clone()
+            });
+        }
+        toc.add_row(row);
+        let mut out = vec![];
+        write_chunk(&mut out, 0, b"CPK ", &header.to_bytes()?);
+        write_chunk(&mut out, 0x800, b"TOC ", &toc.to_bytes()?);
+        Ok(out)
+    }
+
+    #[test]
+    fn get_files_invalid_toc() -> Result<(), Box<dyn Error>> {
+        let mut columns = vec![("DirName", RowValue::String(0)), ("FileName", RowValue::String(0)),
+            ("FileSize", RowValue::UInt32(4)), ("ExtractSize", RowValue::UInt32(4)),
+            ("FileOffset", RowValue::UInt64(0)), ("UserString", RowValue::String(0))];
+        let mut reader = CpkReader::new(Cursor::new(build_toc_only_cpk(&columns)?))?;
+        assert_eq!(reader.get_files()?[0].file_name(), "FileName");
+        columns[3].1 = RowValue::UInt64(4);
+        assert!(matches!(CpkReader::new(Cursor::new(build_toc_only_cpk(&columns)?))?.get_files(),
+            Err(CriError::UnexpectedColumnType { column: "ExtractSize", expected: ColumnType::UInt32,
+                found: Some(ColumnType::UInt64) })));
+        columns.remove(1);
+        assert!(matches!(CpkReader::new(Cursor::new(build_toc_only_cpk(&columns)?))?.get_files(),
+            Err(CriError::MissingColumn("FileName"))));
+        Ok(())
+    }
+
+    fn itoc_sample_files() -> Vec<(u32, Vec<u8>)> {
+        vec![
+            (2, vec![0x22; 0x31]),
+            (0, vec![0x00; 0x10]),
+            (5, (0..0x12345).map(|i| i as u8).collect()),
+            (1, vec![0x11; 0x100])
+        ]
+    }
+
+    #[test]
+    fn get_files_itoc() -> Result<(), Box<dyn Error>> {
+        let files = itoc_sample_files();
+        let mut reader = CpkReader::new(Cursor::new(build_itoc_cpk(&files, &[], None)?))?;
+        let entries = reader.get_files()?;
+        assert_eq!(entries.len(), 4);
+        let ids: Vec<_> = entries.iter().map(|f| f.id()).collect();
+        assert_eq!(ids, vec![Some(0), Some(1), Some(2), Some(5)]);
+        assert_eq!(entries[0].file_offset(), 0);
+        assert_eq!(entries[1].file_offset(), 0x20);
+        assert_eq!(entries[2].file_offset(), 0x120);
+        assert_eq!(entries[3].file_offset(), 0x160);
+        assert_eq!(entries[3].file_size(), 0x12345);
+        assert_eq!(entries[0].file_name(), "");
+        for entry in &entries {
+            let expected = &files.iter().find(|f| Some(f.0) == entry.id()).unwrap().1;
+            assert_eq!(&reader.extract_file(entry)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn get_files_invalid_itoc() -> Result<(), Box<dyn Error>> {
+        let mut cpk = build_itoc_cpk(&itoc_sample_files(), &[], None)?;
+        // rename the size tables' ExtractSize columns
+        while let Some(i) = cpk.windows(11).position(|w| w == b"ExtractSize") {
+            cpk[i + 10] = b'z';
+        }
+        assert!(matches!(CpkReader::new(Cursor::new(cpk))?.get_files(),
+            Err(CriError::MissingColumn("ExtractSize"))));
+        Ok(())
+    }
+
+    #[test]
+    fn get_files_toc_and_itoc() -> Result<(), Box<dyn Error>> {
+        let files = itoc_sample_files();
+        let mut reader = CpkReader::new(Cursor::new(build_itoc_cpk(&files, &[(1, "one.bin"), (5, "five.bin")], None)?))?;
+        let entries = reader.get_files()?;
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].file_name(), "one.bin");
+        assert_eq!(entries[0].id(), Some(1));
+        assert_eq!(entries[1].file_name(), "five.bin");
+        assert_eq!(entries[1].id(), Some(5));
+        // ITOC only files are appended in ID order
+        assert_eq!(entries[2].id(), Some(0));
+        assert_eq!(entries[3].id(), Some(2));
+        for entry in &entries {
+            let expected = &files.iter().find(|f| Some(f.0) == entry.id()).unwrap().1;
+            assert_eq!(&reader.extract_file(entry)?, expected);
+        }
+        Ok(())
+    }
+
+    fn gtoc_subtable(name: &str, columns: &[(&str, ColumnType)], rows: Vec<Vec<RowValue>>,
+        strings: &[&str]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut table = TableWriter::new(name);
+        for (name, ctype) in columns {
+            table.add_column(TableColumn::row(name, *ctype));
         }
-        let mut reader = CpkReader::<_, P5RDecryptor>::new_with_encryption(
-            BufReader::new(File::open(sample_path)?))?;
+        for row in rows {
+            // strings are passed as indices into the strings slice
+            let row = row.into_iter().map(|v| match v {
+                RowValue::String(i) => RowValue::String(table.add_string(strings[i as usize])),
+                v => v
+            }).collect();
+            table.add_row(row);
+        }
+        Ok(table.to_bytes()?)
+    }
+
+    #[test]
+    fn get_files_gtoc() -> Result<(), Box<dyn Error>> {
+        let strings = ["", "STAGE", "BATTLE", "FIELD", "resident", "stream"];
+        // root -> STAGE -> (BATTLE: files 0, 2), (FIELD: file 5)
+        let gdata = gtoc_subtable("CpkGtocGlink",
+            &[("Gname", ColumnType::String), ("Child", ColumnType::Int32), ("Next", ColumnType::Int32)],
+            vec![
+                vec![RowValue::String(0), RowValue::Int32(1), RowValue::Int32(-1)],
+                vec![RowValue::String(1), RowValue::Int32(2), RowValue::Int32(-1)],
+                vec![RowValue::String(2), RowValue::Int32(-1), RowValue::Int32(3)],
+                vec![RowValue::String(3), RowValue::Int32(-3), RowValue::Int32(-1)],
+            ], &strings)?;
+        let adata = gtoc_subtable("CpkGtocAttr", &[("Aname", ColumnType::String)],
+            vec![vec![RowValue::String(4)], vec![RowValue::String(5)]], &strings)?;
+        let flink = gtoc_subtable("CpkGtocFlink",
+            &[("Aindex", ColumnType::UInt16), ("Next", ColumnType::Int32), ("Child", ColumnType::UInt32)],
+            vec![
+                vec![RowValue::UInt16(0), RowValue::Int32(1), RowValue::UInt32(0)],
+                vec![RowValue::UInt16(1), RowValue::Int32(-1), RowValue::UInt32(2)],
+                vec![RowValue::UInt16(0), RowValue::Int32(-1), RowValue::UInt32(5)],
+            ], &strings)?;
+        let mut gtoc = TableWriter::new("CpkGtocInfo");
+        gtoc.add_column(TableColumn::row("Glink", ColumnType::UInt32));
+        gtoc.add_column(TableColumn::row("Gdata", ColumnType::Data));
+        gtoc.add_column(TableColumn::row("Adata", ColumnType::Data));
+        gtoc.add_column(TableColumn::row("Flink", ColumnType::Data));
+        let row = vec![RowValue::UInt32(4), RowValue::Data(gtoc.add_data(&gdata)),
+            RowValue::Data(gtoc.add_data(&adata)), RowValue::Data(gtoc.add_data(&flink))];
+        gtoc.add_row(row);
+
+        let files = itoc_sample_files();
+        let mut reader = CpkReader::new(Cursor::new(build_itoc_cpk(&files, &[], Some(gtoc.to_bytes()?))?))?;
+        let entries = reader.get_files()?;
+        let groups: Vec<_> = entries.iter().map(|f| (f.id().unwrap(), f.group(), f.attribute())).collect();
+        assert_eq!(groups, vec![
+            (0, Some("STAGE/BATTLE"), Some("resident")),
+            (1, None, None),
+            (2, Some("STAGE/BATTLE"), Some("stream")),
+            (5, Some("STAGE/FIELD"), Some("resident")),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_header_info() -> Result<(), Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.set_alignment(0x20)?;
+        builder.add_file(CpkBuilderFile::new("", "a.bin", vec![1; 0x40]));
+        builder.add_file(CpkBuilderFile::new("", "b.bin", vec![2; 0x10]));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        assert!(reader.header().is_none());
+        let header = reader.read_header()?;
+        assert_eq!(header.version(), Some(7));
+        assert_eq!(header.revision(), Some(0));
+        assert_eq!(header.align(), Some(0x20));
+        assert_eq!(header.cpk_mode(), Some(1));
+        assert_eq!(header.files(), Some(2));
+        assert_eq!(header.toc_offset(), Some(0x800));
+        assert_eq!(header.enabled_data_size(), Some(0x50));
+        assert_eq!(header.content_size(), Some(0x60));
+        assert!(header.tvers().unwrap().starts_with("cri-archive-lib"));
+        assert_eq!(header.comment(), Some(""));
+        // zero columns have no value
+        assert_eq!(header.itoc_offset(), None);
+        assert_eq!(header.crc_table(), None);
+        reader.get_files()?;
+        assert_eq!(reader.header(), Some(&header));
+        Ok(())
+    }
+
+    #[test]
+    fn find_file_by_path() -> Result<(), Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("MODEL/CHARACTER/0001", "C0001_002_00.GMD", vec![1; 0x40]));
+        builder.add_file(CpkBuilderFile::new("SOUND", "bgm.acb", vec![2; 0x10]));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        assert!(reader.find_file("SOUND/bgm.acb").is_none());
+        reader.get_files()?;
+        let file = reader.find_file("model/character/0001/c0001_002_00.gmd").unwrap();
+        assert_eq!(reader.extract_file(file)?, vec![1; 0x40]);
+        assert_eq!(reader.list_directory("SOUND").len(), 1);
+        assert_eq!(reader.glob("**/*.GMD").len(), 1);
+        assert_eq!(reader.index().unwrap().list_subdirectories("MODEL"), vec!["MODEL/CHARACTER"]);
+        Ok(())
+    }
+
+    #[test]
+    fn extract_to_writer() -> Result<(), Box<dyn Error>> {
+        let large: Vec<u8> = (0..0x2345f).map(|i: u32| (i % 251) as u8).collect();
+        let mut builder = CpkBuilder::new();
+        builder.set_compression(Some(LaylaCompressionLevel::Fast));
+        builder.add_file(CpkBuilderFile::new("", "empty.bin", vec![]));
+        builder.add_file(CpkBuilderFile::new("", "large.bin", large.clone()));
+        builder.add_file(CpkBuilderFile::new("", "text.txt", "You'll never see it coming. ".repeat(0x100).into_bytes()));
+        builder.add_file(CpkBuilderFile::new("", "encrypted.bin", (0..0x900).map(|i| i as u8).collect())
+            .with_user_string("CRI_CFATTR:ENCRYPT"));
+        builder.add_file(CpkBuilderFile::new("", "stored.bin", (0..0x30).collect()));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::<_, P5RDecryptor>::new_with_encryption(Cursor::new(cpk.into_inner()))?;
         let files = reader.get_files()?;
-        let mut file_lookup = HashMap::new();
+        let mut buffer = vec![];
         for file in &files {
-            file_lookup.insert(format!("{}/{}", file.directory(), file.file_name()), file);
+            let mut out = vec![];
+            let written = reader.extract_to_with_buffer(file, &mut out, &mut buffer)?;
+            assert_eq!(written as usize, out.len());
+            assert_eq!(written, file.extract_size() as u64);
+            assert_eq!(reader.extract_file(file)?, out);
         }
-        let joker_persona_5 = file_lookup.get("MODEL/CHARACTER/0001/C0001_002_00.GMD").unwrap();
-        let joker_persona_5 = reader.extract_file(joker_persona_5)?;
-        let expected = std::fs::read(expected_path)?;
-        assert_eq!(joker_persona_5, expected);
+        let mut out = vec![];
+        let large_file = reader.find_file("large.bin").unwrap().clone();
+        reader.extract_to(&large_file, &mut out)?;
+        assert_eq!(out, large);
         Ok(())
     }
 
     #[test]
-    fn get_files_p4g() -> Result<(), Box<dyn Error>> {
-        let sample_path = "E:/SteamLibrary/steamapps/common/Persona 4 Golden/data.cpk";
-        if !std::fs::exists(sample_path)? {
-            return Ok(());
+    fn extract_corrupt() -> Result<(), Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.set_compression(Some(LaylaCompressionLevel::Fast));
+        builder.add_file(CpkBuilderFile::new("", "text.txt", "You'll never see it coming. ".repeat(0x100).into_bytes()));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut cpk = cpk.into_inner();
+        // uncompressed size in the CRILAYLA header
+        let layla = cpk.windows(8).position(|w| w == b"CRILAYLA").unwrap();
+        cpk[layla + 8..layla + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = CpkReader::new(Cursor::new(cpk))?;
+        let files = reader.get_files()?;
+        assert!(matches!(reader.extract_to(&files[0], &mut vec![]), Err(CriError::Compression(_))));
+        assert!(matches!(reader.extract_file(&files[0]), Err(CriError::Compression(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_decryptor() -> Result<(), Box<dyn Error>> {
+        use crate::cpk::encrypt::data::{DecryptorChain, DynFileDecryptor};
+        use crate::schema::writer::{TableColumn, TableWriter};
+        let mut table = TableWriter::new("Table");
+        table.add_column(TableColumn::constant("Value", crate::schema::rows::RowValue::UInt32(5)));
+        table.add_row(vec![crate::schema::rows::RowValue::None]);
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("", "encrypted.bin", (0..0x900).map(|i| i as u8).collect())
+            .with_user_string("CRI_CFATTR:ENCRYPT"));
+        builder.add_file(CpkBuilderFile::new("", "table.@utf", table.to_encrypted_bytes()?));
+        builder.add_file(CpkBuilderFile::new("", "plain.bin", vec![3; 0x40]));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let cpk = cpk.into_inner();
+        let mut expected = CpkReader::<_, P5RDecryptor>::new_with_encryption(Cursor::new(cpk.clone()))?;
+        expected.get_files()?;
+        let decryptor: Box<dyn DynFileDecryptor> = Box::new(DecryptorChain::from_name("auto").unwrap());
+        let mut reader = CpkReader::new_with_decryptor(Cursor::new(cpk), decryptor)?;
+        reader.get_files()?;
+        let extract = |path: &str| reader.extract_file(reader.find_file(path).unwrap()).map(|f| f.to_vec());
+        assert_eq!(extract("encrypted.bin")?, expected.extract_file(expected.find_file("encrypted.bin").unwrap())?.to_vec());
+        assert_eq!(extract("table.@utf")?, table.to_bytes()?);
+        assert_eq!(extract("plain.bin")?, vec![3; 0x40]);
+        Ok(())
+    }
+
+    #[test]
+    fn static_decryptor() -> Result<(), Box<dyn Error>> {
+        use crate::cpk::encrypt::data::FileDecryptor;
+        // neither Debug, Default, Send nor Sync
+        struct XorDecryptor(std::marker::PhantomData<*const ()>);
+        impl FileDecryptor for XorDecryptor {
+            fn is_encrypted(file: &CpkFile, _: &[u8]) -> bool { file.user_string() == "XOR" }
+            fn decrypt_in_place(input: &mut [u8]) {
+                input.iter_mut().for_each(|b| *b ^= 0xff);
+            }
         }
-        let mut reader = CpkReader::new(BufReader::new(File::open(sample_path)?))?;
-        let _files = reader.get_files()?;
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("", "xor.bin", vec![0xfc; 0x40]).with_user_string("XOR"));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::<_, XorDecryptor>::new_with_encryption(Cursor::new(cpk.into_inner()))?;
+        let files = reader.get_files()?;
+        assert_eq!(reader.extract_file(&files[0])?.to_vec(), vec![3; 0x40]);
         Ok(())
     }
-}
\ No newline at end of file
+}
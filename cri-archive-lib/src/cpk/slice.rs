@@ -0,0 +1,240 @@
+//! # CPK Slice Reader
+//!
+//! Reads a CPK that's entirely in memory, either as a byte buffer or as a memory mapped file
+//! (with the `cpk_mmap` feature). The tables of contents are parsed once on creation, after which
+//! the reader is immutable: files can be extracted from any number of threads without locking.
+//!
+//! Stored files are returned as slices into the archive. Memory is only allocated for files that
+//! have to be decrypted or decompressed.
+
+use std::borrow::Cow;
+use std::io::{Cursor, Write};
+use crate::cpk::compress::layla::LaylaDecompressor;
+use crate::cpk::encrypt::data::{Decryption, DummyDecryptor, DynFileDecryptor, FileDecryptor};
+use crate::cpk::file::CpkFile;
+use crate::cpk::header::CpkHeaderInfo;
+use crate::cpk::index::CpkPathIndex;
+use crate::cpk::reader::CpkReaderError;
+use crate::cpk::toc::CpkToc;
+use crate::error::Result;
+
+#[derive(Debug)]
+pub struct CpkSliceReader<D: AsRef<[u8]>, E: FileDecryptor = DummyDecryptor> {
+    data: D,
+    toc: CpkToc,
+    decryptor: Decryption<E>
+}
+
+impl<D: AsRef<[u8]>> CpkSliceReader<D> {
+    pub fn new(data: D) -> Result<Self> {
+        Self::new_with_encryption(data)
+    }
+
+    /// Create a reader using a decryptor picked at runtime, e.g. a `DecryptorChain`
+    pub fn new_with_decryptor(data: D, decryptor: impl DynFileDecryptor + 'static) -> Result<Self> {
+        Self::new_with(data, Decryption::new_runtime(decryptor))
+    }
+}
+
+#[cfg(feature = "cpk_mmap")]
+impl CpkSliceReader<memmap2::Mmap> {
+    /// Memory map a CPK file.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it's mapped, see `memmap2::Mmap::map`.
+    pub unsafe fn map_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        unsafe { Self::map_file_with_encryption(path) }
+    }
+}
+
+#[cfg(feature = "cpk_mmap")]
+impl<E: FileDecryptor> CpkSliceReader<memmap2::Mmap, E> {
+    /// Memory map a CPK file that may contain encrypted files.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it's mapped, see `memmap2::Mmap::map`.
+    pub unsafe fn map_file_with_encryption<P: AsRef<std::path::Path>>(path: P) -> Result<Self>
+        {
+        let file = std::fs::File::open(path)?;
+        Self::new_with_encryption(unsafe { memmap2::Mmap::map(&file)? })
+    }
+}
+
+impl<D: AsRef<[u8]>, E: FileDecryptor> CpkSliceReader<D, E> {
+    pub fn new_with_encryption(data: D) -> Result<Self> {
+        Self::new_with(data, Decryption::new())
+    }
+
+    fn new_with(data: D, decryptor: Decryption<E>) -> Result<Self> {
+        let toc = CpkToc::new(&mut Cursor::new(data.as_ref()), 0)?;
+        Ok(Self { data, toc, decryptor })
+    }
+
+    pub fn toc(&self) -> &CpkToc { &self.toc }
+
+    pub fn header(&self) -> &CpkHeaderInfo { self.toc.header() }
+
+    pub fn index(&self) -> &CpkPathIndex { self.toc.index() }
+
+    pub fn get_files(&self) -> &[CpkFile] { self.toc.files() }
+
+    /// Find a file by its path. Paths are case-insensitive and may use either slash.
+    pub fn find_file(&self, path: &str) -> Option<&CpkFile> {
+        self.toc.find_file(path)
+    }
+
+    /// Files directly inside a directory
+    pub fn list_directory(&self, directory: &str) -> Vec<&CpkFile> {
+        self.toc.list_directory(directory)
+    }
+
+    /// Files matching a glob pattern, see `CpkPathIndex`
+    pub fn glob(&self, pattern: &str) -> Vec<&CpkFile> {
+        self.toc.glob(pattern)
+    }
+
+    /// Data of the file as it's stored in the archive, without decrypting or decompressing it
+    pub fn get_stored(&self, file: &CpkFile) -> Result<&[u8]> {
+        let data = self.data.as_ref();
+        let start = self.toc.content_offset().checked_add(file.file_offset())
+            .filter(|s| *s <= data.len() as u64).ok_or(CpkReaderError::FileOutOfBounds)? as usize;
+        data[start..].get(..file.file_size() as usize).ok_or(CpkReaderError::FileOutOfBounds.into())
+    }
+
+    /// Extract a file. Files that are neither encrypted nor compressed are borrowed from the archive.
+    pub fn extract_file(&self, file: &CpkFile) -> Result<Cow<'_, [u8]>> {
+        let stored = self.get_stored(file)?;
+        let data = match self.decryptor.is_encrypted(file, stored) {
+            true => {
+                let mut data = stored.to_vec();
+                self.decryptor.decrypt_in_place(file, &mut data);
+                Cow::Owned(data)
+            },
+            false => Cow::Borrowed(stored)
+        };
+        Ok(match LaylaDecompressor::is_compressed(&data) {
+            true => {
+                let mut out = vec![];
+                LaylaDecompressor::try_decompress_into(&data, &mut out)?;
+                Cow::Owned(out)
+            },
+            false => data
+        })
+    }
+
+    /// Extract a file into a writer, returning the number of bytes written
+    pub fn extract_to<W: Write>(&self, file: &CpkFile, out: &mut W) -> Result<u64> {
+        let data = self.extract_file(file)?;
+        out.write_all(&data)?;
+        Ok(data.len() as u64)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::borrow::Cow;
+    use std::error::Error;
+    use std::io::Cursor;
+    use crate::cpk::compress::layla::LaylaCompressionLevel;
+    use crate::cpk::reader::CpkReader;
+    use crate::cpk::slice::CpkSliceReader;
+    use crate::cpk::writer::{CpkBuilder, CpkBuilderFile};
+    use crate::fixtures::sample_data;
+
+    fn build_sample() -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.set_compression(Some(LaylaCompressionLevel::Fast));
+        builder.add_file(CpkBuilderFile::new("", "empty.bin", vec![]));
+        builder.add_file(CpkBuilderFile::new("DATA", "noise.bin", sample_data(0x1234, 7)));
+        // too small to be compressed
+        builder.add_file(CpkBuilderFile::new("DATA", "small.bin", sample_data(0xf0, 3)));
+        builder.add_file(CpkBuilderFile::new("DATA", "text.txt",
+            "Take your time. ".repeat(0x200).into_bytes()));
+        for i in 0..0x20 {
+            builder.add_file(CpkBuilderFile::new("MANY", &format!("{:02}.bin", i), sample_data(0x40 + i, i as u8)));
+        }
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        Ok(cpk.into_inner())
+    }
+
+    #[test]
+    fn matches_stream_reader() -> Result<(), Box<dyn Error>> {
+        let cpk = build_sample()?;
+        let slice = CpkSliceReader::new(cpk.as_slice())?;
+        let mut stream = CpkReader::new(Cursor::new(cpk.clone()))?;
+        let files = stream.get_files()?;
+        assert_eq!(slice.get_files().len(), files.len());
+        assert_eq!(slice.header(), stream.header().unwrap());
+        for (a, b) in slice.get_files().iter().zip(&files) {
+            assert_eq!(a.file_name(), b.file_name());
+            assert_eq!(*slice.extract_file(a)?, stream.extract_file(b)?.to_vec());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn borrows_stored_files() -> Result<(), Box<dyn Error>> {
+        let cpk = build_sample()?;
+        let reader = CpkSliceReader::new(&cpk)?;
+        let small = reader.find_file("data/small.bin").unwrap();
+        let data = reader.extract_file(small)?;
+        assert!(matches!(data, Cow::Borrowed(_)));
+        assert_eq!(*data, sample_data(0xf0, 3));
+        assert!(cpk.as_ptr_range().contains(&data.as_ptr()));
+        let text = reader.find_file("DATA/TEXT.TXT").unwrap();
+        assert!(text.file_size() < text.extract_size());
+        let data = reader.extract_file(text)?;
+        assert!(matches!(data, Cow::Owned(_)));
+        assert_eq!(*data, *"Take your time. ".repeat(0x200).as_bytes());
+        assert!(reader.extract_file(reader.find_file("empty.bin").unwrap())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_truncated_archive() -> Result<(), Box<dyn Error>> {
+        let mut cpk = build_sample()?;
+        let reader = CpkSliceReader::new(cpk.clone())?;
+        let noise = reader.find_file("DATA/noise.bin").unwrap().clone();
+        let end = reader.toc().content_offset() + noise.file_offset() + noise.file_size() as u64;
+        cpk.truncate(end as usize - 1);
+        // keep the tables, which come before the content
+        let truncated = CpkSliceReader::new(cpk)?;
+        assert!(truncated.get_stored(&noise).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn extract_in_parallel() -> Result<(), Box<dyn Error>> {
+        let reader = CpkSliceReader::new(build_sample()?)?;
+        let files = reader.glob("MANY/*");
+        assert_eq!(files.len(), 0x20);
+        std::thread::scope(|scope| {
+            for chunk in files.chunks(5) {
+                let reader = &reader;
+                scope.spawn(move || {
+                    for file in chunk {
+                        let i = file.file_name()[..2].parse::<usize>().unwrap();
+                        assert_eq!(*reader.extract_file(file).unwrap(), sample_data(0x40 + i, i as u8));
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(feature = "cpk_mmap")]
+    #[test]
+    fn map_file() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("cri-archive-lib-mmap-{}.cpk", std::process::id()));
+        std::fs::write(&path, build_sample()?)?;
+        let reader = unsafe { CpkSliceReader::map_file(&path)? };
+        let noise = reader.find_file("DATA/noise.bin").unwrap();
+        assert_eq!(*reader.extract_file(noise)?, sample_data(0x1234, 7));
+        drop(reader);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,487 @@
+//! # CPK Table of Contents
+//!
+//! Parsed CPK header, TOC, ITOC, ETOC and GTOC tables. The tables are kept alive for as long as
+//! the `CpkToc` exists since `CpkFile` entries point into their string pools.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use crate::cpk::file::CpkFile;
+use crate::cpk::header::{CpkHeaderInfo, HighTable, TableContainer};
+use crate::cpk::index::CpkPathIndex;
+use crate::cpk::reader::CpkReaderError;
+use crate::error::{CriError, Result};
+use crate::schema::columns::{Column, ColumnType};
+use crate::schema::rows::{Row, RowValue};
+use crate::schema::strings::{ StringPool, StringPoolFast };
+
+#[derive(Debug)]
+pub struct CpkToc {
+    header: CpkHeaderInfo,
+    content_ofs: u64,
+    files: Vec<CpkFile>,
+    index: CpkPathIndex,
+    toc_table: Option<HighTable<StringPoolFast>>,
+    etoc_table: Option<HighTable<StringPoolFast>>,
+    /// Group paths and attribute names from the GTOC, referenced by CpkFile
+    gtoc_strings: Vec<String>
+}
+
+// CpkFile entries only point into tables owned by the CpkToc, which are never modified after
+// it's been read
+unsafe impl Send for CpkToc {}
+unsafe impl Sync for CpkToc {}
+
+impl CpkToc {
+    /// Read the CPK header table at start_pos
+    pub fn read_header<R: Read + Seek>(stream: &mut R, start_pos: u64) -> Result<CpkHeaderInfo> {
+        stream.seek(SeekFrom::Start(start_pos))?;
+        let cpk_table = HighTable::<StringPoolFast>::new(
+            TableContainer::new(stream)?)?;
+        Ok(CpkHeaderInfo::new(&cpk_table))
+    }
+
+    /// Read the header at start_pos and every table of contents it references
+    pub fn new<R: Read + Seek>(stream: &mut R, start_pos: u64) -> Result<Self> {
+        let header = Self::read_header(stream, start_pos)?;
+        // unused tables may be stored with an offset of 0
+        let toc_offset = header.toc_offset().filter(|v| *v != 0);
+        let itoc_offset = header.itoc_offset().filter(|v| *v != 0);
+        let etoc_offset = header.etoc_offset().filter(|v| *v != 0);
+        let gtoc_offset = header.gtoc_offset().filter(|v| *v != 0);
+        let align = header.align();
+        if toc_offset.is_none() && itoc_offset.is_none() {
+            return Err(CpkReaderError::MissingTocOffset.into())
+        }
+        let content_offset = header.content_offset().ok_or(CpkReaderError::MissingContentOffset)?;
+        // In some CPKs offsets are relative to TOC as opposed to ContentOffset in header.
+        // This happens when TOC address is before ContentOffset.
+        let content_ofs = match toc_offset {
+            Some(toc_offset) if toc_offset < content_offset => toc_offset,
+            _ => content_offset
+        };
+        let mut toc = Self { header, content_ofs, files: vec![], index: CpkPathIndex::default(),
+            toc_table: None, etoc_table: None, gtoc_strings: vec![] };
+        let mut files = match toc_offset {
+            Some(toc_offset) => toc.read_toc(stream, toc_offset)?,
+            None => vec![]
+        };
+        if let Some(itoc_offset) = itoc_offset {
+            stream.seek(SeekFrom::Start(itoc_offset))?;
+            let itoc_table = HighTable::<StringPoolFast>::new(
+                TableContainer::new(stream)?)?;
+            // ID mode files are laid out in ID order from the start of the content area
+            let base = content_offset - content_ofs;
+            Self::merge_itoc(&mut files, &itoc_table, base, align.unwrap_or(1).max(1) as u64)?;
+        }
+        if let Some(etoc_offset) = etoc_offset {
+            toc.read_etoc(stream, etoc_offset, &mut files)?;
+        }
+        if let Some(gtoc_offset) = gtoc_offset {
+            toc.read_gtoc(stream, gtoc_offset, &mut files)?;
+        }
+        toc.index = CpkPathIndex::new(&files);
+        toc.files = files;
+        Ok(toc)
+    }
+
+    pub fn header(&self) -> &CpkHeaderInfo { &self.header }
+
+    /// Offset that file offsets are relative to
+    pub fn content_offset(&self) -> u64 { self.content_ofs }
+
+    /// Files in TOC order, followed by files that only exist in the ITOC
+    pub fn files(&self) -> &[CpkFile] { &self.files }
+
+    pub fn index(&self) -> &CpkPathIndex { &self.index }
+
+    /// Find a file by its path. Paths are case-insensitive and may use either slash.
+    pub fn find_file(&self, path: &str) -> Option<&CpkFile> {
+        self.index.find_file(path)
+    }
+
+    /// Files directly inside a directory
+    pub fn list_directory(&self, directory: &str) -> Vec<&CpkFile> {
+        self.index.list_directory(directory)
+    }
+
+    /// Files matching a glob pattern, see `CpkPathIndex`
+    pub fn glob(&self, pattern: &str) -> Vec<&CpkFile> {
+        self.index.glob(pattern)
+    }
+
+    /// ETOC rows are in the same order as the TOC, with an UpdateDateTime and LocalDir for each
+    /// file.
+    fn read_etoc<R: Read + Seek>(&mut self, stream: &mut R, etoc_offset: u64, files: &mut [CpkFile]) -> Result<()> {
+        stream.seek(SeekFrom::Start(etoc_offset))?;
+        let etoc_table = self.etoc_table.insert(HighTable::<StringPoolFast>::new(
+            TableContainer::new(stream)?)?);
+        let etoc_str = etoc_table.get_strings();
+        let etoc_col = etoc_table.get_columns();
+        let mut update_date_time = usize::MAX;
+        let mut local_dir = usize::MAX;
+        for (i, c) in etoc_col.iter().enumerate() {
+            match etoc_str.get_string(c.get_string_offset()) {
+                Some("UpdateDateTime") => update_date_time = i,
+                Some("LocalDir") => local_dir = i,
+                _ => ()
+            }
+        }
+        for (file, row) in files.iter_mut().zip(etoc_table.get_rows()) {
+            if update_date_time != usize::MAX {
+                match (&row[update_date_time], etoc_col[update_date_time].get_default_value()) {
+                    (RowValue::UInt64(v), _) | (RowValue::None, Some(RowValue::UInt64(v))) =>
+                        file.set_update_date_time(*v),
+                    _ => ()
+                }
+            }
+            // files without a local directory are written as <NULL>
+            if local_dir != usize::MAX
+                && let Ok(dir) = row.cpk_get_string_may_default(&etoc_col[local_dir], etoc_str, local_dir, "LocalDir")
+                && dir != "<NULL>" {
+                file.set_local_dir(dir);
+            }
+        }
+        Ok(())
+    }
+
+    /// Group names and attributes are stored in nested tables in the first GTOC row:
+    /// - Gdata: Gname (string), Child (i32), Next (i32). Groups form a tree, where Next is the
+    ///   index of the next sibling group. A positive Child is the index of the first child group,
+    ///   while a negative Child links to the group's files through Flink[-Child - 1].
+    /// - Adata: Aname (string), indexed by Flink
+    /// - Flink: Aindex (u16), Next (i32), Child (u32). Child is the ID of a file in the group
+    ///   (or TOC index if the TOC has no IDs), and Next is the index of the next link, or -1.
+    fn read_gtoc<R: Read + Seek>(&mut self, stream: &mut R, gtoc_offset: u64, files: &mut [CpkFile]) -> Result<()> {
+        stream.seek(SeekFrom::Start(gtoc_offset))?;
+        let gtoc_table = HighTable::<StringPoolFast>::new(
+            TableContainer::new(stream)?)?;
+        let Some(row) = gtoc_table.get_rows().first() else { return Ok(()) };
+        let mut tables = [None, None, None];
+        for (i, c) in gtoc_table.get_columns().iter().enumerate() {
+            let slot = match gtoc_table.get_strings().get_string(c.get_string_offset()) {
+                Some("Gdata") => 0,
+                Some("Adata") => 1,
+                Some("Flink") => 2,
+                _ => continue
+            };
+            if let RowValue::Data(data) = &row[i] && !data.is_none() {
+                let data = gtoc_table.get_data(data).ok_or(CpkReaderError::InvalidGtoc)?;
+                tables[slot] = Some(HighTable::<StringPoolFast>::new(data.to_vec())?);
+            }
+        }
+        let [Some(gdata), adata, Some(flink)] = tables else { return Ok(()) };
+        let get_i32 = |table: &HighTable<StringPoolFast>, row: &Row, name: &str| {
+            let index = table.get_columns().iter().position(|c| table.get_strings()
+                .get_string(c.get_string_offset()) == Some(name))?;
+            match row[index] {
+                RowValue::Int32(v) => Some(v as i64),
+                RowValue::UInt16(v) => Some(v as i64),
+                RowValue::UInt32(v) => Some(v as i64),
+                _ => None
+            }
+        };
+        let get_str = |table: &HighTable<StringPoolFast>, row: &Row, name: &str| {
+            let index = table.get_columns().iter().position(|c| table.get_strings()
+                .get_string(c.get_string_offset()) == Some(name))?;
+            match row[index] {
+                RowValue::String(ofs) => table.get_strings().get_string(ofs).map(|s| s.to_owned()),
+                _ => None
+            }
+        };
+        // attribute names are stored first so Flink's Aindex can be used directly
+        if let Some(adata) = &adata {
+            for row in adata.get_rows() {
+                self.gtoc_strings.push(get_str(adata, row, "Aname").unwrap_or_default());
+            }
+        }
+        let attr_count = self.gtoc_strings.len();
+        let file_index: HashMap<u32, usize> = files.iter().enumerate()
+            .map(|(i, f)| (f.id().unwrap_or(i as u32), i)).collect();
+        let mut links = vec![];
+        // (group index, parent path)
+        let mut pending = vec![(0i64, String::new())];
+        let mut visited = HashSet::new();
+        while let Some((mut index, parent)) = pending.pop() {
+            while index >= 0 && visited.insert(index) {
+                let Some(group) = gdata.get_rows().get(index as usize) else { break };
+                let name = get_str(&gdata, group, "Gname").unwrap_or_default();
+                let path = match (parent.as_str(), name.as_str()) {
+                    ("", name) | (name, "") => name.to_owned(),
+                    (parent, name) => format!("{}/{}", parent, name)
+                };
+                match get_i32(&gdata, group, "Child") {
+                    Some(child) if child >= 0 => pending.push((child, path)),
+                    Some(child) => {
+                        self.gtoc_strings.push(path);
+                        links.push((self.gtoc_strings.len() - 1, -child - 1));
+                    },
+                    None => ()
+                }
+                index = get_i32(&gdata, group, "Next").unwrap_or(-1);
+            }
+        }
+        for (group, mut link) in links {
+            let mut seen = HashSet::new();
+            while link >= 0 && seen.insert(link) {
+                let Some(row) = flink.get_rows().get(link as usize) else { break };
+                let attribute = get_i32(&flink, row, "Aindex")
+                    .filter(|a| (*a as usize) < attr_count).map(|a| a as usize);
+                if let Some(file) = get_i32(&flink, row, "Child")
+                    .and_then(|id| file_index.get(&(id as u32))) {
+                    files[*file].set_group(&self.gtoc_strings[group],
+                        attribute.map(|a| self.gtoc_strings[a].as_str()));
+                }
+                link = get_i32(&flink, row, "Next").unwrap_or(-1);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_toc<R: Read + Seek>(&mut self, stream: &mut R, toc_offset: u64) -> Result<Vec<CpkFile>> {
+        // Read and cache TOC table
+        stream.seek(SeekFrom::Start(toc_offset))?;
+        self.toc_table = Some(HighTable::<StringPoolFast>::new(
+            TableContainer::new(stream)?)?);
+        let toc_table = self.toc_table.as_mut().unwrap();
+        let toc_str = toc_table.get_strings();
+        let toc_indices = TocTableIndices::new(toc_str, toc_table.get_columns());
+        toc_indices.check_required()?;
+        let toc_col = toc_table.get_columns();
+        let files = toc_table.get_rows();
+        let mut out = Vec::with_capacity(files.len());
+        for file in files {
+            let directory_name = file.cpk_get_directory_name(
+                &toc_col[toc_indices.dir_name], toc_str, toc_indices.dir_name)?;
+            let file_name = file.cpk_get_file_name(toc_str, toc_indices.file_name)?;
+            let file_offset = file.cpk_get_file_offset(toc_indices.file_offset)?;
+            let file_size = file.cpk_get_file_size(toc_indices.file_size)?;
+            let extract_size = file.cpk_get_extract_size(toc_indices.extract_size)?;
+            let user_string = file.cpk_get_user_string(
+                &toc_col[toc_indices.user_string], toc_str, toc_indices.user_string)?;
+            let mut entry = CpkFile::new(directory_name, file_name, file_offset, file_size, extract_size, user_string);
+            if toc_indices.id != usize::MAX
+                && let RowValue::UInt32(id) = file[toc_indices.id] {
+                entry = entry.with_id(id);
+            }
+            out.push(entry)
+        }
+        Ok(out)
+    }
+
+    /// Assign IDs from the ITOC to files from the TOC, and add files that only exist in the ITOC.
+    /// ITOC tables either map IDs to TOC rows (ID, TocIndex) or list file sizes by ID in
+    /// DataL (files smaller than 0x10000 bytes) and DataH nested tables.
+    fn merge_itoc(files: &mut Vec<CpkFile>, itoc: &HighTable<StringPoolFast>, base: u64, align: u64)
+        -> Result<()> {
+        let itoc_str = itoc.get_strings();
+        let itoc_indices = ItocTableIndices::new(itoc_str, itoc.get_columns());
+        if itoc_indices.id != usize::MAX && itoc_indices.toc_index != usize::MAX {
+            for row in itoc.get_rows() {
+                let id = row.cpk_get_integer_value(itoc_indices.id, "ID")?;
+                let index = row.cpk_get_integer_value(itoc_indices.toc_index, "TocIndex")?;
+                if let Some(file) = files.get_mut(index as usize) {
+                    file.set_id(id);
+                }
+            }
+            return Ok(());
+        }
+        let Some(row) = itoc.get_rows().first() else { return Ok(()) };
+        let mut entries = vec![];
+        for index in [itoc_indices.data_l, itoc_indices.data_h] {
+            if index == usize::MAX { continue; }
+            let RowValue::Data(data) = &row[index] else { continue };
+            if data.is_none() { continue; }
+            let data = itoc.get_data(data).ok_or(CpkReaderError::InvalidItoc)?;
+            let table = HighTable::<StringPoolFast>::new(data.to_vec())?;
+            let indices = ItocTableIndices::new(table.get_strings(), table.get_columns());
+            for row in table.get_rows() {
+                let id = row.cpk_get_integer_value(indices.id, "ID")?;
+                let file_size = row.cpk_get_integer_value(indices.file_size, "FileSize")?;
+                let extract_size = row.cpk_get_integer_value(indices.extract_size, "ExtractSize")?;
+                entries.push((id, file_size, extract_size));
+            }
+        }
+        entries.sort_by_key(|e| e.0);
+        let toc_ids: HashSet<u32> = files.iter().filter_map(|f| f.id()).collect();
+        let mut offset = base;
+        for (id, file_size, extract_size) in entries {
+            if !toc_ids.contains(&id) {
+                files.push(CpkFile::new("", "", offset, file_size, extract_size, "").with_id(id));
+            }
+            offset = (offset + file_size as u64).next_multiple_of(align);
+        }
+        Ok(())
+    }
+}
+
+impl Row {
+    fn cpk_get_string<'a, S: StringPool>(&'a self, string_pool: &'a S, col_index: usize, column: &'static str)
+        -> Result<&'a str> {
+        match self[col_index] {
+            RowValue::String(ofs) => string_pool.get_string(ofs)
+                .ok_or(CpkReaderError::InvalidString(ofs).into()),
+            ref v => Err(CriError::UnexpectedColumnType { column, expected: ColumnType::String, found: v.get_type() })
+        }
+    }
+
+    pub(crate) fn cpk_get_file_name<'a, S: StringPool>(&'a self, string_pool: &'a S, col_index: usize)
+        -> Result<&'a str> {
+        self.cpk_get_string(string_pool, col_index, "FileName")
+    }
+
+    /// Get an unsigned integer value of any width, as used by ITOC tables
+    pub(crate) fn cpk_get_integer(&self, col_index: usize) -> Option<u32> {
+        match self[col_index] {
+            RowValue::Byte(v) => Some(v as u32),
+            RowValue::UInt16(v) => Some(v as u32),
+            RowValue::UInt32(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Integer value of a column that ITOC tables require, usize::MAX if the table lacks it
+    fn cpk_get_integer_value(&self, col_index: usize, column: &'static str) -> Result<u32> {
+        if col_index == usize::MAX {
+            return Err(CriError::MissingColumn(column));
+        }
+        self.cpk_get_integer(col_index).ok_or_else(|| CriError::UnexpectedColumnType {
+            column, expected: ColumnType::UInt32, found: self[col_index].get_type() })
+    }
+
+    fn cpk_get_u32_value(&self, col_index: usize, column: &'static str) -> Result<u32> {
+        match self[col_index] {
+            RowValue::UInt32(size) => Ok(size),
+            ref v => Err(CriError::UnexpectedColumnType { column, expected: ColumnType::UInt32, found: v.get_type() })
+        }
+    }
+
+    pub(crate) fn cpk_get_file_size(&self, col_index: usize) -> Result<u32> {
+        self.cpk_get_u32_value(col_index, "FileSize")
+    }
+
+    pub(crate) fn cpk_get_extract_size(&self, col_index: usize) -> Result<u32> {
+        self.cpk_get_u32_value(col_index, "ExtractSize")
+    }
+
+    pub(crate) fn cpk_get_file_offset(&self, col_index: usize) -> Result<u64> {
+        match self[col_index] {
+            RowValue::UInt64(size) => Ok(size),
+            ref v => Err(CriError::UnexpectedColumnType { column: "FileOffset", expected: ColumnType::UInt64, found: v.get_type() })
+        }
+    }
+
+    pub(crate) fn cpk_get_string_may_default<'a, S: StringPool>(&'a self, column: &Column,
+        string_pool: &'a S, col_index: usize, name: &'static str) -> Result<&'a str> {
+        match (&self[col_index], column.get_default_value()) {
+            (RowValue::None, Some(RowValue::String(ofs))) => string_pool.get_string(*ofs)
+                .ok_or(CpkReaderError::InvalidString(*ofs).into()),
+            (RowValue::None, default) => Err(CriError::UnexpectedColumnType {
+                column: name, expected: ColumnType::String, found: default.and_then(|v| v.get_type()) }),
+            _ => self.cpk_get_string(string_pool, col_index, name)
+        }
+    }
+
+    pub(crate) fn cpk_get_directory_name<'a, S: StringPool>(&'a self, column: &Column,
+        string_pool: &'a S, col_index: usize) -> Result<&'a str> {
+        self.cpk_get_string_may_default(column, string_pool, col_index, "DirName")
+    }
+
+    pub(crate) fn cpk_get_user_string<'a, S: StringPool>(&'a self, column: &Column,
+        string_pool: &'a S, col_index: usize) -> Result<&'a str> {
+        self.cpk_get_string_may_default(column, string_pool, col_index, "UserString")
+    }
+}
+
+#[derive(Debug)]
+struct TocTableIndices {
+    dir_name: usize,
+    file_name: usize,
+    file_size: usize,
+    extract_size: usize,
+    file_offset: usize,
+    user_string: usize,
+    id: usize
+}
+
+impl TocTableIndices {
+    pub(crate) fn new<S: StringPool>(pool: &S, cols: &[Column]) -> Self {
+        let mut inst = Self {
+            dir_name: usize::MAX,
+            file_name: usize::MAX,
+            file_size: usize::MAX,
+            extract_size: usize::MAX,
+            file_offset: usize::MAX,
+            user_string: usize::MAX,
+            id: usize::MAX
+        };
+        for (i, c) in cols.iter().enumerate() {
+            if let Some(s) = pool.get_string(c.get_string_offset()) {
+                match s {
+                    "DirName" => inst.dir_name = i,
+                    "FileName" => inst.file_name = i,
+                    "FileSize" => inst.file_size = i,
+                    "ExtractSize" => inst.extract_size = i,
+                    "FileOffset" => inst.file_offset = i,
+                    "UserString" => inst.user_string = i,
+                    "ID" => inst.id = i,
+                    _ => ()
+                }
+            }
+        }
+        inst
+    }
+
+    /// Every column except ID has to be present to build a CpkFile
+    fn check_required(&self) -> Result<()> {
+        let required = [
+            (self.dir_name, "DirName"),
+            (self.file_name, "FileName"),
+            (self.file_size, "FileSize"),
+            (self.extract_size, "ExtractSize"),
+            (self.file_offset, "FileOffset"),
+            (self.user_string, "UserString"),
+        ];
+        match required.iter().find(|(index, _)| *index == usize::MAX) {
+            Some((_, name)) => Err(CriError::MissingColumn(name)),
+            None => Ok(())
+        }
+    }
+}
+
+/// Column indices shared by the ITOC table and its DataL/DataH subtables
+#[derive(Debug)]
+struct ItocTableIndices {
+    id: usize,
+    toc_index: usize,
+    file_size: usize,
+    extract_size: usize,
+    data_l: usize,
+    data_h: usize
+}
+
+impl ItocTableIndices {
+    pub(crate) fn new<S: StringPool>(pool: &S, cols: &[Column]) -> Self {
+        let mut inst = Self {
+            id: usize::MAX,
+            toc_index: usize::MAX,
+            file_size: usize::MAX,
+            extract_size: usize::MAX,
+            data_l: usize::MAX,
+            data_h: usize::MAX
+        };
+        for (i, c) in cols.iter().enumerate() {
+            if let Some(s) = pool.get_string(c.get_string_offset()) {
+                match s {
+                    "ID" => inst.id = i,
+                    "TocIndex" => inst.toc_index = i,
+                    "FileSize" => inst.file_size = i,
+                    "ExtractSize" => inst.extract_size = i,
+                    "DataL" => inst.data_l = i,
+                    "DataH" => inst.data_h = i,
+                    _ => ()
+                }
+            }
+        }
+        inst
+    }
+}
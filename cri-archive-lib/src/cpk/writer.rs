@@ -0,0 +1,819 @@
+//! # CPK Builder
+//!
+//! Creates CPK archives that can be read back using `CpkReader`.
+//!
+//! **Archive Layout:**
+//! - CPK header table: 0x0,
+//! - "(c)CRI" copyright: 0x7fa,
+//! - TOC table: 0x800,
+//! - Content: after TOC, with each file aligned to `Align`
+//! - ETOC table: after content, if any file has an update time or local directory
+//! - GTOC table: after the ETOC, if any file is in a group
+//!
+//! Each table is prefixed with a 0x10 byte chunk header (magic, 0xff, u64 table size). File
+//! offsets in the TOC are relative to the start of the TOC, which places the TOC before
+//! ContentOffset (see `CpkReader::get_files`).
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "cpk_compression_layla")]
+use crate::cpk::compress::layla::{LaylaCompressionLevel, LaylaCompressor};
+use crate::cpk::encrypt::data::FileEncryptor;
+use crate::cpk::file::CpkDateTime;
+use crate::error::{CriError, Result};
+use crate::schema::columns::ColumnType;
+use crate::schema::header::StringEncoding;
+use crate::schema::rows::RowValue;
+use crate::schema::writer::{TableColumn, TableWriter, TableWriterError};
+
+#[derive(Debug)]
+pub enum CpkWriterError {
+    DuplicateFile(String),
+    FileTooLarge(String),
+    HeaderTooLarge,
+    InvalidAlignment(u16),
+}
+
+impl Error for CpkWriterError {}
+
+impl Display for CpkWriterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Debug>::fmt(self, f)
+    }
+}
+
+#[derive(Debug)]
+enum CpkBuilderSource {
+    Memory(Vec<u8>),
+    Path(PathBuf)
+}
+
+/// Encryption applied to a file's data as stored
+#[derive(Debug, Clone, Copy)]
+struct CpkBuilderEncryption {
+    encrypt: fn(&mut [u8]),
+    /// Readers detect the file from its user string rather than its data
+    has_user_string: bool
+}
+
+#[derive(Debug)]
+pub struct CpkBuilderFile {
+    /// Directory in which the file is contained. DirName in CRI Table
+    directory: String,
+    /// Name of the file inside the directory. FileName in CRI Table
+    file_name: String,
+    /// Optional string attached to the file. UserString in CRI Table, written as <NULL> if unset
+    user_string: Option<String>,
+    /// Last modified time. UpdateDateTime in ETOC
+    update_date_time: Option<CpkDateTime>,
+    /// Directory the file was packed from. LocalDir in ETOC
+    local_dir: Option<String>,
+    /// Group path and attribute name. Written to GTOC
+    group: Option<(String, Option<String>)>,
+    /// Applied to the data as stored, after compression
+    encryptor: Option<CpkBuilderEncryption>,
+    source: CpkBuilderSource
+}
+
+impl CpkBuilderFile {
+    pub fn new(directory: &str, file_name: &str, data: Vec<u8>) -> Self {
+        Self { directory: directory.to_owned(), file_name: file_name.to_owned(), user_string: None,
+            update_date_time: None, local_dir: None, group: None, encryptor: None, source: CpkBuilderSource::Memory(data) }
+    }
+
+    /// Creates a file entry that is read from disk when the archive is written
+    pub fn from_path<P: AsRef<Path>>(directory: &str, file_name: &str, path: P) -> Self {
+        Self { directory: directory.to_owned(), file_name: file_name.to_owned(), user_string: None,
+            update_date_time: None, local_dir: None, group: None, encryptor: None,
+            source: CpkBuilderSource::Path(path.as_ref().to_path_buf()) }
+    }
+
+    pub fn with_user_string(mut self, user_string: &str) -> Self {
+        self.user_string = Some(user_string.to_owned());
+        self
+    }
+
+    /// Encrypt the file when the archive is written, setting the user string the matching
+    /// decryptor looks for
+    pub fn with_encryption<E: FileEncryptor>(mut self) -> Self {
+        if let Some(user_string) = E::USER_STRING {
+            self.user_string = Some(user_string.to_owned());
+        }
+        self.encryptor = Some(CpkBuilderEncryption { encrypt: E::encrypt_in_place,
+            has_user_string: E::USER_STRING.is_some() });
+        self
+    }
+
+    pub fn with_update_date_time(mut self, update_date_time: CpkDateTime) -> Self {
+        self.update_date_time = Some(update_date_time);
+        self
+    }
+
+    pub fn with_local_dir(mut self, local_dir: &str) -> Self {
+        self.local_dir = Some(local_dir.to_owned());
+        self
+    }
+
+    /// Put the file in a group, which is a path of group names separated by forward slashes
+    pub fn with_group(mut self, group: &str, attribute: Option<&str>) -> Self {
+        self.group = Some((group.to_owned(), attribute.map(|a| a.to_owned())));
+        self
+    }
+
+    pub fn directory(&self) -> &str { &self.directory }
+    pub fn file_name(&self) -> &str { &self.file_name }
+    pub fn user_string(&self) -> Option<&str> { self.user_string.as_deref() }
+    pub fn update_date_time(&self) -> Option<CpkDateTime> { self.update_date_time }
+    pub fn local_dir(&self) -> Option<&str> { self.local_dir.as_deref() }
+    pub fn group(&self) -> Option<&str> { self.group.as_ref().map(|g| g.0.as_str()) }
+    pub fn attribute(&self) -> Option<&str> { self.group.as_ref().and_then(|g| g.1.as_deref()) }
+    pub fn is_encrypted(&self) -> bool { self.encryptor.is_some() }
+
+    fn path(&self) -> String {
+        match self.directory.as_str() {
+            "" => self.file_name.clone(),
+            d => format!("{}/{}", d, self.file_name)
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(match &self.source {
+            CpkBuilderSource::Memory(v) => v.len() as u64,
+            CpkBuilderSource::Path(p) => std::fs::metadata(p)?.len()
+        })
+    }
+
+    fn write_to<W: Write>(&self, stream: &mut W) -> Result<()> {
+        match &self.source {
+            CpkBuilderSource::Memory(v) => stream.write_all(v)?,
+            CpkBuilderSource::Path(p) => { std::io::copy(&mut std::fs::File::open(p)?, stream)?; }
+        };
+        Ok(())
+    }
+
+    fn read(&self) -> Result<Cow<'_, [u8]>> {
+        Ok(match &self.source {
+            CpkBuilderSource::Memory(v) => Cow::Borrowed(v.as_slice()),
+            CpkBuilderSource::Path(p) => Cow::Owned(std::fs::read(p)?)
+        })
+    }
+}
+
+/// File data as it will be stored in the content area
+#[derive(Debug)]
+enum CpkPayload<'a> {
+    Stored(&'a CpkBuilderFile, u32),
+    /// Compressed or encrypted data, with the size once extracted
+    Processed(Vec<u8>, u32)
+}
+
+impl CpkPayload<'_> {
+    fn file_size(&self) -> u32 {
+        match self {
+            Self::Stored(_, size) => *size,
+            Self::Processed(data, _) => data.len() as u32
+        }
+    }
+
+    fn extract_size(&self) -> u32 {
+        match self {
+            Self::Stored(_, size) => *size,
+            Self::Processed(_, size) => *size
+        }
+    }
+
+    fn write_to<W: Write>(&self, stream: &mut W) -> Result<()> {
+        match self {
+            Self::Stored(file, _) => file.write_to(stream),
+            Self::Processed(data, _) => Ok(stream.write_all(data)?)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CpkBuilder {
+    files: Vec<CpkBuilderFile>,
+    align: u16,
+    encoding: StringEncoding,
+    #[cfg(feature = "cpk_compression_layla")]
+    compression: Option<LaylaCompressionLevel>
+}
+
+impl Default for CpkBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpkBuilder {
+    pub const DEFAULT_ALIGNMENT: u16 = 0x800;
+    const TOC_OFFSET: u64 = 0x800;
+    const COPYRIGHT: &'static [u8] = b"(c)CRI";
+    const TOOL_VERSION: &'static str = concat!("cri-archive-lib ", env!("CARGO_PKG_VERSION"));
+
+    pub fn new() -> Self {
+        Self { files: vec![], align: Self::DEFAULT_ALIGNMENT, encoding: StringEncoding::UTF8,
+            #[cfg(feature = "cpk_compression_layla")]
+            compression: None }
+    }
+
+    /// Set the alignment of each file in the content area. Must be a power of two.
+    pub fn set_alignment(&mut self, align: u16) -> Result<()> {
+        if !align.is_power_of_two() {
+            return Err(CpkWriterError::InvalidAlignment(align).into());
+        }
+        self.align = align;
+        Ok(())
+    }
+
+    pub fn get_alignment(&self) -> u16 { self.align }
+
+    /// Set the encoding of strings in the archive's tables. Older games such as Persona 4 Golden
+    /// use Shift-JIS.
+    pub fn set_encoding(&mut self, encoding: StringEncoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn get_encoding(&self) -> StringEncoding { self.encoding }
+
+    /// Compress files with CRILAYLA when writing the archive. Files are only stored compressed if
+    /// that makes them smaller.
+    #[cfg(feature = "cpk_compression_layla")]
+    pub fn set_compression(&mut self, level: Option<LaylaCompressionLevel>) {
+        self.compression = level;
+    }
+
+    #[cfg(feature = "cpk_compression_layla")]
+    pub fn get_compression(&self) -> Option<LaylaCompressionLevel> { self.compression }
+
+    pub fn files(&self) -> &[CpkBuilderFile] { &self.files }
+
+    pub fn add_file(&mut self, file: CpkBuilderFile) {
+        self.files.push(file);
+    }
+
+    /// Add every file inside of a directory tree. Directory names are stored relative to `root`
+    /// using forward slashes, with files at the root of the tree having an empty DirName.
+    pub fn add_directory<P: AsRef<Path>>(&mut self, root: P) -> Result<()> {
+        let mut pending = vec![(root.as_ref().to_path_buf(), String::new())];
+        while let Some((path, directory)) = pending.pop() {
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if entry.file_type()?.is_dir() {
+                    let child = match directory.as_str() {
+                        "" => name,
+                        d => format!("{}/{}", d, name)
+                    };
+                    pending.push((entry.path(), child));
+                } else {
+                    self.add_file(CpkBuilderFile::from_path(&directory, &name, entry.path()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn align_up(value: u64, align: u64) -> u64 {
+        (value + align - 1) & !(align - 1)
+    }
+
+    fn write_chunk<W: Write>(stream: &mut W, magic: &[u8; 4], table: &[u8]) -> Result<()> {
+        stream.write_all(magic)?;
+        stream.write_all(&0xffu32.to_le_bytes())?;
+        stream.write_all(&(table.len() as u64).to_le_bytes())?;
+        stream.write_all(table)?;
+        Ok(())
+    }
+
+    fn write_padding<W: Write + Seek>(stream: &mut W, start: u64, target: u64) -> Result<()> {
+        let current = stream.stream_position()? - start;
+        if target > current {
+            std::io::copy(&mut std::io::repeat(0).take(target - current), stream)?;
+        }
+        Ok(())
+    }
+
+    fn prepare<'a>(&self, file: &'a CpkBuilderFile) -> Result<CpkPayload<'a>> {
+        let size = file.size()?;
+        if size > u32::MAX as u64 {
+            return Err(CpkWriterError::FileTooLarge(file.path()).into());
+        }
+        #[cfg(feature = "cpk_compression_layla")]
+        if let Some(level) = self.compression
+            && size >= 0x100
+            && let Ok(mut compressed) = LaylaCompressor::compress(&file.read()?, level)
+            && (compressed.len() as u64) < size {
+            // readers decrypt before decompressing
+            if let Some(encryption) = file.encryptor {
+                // without a user string, encryption is detected from the decrypted data, which
+                // has to be the original file rather than CRILAYLA data
+                if !encryption.has_user_string {
+                    return Err(CriError::Encryption(file.path()));
+                }
+                (encryption.encrypt)(&mut compressed);
+            }
+            return Ok(CpkPayload::Processed(compressed, size as u32));
+        }
+        if let Some(encryption) = file.encryptor {
+            let mut data = file.read()?.into_owned();
+            (encryption.encrypt)(&mut data);
+            return Ok(CpkPayload::Processed(data, size as u32));
+        }
+        Ok(CpkPayload::Stored(file, size as u32))
+    }
+
+    fn build_toc(&self, files: &[&CpkBuilderFile], payloads: &[CpkPayload], offsets: &[u64])
+        -> Result<Vec<u8>, TableWriterError> {
+        let mut toc = TableWriter::new_with_encoding("CpkTocInfo", self.encoding);
+        let columns = [
+            ("DirName", ColumnType::String),
+            ("FileName", ColumnType::String),
+            ("FileSize", ColumnType::UInt32),
+            ("ExtractSize", ColumnType::UInt32),
+            ("FileOffset", ColumnType::UInt64),
+            ("ID", ColumnType::UInt32),
+            ("UserString", ColumnType::String),
+        ];
+        for (name, ctype) in columns {
+            toc.add_column(TableColumn::row(name, ctype));
+        }
+        for (i, file) in files.iter().enumerate() {
+            let row = vec![
+                RowValue::String(toc.add_string(&file.directory)),
+                RowValue::String(toc.add_string(&file.file_name)),
+                RowValue::UInt32(payloads[i].file_size()),
+                RowValue::UInt32(payloads[i].extract_size()),
+                RowValue::UInt64(offsets[i]),
+                RowValue::UInt32(i as u32),
+                RowValue::String(file.user_string.as_deref().map_or(0, |s| toc.add_string(s)))
+            ];
+            toc.add_row(row);
+        }
+        toc.to_bytes()
+    }
+
+    fn build_etoc(&self, files: &[&CpkBuilderFile]) -> Result<Option<Vec<u8>>, TableWriterError> {
+        if files.iter().all(|f| f.update_date_time.is_none() && f.local_dir.is_none()) {
+            return Ok(None);
+        }
+        let mut etoc = TableWriter::new_with_encoding("CpkEtocInfo", self.encoding);
+        etoc.add_column(TableColumn::row("UpdateDateTime", ColumnType::UInt64));
+        etoc.add_column(TableColumn::row("LocalDir", ColumnType::String));
+        for file in files {
+            let row = vec![
+                RowValue::UInt64(file.update_date_time.map_or(0, |t| t.raw())),
+                RowValue::String(file.local_dir.as_deref().map_or(0, |s| etoc.add_string(s)))
+            ];
+            etoc.add_row(row);
+        }
+        Ok(Some(etoc.to_bytes()?))
+    }
+
+    /// Groups are written as children of an unnamed root group, using the full group path as
+    /// each group's name. See `CpkToc::read_gtoc` for the table layout. Returns the table along
+    /// with the number of groups and attributes.
+    fn build_gtoc(&self, files: &[&CpkBuilderFile]) -> Result<Option<(Vec<u8>, u32, u32)>, TableWriterError> {
+        // group path and the IDs of the files inside it
+        let mut groups: Vec<(&str, Vec<usize>)> = vec![];
+        let mut attributes: Vec<&str> = vec![];
+        for (id, file) in files.iter().enumerate() {
+            let Some((group, attribute)) = &file.group else { continue };
+            let attribute = attribute.as_deref();
+            if let Some(attribute) = attribute && !attributes.contains(&attribute) {
+                attributes.push(attribute);
+            }
+            match groups.iter_mut().find(|g| g.0 == group) {
+                Some((_, links)) => links.push(id),
+                None => groups.push((group, vec![id]))
+            }
+        }
+        if groups.is_empty() {
+            return Ok(None);
+        }
+        let mut gdata = vec![("", 1, -1)];
+        let mut flink = vec![];
+        for (i, (group, links)) in groups.iter().enumerate() {
+            let next = if i + 1 < groups.len() { i as i32 + 2 } else { -1 };
+            gdata.push((*group, -(flink.len() as i32) - 1, next));
+            for (j, id) in links.iter().enumerate() {
+                let next = if j + 1 < links.len() { flink.len() as i32 + 1 } else { -1 };
+                // files without an attribute point past the end of Adata
+                let aindex = files[*id].attribute()
+                    .and_then(|a| attributes.iter().position(|b| *b == a))
+                    .map_or(u16::MAX, |a| a as u16);
+                flink.push((aindex, next, *id as u32));
+            }
+        }
+
+        let gdata_rows = gdata.len() as u32;
+        let gdata = {
+            let mut table = TableWriter::new_with_encoding("CpkGtocGlink", self.encoding);
+            table.add_column(TableColumn::row("Gname", ColumnType::String));
+            table.add_column(TableColumn::row("Child", ColumnType::Int32));
+            table.add_column(TableColumn::row("Next", ColumnType::Int32));
+            for (name, child, next) in gdata {
+                let row = vec![RowValue::String(table.add_string(name)), RowValue::Int32(child),
+                    RowValue::Int32(next)];
+                table.add_row(row);
+            }
+            table.to_bytes()?
+        };
+        let adata = {
+            let mut table = TableWriter::new_with_encoding("CpkGtocAttr", self.encoding);
+            table.add_column(TableColumn::row("Aname", ColumnType::String));
+            for attribute in &attributes {
+                let row = vec![RowValue::String(table.add_string(attribute))];
+                table.add_row(row);
+            }
+            table.to_bytes()?
+        };
+        let flink = {
+            let mut table = TableWriter::new_with_encoding("CpkGtocFlink", self.encoding);
+            table.add_column(TableColumn::row("Aindex", ColumnType::UInt16));
+            table.add_column(TableColumn::row("Next", ColumnType::Int32));
+            table.add_column(TableColumn::row("Child", ColumnType::UInt32));
+            for (aindex, next, id) in flink {
+                table.add_row(vec![RowValue::UInt16(aindex), RowValue::Int32(next), RowValue::UInt32(id)]);
+            }
+            table.to_bytes()?
+        };
+        let mut gtoc = TableWriter::new_with_encoding("CpkGtocInfo", self.encoding);
+        gtoc.add_column(TableColumn::row("Glink", ColumnType::UInt32));
+        gtoc.add_column(TableColumn::row("Gdata", ColumnType::Data));
+        gtoc.add_column(TableColumn::row("Adata", ColumnType::Data));
+        gtoc.add_column(TableColumn::row("Flink", ColumnType::Data));
+        let row = vec![RowValue::UInt32(gdata_rows), RowValue::Data(gtoc.add_data(&gdata)),
+            RowValue::Data(gtoc.add_data(&adata)), RowValue::Data(gtoc.add_data(&flink))];
+        gtoc.add_row(row);
+        Ok(Some((gtoc.to_bytes()?, groups.len() as u32, attributes.len() as u32)))
+    }
+
+    fn build_header(&self, info: &CpkLayout) -> Result<Vec<u8>, TableWriterError> {
+        let mut header = TableWriter::new_with_encoding("CpkHeader", self.encoding);
+        let tvers = header.add_string(Self::TOOL_VERSION);
+        let comment = header.add_string("");
+        let columns = [
+            ("UpdateDateTime", ColumnType::UInt64, RowValue::UInt64(1)),
+            ("FileSize", ColumnType::UInt64, RowValue::None),
+            ("ContentOffset", ColumnType::UInt64, RowValue::UInt64(info.content_offset)),
+            ("ContentSize", ColumnType::UInt64, RowValue::UInt64(info.content_size)),
+            ("TocOffset", ColumnType::UInt64, RowValue::UInt64(Self::TOC_OFFSET)),
+            ("TocSize", ColumnType::UInt64, RowValue::UInt64(info.toc_size)),
+            ("TocCrc", ColumnType::UInt32, RowValue::None),
+            ("HtocOffset", ColumnType::UInt64, RowValue::None),
+            ("HtocSize", ColumnType::UInt64, RowValue::None),
+            ("EtocOffset", ColumnType::UInt64, info.etoc.map_or(RowValue::None, |e| RowValue::UInt64(e.0))),
+            ("EtocSize", ColumnType::UInt64, info.etoc.map_or(RowValue::None, |e| RowValue::UInt64(e.1))),
+            ("ItocOffset", ColumnType::UInt64, RowValue::None),
+            ("ItocSize", ColumnType::UInt64, RowValue::None),
+            ("ItocCrc", ColumnType::UInt32, RowValue::None),
+            ("GtocOffset", ColumnType::UInt64, info.gtoc.map_or(RowValue::None, |g| RowValue::UInt64(g.0))),
+            ("GtocSize", ColumnType::UInt64, info.gtoc.map_or(RowValue::None, |g| RowValue::UInt64(g.1))),
+            ("GtocCrc", ColumnType::UInt32, RowValue::None),
+            ("HgtocOffset", ColumnType::UInt64, RowValue::None),
+            ("HgtocSize", ColumnType::UInt64, RowValue::None),
+            ("EnabledPackedSize", ColumnType::UInt64, RowValue::UInt64(info.packed_size)),
+            ("EnabledDataSize", ColumnType::UInt64, RowValue::UInt64(info.data_size)),
+            ("TotalDataSize", ColumnType::UInt64, RowValue::None),
+            ("Tocs", ColumnType::UInt32, RowValue::None),
+            ("Files", ColumnType::UInt32, RowValue::UInt32(self.files.len() as u32)),
+            ("Groups", ColumnType::UInt32, RowValue::UInt32(info.groups)),
+            ("Attrs", ColumnType::UInt32, RowValue::UInt32(info.attrs)),
+            ("TotalFiles", ColumnType::UInt32, RowValue::None),
+            ("Directories", ColumnType::UInt32, RowValue::None),
+            ("Updates", ColumnType::UInt32, RowValue::None),
+            ("Version", ColumnType::UInt16, RowValue::UInt16(7)),
+            ("Revision", ColumnType::UInt16, RowValue::UInt16(0)),
+            ("Align", ColumnType::UInt16, RowValue::UInt16(self.align)),
+            ("Sorted", ColumnType::UInt16, RowValue::UInt16(1)),
+            ("EnableFileName", ColumnType::UInt16, RowValue::UInt16(1)),
+            ("EID", ColumnType::UInt16, RowValue::None),
+            ("CpkMode", ColumnType::UInt32, RowValue::UInt32(1)),
+            ("Tvers", ColumnType::String, RowValue::String(tvers)),
+            ("Comment", ColumnType::String, RowValue::String(comment)),
+            ("Codec", ColumnType::UInt32, RowValue::UInt32(0)),
+            ("DpkItoc", ColumnType::UInt32, RowValue::UInt32(0)),
+            ("EnableTocCrc", ColumnType::UInt16, RowValue::None),
+            ("EnableFileCrc", ColumnType::UInt16, RowValue::None),
+            ("CrcMode", ColumnType::UInt32, RowValue::None),
+            ("CrcTable", ColumnType::Data, RowValue::None),
+        ];
+        let mut row = Vec::with_capacity(columns.len());
+        for (name, ctype, value) in columns {
+            header.add_column(match value {
+                RowValue::None => TableColumn::zero(name, ctype),
+                _ => TableColumn::row(name, ctype)
+            });
+            row.push(value);
+        }
+        header.add_row(row);
+        header.to_bytes()
+    }
+
+    /// Write the archive into the stream, starting at its current position.
+    pub fn write<W: Write + Seek>(&self, stream: &mut W) -> Result<()> {
+        let start = stream.stream_position()?;
+        // TOC is sorted by path
+        let mut files: Vec<&CpkBuilderFile> = self.files.iter().collect();
+        files.sort_by(|a, b| (&a.directory, &a.file_name).cmp(&(&b.directory, &b.file_name)));
+        for pair in files.windows(2) {
+            if pair[0].directory == pair[1].directory && pair[0].file_name == pair[1].file_name {
+                return Err(CpkWriterError::DuplicateFile(pair[0].path()).into());
+            }
+        }
+        let mut payloads = Vec::with_capacity(files.len());
+        for file in &files {
+            payloads.push(self.prepare(file)?);
+        }
+        // TOC size doesn't depend on the file offsets, so measure it first to find where
+        // the content area starts.
+        let align = self.align as u64;
+        let toc_size = self.build_toc(&files, &payloads, &vec![0; files.len()])?.len() as u64 + 0x10;
+        let content_offset = Self::align_up(Self::TOC_OFFSET + toc_size, align);
+        let mut offsets = Vec::with_capacity(files.len());
+        let mut content_end = content_offset;
+        for payload in &payloads {
+            offsets.push(content_end - Self::TOC_OFFSET);
+            content_end = Self::align_up(content_end + payload.file_size() as u64, align);
+        }
+        let toc = self.build_toc(&files, &payloads, &offsets)?;
+        let etoc = self.build_etoc(&files)?;
+        let gtoc = self.build_gtoc(&files)?;
+        let etoc_end = content_end + etoc.as_ref().map_or(0, |e| e.len() as u64 + 0x10);
+        let header = self.build_header(&CpkLayout {
+            content_offset,
+            content_size: content_end - content_offset,
+            toc_size,
+            packed_size: payloads.iter().map(|p| p.file_size() as u64).sum(),
+            data_size: payloads.iter().map(|p| p.extract_size() as u64).sum(),
+            etoc: etoc.as_ref().map(|e| (content_end, e.len() as u64 + 0x10)),
+            gtoc: gtoc.as_ref().map(|g| (etoc_end, g.0.len() as u64 + 0x10)),
+            groups: gtoc.as_ref().map_or(0, |g| g.1),
+            attrs: gtoc.as_ref().map_or(0, |g| g.2)
+        })?;
+        if header.len() + 0x10 > Self::TOC_OFFSET as usize - Self::COPYRIGHT.len() {
+            return Err(CpkWriterError::HeaderTooLarge.into());
+        }
+        Self::write_chunk(stream, b"CPK ", &header)?;
+        Self::write_padding(stream, start, Self::TOC_OFFSET - Self::COPYRIGHT.len() as u64)?;
+        stream.write_all(Self::COPYRIGHT)?;
+        Self::write_chunk(stream, b"TOC ", &toc)?;
+        for (payload, offset) in payloads.iter().zip(offsets.iter()) {
+            Self::write_padding(stream, start, Self::TOC_OFFSET + offset)?;
+            payload.write_to(stream)?;
+        }
+        Self::write_padding(stream, start, content_end)?;
+        let mut end = content_end;
+        if let Some(etoc) = &etoc {
+            stream.seek(SeekFrom::Start(start + content_end))?;
+            Self::write_chunk(stream, b"ETOC", etoc)?;
+            end += etoc.len() as u64 + 0x10;
+        }
+        if let Some((gtoc, _, _)) = &gtoc {
+            stream.seek(SeekFrom::Start(start + end))?;
+            Self::write_chunk(stream, b"GTOC", gtoc)?;
+            end += gtoc.len() as u64 + 0x10;
+        }
+        stream.seek(SeekFrom::Start(start + end))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct CpkLayout {
+    content_offset: u64,
+    content_size: u64,
+    toc_size: u64,
+    packed_size: u64,
+    data_size: u64,
+    /// Offset and size of the ETOC
+    etoc: Option<(u64, u64)>,
+    /// Offset and size of the GTOC
+    gtoc: Option<(u64, u64)>,
+    groups: u32,
+    attrs: u32
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use std::io::Cursor;
+    use crate::cpk::file::CpkDateTime;
+    use crate::cpk::reader::CpkReader;
+    use crate::cpk::writer::{CpkBuilder, CpkBuilderFile};
+    use crate::error::CriError;
+    use crate::fixtures::sample_data;
+
+    #[test]
+    fn build_and_read_back() -> Result<(), Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("", "root.bin", sample_data(0x1234, 3)));
+        builder.add_file(CpkBuilderFile::new("MODEL/CHARACTER/0001", "C0001_002_00.GMD",
+            sample_data(0x9001, 7)).with_user_string("CRI_CFATTR:ENCRYPT"));
+        builder.add_file(CpkBuilderFile::new("MODEL/CHARACTER/0001", "C0001_001_00.GMD",
+            sample_data(0x10, 11)));
+        builder.add_file(CpkBuilderFile::new("SOUND", "empty.acb", vec![]));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        let files = reader.get_files()?;
+        assert_eq!(files.len(), 4);
+        // TOC is sorted by path
+        assert_eq!(files[0].directory(), "");
+        assert_eq!(files[0].file_name(), "root.bin");
+        assert_eq!(files[0].user_string(), "<NULL>");
+        assert_eq!(files[1].directory(), "MODEL/CHARACTER/0001");
+        assert_eq!(files[1].file_name(), "C0001_001_00.GMD");
+        assert_eq!(files[2].file_name(), "C0001_002_00.GMD");
+        assert_eq!(files[2].user_string(), "CRI_CFATTR:ENCRYPT");
+        assert_eq!(files[2].file_size(), 0x9001);
+        assert_eq!(files[2].extract_size(), 0x9001);
+        assert_eq!(files[3].directory(), "SOUND");
+        assert_eq!(files[3].file_size(), 0);
+        for file in &files {
+            assert_eq!(file.file_offset() % CpkBuilder::DEFAULT_ALIGNMENT as u64, 0);
+        }
+        assert_eq!(reader.extract_file(&files[0])?, sample_data(0x1234, 3));
+        assert_eq!(reader.extract_file(&files[1])?, sample_data(0x10, 11));
+        assert_eq!(reader.extract_file(&files[2])?, sample_data(0x9001, 7));
+        assert_eq!(reader.extract_file(&files[3])?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn build_custom_alignment() -> Result<(), Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        assert!(builder.set_alignment(0x30).is_err());
+        builder.set_alignment(0x20)?;
+        builder.add_file(CpkBuilderFile::new("a", "1.bin", sample_data(0x21, 5)));
+        builder.add_file(CpkBuilderFile::new("a", "2.bin", sample_data(0x3, 9)));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        let files = reader.get_files()?;
+        assert_eq!(files[1].file_offset() - files[0].file_offset(), 0x40);
+        assert_eq!(reader.extract_file(&files[0])?, sample_data(0x21, 5));
+        assert_eq!(reader.extract_file(&files[1])?, sample_data(0x3, 9));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "cpk_compression_layla")]
+    fn build_compressed() -> Result<(), Box<dyn Error>> {
+        use crate::cpk::compress::layla::LaylaCompressionLevel;
+        let text = "Everything's going to be alright. ".repeat(0x80).into_bytes();
+        let mut builder = CpkBuilder::new();
+        builder.set_compression(Some(LaylaCompressionLevel::Normal));
+        builder.add_file(CpkBuilderFile::new("", "small.txt", b"too small to compress".to_vec()));
+        builder.add_file(CpkBuilderFile::new("", "text.txt", text.clone()));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        let files = reader.get_files()?;
+        assert_eq!(files[0].file_size(), files[0].extract_size());
+        assert!(files[1].file_size() < files[1].extract_size());
+        assert_eq!(files[1].extract_size() as usize, text.len());
+        assert_eq!(reader.extract_file(&files[0])?, b"too small to compress".to_vec());
+        assert_eq!(reader.extract_file(&files[1])?, text);
+        Ok(())
+    }
+
+    #[test]
+    fn build_with_etoc() -> Result<(), Box<dyn Error>> {
+        let time = CpkDateTime::new(2016, 9, 15, 12, 34, 56);
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("", "a.bin", sample_data(0x20, 3))
+            .with_update_date_time(time).with_local_dir("C:/work/data"));
+        builder.add_file(CpkBuilderFile::new("", "b.bin", sample_data(0x30, 5)));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        let files = reader.get_files()?;
+        assert_eq!(files[0].update_date_time(), Some(time));
+        assert_eq!(files[0].update_date_time().unwrap().to_string(), "2016-09-15 12:34:56");
+        assert_eq!(files[0].local_dir(), Some("C:/work/data"));
+        assert_eq!(files[1].update_date_time().map(|t| t.raw()), Some(0));
+        assert_eq!(files[1].local_dir(), None);
+        assert_eq!(reader.extract_file(&files[1])?, sample_data(0x30, 5));
+        Ok(())
+    }
+
+    #[test]
+    fn build_with_gtoc() -> Result<(), Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("", "a.bin", sample_data(0x20, 3))
+            .with_group("STAGE/BATTLE", Some("resident")));
+        builder.add_file(CpkBuilderFile::new("", "b.bin", sample_data(0x30, 5)));
+        builder.add_file(CpkBuilderFile::new("", "c.bin", sample_data(0x40, 7))
+            .with_group("STAGE/FIELD", None).with_update_date_time(CpkDateTime::new(2016, 9, 15, 0, 0, 0)));
+        builder.add_file(CpkBuilderFile::new("", "d.bin", sample_data(0x50, 9))
+            .with_group("STAGE/BATTLE", Some("stream")));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        let header = reader.read_header()?;
+        assert_eq!(header.groups(), Some(2));
+        assert_eq!(header.attrs(), Some(2));
+        assert!(header.gtoc_offset().unwrap() > header.etoc_offset().unwrap());
+        let files = reader.get_files()?;
+        let groups: Vec<_> = files.iter().map(|f| (f.file_name(), f.group(), f.attribute())).collect();
+        assert_eq!(groups, vec![
+            ("a.bin", Some("STAGE/BATTLE"), Some("resident")),
+            ("b.bin", None, None),
+            ("c.bin", Some("STAGE/FIELD"), None),
+            ("d.bin", Some("STAGE/BATTLE"), Some("stream")),
+        ]);
+        assert_eq!(reader.extract_file(&files[3])?, sample_data(0x50, 9));
+        Ok(())
+    }
+
+    #[test]
+    fn build_rejects_duplicates() -> Result<(), Box<dyn Error>> {
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("a", "1.bin", vec![1]));
+        builder.add_file(CpkBuilderFile::new("a", "1.bin", vec![2]));
+        assert!(builder.write(&mut Cursor::new(vec![])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn build_from_directory() -> Result<(), Box<dyn Error>> {
+        let root = std::env::temp_dir().join(format!("cri-archive-lib-cpk-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("DATA/FIELD"))?;
+        std::fs::write(root.join("top.txt"), b"top level")?;
+        std::fs::write(root.join("DATA/FIELD/f001.bin"), sample_data(0x801, 13))?;
+        let mut builder = CpkBuilder::new();
+        builder.add_directory(&root)?;
+        let mut cpk = Cursor::new(vec![]);
+        let result = builder.write(&mut cpk);
+        std::fs::remove_dir_all(&root)?;
+        result?;
+        let mut reader = CpkReader::new(Cursor::new(cpk.into_inner()))?;
+        let files = reader.get_files()?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].directory(), "");
+        assert_eq!(files[0].file_name(), "top.txt");
+        assert_eq!(files[1].directory(), "DATA/FIELD");
+        assert_eq!(files[1].file_name(), "f001.bin");
+        assert_eq!(reader.extract_file(&files[0])?, b"top level".to_vec());
+        assert_eq!(reader.extract_file(&files[1])?, sample_data(0x801, 13));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "cpk_encryption_p5r")]
+    fn build_p5r_encrypted() -> Result<(), Box<dyn Error>> {
+        use crate::cpk::encrypt::p5r::{P5RDecryptor, P5REncryptor};
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("", "plain.bin", sample_data(0x1000, 3)));
+        builder.add_file(CpkBuilderFile::new("", "secret.bin", sample_data(0x1000, 5))
+            .with_encryption::<P5REncryptor>());
+        assert!(builder.files()[1].is_encrypted());
+        assert_eq!(builder.files()[1].user_string(), Some("CRI_CFATTR:ENCRYPT"));
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let cpk = cpk.into_inner();
+        let mut reader = CpkReader::<_, P5RDecryptor>::new_with_encryption(Cursor::new(cpk.clone()))?;
+        let files = reader.get_files()?;
+        assert_eq!(files[1].user_string(), "CRI_CFATTR:ENCRYPT");
+        assert_eq!(reader.extract_file(&files[0])?, sample_data(0x1000, 3));
+        assert_eq!(reader.extract_file(&files[1])?, sample_data(0x1000, 5));
+        // stored encrypted
+        let mut reader = CpkReader::new(Cursor::new(cpk))?;
+        let files = reader.get_files()?;
+        assert_ne!(reader.extract_file(&files[1])?, sample_data(0x1000, 5));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "cpk_encryption_table")]
+    fn build_table_encrypted() -> Result<(), Box<dyn Error>> {
+        use crate::cpk::encrypt::table::{TableDecryptor, TableEncryptor, TableFileDecryptor};
+        let table = b"@UTF".iter().copied().chain(sample_data(0x100, 7)).collect::<Vec<u8>>();
+        let mut builder = CpkBuilder::new();
+        builder.add_file(CpkBuilderFile::new("", "table.@utf", table.clone()).with_encryption::<TableEncryptor>());
+        assert_eq!(builder.files()[0].user_string(), None);
+        let mut cpk = Cursor::new(vec![]);
+        builder.write(&mut cpk)?;
+        let cpk = cpk.into_inner();
+        let mut reader = CpkReader::<_, TableFileDecryptor>::new_with_encryption(Cursor::new(cpk.clone()))?;
+        let files = reader.get_files()?;
+        assert_eq!(reader.extract_file(&files[0])?, table);
+        let mut reader = CpkReader::new(Cursor::new(cpk))?;
+        let files = reader.get_files()?;
+        assert!(TableDecryptor::is_encrypted(reader.extract_file(&files[0])?.as_slice()));
+        // readers detect it from the decrypted data, which can't be compressed
+        #[cfg(feature = "cpk_compression_layla")] {
+            builder.set_compression(Some(crate::cpk::compress::layla::LaylaCompressionLevel::default()));
+            builder.add_file(CpkBuilderFile::new("", "zeros.@utf", vec![0; 0x1000]).with_encryption::<TableEncryptor>());
+            assert!(matches!(builder.write(&mut Cursor::new(vec![])), Err(CriError::Encryption(_))));
+        }
+        Ok(())
+    }
+}
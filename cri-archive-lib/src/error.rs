@@ -0,0 +1,200 @@
+//! # Errors
+//!
+//! Every fallible public API returns `CriError`. Failures that are specific to one format are
+//! kept in that module's error enum and wrapped here, so callers can either match on the exact
+//! cause or pass the error between threads (`CriError` is `Send + Sync`).
+
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use crate::schema::columns::ColumnType;
+use crate::schema::writer::TableWriterError;
+
+pub type Result<T, E = CriError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum CriError {
+    Io(std::io::Error),
+    /// @UTF table data is truncated or inconsistent. Offset is from the start of the table, and
+    /// column is the index of the column being read, if any
+    MalformedTable { offset: u64, column: Option<usize>, reason: &'static str },
+    /// Column type is outside of the 13 types defined by `ColumnType`
+    UnsupportedColumnType { offset: u64, value: u8 },
+    /// Column holds a different type than the one that was expected. None if it has no value
+    UnexpectedColumnType { column: &'static str, expected: ColumnType, found: Option<ColumnType> },
+    /// Table is missing a column that's required to read it
+    MissingColumn(&'static str),
+    /// CRILAYLA data couldn't be compressed or decompressed
+    #[cfg(feature = "cpk_compression_layla")]
+    Compression(crate::cpk::compress::layla::LaylaError),
+    /// File can't be encrypted in a way that readers are able to detect, with the file's path
+    Encryption(String),
+    TableWriter(TableWriterError),
+    /// Table couldn't be deserialized into the requested type
+    #[cfg(feature = "serde")]
+    Deserialize(String),
+    #[cfg(feature = "cpk")]
+    CpkReader(crate::cpk::reader::CpkReaderError),
+    #[cfg(feature = "cpk")]
+    CpkWriter(crate::cpk::writer::CpkWriterError),
+    #[cfg(feature = "acb")]
+    Acb(crate::acb::error::AcbError),
+    #[cfg(feature = "adx")]
+    Adx(crate::adx::error::AdxError),
+    #[cfg(feature = "awb")]
+    Awb(crate::awb::error::AwbError),
+    #[cfg(feature = "hca")]
+    Hca(crate::hca::error::HcaError),
+    #[cfg(feature = "usm")]
+    Usm(crate::usm::error::UsmError),
+}
+
+impl CriError {
+    pub(crate) fn malformed(offset: u64, reason: &'static str) -> Self {
+        Self::MalformedTable { offset, column: None, reason }
+    }
+
+    /// Reads that run out of data inside of a table mean the table is malformed, rather than
+    /// the stream failing
+    pub(crate) fn table_read(error: std::io::Error, offset: u64, column: Option<usize>,
+        reason: &'static str) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::MalformedTable { offset, column, reason },
+            _ => Self::Io(error)
+        }
+    }
+}
+
+impl Error for CriError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            #[cfg(feature = "cpk_compression_layla")]
+            Self::Compression(e) => Some(e),
+            Self::TableWriter(e) => Some(e),
+            #[cfg(feature = "cpk")]
+            Self::CpkReader(e) => Some(e),
+            #[cfg(feature = "cpk")]
+            Self::CpkWriter(e) => Some(e),
+            #[cfg(feature = "acb")]
+            Self::Acb(e) => Some(e),
+            #[cfg(feature = "adx")]
+            Self::Adx(e) => Some(e),
+            #[cfg(feature = "awb")]
+            Self::Awb(e) => Some(e),
+            #[cfg(feature = "hca")]
+            Self::Hca(e) => Some(e),
+            #[cfg(feature = "usm")]
+            Self::Usm(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl Display for CriError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // wrapped errors are returned from source, so they aren't repeated here
+        match self {
+            Self::Io(_) => write!(f, "I/O error"),
+            Self::MalformedTable { offset, column: Some(column), reason } =>
+                write!(f, "malformed table at {offset:#x} (column {column}): {reason}"),
+            Self::MalformedTable { offset, column: None, reason } =>
+                write!(f, "malformed table at {offset:#x}: {reason}"),
+            Self::UnsupportedColumnType { offset, value } =>
+                write!(f, "unsupported column type {value:#x} at {offset:#x}"),
+            Self::UnexpectedColumnType { column, expected, found: Some(found) } =>
+                write!(f, "column {column} should be {expected:?}, found {found:?}"),
+            Self::UnexpectedColumnType { column, expected, found: None } =>
+                write!(f, "column {column} should be {expected:?}, but has no value"),
+            Self::MissingColumn(column) => write!(f, "table is missing the {column} column"),
+            #[cfg(feature = "cpk_compression_layla")]
+            Self::Compression(_) => write!(f, "CRILAYLA compression error"),
+            Self::Encryption(path) => write!(f, "{path} can't be encrypted so that readers detect it"),
+            Self::TableWriter(_) => write!(f, "table writer error"),
+            #[cfg(feature = "serde")]
+            Self::Deserialize(message) => write!(f, "couldn't deserialize table: {message}"),
+            #[cfg(feature = "cpk")]
+            Self::CpkReader(_) => write!(f, "CPK reader error"),
+            #[cfg(feature = "cpk")]
+            Self::CpkWriter(_) => write!(f, "CPK writer error"),
+            #[cfg(feature = "acb")]
+            Self::Acb(_) => write!(f, "ACB error"),
+            #[cfg(feature = "adx")]
+            Self::Adx(_) => write!(f, "ADX error"),
+            #[cfg(feature = "awb")]
+            Self::Awb(_) => write!(f, "AWB error"),
+            #[cfg(feature = "hca")]
+            Self::Hca(_) => write!(f, "HCA error"),
+            #[cfg(feature = "usm")]
+            Self::Usm(_) => write!(f, "USM error")
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::de::Error for CriError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self::Deserialize(msg.to_string())
+    }
+}
+
+macro_rules! impl_from_error {
+    ($($(#[$attr:meta])* $variant:ident($error:ty)),* $(,)?) => {
+        $(
+            $(#[$attr])*
+            impl From<$error> for CriError {
+                fn from(value: $error) -> Self {
+                    Self::$variant(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_error!(
+    Io(std::io::Error),
+    #[cfg(feature = "cpk_compression_layla")]
+    Compression(crate::cpk::compress::layla::LaylaError),
+    TableWriter(TableWriterError),
+    #[cfg(feature = "cpk")]
+    CpkReader(crate::cpk::reader::CpkReaderError),
+    #[cfg(feature = "cpk")]
+    CpkWriter(crate::cpk::writer::CpkWriterError),
+    #[cfg(feature = "acb")]
+    Acb(crate::acb::error::AcbError),
+    #[cfg(feature = "adx")]
+    Adx(crate::adx::error::AdxError),
+    #[cfg(feature = "awb")]
+    Awb(crate::awb::error::AwbError),
+    #[cfg(feature = "hca")]
+    Hca(crate::hca::error::HcaError),
+    #[cfg(feature = "usm")]
+    Usm(crate::usm::error::UsmError),
+);
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::error::CriError;
+
+    #[test]
+    fn error_is_send_sync() -> Result<(), Box<dyn Error>> {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<CriError>();
+        // still usable with ? in functions returning Box<dyn Error>
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(CriError::MissingColumn("FileName"));
+        assert_eq!(boxed.to_string(), "table is missing the FileName column");
+        Ok(())
+    }
+
+    #[test]
+    fn error_display_and_source() -> Result<(), Box<dyn Error>> {
+        let io = std::io::Error::other("disk on fire");
+        let error = CriError::from(std::io::Error::other("disk on fire"));
+        assert_eq!(error.to_string(), "I/O error");
+        assert_eq!(error.source().map(|e| e.to_string()), Some(io.to_string()));
+        let error = CriError::MalformedTable { offset: 0x20, column: Some(1), reason: "column extends past the end of the table" };
+        assert_eq!(error.to_string(), "malformed table at 0x20 (column 1): column extends past the end of the table");
+        assert!(error.source().is_none());
+        Ok(())
+    }
+}
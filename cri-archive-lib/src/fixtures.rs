@@ -0,0 +1,268 @@
+//! # Test Fixtures
+//!
+//! Small synthetic files built with `TableWriter` and `CpkBuilder`, so that tests don't depend on
+//! game data being present:
+//! - `sample_acb`: ACB header with the usual leading columns, plus cue, waveform, synth, sequence
+//!   and track tables
+//! - `sample_acf`: ACF with categories, AISAC controls, buses, DSP settings and game variables
+//! - `sample_cpk`: CPK with stored and compressed files, optionally with encrypted tables
+//! - `p5r_cpk`: CPK with encrypted tables and a compressed file with P5R encryption
+//! - `shift_jis_cpk`: CPK with Shift-JIS tables
+
+use std::error::Error;
+#[cfg(feature = "cpk")]
+use std::io::Cursor;
+use crate::schema::columns::ColumnType;
+use crate::schema::rows::RowValue;
+use crate::schema::writer::{TableColumn, TableWriter};
+#[cfg(feature = "cpk")]
+use crate::cpk::writer::{CpkBuilder, CpkBuilderFile};
+
+/// Deterministic bytes which compress well
+#[cfg(feature = "cpk")]
+pub(crate) fn sample_data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(seed).wrapping_add(i as u8 >> 3)).collect()
+}
+
+/// Deterministic bytes which don't compress (xorshift)
+#[cfg(feature = "cpk")]
+pub(crate) fn noise(len: usize) -> Vec<u8> {
+    noise_with_seed(len, 0x2545f491)
+}
+
+#[cfg(feature = "cpk")]
+pub(crate) fn noise_with_seed(len: usize, mut state: u32) -> Vec<u8> {
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect()
+}
+
+/// Text of the given length
+#[cfg(feature = "cpk")]
+pub(crate) fn text(len: usize) -> Vec<u8> {
+    let line = "In the lonely night, the stars are shining bright. Take your time, take your heart. ";
+    line.repeat(len / line.len() + 1).into_bytes()[..len].to_vec()
+}
+
+/// Noise interleaved with repeated runs, like a model file
+#[cfg(feature = "cpk")]
+pub(crate) fn model(len: usize) -> Vec<u8> {
+    let mut out = noise(0x800.min(len));
+    let mut i = 0;
+    while out.len() < len {
+        let start = (i * 0x1f) % (out.len() - 0x40);
+        out.extend_from_within(start..start + 0x40);
+        out.extend(noise_with_seed(i % 0x20, i as u32 + 1));
+        i += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+pub(crate) enum TableValue {
+    Value(RowValue),
+    String(String),
+    Data(Vec<u8>)
+}
+
+/// Build a table with the given columns, adding strings and Data values through the table's pools
+pub(crate) fn build_table(name: &str, columns: &[(&str, ColumnType)], rows: Vec<Vec<TableValue>>)
+    -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut table = TableWriter::new(name);
+    for (name, ctype) in columns {
+        table.add_column(TableColumn::row(name, *ctype));
+    }
+    for row in rows {
+        let row = row.into_iter().map(|v| match v {
+            TableValue::Value(v) => v,
+            TableValue::String(s) => RowValue::String(table.add_string(&s)),
+            TableValue::Data(d) => RowValue::Data(table.add_data(&d))
+        }).collect();
+        table.add_row(row);
+    }
+    Ok(table.to_bytes()?)
+}
+
+fn u16_list(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// AcfMd5Hash of `sample_acb`
+pub(crate) const SAMPLE_ACF_MD5: [u8; 16] = [236, 103, 97, 106, 90, 25, 172, 164, 161, 234, 209, 75, 242, 34, 227, 209];
+/// Version of `sample_acb`
+pub(crate) const SAMPLE_ACB_VERSION: u32 = 0x01370200;
+
+/// ACB named "sample" with three cues: "direct" plays a waveform, "synth" plays a synth with two
+/// waveforms and "sequence" plays a sequence whose tracks refer to a synth (which loops back to the
+/// sequence) and a waveform.
+///
+/// Header columns start with FileIdentifier, Size, Version, Type, Target, AcfMd5Hash,
+/// CategoryExtension then CueTable.
+pub(crate) fn sample_acb() -> Result<Vec<u8>, Box<dyn Error>> {
+    use TableValue::*;
+    let u8v = |v: u8| Value(RowValue::Byte(v));
+    let u16v = |v: u16| Value(RowValue::UInt16(v));
+    let u32v = |v: u32| Value(RowValue::UInt32(v));
+    let cues = build_table("Cue", &[("CueId", ColumnType::UInt32), ("ReferenceType", ColumnType::Byte),
+        ("ReferenceIndex", ColumnType::UInt16)], vec![
+        vec![u32v(10), u8v(1), u16v(0)],
+        vec![u32v(20), u8v(2), u16v(0)],
+        vec![u32v(30), u8v(3), u16v(0)],
+    ])?;
+    let cue_names = build_table("CueName", &[("CueName", ColumnType::String), ("CueIndex", ColumnType::UInt16)],
+        // sorted by name like in real ACBs, so it doesn't follow the Cue table's order
+        [("direct", 0), ("sequence", 2), ("synth", 1)].iter()
+            .map(|(n, i)| vec![String(n.to_string()), u16v(*i)]).collect())?;
+    let waveforms = build_table("Waveform", &[("MemoryAwbId", ColumnType::UInt16),
+        ("StreamAwbId", ColumnType::UInt16), ("EncodeType", ColumnType::Byte), ("Streaming", ColumnType::Byte),
+        ("NumChannels", ColumnType::Byte), ("LoopFlag", ColumnType::Byte), ("SamplingRate", ColumnType::UInt16),
+        ("NumSamples", ColumnType::UInt32), ("ExtensionData", ColumnType::UInt16)], vec![
+        vec![u16v(0), u16v(0xffff), u8v(2), u8v(0), u8v(2), u8v(1), u16v(48000), u32v(1000), u16v(0)],
+        vec![u16v(1), u16v(0xffff), u8v(2), u8v(0), u8v(1), u8v(0), u16v(44100), u32v(2000), u16v(0xffff)],
+        vec![u16v(0xffff), u16v(5), u8v(0), u8v(1), u8v(2), u8v(0), u16v(32000), u32v(3000), u16v(0xffff)],
+    ])?;
+    let extensions = build_table("WaveformExtensionData",
+        &[("LoopStart", ColumnType::UInt32), ("LoopEnd", ColumnType::UInt32)], vec![vec![u32v(100), u32v(900)]])?;
+    let synths = build_table("Synth", &[("ReferenceItems", ColumnType::Data)], vec![
+        vec![Data(u16_list(&[1, 1, 1, 2]))],
+        vec![Data(u16_list(&[1, 0, 3, 0]))],
+    ])?;
+    let sequences = build_table("Sequence", &[("NumTracks", ColumnType::UInt16), ("TrackIndex", ColumnType::Data)],
+        vec![vec![u16v(2), Data(u16_list(&[0, 1, 1]))]])?;
+    let tracks = build_table("Track", &[("EventIndex", ColumnType::UInt16)], vec![vec![u16v(0)], vec![u16v(1)]])?;
+    let events = build_table("TrackEvent", &[("Command", ColumnType::Data)], vec![
+        vec![Data([&[0x07, 0xd0, 4][..], &u16_list(&[2, 1]), &[0, 0, 0]].concat())],
+        vec![Data([&[0x00, 0x41, 2, 0x12, 0x34, 0x07, 0xd3, 4][..], &u16_list(&[1, 2])].concat())],
+    ])?;
+    let mut columns: Vec<(&str, ColumnType)> = vec![("FileIdentifier", ColumnType::UInt32),
+        ("Size", ColumnType::UInt32), ("Version", ColumnType::UInt32), ("Type", ColumnType::Byte),
+        ("Target", ColumnType::Byte), ("AcfMd5Hash", ColumnType::Data), ("CategoryExtension", ColumnType::Byte)];
+    let mut row = vec![u32v(0), u32v(0), u32v(SAMPLE_ACB_VERSION), u8v(0), u8v(0), Data(SAMPLE_ACF_MD5.to_vec()), u8v(0)];
+    let tables = [("CueTable", cues), ("CueNameTable", cue_names), ("WaveformTable", waveforms),
+        ("WaveformExtensionDataTable", extensions), ("SynthTable", synths), ("SequenceTable", sequences),
+        ("TrackTable", tracks), ("TrackEventTable", events)];
+    columns.extend(tables.iter().map(|(n, _)| (*n, ColumnType::Data)));
+    row.extend(tables.into_iter().map(|(_, t)| Data(t)));
+    columns.push(("Name", ColumnType::String));
+    row.push(String("sample".to_owned()));
+    build_table("Header", &columns, vec![row])
+}
+
+/// Table of name plus one other column per row
+fn name_table(name: &str, column: (&str, ColumnType), rows: &[(&str, RowValue)]) -> Result<Vec<u8>, Box<dyn Error>> {
+    build_table(name, &[("Name", ColumnType::String), column], rows.iter()
+        .map(|(n, v)| vec![TableValue::String(n.to_string()), TableValue::Value(v.clone())]).collect())
+}
+
+/// ACF named "sound" with categories "bgm" and "voice", AISAC controls "Any" and "Distance", buses
+/// "MasterOut" and "BUS1", a DSP setting and the game variable "Speed"
+pub(crate) fn sample_acf() -> Result<Vec<u8>, Box<dyn Error>> {
+    let settings = build_table("DspSetting", &[("Name", ColumnType::String), ("BusIndexes", ColumnType::Data)],
+        vec![vec![TableValue::String("DspBusSetting_0".to_owned()), TableValue::Data(vec![0, 0, 0, 1])]])?;
+    let tables = [
+        ("CategoryTable", name_table("Category", ("GroupNo", ColumnType::UInt32),
+            &[("bgm", RowValue::UInt32(0)), ("voice", RowValue::UInt32(1))])?),
+        ("AisacControlNameTable", name_table("AisacControlName", ("Id", ColumnType::UInt16),
+            &[("Any", RowValue::UInt16(0)), ("Distance", RowValue::UInt16(3))])?),
+        ("DspBusTable", name_table("DspBus", ("Volume", ColumnType::Single),
+            &[("MasterOut", RowValue::Single(1.)), ("BUS1", RowValue::Single(0.5))])?),
+        ("DspSettingTable", settings),
+        ("GameVariableTable", name_table("GameVariable", ("Value", ColumnType::Single),
+            &[("Speed", RowValue::Single(0.25))])?),
+    ];
+    let mut columns = vec![("Name", ColumnType::String), ("Version", ColumnType::UInt32)];
+    columns.extend(tables.iter().map(|(n, _)| (*n, ColumnType::Data)));
+    let mut row = vec![TableValue::String("sound".to_owned()), TableValue::Value(RowValue::UInt32(0x01300000))];
+    row.extend(tables.into_iter().map(|(_, t)| TableValue::Data(t)));
+    build_table("Header", &columns, vec![row])
+}
+
+/// Files in `sample_cpk`, in TOC order. Audio and image are noise so they stay uncompressed.
+#[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
+pub(crate) fn sample_cpk_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("Audio-NoCompression.flac", [&b"fLaC"[..], &noise_with_seed(48427, 1)].concat()),
+        ("Image-NoCompression.jpg", [&[0xff, 0xd8, 0xff, 0xe0][..], &noise_with_seed(120715, 2)].concat()),
+        ("Text-Compressed.txt", text(3592)),
+    ]
+}
+
+#[cfg(feature = "cpk")]
+fn write_cpk(builder: &CpkBuilder) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut cpk = Cursor::new(vec![]);
+    builder.write(&mut cpk)?;
+    Ok(cpk.into_inner())
+}
+
+/// Encrypt the CPK header and TOC tables in place
+#[cfg(feature = "cpk_encryption_table")]
+pub(crate) fn encrypt_tables(cpk: &mut [u8]) {
+    use crate::cpk::encrypt::table::TableEncryptor;
+    for offset in [0, 0x800] {
+        let size = u64::from_le_bytes(cpk[offset + 0x8..offset + 0x10].try_into().unwrap()) as usize;
+        TableEncryptor::encrypt_utf_in_place(&mut cpk[offset + 0x10..offset + 0x10 + size]);
+    }
+}
+
+/// CPK containing `sample_cpk_files` at the root, compressed where that's smaller
+#[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
+pub(crate) fn sample_cpk(encrypted_tables: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    use crate::cpk::compress::layla::LaylaCompressionLevel;
+    let mut builder = CpkBuilder::new();
+    builder.set_compression(Some(LaylaCompressionLevel::Normal));
+    for (name, data) in sample_cpk_files() {
+        builder.add_file(CpkBuilderFile::new("", name, data));
+    }
+    let mut cpk = write_cpk(&builder)?;
+    if encrypted_tables {
+        encrypt_tables(&mut cpk);
+    }
+    Ok(cpk)
+}
+
+/// Path and contents of the P5R encrypted file in `p5r_cpk`
+#[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_p5r", feature = "cpk_encryption_table"))]
+pub(crate) fn p5r_cpk_model() -> (&'static str, Vec<u8>) {
+    ("MODEL/CHARACTER/0001/C0001_002_00.GMD", model(0x9000))
+}
+
+/// CPK laid out like Persona 5 Royal's: encrypted tables, a compressed file with P5R encryption
+/// from `p5r_cpk_model` and a stored plain file "SOUND/empty.bin"
+#[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_p5r", feature = "cpk_encryption_table"))]
+pub(crate) fn p5r_cpk() -> Result<Vec<u8>, Box<dyn Error>> {
+    use crate::cpk::compress::layla::LaylaCompressionLevel;
+    use crate::cpk::encrypt::p5r::P5REncryptor;
+    let (path, data) = p5r_cpk_model();
+    let (directory, file_name) = path.rsplit_once('/').unwrap();
+    let mut builder = CpkBuilder::new();
+    builder.set_compression(Some(LaylaCompressionLevel::Normal));
+    builder.add_file(CpkBuilderFile::new(directory, file_name, data).with_encryption::<P5REncryptor>());
+    builder.add_file(CpkBuilderFile::new("SOUND", "empty.bin", vec![]));
+    let mut cpk = write_cpk(&builder)?;
+    encrypt_tables(&mut cpk);
+    Ok(cpk)
+}
+
+/// Files in `shift_jis_cpk`: (directory, file name, contents)
+#[cfg(feature = "cpk")]
+pub(crate) fn shift_jis_cpk_files() -> Vec<(&'static str, &'static str, Vec<u8>)> {
+    vec![
+        ("データ", "テスト.bin", sample_data(0x40, 3)),
+        ("font", "フォント.fnt", sample_data(0x20, 5)),
+    ]
+}
+
+/// CPK with Shift-JIS tables, like Persona 4 Golden's
+#[cfg(feature = "cpk")]
+pub(crate) fn shift_jis_cpk() -> Result<Vec<u8>, Box<dyn Error>> {
+    use crate::schema::header::StringEncoding;
+    let mut builder = CpkBuilder::new();
+    builder.set_encoding(StringEncoding::ShiftJIS);
+    for (directory, file_name, data) in shift_jis_cpk_files() {
+        builder.add_file(CpkBuilderFile::new(directory, file_name, data));
+    }
+    write_cpk(&builder)
+}
@@ -0,0 +1,88 @@
+/// MSB first bit reader over an HCA frame. Reading past the end returns zeroes, callers check
+/// `position` against the frame's size once they're done.
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize
+}
+
+impl<'a> BitReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, bit: 0 }
+    }
+
+    pub(crate) fn position(&self) -> usize { self.bit }
+
+    /// Read up to 32 bits without advancing
+    pub(crate) fn peek(&self, bits: u32) -> u32 {
+        if bits == 0 {
+            return 0;
+        }
+        let byte = self.bit >> 3;
+        let window = (0..5).fold(0u64, |w, i| (w << 8) | *self.data.get(byte + i).unwrap_or(&0) as u64);
+        let shift = 40 - (self.bit & 7) as u32 - bits;
+        ((window >> shift) & ((1 << bits) - 1)) as u32
+    }
+
+    pub(crate) fn read(&mut self, bits: u32) -> u32 {
+        let value = self.peek(bits);
+        self.bit += bits as usize;
+        value
+    }
+
+    pub(crate) fn skip(&mut self, bits: i32) {
+        self.bit = self.bit.saturating_add_signed(bits as isize);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::hca::bits::BitReader;
+
+    /// MSB first bit writer for building test frames
+    pub(crate) struct BitWriter {
+        data: Vec<u8>,
+        bit: usize
+    }
+
+    impl BitWriter {
+        pub(crate) fn new() -> Self { Self { data: vec![], bit: 0 } }
+
+        pub(crate) fn write(&mut self, bits: u32, value: u32) {
+            for i in (0..bits).rev() {
+                if self.bit >> 3 == self.data.len() {
+                    self.data.push(0);
+                }
+                let set = (value >> i) & 1;
+                self.data[self.bit >> 3] |= (set << (7 - (self.bit & 7))) as u8;
+                self.bit += 1;
+            }
+        }
+
+        pub(crate) fn into_inner(self) -> Vec<u8> { self.data }
+    }
+
+    #[test]
+    fn read_bits() -> Result<(), Box<dyn Error>> {
+        let mut writer = BitWriter::new();
+        writer.write(3, 0b101);
+        writer.write(9, 0x1ff);
+        writer.write(32, 0xdeadbeef);
+        writer.write(1, 0);
+        writer.write(12, 0x123);
+        let data = writer.into_inner();
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.read(3), 0b101);
+        assert_eq!(reader.peek(9), 0x1ff);
+        reader.skip(9);
+        assert_eq!(reader.read(32), 0xdeadbeef);
+        assert_eq!(reader.read(1), 0);
+        reader.skip(-1);
+        assert_eq!(reader.read(13), 0x123);
+        assert_eq!(reader.position(), 57);
+        // past the end
+        assert_eq!(reader.read(16), 0);
+        assert_eq!(reader.position(), 73);
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! # HCA Frame Encryption
+//!
+//! Frames are encrypted by substituting each byte through a 256 entry table. 0x00 and 0xFF map to
+//! themselves, so frame sync words are left intact.
+//!
+//! - **Type 0**: No encryption
+//! - **Type 1**: Fixed table, no key needed
+//! - **Type 56**: Table derived from a 56-bit key. Waveforms stored in an AWB mix the AWB's subkey
+//!   into the key, see `HcaCipher::mix_key`.
+
+use crate::hca::error::HcaError;
+
+#[derive(Debug, Clone)]
+pub struct HcaCipher {
+    table: [u8; 256]
+}
+
+impl HcaCipher {
+    /// Build the decryption table for a cipher type. Type 56 with a key of 0 isn't encrypted.
+    pub fn new(cipher_type: u16, key: u64) -> Result<Self, HcaError> {
+        let table = match cipher_type {
+            0 => Self::identity(),
+            1 => Self::fixed_table(),
+            56 if key == 0 => Self::identity(),
+            56 => Self::keyed_table(key),
+            t => return Err(HcaError::UnsupportedCipherType(t))
+        };
+        Ok(Self { table })
+    }
+
+    /// Combine a key with the subkey from an AWB header (or the HCA's ACB cue)
+    pub fn mix_key(key: u64, subkey: u16) -> u64 {
+        match subkey {
+            0 => key,
+            s => key.wrapping_mul(((s as u64) << 16) | ((!s) as u64 + 2))
+        }
+    }
+
+    /// Decryption table, maps each encrypted byte to its plain value
+    pub fn table(&self) -> &[u8; 256] { &self.table }
+
+    pub fn decrypt(&self, data: &mut [u8]) {
+        data.iter_mut().for_each(|b| *b = self.table[*b as usize]);
+    }
+
+    /// Reverse of `decrypt`
+    pub fn encrypt(&self, data: &mut [u8]) {
+        let mut inverse = [0u8; 256];
+        for (i, v) in self.table.iter().enumerate() {
+            inverse[*v as usize] = i as u8;
+        }
+        data.iter_mut().for_each(|b| *b = inverse[*b as usize]);
+    }
+
+    fn identity() -> [u8; 256] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    fn fixed_table() -> [u8; 256] {
+        let mut table = [0; 256];
+        let mut v = 0u8;
+        for entry in &mut table[1..0xff] {
+            v = v.wrapping_mul(13).wrapping_add(11);
+            if v == 0 || v == 0xff {
+                v = v.wrapping_mul(13).wrapping_add(11);
+            }
+            *entry = v;
+        }
+        table[0xff] = 0xff;
+        table
+    }
+
+    /// 16 nibbles from a linear congruential generator seeded by a key byte
+    fn nibble_sequence(key: u8) -> [u8; 16] {
+        let mul = ((key & 1) << 3) | 5;
+        let add = (key & 0xe) | 1;
+        let mut value = key >> 4;
+        std::array::from_fn(|_| {
+            value = (value.wrapping_mul(mul).wrapping_add(add)) & 0xf;
+            value
+        })
+    }
+
+    fn keyed_table(key: u64) -> [u8; 256] {
+        // only the lower 7 bytes are used
+        let kc = (key - 1).to_le_bytes();
+        let seed = [
+            kc[1], kc[1] ^ kc[6], kc[2] ^ kc[3], kc[2],
+            kc[2] ^ kc[1], kc[3] ^ kc[4], kc[3], kc[3] ^ kc[2],
+            kc[4] ^ kc[5], kc[4], kc[4] ^ kc[3], kc[5] ^ kc[6],
+            kc[5], kc[5] ^ kc[4], kc[6] ^ kc[1], kc[6]
+        ];
+        let rows = Self::nibble_sequence(kc[0]);
+        let mut base = [0u8; 256];
+        for (r, row) in rows.iter().enumerate() {
+            let columns = Self::nibble_sequence(seed[r]);
+            for (c, column) in columns.iter().enumerate() {
+                base[r * 0x10 + c] = (row << 4) | column;
+            }
+        }
+        // shuffle, skipping the fixed 0x00 and 0xFF
+        let mut table = [0; 256];
+        let mut pos = 1;
+        let mut x = 0u8;
+        for _ in 0..256 {
+            x = x.wrapping_add(17);
+            if base[x as usize] != 0 && base[x as usize] != 0xff {
+                table[pos] = base[x as usize];
+                pos += 1;
+            }
+        }
+        table[0xff] = 0xff;
+        table
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::hca::cipher::HcaCipher;
+    use crate::hca::error::HcaError;
+
+    fn is_permutation(table: &[u8; 256]) -> bool {
+        let mut seen = [false; 256];
+        table.iter().for_each(|v| seen[*v as usize] = true);
+        seen.iter().all(|s| *s)
+    }
+
+    #[test]
+    fn cipher_tables() -> Result<(), Box<dyn Error>> {
+        let plain = HcaCipher::new(0, 0x1234)?;
+        assert!(plain.table().iter().enumerate().all(|(i, v)| i as u8 == *v));
+        let fixed = HcaCipher::new(1, 0)?;
+        assert_eq!(fixed.table()[..4], [0, 11, 154, 221]);
+        assert!(is_permutation(fixed.table()));
+        let keyed = HcaCipher::new(56, 0xCF222F1FE0748978)?;
+        assert_eq!((keyed.table()[0], keyed.table()[0xff]), (0, 0xff));
+        assert!(is_permutation(keyed.table()));
+        assert_ne!(keyed.table(), HcaCipher::new(56, 0xCF222F1FE0748979)?.table());
+        // no key means no encryption
+        assert_eq!(HcaCipher::new(56, 0)?.table(), plain.table());
+        assert!(matches!(HcaCipher::new(2, 0), Err(HcaError::UnsupportedCipherType(2))));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), Box<dyn Error>> {
+        let cipher = HcaCipher::new(56, HcaCipher::mix_key(0x30DBE1AB, 0x4d2))?;
+        let data: Vec<u8> = (0..=255).collect();
+        let mut encrypted = data.clone();
+        cipher.encrypt(&mut encrypted);
+        assert_ne!(encrypted, data);
+        cipher.decrypt(&mut encrypted);
+        assert_eq!(encrypted, data);
+        Ok(())
+    }
+
+    #[test]
+    fn mix_subkey() -> Result<(), Box<dyn Error>> {
+        assert_eq!(HcaCipher::mix_key(0x1234, 0), 0x1234);
+        assert_eq!(HcaCipher::mix_key(1, 0x1234), 0x1234EDCD);
+        assert_eq!(HcaCipher::mix_key(3, 1), 0x30000);
+        Ok(())
+    }
+}
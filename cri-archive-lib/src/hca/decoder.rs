@@ -0,0 +1,718 @@
+//! # HCA Decoder
+//!
+//! Each frame holds 1024 samples per channel, split into 8 subframes of 128 MDCT coefficients.
+//! Decoding a frame goes through the following steps:
+//!
+//! - Check the frame's sync word and CRC16, then decrypt it
+//! - Per channel, unpack scalefactors and intensities, then derive each band's resolution (bits
+//!   per coefficient) from the scalefactors and the frame's noise level
+//! - Per subframe, dequantize coefficients, fill unencoded bands with noise and high frequency
+//!   bands from lower ones, split intensity stereo pairs and run the inverse MDCT
+//!
+//! Implementation based on CRI's library as documented by vgmstream's clHCA.
+
+use crate::error::Result;
+use crate::hca::bits::BitReader;
+use crate::hca::cipher::HcaCipher;
+use crate::hca::error::HcaError;
+use crate::hca::header::{HcaHeader, VERSION_200};
+use crate::hca::tables::{ath_curve, crc16, HcaTables, INVERT_TABLE, MAX_BIT_SIZE, READ_BIT_COUNT, READ_VALUES,
+    SAMPLES_PER_FRAME, SAMPLES_PER_SUBFRAME, SUBFRAMES};
+
+const DEFAULT_RANDOM: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChannelType {
+    Discrete,
+    StereoPrimary,
+    StereoSecondary
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    kind: ChannelType,
+    /// Number of bands with coefficients in the bitstream
+    coded_count: usize,
+    intensity: [u8; SUBFRAMES],
+    scalefactors: [u8; SAMPLES_PER_SUBFRAME],
+    resolution: [u8; SAMPLES_PER_SUBFRAME],
+    /// Bands with resolution 0 from the start, bands with coefficients from the end
+    noises: [u8; SAMPLES_PER_SUBFRAME],
+    noise_count: usize,
+    valid_count: usize,
+    gain: [f32; SAMPLES_PER_SUBFRAME],
+    spectra: [[f32; SAMPLES_PER_SUBFRAME]; SUBFRAMES],
+    imdct_previous: [f32; SAMPLES_PER_SUBFRAME],
+    wave: [[f32; SAMPLES_PER_SUBFRAME]; SUBFRAMES]
+}
+
+impl Channel {
+    fn new(kind: ChannelType, coded_count: usize) -> Self {
+        Self {
+            kind, coded_count,
+            intensity: [0; SUBFRAMES],
+            scalefactors: [0; SAMPLES_PER_SUBFRAME],
+            resolution: [0; SAMPLES_PER_SUBFRAME],
+            noises: [0; SAMPLES_PER_SUBFRAME],
+            noise_count: 0,
+            valid_count: 0,
+            gain: [0.; SAMPLES_PER_SUBFRAME],
+            spectra: [[0.; SAMPLES_PER_SUBFRAME]; SUBFRAMES],
+            imdct_previous: [0.; SAMPLES_PER_SUBFRAME],
+            wave: [[0.; SAMPLES_PER_SUBFRAME]; SUBFRAMES]
+        }
+    }
+
+    fn unpack_scalefactors(&mut self, reader: &mut BitReader, hfr_group_count: usize, version: u16) -> Result<(), HcaError> {
+        let delta_bits = reader.read(3);
+        // 3.0 stores the HFR scalefactors after the coded ones
+        let extra_count = match self.kind == ChannelType::StereoSecondary || version <= VERSION_200 {
+            true => 0,
+            false => hfr_group_count
+        };
+        let count = self.coded_count + extra_count;
+        if count > SAMPLES_PER_SUBFRAME {
+            return Err(HcaError::InvalidFrameData);
+        }
+        match delta_bits {
+            6.. => {
+                for sf in &mut self.scalefactors[..count] {
+                    *sf = reader.read(6) as u8;
+                }
+            },
+            // the first scalefactor is stored as is, so there has to be at least one band
+            1.. if count == 0 => return Err(HcaError::InvalidFrameData),
+            1.. => {
+                let escape = (1 << delta_bits) - 1;
+                let mut value = reader.read(6) as i32;
+                self.scalefactors[0] = value as u8;
+                for sf in &mut self.scalefactors[1..count] {
+                    let delta = reader.read(delta_bits) as i32;
+                    value = match delta == escape {
+                        true => reader.read(6) as i32,
+                        false => value + delta - (escape >> 1)
+                    };
+                    // wrong keys end up here, scalefactors are 6 bits
+                    if !(0..64).contains(&value) {
+                        return Err(HcaError::InvalidFrameData);
+                    }
+                    *sf = value as u8;
+                }
+            },
+            _ => self.scalefactors.fill(0)
+        }
+        for i in 0..extra_count {
+            self.scalefactors[SAMPLES_PER_SUBFRAME - 1 - i] = self.scalefactors[count - 1 - i];
+        }
+        Ok(())
+    }
+
+    fn unpack_intensity(&mut self, reader: &mut BitReader, hfr_group_count: usize, version: u16) -> Result<(), HcaError> {
+        if self.kind != ChannelType::StereoSecondary {
+            // 3.0 reads these with the scalefactors
+            if version <= VERSION_200 {
+                for sf in &mut self.scalefactors[SAMPLES_PER_SUBFRAME - hfr_group_count..] {
+                    *sf = reader.read(6) as u8;
+                }
+            }
+            return Ok(());
+        }
+        let mut value = reader.peek(4) as u8;
+        if version <= VERSION_200 {
+            self.intensity[0] = value;
+            // 15 reuses the previous intensities
+            if value < 15 {
+                reader.skip(4);
+                for intensity in &mut self.intensity[1..] {
+                    *intensity = reader.read(4) as u8;
+                }
+            }
+            return Ok(());
+        }
+        reader.skip(4);
+        if value >= 15 {
+            self.intensity.fill(7);
+            return Ok(());
+        }
+        let delta_bits = reader.read(2);
+        self.intensity[0] = value;
+        if delta_bits == 3 {
+            for intensity in &mut self.intensity[1..] {
+                *intensity = reader.read(4) as u8;
+            }
+            return Ok(());
+        }
+        let escape = (2 << delta_bits) - 1;
+        for intensity in &mut self.intensity[1..] {
+            let delta = reader.read(delta_bits + 1);
+            value = match delta == escape {
+                true => reader.read(4) as u8,
+                false => {
+                    let next = value as i32 + delta as i32 - (escape >> 1) as i32;
+                    if !(0..16).contains(&next) {
+                        return Err(HcaError::InvalidFrameData);
+                    }
+                    next as u8
+                }
+            };
+            *intensity = value;
+        }
+        Ok(())
+    }
+
+    fn calculate_resolution(&mut self, packed_noise_level: u32, ath_curve: &[u8; SAMPLES_PER_SUBFRAME],
+                            min_resolution: u8, max_resolution: u8) {
+        let mut noise_count = 0;
+        let mut valid_count = 0;
+        for (i, ath) in ath_curve[..self.coded_count].iter().enumerate() {
+            let mut resolution = 0;
+            let scalefactor = self.scalefactors[i] as i32;
+            if scalefactor > 0 {
+                let noise_level = *ath as i32 + (packed_noise_level.wrapping_add(i as u32) >> 8) as i32;
+                let position = noise_level + 1 - ((5 * scalefactor) >> 1);
+                resolution = match position {
+                    ..0 => 15,
+                    0..66 => INVERT_TABLE[position as usize],
+                    _ => 0
+                }.clamp(min_resolution, max_resolution);
+                if resolution == 0 {
+                    self.noises[noise_count] = i as u8;
+                    noise_count += 1;
+                } else {
+                    self.noises[SAMPLES_PER_SUBFRAME - 1 - valid_count] = i as u8;
+                    valid_count += 1;
+                }
+            }
+            self.resolution[i] = resolution;
+        }
+        self.resolution[self.coded_count..].fill(0);
+        self.noise_count = noise_count;
+        self.valid_count = valid_count;
+    }
+
+    fn calculate_gain(&mut self, tables: &HcaTables) {
+        for i in 0..self.coded_count {
+            self.gain[i] = tables.dequantizer_scaling[self.scalefactors[i] as usize]
+                * tables.quantizer_step[self.resolution[i] as usize];
+        }
+    }
+
+    fn dequantize(&mut self, reader: &mut BitReader, subframe: usize) {
+        let spectra = &mut self.spectra[subframe];
+        for (i, spectrum) in spectra[..self.coded_count].iter_mut().enumerate() {
+            let resolution = self.resolution[i] as usize;
+            let bits = MAX_BIT_SIZE[resolution] as u32;
+            let code = reader.read(bits);
+            let value = match resolution {
+                8.. => {
+                    // sign and magnitude, with the sign in the lowest bit. Zero has no sign bit.
+                    let magnitude = (code >> 1) as i32;
+                    if magnitude == 0 {
+                        reader.skip(-1);
+                    }
+                    match code & 1 {
+                        0 => magnitude,
+                        _ => -magnitude
+                    }
+                },
+                _ => {
+                    let index = (resolution << 4) + code as usize;
+                    reader.skip(READ_BIT_COUNT[index] as i32 - bits as i32);
+                    READ_VALUES[index] as i32
+                }
+            };
+            *spectrum = self.gain[i] * value as f32;
+        }
+        spectra[self.coded_count..].fill(0.);
+    }
+
+    /// Fill bands without coefficients with scaled copies of random coded bands
+    fn reconstruct_noise(&mut self, tables: &HcaTables, min_resolution: u8, ms_stereo: bool, random: &mut u32, subframe: usize) {
+        if min_resolution > 0 || self.valid_count == 0 || self.noise_count == 0 {
+            return;
+        }
+        if ms_stereo && self.kind != ChannelType::StereoPrimary {
+            return;
+        }
+        for i in 0..self.noise_count {
+            *random = random.wrapping_mul(0x343FD).wrapping_add(0x269EC3);
+            let random_index = SAMPLES_PER_SUBFRAME - self.valid_count
+                + (((*random & 0x7FFF) as usize * self.valid_count) >> 15);
+            let noise_index = self.noises[i] as usize;
+            let valid_index = self.noises[random_index] as usize;
+            let sc_index = (self.scalefactors[noise_index] as i32 - self.scalefactors[valid_index] as i32 + 62).max(0);
+            self.spectra[subframe][noise_index] =
+                tables.scale_conversion[sc_index as usize] * self.spectra[subframe][valid_index];
+        }
+    }
+
+    /// Mirror lower bands into the bands above the coded ones
+    fn reconstruct_high_frequency(&mut self, tables: &HcaTables, header: &HcaHeader, subframe: usize) {
+        let bands_per_group = header.bands_per_hfr_group() as usize;
+        if bands_per_group == 0 || self.kind == ChannelType::StereoSecondary {
+            return;
+        }
+        let group_count = header.hfr_group_count() as usize;
+        let total = header.total_band_count() as usize;
+        let start = header.base_band_count() as usize + header.stereo_band_count() as usize;
+        // 3.0 only moves down for the first half of the groups
+        let group_limit = match header.version() <= VERSION_200 {
+            true => group_count,
+            false => group_count >> 1
+        };
+        let hfr_scales = SAMPLES_PER_SUBFRAME - group_count;
+        let spectra = &mut self.spectra[subframe];
+        let mut high = start;
+        let mut low = start as isize - 1;
+        for group in 0..group_count {
+            let step = (group < group_limit) as isize;
+            for _ in 0..bands_per_group {
+                if high >= total || low < 0 {
+                    break;
+                }
+                let sc_index = (self.scalefactors[hfr_scales + group] as i32
+                    - self.scalefactors[low as usize] as i32 + 63).max(0);
+                spectra[high] = tables.scale_conversion[sc_index as usize] * spectra[low as usize];
+                high += 1;
+                low -= step;
+            }
+        }
+        // the last coefficient is always 0
+        if high > 0 {
+            spectra[high - 1] = 0.;
+        }
+    }
+
+    fn imdct(&mut self, tables: &HcaTables, subframe: usize) {
+        const HALF: usize = SAMPLES_PER_SUBFRAME / 2;
+        let spectra = &self.spectra[subframe];
+        let mut dct = [0f32; SAMPLES_PER_SUBFRAME];
+        for (out, row) in dct.iter_mut().zip(tables.dct.iter()) {
+            *out = row.iter().zip(spectra).map(|(a, b)| a * b).sum();
+        }
+        // unfold the DCT-IV into the 256 sample IMDCT output, window it and overlap the first half
+        // with the second half of the previous subframe
+        let window = &tables.window;
+        let wave = &mut self.wave[subframe];
+        let previous = &mut self.imdct_previous;
+        for i in 0..HALF {
+            wave[i] = window[i] * dct[HALF + i] + previous[i];
+            wave[HALF + i] = -window[HALF + i] * dct[SAMPLES_PER_SUBFRAME - 1 - i] + previous[HALF + i];
+        }
+        for i in 0..HALF {
+            previous[i] = -window[SAMPLES_PER_SUBFRAME - 1 - i] * dct[HALF - 1 - i];
+            previous[HALF + i] = -window[HALF - 1 - i] * dct[i];
+        }
+    }
+}
+
+/// Apply intensity stereo and mid/side stereo to a primary and secondary channel pair
+fn apply_stereo(tables: &HcaTables, primary: &mut Channel, secondary: &mut Channel, subframe: usize,
+                header: &HcaHeader) {
+    let bands = header.base_band_count() as usize..header.total_band_count() as usize;
+    let ratio_l = tables.intensity_ratio[secondary.intensity[subframe] as usize];
+    let ratio_r = 2. - ratio_l;
+    let left = &mut primary.spectra[subframe];
+    let right = &mut secondary.spectra[subframe];
+    for band in bands.clone() {
+        right[band] = left[band] * ratio_r;
+        left[band] *= ratio_l;
+    }
+    if header.ms_stereo() {
+        let ratio = std::f32::consts::FRAC_1_SQRT_2;
+        for band in bands {
+            let (l, r) = (left[band], right[band]);
+            left[band] = (l + r) * ratio;
+            right[band] = (l - r) * ratio;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HcaDecoder {
+    header: HcaHeader,
+    cipher: HcaCipher,
+    ath_curve: [u8; SAMPLES_PER_SUBFRAME],
+    channels: Vec<Channel>,
+    random: u32,
+    frame: Vec<u8>
+}
+
+impl HcaDecoder {
+    /// Create a decoder from the start of an HCA file, which must contain the whole header
+    pub fn new(data: &[u8]) -> Result<Self> {
+        Self::new_with_key(data, 0, 0)
+    }
+
+    /// Create a decoder for a file encrypted with cipher type 56. `subkey` comes from the AWB the
+    /// file is stored in, and is 0 for standalone files.
+    pub fn new_with_key(data: &[u8], key: u64, subkey: u16) -> Result<Self> {
+        let header = HcaHeader::parse(data)?;
+        let cipher = HcaCipher::new(header.cipher_type(), HcaCipher::mix_key(key, subkey))?;
+        // type 1 is a fixed curve used by 1.x files, scaled to the sample rate
+        let ath_curve = match header.ath_type() {
+            0 => [0; SAMPLES_PER_SUBFRAME],
+            1 => ath_curve(header.sample_rate()),
+            t => return Err(HcaError::UnsupportedAthType(t).into())
+        };
+        let channels = Self::channel_types(&header).into_iter().map(|kind| {
+            let coded_count = match kind {
+                ChannelType::StereoSecondary => header.base_band_count() as usize,
+                _ => header.base_band_count() as usize + header.stereo_band_count() as usize
+            };
+            Channel::new(kind, coded_count)
+        }).collect();
+        let frame = vec![0; header.frame_size() as usize];
+        Ok(Self { header, cipher, ath_curve, channels, random: DEFAULT_RANDOM, frame })
+    }
+
+    fn channel_types(header: &HcaHeader) -> Vec<ChannelType> {
+        use ChannelType::{Discrete as D, StereoPrimary as P, StereoSecondary as S};
+        let channels = header.channels() as usize;
+        let per_track = channels / header.track_count() as usize;
+        let mut types = vec![D; channels];
+        if header.stereo_band_count() == 0 || per_track < 2 {
+            return types;
+        }
+        let config = header.channel_config();
+        let track: &[ChannelType] = match per_track {
+            2 => &[P, S],
+            3 => &[P, S, D],
+            4 if config == 0 => &[P, S, P, S],
+            4 => &[P, S, D, D],
+            5 if config <= 2 => &[P, S, D, P, S],
+            5 => &[P, S, D, D, D],
+            6 => &[P, S, D, D, P, S],
+            7 => &[P, S, D, D, P, S, D],
+            8 => &[P, S, D, D, P, S, P, S],
+            _ => &[]
+        };
+        for chunk in types.chunks_exact_mut(per_track) {
+            chunk[..track.len()].copy_from_slice(track);
+        }
+        types
+    }
+
+    pub fn header(&self) -> &HcaHeader { &self.header }
+
+    /// Offset of a frame from the start of the file
+    pub fn frame_offset(&self, index: u32) -> u64 {
+        self.header.header_size() as u64 + index as u64 * self.header.frame_size() as u64
+    }
+
+    /// Clear the state carried between frames, for seeking
+    pub fn reset(&mut self) {
+        self.random = DEFAULT_RANDOM;
+        for channel in &mut self.channels {
+            channel.imdct_previous.fill(0.);
+        }
+    }
+
+    fn decode(&mut self, frame: &[u8]) -> Result<(), HcaError> {
+        let frame_size = self.header.frame_size() as usize;
+        let frame = frame.get(..frame_size).ok_or(HcaError::Truncated)?;
+        if frame[..2] != [0xff, 0xff] {
+            return Err(HcaError::InvalidSync);
+        }
+        if crc16(frame) != 0 {
+            return Err(HcaError::FrameChecksum);
+        }
+        self.frame.copy_from_slice(frame);
+        self.cipher.decrypt(&mut self.frame);
+
+        let tables = HcaTables::get();
+        let header = &self.header;
+        let mut reader = BitReader::new(&self.frame);
+        reader.skip(16);
+        let acceptable_noise_level = reader.read(9);
+        let evaluation_boundary = reader.read(7);
+        let packed_noise_level = (acceptable_noise_level << 8).wrapping_sub(evaluation_boundary);
+        let hfr_group_count = header.hfr_group_count() as usize;
+        for channel in &mut self.channels {
+            channel.unpack_scalefactors(&mut reader, hfr_group_count, header.version())?;
+            channel.unpack_intensity(&mut reader, hfr_group_count, header.version())?;
+            channel.calculate_resolution(packed_noise_level, &self.ath_curve,
+                header.min_resolution(), header.max_resolution());
+            channel.calculate_gain(tables);
+        }
+        for subframe in 0..SUBFRAMES {
+            for channel in &mut self.channels {
+                channel.dequantize(&mut reader, subframe);
+            }
+            for channel in &mut self.channels {
+                channel.reconstruct_noise(tables, header.min_resolution(), header.ms_stereo(), &mut self.random, subframe);
+                channel.reconstruct_high_frequency(tables, header, subframe);
+            }
+            if header.stereo_band_count() > 0 {
+                for ch in 1..self.channels.len() {
+                    let (left, right) = self.channels.split_at_mut(ch);
+                    let primary = &mut left[ch - 1];
+                    if primary.kind == ChannelType::StereoPrimary {
+                        apply_stereo(tables, primary, &mut right[0], subframe, header);
+                    }
+                }
+            }
+            for channel in &mut self.channels {
+                channel.imdct(tables, subframe);
+            }
+        }
+        if reader.position() > frame_size * 8 {
+            return Err(HcaError::InvalidFrameData);
+        }
+        Ok(())
+    }
+
+    /// Decode a frame, appending 1024 interleaved samples per channel in the range [-1, 1]
+    pub fn decode_frame_f32(&mut self, frame: &[u8], out: &mut Vec<f32>) -> Result<()> {
+        self.decode(frame)?;
+        out.reserve(SAMPLES_PER_FRAME * self.channels.len());
+        for subframe in 0..SUBFRAMES {
+            for i in 0..SAMPLES_PER_SUBFRAME {
+                out.extend(self.channels.iter().map(|c| c.wave[subframe][i]));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode a frame, appending 1024 interleaved 16-bit samples per channel
+    pub fn decode_frame(&mut self, frame: &[u8], out: &mut Vec<i16>) -> Result<()> {
+        self.decode(frame)?;
+        out.reserve(SAMPLES_PER_FRAME * self.channels.len());
+        for subframe in 0..SUBFRAMES {
+            for i in 0..SAMPLES_PER_SUBFRAME {
+                // float to int casts saturate
+                out.extend(self.channels.iter().map(|c| (c.wave[subframe][i] * 32768.) as i16));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode a whole HCA file into interleaved 16-bit samples, without the encoder's delay and
+    /// padding. `data` is the same data the decoder was created from.
+    pub fn decode_all(&mut self, data: &[u8]) -> Result<Vec<i16>> {
+        self.reset();
+        let channels = self.channels.len();
+        // the header's frame count isn't trusted further than the frames that fit in data
+        let frames = (self.header.frame_count() as usize)
+            .min(data.len() / (self.header.frame_size() as usize).max(1));
+        let mut pcm = Vec::with_capacity(frames.saturating_mul(SAMPLES_PER_FRAME * channels));
+        for i in 0..self.header.frame_count() {
+            let frame = data.get(self.frame_offset(i) as usize..).ok_or(HcaError::Truncated)?;
+            self.decode_frame(frame, &mut pcm)?;
+        }
+        pcm.drain(..(self.header.encoder_delay() as usize * channels).min(pcm.len()));
+        pcm.truncate(self.header.sample_count() as usize * channels);
+        Ok(pcm)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use std::f64::consts::PI;
+    use crate::error::CriError;
+    use crate::hca::bits::tests::BitWriter;
+    use crate::hca::cipher::HcaCipher;
+    use crate::hca::decoder::{Channel, ChannelType, HcaDecoder};
+    use crate::hca::error::HcaError;
+    use crate::hca::header::tests::{build_header, TestHeader};
+    use crate::hca::tables::{ath_curve, crc16, HcaTables, SAMPLES_PER_SUBFRAME};
+
+    /// Finish a frame: pad it to its size, encrypt it and append the checksum
+    fn finish_frame(writer: BitWriter, frame_size: usize, cipher: Option<&HcaCipher>) -> Vec<u8> {
+        let mut frame = writer.into_inner();
+        frame.resize(frame_size - 2, 0);
+        if let Some(cipher) = cipher {
+            cipher.encrypt(&mut frame);
+        }
+        let crc = crc16(&frame);
+        frame.extend(crc.to_be_bytes());
+        frame
+    }
+
+    /// Mono frame where only band 0 has a coefficient, `value` in every subframe
+    fn tone_frame(value: i32, cipher: Option<&HcaCipher>) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write(16, 0xffff);
+        // noise level, evaluation boundary
+        writer.write(9, 0);
+        writer.write(7, 0);
+        // fixed scalefactors, band 0 gets the highest resolution
+        writer.write(3, 6);
+        writer.write(6, 63);
+        for _ in 1..128 {
+            writer.write(6, 0);
+        }
+        for _ in 0..8 {
+            writer.write(12, (value.unsigned_abs() << 1) | (value < 0) as u32);
+        }
+        finish_frame(writer, 0x100, cipher)
+    }
+
+    #[test]
+    fn decode_silence() -> Result<(), Box<dyn Error>> {
+        let header = build_header(&TestHeader { channels: 2, bands: [128, 96, 32, 0], ..Default::default() });
+        let mut decoder = HcaDecoder::new(&header)?;
+        let mut writer = BitWriter::new();
+        writer.write(16, 0xffff);
+        writer.write(16, 0);
+        // no scalefactors for either channel, secondary channel reuses intensities
+        writer.write(3, 0);
+        writer.write(3, 0);
+        writer.write(4, 15);
+        let frame = finish_frame(writer, 0x100, None);
+        let mut data = header.clone();
+        for _ in 0..4 {
+            data.extend(&frame);
+        }
+        let pcm = decoder.decode_all(&data)?;
+        assert_eq!(pcm.len(), decoder.header().sample_count() as usize * 2);
+        assert!(pcm.iter().all(|s| *s == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_all_untrusted_header() -> Result<(), Box<dyn Error>> {
+        let silence = |header: &[u8]| {
+            let mut writer = BitWriter::new();
+            writer.write(16, 0xffff);
+            writer.write(16, 0);
+            writer.write(3, 0);
+            let mut data = header.to_vec();
+            data.extend(finish_frame(writer, 0x100, None));
+            data
+        };
+        // frame count far past the end of the data
+        let header = build_header(&TestHeader { frame_count: u32::MAX, ..Default::default() });
+        let result = HcaDecoder::new(&header)?.decode_all(&silence(&header));
+        assert!(matches!(result, Err(CriError::Hca(HcaError::Truncated))));
+        // encoder delay longer than the decoded samples
+        let mut header = build_header(&TestHeader { frame_count: 1, ..Default::default() });
+        let delay = header.windows(4).position(|w| w == b"fmt\0").unwrap() + 12;
+        header[delay..delay + 2].copy_from_slice(&0xffffu16.to_be_bytes());
+        let size = header.len() - 2;
+        let crc = crc16(&header[..size]);
+        header[size..].copy_from_slice(&crc.to_be_bytes());
+        assert!(HcaDecoder::new(&header)?.decode_all(&silence(&header))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_no_base_bands() -> Result<(), Box<dyn Error>> {
+        let header = build_header(&TestHeader { channels: 1, bands: [128, 0, 0, 0], ..Default::default() });
+        let mut decoder = HcaDecoder::new(&header)?;
+        let mut writer = BitWriter::new();
+        writer.write(16, 0xffff);
+        writer.write(16, 0);
+        // delta coded scalefactors, with no bands to hold them
+        writer.write(3, 1);
+        let mut data = header.clone();
+        data.extend(finish_frame(writer, 0x100, None));
+        assert!(matches!(decoder.decode_all(&data), Err(CriError::Hca(HcaError::InvalidFrameData))));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_tone() -> Result<(), Box<dyn Error>> {
+        let header = build_header(&TestHeader::default());
+        let mut decoder = HcaDecoder::new(&header)?;
+        let mut pcm = vec![];
+        decoder.decode_frame_f32(&tone_frame(1000, None), &mut pcm)?;
+        // the first subframe has no previous subframe to overlap with
+        let tables = HcaTables::get();
+        let coefficient = tables.dequantizer_scaling[63] as f64 * tables.quantizer_step[15] as f64 * 1000.;
+        for (n, sample) in pcm[..64].iter().enumerate() {
+            let imdct = (2. / 128f64).sqrt() * coefficient * (PI / 128. * (n as f64 + 64.5) * 0.5).cos();
+            assert!((*sample as f64 - tables.window[n] as f64 * imdct).abs() < 1e-5);
+        }
+        assert!(pcm[128..].iter().any(|s| s.abs() > 0.1));
+        assert!(pcm.iter().all(|s| s.abs() <= 1.));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_encrypted() -> Result<(), Box<dyn Error>> {
+        let key = 0xCF222F1FE0748978;
+        let header = build_header(&TestHeader { cipher_type: Some(56), masked: true, ..Default::default() });
+        let cipher = HcaCipher::new(56, HcaCipher::mix_key(key, 0x55aa))?;
+        let frame = tone_frame(-321, Some(&cipher));
+        let mut expected = vec![];
+        HcaDecoder::new(&build_header(&TestHeader::default()))?.decode_frame(&tone_frame(-321, None), &mut expected)?;
+        let mut pcm = vec![];
+        HcaDecoder::new_with_key(&header, key, 0x55aa)?.decode_frame(&frame, &mut pcm)?;
+        assert_eq!(pcm, expected);
+        // a wrong key decodes garbage or fails, but never matches
+        let mut wrong = vec![];
+        let result = HcaDecoder::new_with_key(&header, key, 0)?.decode_frame(&frame, &mut wrong);
+        assert!(result.is_err() || wrong != expected);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_frames() -> Result<(), Box<dyn Error>> {
+        let header = build_header(&TestHeader::default());
+        let mut decoder = HcaDecoder::new(&header)?;
+        let error = |decoder: &mut HcaDecoder, frame: &[u8]| {
+            match decoder.decode_frame(frame, &mut vec![]).unwrap_err() {
+                CriError::Hca(e) => e,
+                e => panic!("{}", e)
+            }
+        };
+        let frame = tone_frame(5, None);
+        assert!(matches!(error(&mut decoder, &frame[..0xff]), HcaError::Truncated));
+        let mut bad = frame.clone();
+        bad[0x20] ^= 0x10;
+        assert!(matches!(error(&mut decoder, &bad), HcaError::FrameChecksum));
+        bad[0] = 0;
+        assert!(matches!(error(&mut decoder, &bad), HcaError::InvalidSync));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_ath_type_1() -> Result<(), Box<dyn Error>> {
+        // 1.x files without an ath chunk use the base curve, scaled to their sample rate
+        let old = build_header(&TestHeader { version: 0x0103, ..Default::default() });
+        let mut decoder = HcaDecoder::new(&old)?;
+        assert_eq!(decoder.header().ath_type(), 1);
+        assert_eq!(decoder.ath_curve, ath_curve(48000));
+        let mut pcm = vec![];
+        decoder.decode_frame(&tone_frame(1000, None), &mut pcm)?;
+        assert_eq!(pcm.len(), 1024);
+        // band i reads the base curve at (i + 1) * 48000 >> 13, and is cut off past 654
+        let curve = ath_curve(48000);
+        assert_eq!(curve[0], 0x4C);
+        assert_ne!(curve[110], 0xFF);
+        assert!(curve[111..].iter().all(|v| *v == 0xFF));
+        // low sample rates never reach the cutoff
+        let curve = ath_curve(8000);
+        assert_eq!(curve[0], 0x78);
+        assert!(curve.iter().all(|v| *v != 0xFF));
+        Ok(())
+    }
+
+    #[test]
+    fn imdct_reconstructs_signal() -> Result<(), Box<dyn Error>> {
+        // forward MDCT with the same window, then check the overlapped output matches the input
+        let tables = HcaTables::get();
+        let n = SAMPLES_PER_SUBFRAME;
+        let window = |i: usize| tables.window[if i < n { i } else { 2 * n - 1 - i }] as f64;
+        let signal: Vec<f64> = (0..n * 4).map(|i| ((i * 7919) % 1000) as f64 / 1000. - 0.5).collect();
+        let mut channel = Channel::new(ChannelType::Discrete, n);
+        let mut output = vec![];
+        for block in 0..3 {
+            let input = &signal[block * n..block * n + 2 * n];
+            for k in 0..n {
+                channel.spectra[0][k] = ((2. / n as f64).sqrt() * (0..2 * n).map(|i| {
+                    window(i) * input[i] * (PI / n as f64 * (i as f64 + 0.5 + n as f64 / 2.) * (k as f64 + 0.5)).cos()
+                }).sum::<f64>()) as f32;
+            }
+            channel.imdct(tables, 0);
+            output.extend(channel.wave[0]);
+        }
+        // the first block only has half of its overlap
+        for (a, b) in output[n..].iter().zip(&signal[n..]) {
+            assert!((*a as f64 - b).abs() < 1e-4);
+        }
+        Ok(())
+    }
+}
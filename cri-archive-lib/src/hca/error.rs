@@ -0,0 +1,35 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+#[derive(Debug)]
+pub enum HcaError {
+    /// Data doesn't start with HCA
+    InvalidMagic,
+    /// Header or frame extends past the end of the data
+    Truncated,
+    /// Header CRC16 doesn't match
+    HeaderChecksum,
+    /// Required header chunk (fmt, comp/dec) is missing
+    MissingChunk(&'static str),
+    /// Only versions 1.1 to 3.0 are supported
+    UnsupportedVersion(u16),
+    /// Header field is outside of its valid range
+    InvalidValue(&'static str),
+    /// Only ATH curve types 0 and 1 exist
+    UnsupportedAthType(u16),
+    /// Only cipher types 0, 1 and 56 exist
+    UnsupportedCipherType(u16),
+    /// Frame doesn't start with 0xFFFF
+    InvalidSync,
+    /// Frame CRC16 doesn't match
+    FrameChecksum,
+    /// Frame data is inconsistent, usually because of a wrong key
+    InvalidFrameData,
+}
+
+impl Error for HcaError {}
+impl Display for HcaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Debug>::fmt(self, f)
+    }
+}
@@ -0,0 +1,438 @@
+//! # HCA Header
+//!
+//! A big endian sequence of chunks in a fixed order, followed by a CRC16 of the whole header.
+//! Encrypted files set the top bit of each chunk's name, so names are compared with it masked out.
+//!
+//! ```text
+//! "HCA\0": version (u16), header size (u16)
+//! "fmt\0": channels (u8), sample rate (u24), frame count (u32), encoder delay (u16), padding (u16)
+//! "comp": frame size (u16), min/max resolution (u8), track count (u8), channel config (u8),
+//!         total/base/stereo band count (u8), bands per HFR group (u8), MS stereo (u8), reserved (u8)
+//!  or "dec\0": frame size (u16), min/max resolution (u8), total/base band count - 1 (u8),
+//!         track count (u4), channel config (u4), stereo type (u8)
+//! "vbr\0": max frame size (u16), noise level (u16) (optional)
+//! "ath\0": type (u16) (optional)
+//! "loop": start frame (u32), end frame (u32), start delay (u16), end padding (u16) (optional)
+//! "ciph": type (u16) (optional)
+//! "rva\0": volume (f32) (optional)
+//! "comm": length (u8), comment (optional)
+//! "pad\0": fills the rest of the header (optional)
+//! ```
+
+use encoding_rs::SHIFT_JIS;
+use crate::hca::error::HcaError;
+use crate::hca::tables::{crc16, SAMPLES_PER_FRAME, SAMPLES_PER_SUBFRAME};
+use crate::utils::endianness::BigEndian;
+use crate::utils::slice::FromSlice;
+
+const CHUNK_MASK: u32 = 0x7F7F7F7F;
+const HCA: u32 = 0x48434100;
+const FMT: u32 = 0x666D7400;
+const COMP: u32 = 0x636F6D70;
+const DEC: u32 = 0x64656300;
+const VBR: u32 = 0x76627200;
+const ATH: u32 = 0x61746800;
+const LOOP: u32 = 0x6C6F6F70;
+const CIPH: u32 = 0x63697068;
+const RVA: u32 = 0x72766100;
+const COMM: u32 = 0x636F6D6D;
+
+pub const VERSION_101: u16 = 0x0101;
+pub const VERSION_102: u16 = 0x0102;
+pub const VERSION_103: u16 = 0x0103;
+pub const VERSION_200: u16 = 0x0200;
+pub const VERSION_300: u16 = 0x0300;
+
+const MAX_CHANNELS: u8 = 16;
+const MAX_SAMPLE_RATE: u32 = 0x7FFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HcaLoop {
+    start_frame: u32,
+    end_frame: u32,
+    start_delay: u16,
+    end_padding: u16
+}
+
+impl HcaLoop {
+    pub fn start_frame(&self) -> u32 { self.start_frame }
+    pub fn end_frame(&self) -> u32 { self.end_frame }
+    /// Samples to skip in the start frame
+    pub fn start_delay(&self) -> u16 { self.start_delay }
+    /// Samples to drop from the end of the end frame
+    pub fn end_padding(&self) -> u16 { self.end_padding }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HcaHeader {
+    version: u16,
+    header_size: u16,
+    channels: u8,
+    sample_rate: u32,
+    frame_count: u32,
+    encoder_delay: u16,
+    encoder_padding: u16,
+    frame_size: u16,
+    min_resolution: u8,
+    max_resolution: u8,
+    track_count: u8,
+    channel_config: u8,
+    total_band_count: u8,
+    base_band_count: u8,
+    stereo_band_count: u8,
+    bands_per_hfr_group: u8,
+    ms_stereo: bool,
+    vbr_max_frame_size: u16,
+    vbr_noise_level: u16,
+    ath_type: u16,
+    loop_info: Option<HcaLoop>,
+    cipher_type: u16,
+    rva_volume: f32,
+    comment: String
+}
+
+impl HcaHeader {
+    /// Check for the HCA magic, which is masked in encrypted files
+    pub fn is_hca(data: &[u8]) -> bool {
+        data.len() >= 4 && u32::from_slice::<BigEndian>(data, 0) & CHUNK_MASK == HCA
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, HcaError> {
+        if data.len() < 8 {
+            return Err(HcaError::Truncated);
+        }
+        if !Self::is_hca(data) {
+            return Err(HcaError::InvalidMagic);
+        }
+        let version = u16::from_slice::<BigEndian>(data, 4);
+        let header_size = u16::from_slice::<BigEndian>(data, 6);
+        let data = data.get(..header_size as usize).ok_or(HcaError::Truncated)?;
+        if header_size < 8 + 2 {
+            return Err(HcaError::InvalidValue("header_size"));
+        }
+        if crc16(data) != 0 {
+            return Err(HcaError::HeaderChecksum);
+        }
+        // chunks can't extend into the checksum
+        let end = data.len() - 2;
+        let mut ofs = 8;
+        let mut chunk = |name: u32, size: usize| {
+            let found = ofs + size <= end && u32::from_slice::<BigEndian>(data, ofs) & CHUNK_MASK == name;
+            let start = ofs + 4;
+            if found {
+                ofs += size;
+            }
+            found.then_some(start)
+        };
+
+        let fmt = chunk(FMT, 0x10).ok_or(HcaError::MissingChunk("fmt"))?;
+        let channels = data[fmt];
+        let sample_rate = u32::from_slice::<BigEndian>(data, fmt) & 0xFFFFFF;
+        let frame_count = u32::from_slice::<BigEndian>(data, fmt + 4);
+        let encoder_delay = u16::from_slice::<BigEndian>(data, fmt + 8);
+        let encoder_padding = u16::from_slice::<BigEndian>(data, fmt + 10);
+
+        let (frame_size, min_resolution, max_resolution, track_count, channel_config,
+            total_band_count, base_band_count, stereo_band_count, bands_per_hfr_group, ms_stereo);
+        if let Some(comp) = chunk(COMP, 0x10) {
+            frame_size = u16::from_slice::<BigEndian>(data, comp);
+            min_resolution = data[comp + 2];
+            max_resolution = data[comp + 3];
+            track_count = data[comp + 4];
+            channel_config = data[comp + 5];
+            total_band_count = data[comp + 6];
+            base_band_count = data[comp + 7];
+            stereo_band_count = data[comp + 8];
+            bands_per_hfr_group = data[comp + 9];
+            ms_stereo = data[comp + 10] != 0;
+        } else if let Some(dec) = chunk(DEC, 0xc) {
+            frame_size = u16::from_slice::<BigEndian>(data, dec);
+            min_resolution = data[dec + 2];
+            max_resolution = data[dec + 3];
+            total_band_count = data[dec + 4].wrapping_add(1);
+            track_count = data[dec + 6] >> 4;
+            channel_config = data[dec + 6] & 0xf;
+            // stereo type 0 has no intensity stereo bands
+            base_band_count = match data[dec + 7] {
+                0 => total_band_count,
+                _ => data[dec + 5].wrapping_add(1)
+            };
+            stereo_band_count = total_band_count.wrapping_sub(base_band_count);
+            bands_per_hfr_group = 0;
+            ms_stereo = false;
+        } else {
+            return Err(HcaError::MissingChunk("comp"));
+        }
+
+        let (vbr_max_frame_size, vbr_noise_level) = match chunk(VBR, 8) {
+            Some(vbr) => (u16::from_slice::<BigEndian>(data, vbr), u16::from_slice::<BigEndian>(data, vbr + 2)),
+            None => (0, 0)
+        };
+        // removed in 2.0, older files without it use type 1
+        let ath_type = match chunk(ATH, 6) {
+            Some(ath) => u16::from_slice::<BigEndian>(data, ath),
+            None if version < VERSION_200 => 1,
+            None => 0
+        };
+        let loop_info = chunk(LOOP, 0x10).map(|l| HcaLoop {
+            start_frame: u32::from_slice::<BigEndian>(data, l),
+            end_frame: u32::from_slice::<BigEndian>(data, l + 4),
+            start_delay: u16::from_slice::<BigEndian>(data, l + 8),
+            end_padding: u16::from_slice::<BigEndian>(data, l + 10)
+        });
+        let cipher_type = match chunk(CIPH, 6) {
+            Some(ciph) => u16::from_slice::<BigEndian>(data, ciph),
+            None => 0
+        };
+        let rva_volume = match chunk(RVA, 8) {
+            Some(rva) => f32::from_slice::<BigEndian>(data, rva),
+            None => 1.
+        };
+        let comment = match chunk(COMM, 5) {
+            Some(comm) => {
+                let bytes = data.get(comm + 1..comm + 1 + data[comm] as usize)
+                    .ok_or(HcaError::InvalidValue("comment"))?;
+                SHIFT_JIS.decode(bytes).0.into_owned()
+            },
+            None => String::new()
+        };
+
+        let header = Self {
+            version, header_size, channels, sample_rate, frame_count, encoder_delay, encoder_padding,
+            frame_size, min_resolution, max_resolution,
+            // can be 0 in older files
+            track_count: track_count.max(1),
+            channel_config, total_band_count, base_band_count, stereo_band_count, bands_per_hfr_group,
+            ms_stereo, vbr_max_frame_size, vbr_noise_level, ath_type, loop_info, cipher_type, rva_volume,
+            comment
+        };
+        header.validate()?;
+        Ok(header)
+    }
+
+    fn validate(&self) -> Result<(), HcaError> {
+        if !matches!(self.version, VERSION_101 | VERSION_102 | VERSION_103 | VERSION_200 | VERSION_300) {
+            return Err(HcaError::UnsupportedVersion(self.version));
+        }
+        if self.channels == 0 || self.channels > MAX_CHANNELS {
+            return Err(HcaError::InvalidValue("channels"));
+        }
+        if self.sample_rate == 0 || self.sample_rate > MAX_SAMPLE_RATE {
+            return Err(HcaError::InvalidValue("sample_rate"));
+        }
+        if self.frame_count == 0 {
+            return Err(HcaError::InvalidValue("frame_count"));
+        }
+        if self.frame_size < 8 {
+            return Err(HcaError::InvalidValue("frame_size"));
+        }
+        let resolution_valid = match self.version {
+            ..=VERSION_200 => self.min_resolution == 1 && self.max_resolution == 15,
+            _ => self.min_resolution <= self.max_resolution && self.max_resolution <= 15
+        };
+        if !resolution_valid {
+            return Err(HcaError::InvalidValue("resolution"));
+        }
+        if self.track_count > self.channels {
+            return Err(HcaError::InvalidValue("track_count"));
+        }
+        let bands = SAMPLES_PER_SUBFRAME as u32;
+        if self.total_band_count as u32 > bands
+            || self.base_band_count as u32 + self.stereo_band_count as u32 > bands
+            || self.bands_per_hfr_group as u32 > bands {
+            return Err(HcaError::InvalidValue("band_count"));
+        }
+        if let Some(l) = &self.loop_info && (l.start_frame > l.end_frame || l.end_frame >= self.frame_count) {
+            return Err(HcaError::InvalidValue("loop"));
+        }
+        if !matches!(self.cipher_type, 0 | 1 | 56) {
+            return Err(HcaError::UnsupportedCipherType(self.cipher_type));
+        }
+        Ok(())
+    }
+
+    pub fn version(&self) -> u16 { self.version }
+    /// Size of the header, frames start right after it
+    pub fn header_size(&self) -> u16 { self.header_size }
+    pub fn channels(&self) -> u8 { self.channels }
+    pub fn sample_rate(&self) -> u32 { self.sample_rate }
+    pub fn frame_count(&self) -> u32 { self.frame_count }
+    /// Samples to skip at the start of the first frame
+    pub fn encoder_delay(&self) -> u16 { self.encoder_delay }
+    /// Samples to drop from the end of the last frame
+    pub fn encoder_padding(&self) -> u16 { self.encoder_padding }
+    pub fn frame_size(&self) -> u16 { self.frame_size }
+    pub fn min_resolution(&self) -> u8 { self.min_resolution }
+    pub fn max_resolution(&self) -> u8 { self.max_resolution }
+    pub fn track_count(&self) -> u8 { self.track_count }
+    pub fn channel_config(&self) -> u8 { self.channel_config }
+    pub fn total_band_count(&self) -> u8 { self.total_band_count }
+    pub fn base_band_count(&self) -> u8 { self.base_band_count }
+    pub fn stereo_band_count(&self) -> u8 { self.stereo_band_count }
+    pub fn bands_per_hfr_group(&self) -> u8 { self.bands_per_hfr_group }
+    pub fn ms_stereo(&self) -> bool { self.ms_stereo }
+    pub fn vbr_max_frame_size(&self) -> u16 { self.vbr_max_frame_size }
+    pub fn vbr_noise_level(&self) -> u16 { self.vbr_noise_level }
+    pub fn ath_type(&self) -> u16 { self.ath_type }
+    pub fn loop_info(&self) -> Option<&HcaLoop> { self.loop_info.as_ref() }
+    pub fn cipher_type(&self) -> u16 { self.cipher_type }
+    /// Volume to apply to the decoded samples, the decoder leaves this to the caller
+    pub fn rva_volume(&self) -> f32 { self.rva_volume }
+    pub fn comment(&self) -> &str { &self.comment }
+
+    /// Number of high frequency bands reconstructed from the lower bands, in groups
+    pub fn hfr_group_count(&self) -> u32 {
+        let bands = (self.total_band_count as u32)
+            .saturating_sub(self.base_band_count as u32 + self.stereo_band_count as u32);
+        match self.bands_per_hfr_group {
+            0 => 0,
+            b => bands.div_ceil(b as u32)
+        }
+    }
+
+    /// Decoded samples per channel, without the encoder's delay and padding
+    pub fn sample_count(&self) -> u64 {
+        (self.frame_count as u64 * SAMPLES_PER_FRAME as u64)
+            .saturating_sub(self.encoder_delay as u64 + self.encoder_padding as u64)
+    }
+
+    /// Loop start and end as sample positions in the decoded output, end exclusive
+    pub fn loop_samples(&self) -> Option<(u64, u64)> {
+        self.loop_info.map(|l| {
+            let delay = self.encoder_delay as u64;
+            let start = l.start_frame as u64 * SAMPLES_PER_FRAME as u64 + l.start_delay as u64;
+            let end = (l.end_frame as u64 + 1) * SAMPLES_PER_FRAME as u64 - l.end_padding as u64;
+            (start.saturating_sub(delay), end.saturating_sub(delay))
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::hca::error::HcaError;
+    use crate::hca::header::{HcaHeader, VERSION_200};
+    use crate::hca::tables::crc16;
+
+    /// Options for `build_header`, covering the fields tests care about
+    pub(crate) struct TestHeader {
+        pub(crate) version: u16,
+        pub(crate) channels: u8,
+        pub(crate) frame_count: u32,
+        pub(crate) frame_size: u16,
+        /// total, base, stereo, per HFR group
+        pub(crate) bands: [u8; 4],
+        pub(crate) cipher_type: Option<u16>,
+        pub(crate) loop_frames: Option<(u32, u32)>,
+        /// Set the top bit of chunk names, like encrypted files do
+        pub(crate) masked: bool
+    }
+
+    impl Default for TestHeader {
+        fn default() -> Self {
+            Self {
+                version: VERSION_200, channels: 1, frame_count: 4, frame_size: 0x100,
+                bands: [128, 128, 0, 0], cipher_type: None, loop_frames: None, masked: false
+            }
+        }
+    }
+
+    pub(crate) fn build_header(options: &TestHeader) -> Vec<u8> {
+        let mut out = vec![];
+        let mut chunk = |name: &[u8; 4], body: &[u8]| {
+            let mut name = *name;
+            if options.masked {
+                name.iter_mut().filter(|b| **b != 0).for_each(|b| *b |= 0x80);
+            }
+            out.extend(name);
+            out.extend(body);
+        };
+        chunk(b"HCA\0", &[options.version.to_be_bytes(), [0, 0]].concat());
+        let mut fmt = (48000u32 | (options.channels as u32) << 24).to_be_bytes().to_vec();
+        fmt.extend(options.frame_count.to_be_bytes());
+        fmt.extend(0x80u16.to_be_bytes());
+        fmt.extend(0x20u16.to_be_bytes());
+        chunk(b"fmt\0", &fmt);
+        let mut comp = options.frame_size.to_be_bytes().to_vec();
+        comp.extend([1, 15, 1, 0]);
+        comp.extend(options.bands);
+        comp.extend([0, 0]);
+        chunk(b"comp", &comp);
+        if let Some((start, end)) = options.loop_frames {
+            chunk(b"loop", &[start.to_be_bytes(), end.to_be_bytes(), [0, 0x10, 0, 0x30]].concat());
+        }
+        if let Some(cipher_type) = options.cipher_type {
+            chunk(b"ciph", &cipher_type.to_be_bytes());
+        }
+        chunk(b"comm", &[4, b't', b'e', b's', b't']);
+        chunk(b"pad\0", &[0; 4]);
+        let size = out.len() as u16 + 2;
+        out[6..8].copy_from_slice(&size.to_be_bytes());
+        let crc = crc16(&out);
+        out.extend(crc.to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn parse_header() -> Result<(), Box<dyn Error>> {
+        let data = build_header(&TestHeader {
+            channels: 2, bands: [128, 96, 16, 4], cipher_type: Some(56), loop_frames: Some((1, 3)),
+            ..Default::default()
+        });
+        let header = HcaHeader::parse(&data)?;
+        assert_eq!(header.version(), VERSION_200);
+        assert_eq!(header.header_size() as usize, data.len());
+        assert_eq!(header.channels(), 2);
+        assert_eq!(header.sample_rate(), 48000);
+        assert_eq!(header.frame_count(), 4);
+        assert_eq!(header.frame_size(), 0x100);
+        assert_eq!((header.min_resolution(), header.max_resolution()), (1, 15));
+        assert_eq!(header.track_count(), 1);
+        assert_eq!(header.total_band_count(), 128);
+        assert_eq!(header.base_band_count(), 96);
+        assert_eq!(header.stereo_band_count(), 16);
+        assert_eq!(header.hfr_group_count(), 4);
+        assert_eq!(header.ath_type(), 0);
+        assert_eq!(header.cipher_type(), 56);
+        assert_eq!(header.rva_volume(), 1.);
+        assert_eq!(header.comment(), "test");
+        assert_eq!(header.sample_count(), 4 * 1024 - 0x80 - 0x20);
+        let l = header.loop_info().unwrap();
+        assert_eq!((l.start_frame(), l.end_frame(), l.start_delay(), l.end_padding()), (1, 3, 0x10, 0x30));
+        assert_eq!(header.loop_samples(), Some((1024 + 0x10 - 0x80, 4 * 1024 - 0x30 - 0x80)));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_masked_header() -> Result<(), Box<dyn Error>> {
+        let plain = build_header(&TestHeader { cipher_type: Some(1), ..Default::default() });
+        let masked = build_header(&TestHeader { cipher_type: Some(1), masked: true, ..Default::default() });
+        assert_ne!(plain, masked);
+        assert!(HcaHeader::is_hca(&masked));
+        assert_eq!(HcaHeader::parse(&plain)?, HcaHeader::parse(&masked)?);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid() -> Result<(), Box<dyn Error>> {
+        let error = |data: &[u8]| HcaHeader::parse(data).unwrap_err();
+        let data = build_header(&TestHeader::default());
+        assert!(matches!(error(&data[..6]), HcaError::Truncated));
+        assert!(matches!(error(&data[..data.len() - 1]), HcaError::Truncated));
+        assert!(matches!(error(b"ADX\0\0\0\0\0"), HcaError::InvalidMagic));
+        let mut bad = data.clone();
+        bad[0x10] ^= 1;
+        assert!(matches!(error(&bad), HcaError::HeaderChecksum));
+        let old = build_header(&TestHeader { version: 0x0103, ..Default::default() });
+        assert_eq!(HcaHeader::parse(&old)?.ath_type(), 1);
+        let future = build_header(&TestHeader { version: 0x0400, ..Default::default() });
+        assert!(matches!(error(&future), HcaError::UnsupportedVersion(0x0400)));
+        let bands = build_header(&TestHeader { bands: [128, 128, 16, 0], ..Default::default() });
+        assert!(matches!(error(&bands), HcaError::InvalidValue("band_count")));
+        let looped = build_header(&TestHeader { loop_frames: Some((2, 4)), ..Default::default() });
+        assert!(matches!(error(&looped), HcaError::InvalidValue("loop")));
+        let cipher = build_header(&TestHeader { cipher_type: Some(3), ..Default::default() });
+        assert!(matches!(error(&cipher), HcaError::UnsupportedCipherType(3)));
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+//! Lookup tables for the HCA decoder. Tables with a closed form are generated on first use, the
+//! rest are taken as is from CRI's library.
+
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+pub(crate) const SUBFRAMES: usize = 8;
+pub(crate) const SAMPLES_PER_SUBFRAME: usize = 128;
+pub(crate) const SAMPLES_PER_FRAME: usize = SUBFRAMES * SAMPLES_PER_SUBFRAME;
+
+/// Resolution for a position on the noise curve. Versions before 3.0 stop at 56, clamping the rest
+/// to 1, which gives the same result.
+pub(crate) static INVERT_TABLE: [u8; 66] = [
+    14, 14, 14, 14, 14, 14, 13, 13, 13, 13, 13, 13, 12, 12, 12, 12,
+    12, 12, 11, 11, 11, 11, 11, 11, 10, 10, 10, 10, 10, 10, 10, 9,
+    9, 9, 9, 9, 9, 8, 8, 8, 8, 8, 8, 7, 6, 6, 5, 4,
+    4, 4, 3, 3, 3, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1,
+    1, 1
+];
+
+/// Bits to read for a coefficient at each resolution
+pub(crate) static MAX_BIT_SIZE: [u8; 16] = [0, 2, 3, 3, 4, 4, 4, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+/// Prefix codes for resolutions 0 to 7, indexed by `(resolution << 4) + code`. Rarer values use
+/// more bits, so this is the number of bits the code actually took...
+pub(crate) static READ_BIT_COUNT: [u8; 128] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    2, 2, 2, 2, 2, 2, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0,
+    2, 2, 3, 3, 3, 3, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0,
+    3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4,
+    3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4,
+    3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4,
+    3, 3, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4
+];
+
+/// ...and this is the value it stands for
+pub(crate) static READ_VALUES: [i8; 128] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 1, -1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 1, 1, -1, -1, 2, -2, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 1, -1, 2, -2, 3, -3, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 1, 1, -1, -1, 2, 2, -2, -2, 3, 3, -3, -3, 4, -4,
+    0, 0, 1, 1, -1, -1, 2, 2, -2, -2, 3, -3, 4, -4, 5, -5,
+    0, 0, 1, 1, -1, -1, 2, -2, 3, -3, 4, -4, 5, -5, 6, -6,
+    0, 0, 1, -1, 2, -2, 3, -3, 4, -4, 5, -5, 6, -6, 7, -7
+];
+
+/// Absolute threshold of hearing used by ATH type 1. See `ath_curve` for how it maps to bands.
+static ATH_BASE_CURVE: [u8; 656] = [
+    0x78, 0x5F, 0x56, 0x51, 0x4E, 0x4C, 0x4B, 0x49, 0x48, 0x48, 0x47, 0x46, 0x46, 0x45, 0x45, 0x45,
+    0x44, 0x44, 0x44, 0x44, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42,
+    0x42, 0x42, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x40, 0x40, 0x40, 0x40,
+    0x40, 0x40, 0x40, 0x40, 0x40, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F,
+    0x3F, 0x3F, 0x3F, 0x3E, 0x3E, 0x3E, 0x3E, 0x3E, 0x3E, 0x3D, 0x3D, 0x3D, 0x3D, 0x3D, 0x3D, 0x3D,
+    0x3C, 0x3C, 0x3C, 0x3C, 0x3C, 0x3C, 0x3C, 0x3C, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B,
+    0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B,
+    0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3B, 0x3C, 0x3C, 0x3C, 0x3C, 0x3C, 0x3C, 0x3C, 0x3C,
+    0x3D, 0x3D, 0x3D, 0x3D, 0x3D, 0x3D, 0x3D, 0x3D, 0x3E, 0x3E, 0x3E, 0x3E, 0x3E, 0x3E, 0x3E, 0x3F,
+    0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F, 0x3F,
+    0x3F, 0x3F, 0x3F, 0x3F, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40,
+    0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41,
+    0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41,
+    0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42,
+    0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x43, 0x43, 0x43,
+    0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x43, 0x44, 0x44,
+    0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x45, 0x45, 0x45, 0x45,
+    0x45, 0x45, 0x45, 0x45, 0x45, 0x45, 0x45, 0x45, 0x46, 0x46, 0x46, 0x46, 0x46, 0x46, 0x46, 0x46,
+    0x46, 0x46, 0x47, 0x47, 0x47, 0x47, 0x47, 0x47, 0x47, 0x47, 0x47, 0x47, 0x48, 0x48, 0x48, 0x48,
+    0x48, 0x48, 0x48, 0x48, 0x49, 0x49, 0x49, 0x49, 0x49, 0x49, 0x49, 0x49, 0x4A, 0x4A, 0x4A, 0x4A,
+    0x4A, 0x4A, 0x4A, 0x4A, 0x4B, 0x4B, 0x4B, 0x4B, 0x4B, 0x4B, 0x4B, 0x4C, 0x4C, 0x4C, 0x4C, 0x4C,
+    0x4C, 0x4D, 0x4D, 0x4D, 0x4D, 0x4D, 0x4D, 0x4E, 0x4E, 0x4E, 0x4E, 0x4E, 0x4E, 0x4F, 0x4F, 0x4F,
+    0x4F, 0x4F, 0x4F, 0x50, 0x50, 0x50, 0x50, 0x50, 0x51, 0x51, 0x51, 0x51, 0x51, 0x52, 0x52, 0x52,
+    0x52, 0x52, 0x53, 0x53, 0x53, 0x53, 0x54, 0x54, 0x54, 0x54, 0x54, 0x55, 0x55, 0x55, 0x55, 0x56,
+    0x56, 0x56, 0x56, 0x57, 0x57, 0x57, 0x57, 0x57, 0x58, 0x58, 0x58, 0x59, 0x59, 0x59, 0x59, 0x5A,
+    0x5A, 0x5A, 0x5A, 0x5B, 0x5B, 0x5B, 0x5B, 0x5C, 0x5C, 0x5C, 0x5D, 0x5D, 0x5D, 0x5D, 0x5E, 0x5E,
+    0x5E, 0x5F, 0x5F, 0x5F, 0x60, 0x60, 0x60, 0x61, 0x61, 0x61, 0x61, 0x62, 0x62, 0x62, 0x63, 0x63,
+    0x63, 0x64, 0x64, 0x64, 0x65, 0x65, 0x66, 0x66, 0x66, 0x67, 0x67, 0x67, 0x68, 0x68, 0x68, 0x69,
+    0x69, 0x6A, 0x6A, 0x6A, 0x6B, 0x6B, 0x6B, 0x6C, 0x6C, 0x6D, 0x6D, 0x6D, 0x6E, 0x6E, 0x6F, 0x6F,
+    0x70, 0x70, 0x70, 0x71, 0x71, 0x72, 0x72, 0x73, 0x73, 0x73, 0x74, 0x74, 0x75, 0x75, 0x76, 0x76,
+    0x77, 0x77, 0x78, 0x78, 0x78, 0x79, 0x79, 0x7A, 0x7A, 0x7B, 0x7B, 0x7C, 0x7C, 0x7D, 0x7D, 0x7E,
+    0x7E, 0x7F, 0x7F, 0x80, 0x80, 0x81, 0x81, 0x82, 0x83, 0x83, 0x84, 0x84, 0x85, 0x85, 0x86, 0x86,
+    0x87, 0x88, 0x88, 0x89, 0x89, 0x8A, 0x8A, 0x8B, 0x8C, 0x8C, 0x8D, 0x8D, 0x8E, 0x8F, 0x8F, 0x90,
+    0x90, 0x91, 0x92, 0x92, 0x93, 0x94, 0x94, 0x95, 0x95, 0x96, 0x97, 0x97, 0x98, 0x99, 0x99, 0x9A,
+    0x9B, 0x9B, 0x9C, 0x9D, 0x9D, 0x9E, 0x9F, 0xA0, 0xA0, 0xA1, 0xA2, 0xA2, 0xA3, 0xA4, 0xA5, 0xA5,
+    0xA6, 0xA7, 0xA7, 0xA8, 0xA9, 0xAA, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAE, 0xAF, 0xB0, 0xB1, 0xB1,
+    0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF,
+    0xC0, 0xC1, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xC9, 0xCA, 0xCB, 0xCC, 0xCD,
+    0xCE, 0xCF, 0xD0, 0xD1, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xDB, 0xDC, 0xDD,
+    0xDE, 0xDF, 0xE0, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xEB, 0xED, 0xEE,
+    0xEF, 0xF0, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF7, 0xF8, 0xF9, 0xFA, 0xFB, 0xFC, 0xFD, 0xFF, 0xFF
+];
+
+/// ATH curve for type 1, added to each band's noise level. Band `i` reads the base curve at
+/// `(i + 1) * sample_rate >> 13`, and bands past index 654 are cut off with 0xFF.
+pub(crate) fn ath_curve(sample_rate: u32) -> [u8; SAMPLES_PER_SUBFRAME] {
+    let mut curve = [0xFF; SAMPLES_PER_SUBFRAME];
+    let mut acc = 0u64;
+    for ath in curve.iter_mut() {
+        acc += sample_rate as u64;
+        let index = (acc >> 13) as usize;
+        if index >= 654 {
+            break;
+        }
+        *ath = ATH_BASE_CURVE[index];
+    }
+    curve
+}
+
+/// Rising half of the MDCT window, up to the middle. The rest is derived from the Princen-Bradley
+/// condition (`w[i]^2 + w[127 - i]^2 = 1`).
+static WINDOW_START: [u32; 64] = [
+    0x3A3504F0, 0x3B0183B8, 0x3B70C538, 0x3BBB9268, 0x3C04A809, 0x3C308200, 0x3C61284C, 0x3C8B3F17,
+    0x3CA83992, 0x3CC77FBD, 0x3CE91110, 0x3D0677CD, 0x3D198FC4, 0x3D2DD35C, 0x3D434643, 0x3D59ECC1,
+    0x3D71CBA8, 0x3D85741E, 0x3D92A413, 0x3DA078B4, 0x3DAEF522, 0x3DBE1C9E, 0x3DCDF27B, 0x3DDE7A1D,
+    0x3DEFB6ED, 0x3E00D62B, 0x3E0A2EDA, 0x3E13E72A, 0x3E1E00B1, 0x3E287CF2, 0x3E335D55, 0x3E3EA321,
+    0x3E4A4F75, 0x3E56633F, 0x3E62DF37, 0x3E6FC3D1, 0x3E7D1138, 0x3E8563A2, 0x3E8C72B7, 0x3E93B561,
+    0x3E9B2AEF, 0x3EA2D26F, 0x3EAAAAAB, 0x3EB2B222, 0x3EBAE706, 0x3EC34737, 0x3ECBD03D, 0x3ED47F46,
+    0x3EDD5128, 0x3EE6425C, 0x3EEF4EFF, 0x3EF872D7, 0x3F00D4A9, 0x3F0576CA, 0x3F0A1D3B, 0x3F0EC548,
+    0x3F136C25, 0x3F180EF2, 0x3F1CAAC2, 0x3F213CA2, 0x3F25C1A5, 0x3F2A36E7, 0x3F2E9998, 0x3F32E705
+];
+
+static CRC16_TABLE: [u16; 256] = crc16_table();
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC16 (polynomial 0x8005) used by headers and frames. Both end with their checksum, so a valid
+/// block sums to 0.
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |sum, b| (sum << 8) ^ CRC16_TABLE[((sum >> 8) as u8 ^ b) as usize])
+}
+
+pub(crate) struct HcaTables {
+    /// Gain for each scalefactor, `sqrt(128) * 2^((i - 63) * 53 / 128)`
+    pub(crate) dequantizer_scaling: [f32; 64],
+    /// Distance between quantized values at each resolution
+    pub(crate) quantizer_step: [f32; 16],
+    /// Ratio between two scalefactors, indexed by their difference + 63
+    pub(crate) scale_conversion: [f32; 128],
+    /// Left channel's share of the primary channel for each intensity
+    pub(crate) intensity_ratio: [f32; 16],
+    /// Rising half of the MDCT window
+    pub(crate) window: [f32; SAMPLES_PER_SUBFRAME],
+    /// Orthonormal DCT-IV matrix
+    pub(crate) dct: Box<[[f32; SAMPLES_PER_SUBFRAME]; SAMPLES_PER_SUBFRAME]>
+}
+
+impl HcaTables {
+    fn new() -> Self {
+        let scale = |i: usize| 2f64.powf((i as f64 - 63.) * 53. / 128.);
+        let dequantizer_scaling = std::array::from_fn(|i| (128f64.sqrt() * scale(i)) as f32);
+        let quantizer_step = std::array::from_fn(|i| match i {
+            0 => 0.,
+            1..8 => 2. / (2 * i + 1) as f32,
+            _ => 2. / ((1 << (i - 3)) - 1) as f32
+        });
+        let scale_conversion = std::array::from_fn(|i| match i {
+            0 => 0.,
+            _ => scale(i) as f32
+        });
+        let intensity_ratio = std::array::from_fn(|i| match i {
+            0..14 => (14 - i) as f32 / 7.,
+            _ => 0.
+        });
+        let window = std::array::from_fn(|i| match i {
+            0..64 => f32::from_bits(WINDOW_START[i]),
+            _ => {
+                let mirror = f32::from_bits(WINDOW_START[127 - i]) as f64;
+                (1. - mirror * mirror).sqrt() as f32
+            }
+        });
+        let n = SAMPLES_PER_SUBFRAME as f64;
+        let mut dct = Box::new([[0f32; SAMPLES_PER_SUBFRAME]; SAMPLES_PER_SUBFRAME]);
+        for (k, row) in dct.iter_mut().enumerate() {
+            for (i, value) in row.iter_mut().enumerate() {
+                *value = ((2. / n).sqrt() * (PI / n * (i as f64 + 0.5) * (k as f64 + 0.5)).cos()) as f32;
+            }
+        }
+        Self { dequantizer_scaling, quantizer_step, scale_conversion, intensity_ratio, window, dct }
+    }
+
+    pub(crate) fn get() -> &'static Self {
+        static TABLES: OnceLock<HcaTables> = OnceLock::new();
+        TABLES.get_or_init(Self::new)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::hca::tables::{crc16, HcaTables};
+
+    #[test]
+    fn generated_tables_match_library() -> Result<(), Box<dyn Error>> {
+        // spot checks against the constants in CRI's library
+        let tables = HcaTables::get();
+        assert_eq!(tables.dequantizer_scaling[0].to_bits(), 0x342A8D26);
+        assert_eq!(tables.dequantizer_scaling[62].to_bits(), 0x4107DB35);
+        assert_eq!(tables.dequantizer_scaling[63].to_bits(), 0x413504F3);
+        assert_eq!(tables.scale_conversion[1].to_bits(), 0x32A0B051);
+        assert_eq!(tables.scale_conversion[63], 1.);
+        assert_eq!(tables.quantizer_step[1].to_bits(), 0x3F2AAAAB);
+        assert_eq!(tables.quantizer_step[15].to_bits(), 0x3A000801);
+        assert_eq!(tables.intensity_ratio[7], 1.);
+        assert_eq!(tables.window[64].to_bits(), 0x3F371C9E);
+        assert_eq!(tables.window[65].to_bits(), 0x3F3B37FE);
+        for i in 0..64 {
+            let sum = tables.window[i].powi(2) + tables.window[127 - i].powi(2);
+            assert!((sum - 1.).abs() < 1e-6);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn checksum() -> Result<(), Box<dyn Error>> {
+        let mut data = b"123456789".to_vec();
+        assert_eq!(crc16(&data), 0xFEE8);
+        data.extend(0xFEE8u16.to_be_bytes());
+        assert_eq!(crc16(&data), 0);
+        Ok(())
+    }
+}
@@ -4,6 +4,31 @@ pub mod acb {
     pub mod header;
     pub mod reader;
 }
+#[cfg(feature = "acf")]
+pub mod acf {
+    pub mod reader;
+}
+#[cfg(feature = "adx")]
+pub mod adx {
+    pub mod decoder;
+    pub mod error;
+    pub mod header;
+    pub mod key;
+}
+#[cfg(feature = "awb")]
+pub mod awb {
+    pub mod error;
+    pub mod reader;
+}
+#[cfg(feature = "hca")]
+pub mod hca {
+    mod bits;
+    pub mod cipher;
+    pub mod decoder;
+    pub mod error;
+    pub mod header;
+    mod tables;
+}
 #[cfg(feature = "cpk")]
 pub mod cpk {
     pub mod compress {
@@ -20,13 +45,31 @@ pub mod cpk {
     pub mod file;
     pub mod free_list;
     pub mod reader;
+    pub mod toc;
     pub mod header;
+    pub mod index;
+    pub mod positional;
+    pub mod slice;
+    pub mod writer;
+}
+pub mod error;
+#[cfg(feature = "usm")]
+pub mod usm {
+    pub mod chunk;
+    pub mod error;
+    pub mod mask;
+    pub mod reader;
+    pub mod table;
 }
 pub mod schema {
     pub mod columns;
+    #[cfg(feature = "serde")]
+    pub mod de;
     pub mod header;
     pub mod rows;
     pub mod strings;
+    pub mod view;
+    pub mod writer;
 }
 pub mod utils {
     pub mod endianness;
@@ -35,4 +78,6 @@ pub mod utils {
     pub mod intrinsics;
     // #[cfg_attr(target_arch = "arm", path = "arm.rs")]
     // pub mod intrinsics;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod fixtures;
@@ -1,8 +1,8 @@
-use std::error::Error;
 use std::fmt::{Debug, Formatter};
 use std::io::{Read, Seek, SeekFrom};
 use std::mem::MaybeUninit;
 use bitflags::bitflags;
+use crate::error::{CriError, Result};
 use crate::from_slice;
 use crate::schema::header::TableHeader;
 use crate::schema::rows::{Row, RowValue};
@@ -48,6 +48,17 @@ impl ColumnType {
     }
 }
 
+impl TryFrom<u8> for ColumnType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0..=12 => Ok(unsafe { std::mem::transmute::<u8, Self>(value) }),
+            v => Err(v)
+        }
+    }
+}
+
 const TYPE_MASK: u8 = 0xf;
 
 #[repr(transparent)]
@@ -55,12 +66,18 @@ const TYPE_MASK: u8 = 0xf;
 pub struct ColumnValue(u8);
 
 impl ColumnValue {
+    pub(crate) const fn new(ctype: ColumnType, flags: ColumnFlag) -> Self {
+        Self(ctype as u8 | flags.bits())
+    }
     pub const fn get_flags(&self) -> ColumnFlag {
         ColumnFlag::from_bits_retain(self.0 & !TYPE_MASK)
     }
     pub const fn get_type(&self) -> ColumnType {
         unsafe { std::mem::transmute(self.0 & TYPE_MASK) }
     }
+    pub(crate) const fn get_raw(&self) -> u8 {
+        self.0
+    }
 }
 
 impl Debug for ColumnValue {
@@ -92,21 +109,29 @@ impl Column {
         self.default.as_ref()
     }
 
-    pub fn new_list<C: Read + Seek>(handle: &mut C, header: &TableHeader) -> Result<Vec<Self>, Box<dyn Error>> {
+    pub fn new_list<C: Read + Seek>(handle: &mut C, header: &TableHeader) -> Result<Vec<Self>> {
         handle.seek(SeekFrom::Start(crate::schema::header::HEADER_SIZE as u64))?;
         let mut columns: Vec<Self> = Vec::with_capacity(header.column_count() as usize);
         let mut current: MaybeUninit<[u8; 5]> = MaybeUninit::uninit(); // maximum possible size for row
         let mut default: MaybeUninit<[u8; 0x10]> = MaybeUninit::uninit();
-        for _ in 0..header.column_count() as usize {
-            handle.read_exact(unsafe { current.assume_init_mut() })?;
+        let mut offset = crate::schema::header::HEADER_SIZE as u64;
+        for i in 0..header.column_count() as usize {
+            handle.read_exact(unsafe { current.assume_init_mut() })
+                .map_err(|e| CriError::table_read(e, offset, Some(i), "column extends past the end of the table"))?;
             let flag = ColumnValue(from_slice!(unsafe { current.assume_init_ref() }, u8));
+            // get_type is only valid for the 13 known types
+            ColumnType::try_from(flag.0 & TYPE_MASK)
+                .map_err(|value| CriError::UnsupportedColumnType { offset, value })?;
             let string_offset = from_slice!(unsafe { current.assume_init_ref() }, u32, 0x1);
+            offset += 5;
             let default = match flag.get_flags().contains(ColumnFlag::DEFAULT_VALUE) {
                 true => {
                     let ctype = flag.get_type();
                     let slice = unsafe { std::slice::from_raw_parts_mut(
                         default.as_mut_ptr() as *mut u8, ctype.get_size() as usize) };
-                    handle.read_exact(slice)?;
+                    handle.read_exact(slice).map_err(|e| CriError::table_read(
+                        e, offset, Some(i), "default value extends past the end of the table"))?;
+                    offset += slice.len() as u64;
                     Some(Row::row_value(ctype, unsafe { default.assume_init_ref() }))
                 },
                 false => None
@@ -120,20 +145,18 @@ impl Column {
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use std::fs::File;
-    use std::io::{BufReader, Read};
+    use std::io::{Cursor, Read};
     use std::mem::MaybeUninit;
+    use crate::fixtures::sample_acb;
     use crate::schema::columns::{Column, ColumnFlag, ColumnType};
     use crate::schema::header::{TableHeader, HEADER_SIZE};
+    use crate::error::CriError;
     use crate::schema::strings::{ StringPool, StringPoolImpl };
+    use crate::schema::writer::{TableColumn, TableWriter};
 
     #[test]
     fn read_columns_acb() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/Metaphor/base_cpk/COMMON/sound/bgm.acb";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let mut handle = BufReader::new(File::open(target_table)?);
+        let mut handle = Cursor::new(sample_acb()?);
         let mut header_serial: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
         handle.read_exact(unsafe { header_serial.assume_init_mut() })?;
         let header_serial = unsafe { header_serial.assume_init() };
@@ -162,4 +185,22 @@ pub mod tests {
         */
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn read_columns_invalid() -> Result<(), Box<dyn Error>> {
+        let mut table = TableWriter::new("Invalid");
+        table.add_column(TableColumn::row("Value", ColumnType::UInt32));
+        table.add_row(vec![crate::schema::rows::RowValue::UInt32(0)]);
+        let mut bytes = table.to_bytes()?;
+        // type nibble of the first column
+        bytes[HEADER_SIZE] |= 0xf;
+        let header = TableHeader::new(&bytes);
+        assert!(matches!(Column::new_list(&mut Cursor::new(bytes.as_slice()), &header),
+            Err(CriError::UnsupportedColumnType { offset: 0x20, value: 0xf })));
+        let truncated = &bytes[..HEADER_SIZE + 2];
+        let header = TableHeader::new(truncated);
+        assert!(matches!(Column::new_list(&mut Cursor::new(truncated), &header),
+            Err(CriError::MalformedTable { offset: 0x20, column: Some(0), .. })));
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+//! # Table Deserialization
+//!
+//! Deserialize tables into any type implementing `serde::Deserialize`, through a `TableView`.
+//!
+//! - A table is a sequence of rows, or its first row when it's deserialized into a struct or map
+//!   (such as single row header tables).
+//! - A row is a map from column names to values. Values fall back to the column's default value,
+//!   and columns with no value are left out, so `Option` fields become None and
+//!   `#[serde(default)]` fields take their default.
+//! - Integers can be read into any type that holds the value. Strings are borrowed from the table
+//!   where they're already valid UTF-8.
+//! - Data columns are read as bytes, or as a nested table when they're deserialized into a
+//!   sequence, struct or map and start with `@UTF`.
+
+use std::borrow::Cow;
+use serde::de::value::{SeqDeserializer, StrDeserializer};
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize};
+use crate::error::{CriError, Result};
+use crate::schema::rows::{DataValue, RowValue};
+use crate::schema::view::TableView;
+
+const TABLE_SIGNATURE: &[u8] = b"@UTF";
+
+/// Deserialize a table, either as a sequence of rows or as its first row
+pub fn from_table<'de, T: Deserialize<'de>>(view: &TableView<'de>) -> Result<T> {
+    T::deserialize(TableDeserializer::new(view))
+}
+
+/// Deserialize a table from a slice starting at its header
+pub fn from_bytes<'de, T: Deserialize<'de>>(table: &'de [u8]) -> Result<T> {
+    from_table(&TableView::new(table)?)
+}
+
+/// Deserialize a single row of a table
+pub fn from_row<'de, T: Deserialize<'de>>(view: &TableView<'de>, row: usize) -> Result<T> {
+    if row >= view.row_count() {
+        return Err(de::Error::custom(format_args!("row {} is out of range ({} rows)", row, view.row_count())));
+    }
+    T::deserialize(RowDeserializer { view, row })
+}
+
+pub struct TableDeserializer<'a, 'de> {
+    view: &'a TableView<'de>
+}
+
+impl<'a, 'de> TableDeserializer<'a, 'de> {
+    pub fn new(view: &'a TableView<'de>) -> Self {
+        Self { view }
+    }
+
+    fn first_row(&self) -> Result<RowDeserializer<'a, 'de>> {
+        match self.view.row_count() {
+            0 => Err(de::Error::custom(format_args!("table {} has no rows", self.view.name()))),
+            _ => Ok(RowDeserializer { view: self.view, row: 0 })
+        }
+    }
+}
+
+impl<'de> Deserializer<'de> for TableDeserializer<'_, 'de> {
+    type Error = CriError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(RowSeqAccess { view: self.view, row: 0 })
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.first_row()?.deserialize_map(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str],
+        visitor: V) -> Result<V::Value> {
+        self.first_row()?.deserialize_struct(name, fields, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct enum identifier ignored_any
+    }
+}
+
+struct RowSeqAccess<'a, 'de> {
+    view: &'a TableView<'de>,
+    row: usize
+}
+
+impl<'de> SeqAccess<'de> for RowSeqAccess<'_, 'de> {
+    type Error = CriError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.row >= self.view.row_count() {
+            return Ok(None);
+        }
+        self.row += 1;
+        seed.deserialize(RowDeserializer { view: self.view, row: self.row - 1 }).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.view.row_count() - self.row)
+    }
+}
+
+struct RowDeserializer<'a, 'de> {
+    view: &'a TableView<'de>,
+    row: usize
+}
+
+impl<'de> Deserializer<'de> for RowDeserializer<'_, 'de> {
+    type Error = CriError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(RowMapAccess { view: self.view, row: self.row, column: 0, value: None })
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct RowMapAccess<'a, 'de> {
+    view: &'a TableView<'de>,
+    row: usize,
+    column: usize,
+    /// Value of the last key
+    value: Option<RowValue>
+}
+
+impl<'de> MapAccess<'de> for RowMapAccess<'_, 'de> {
+    type Error = CriError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        while self.column < self.view.column_count() {
+            let column = self.column;
+            self.column += 1;
+            // columns without a value are treated as missing
+            if let Some(value) = self.view.get_value_at(self.row, column) {
+                self.value = Some(value);
+                let name = self.view.column_name(column).unwrap_or_default();
+                return seed.deserialize(StrDeserializer::<CriError>::new(name)).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let value = self.value.take()
+            .ok_or_else(|| <CriError as de::Error>::custom("value requested before its column"))?;
+        seed.deserialize(ValueDeserializer { view: self.view, value })
+    }
+}
+
+struct ValueDeserializer<'a, 'de> {
+    view: &'a TableView<'de>,
+    value: RowValue
+}
+
+impl<'de> ValueDeserializer<'_, 'de> {
+    fn data(&self, data: &DataValue) -> Result<&'de [u8]> {
+        match data.is_none() {
+            true => Ok(&[]),
+            false => self.view.get_pool_data(data).ok_or(de::Error::custom(format_args!(
+                "data at {:#x} ({:#x} bytes) lies outside the data pool", data.get_offset(), data.get_length())))
+        }
+    }
+
+    /// Nested table in a data column
+    fn table(&self) -> Result<Option<TableView<'de>>> {
+        match &self.value {
+            RowValue::Data(data) => match self.data(data)? {
+                table if table.starts_with(TABLE_SIGNATURE) => Ok(Some(TableView::new(table)?)),
+                _ => Ok(None)
+            },
+            _ => Ok(None)
+        }
+    }
+}
+
+impl<'de> Deserializer<'de> for ValueDeserializer<'_, 'de> {
+    type Error = CriError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            RowValue::None => visitor.visit_none(),
+            RowValue::Byte(v) => visitor.visit_u8(v),
+            RowValue::SByte(v) => visitor.visit_i8(v),
+            RowValue::UInt16(v) => visitor.visit_u16(v),
+            RowValue::Int16(v) => visitor.visit_i16(v),
+            RowValue::UInt32(v) => visitor.visit_u32(v),
+            RowValue::Int32(v) => visitor.visit_i32(v),
+            RowValue::UInt64(v) => visitor.visit_u64(v),
+            RowValue::Int64(v) => visitor.visit_i64(v),
+            RowValue::Single(v) => visitor.visit_f32(v),
+            RowValue::Double(v) => visitor.visit_f64(v),
+            RowValue::String(offset) => match self.view.get_pool_string(offset) {
+                Some(Cow::Borrowed(v)) => visitor.visit_borrowed_str(v),
+                Some(Cow::Owned(v)) => visitor.visit_string(v),
+                None => Err(de::Error::custom(format_args!("string at {:#x} lies outside the string pool", offset)))
+            },
+            RowValue::Data(data) => visitor.visit_borrowed_bytes(self.data(&data)?),
+            RowValue::Guid(v) => visitor.visit_bytes(v.as_bytes())
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match &self.value {
+            RowValue::None => visitor.visit_none(),
+            RowValue::Data(data) if data.is_none() => visitor.visit_none(),
+            _ => visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Rows of a nested table, otherwise the bytes of a data or GUID column
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if let Some(table) = self.table()? {
+            return TableDeserializer::new(&table).deserialize_seq(visitor);
+        }
+        match &self.value {
+            RowValue::Data(data) => SeqDeserializer::new(self.data(data)?.iter().copied()).deserialize_seq(visitor),
+            RowValue::Guid(v) => SeqDeserializer::new(v.as_bytes().iter().copied()).deserialize_seq(visitor),
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.table()? {
+            Some(table) => TableDeserializer::new(&table).deserialize_map(visitor),
+            None => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str],
+        visitor: V) -> Result<V::Value> {
+        match self.table()? {
+            Some(table) => TableDeserializer::new(&table).deserialize_struct(name, fields, visitor),
+            None => self.deserialize_any(visitor)
+        }
+    }
+
+    /// GUIDs can be read as their 8-4-4-4-12 representation
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            RowValue::Guid(v) => visitor.visit_string(v.to_string()),
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct
+        tuple tuple_struct enum identifier
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use serde::Deserialize;
+    use serde::de::IgnoredAny;
+    use crate::error::CriError;
+    use crate::schema::columns::ColumnType;
+    use crate::schema::de::{from_bytes, from_row, from_table};
+    use crate::schema::header::StringEncoding;
+    use crate::schema::rows::{DataValue, Guid, RowValue};
+    use crate::schema::view::TableView;
+    use crate::schema::writer::{TableColumn, TableWriter};
+
+    #[allow(non_snake_case)]
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Entry<'a> {
+        Name: &'a str,
+        Size: u64,
+        Version: u32,
+        Unused: Option<i32>,
+        #[serde(default)]
+        Missing: u16,
+        Id: Guid,
+        Hash: &'a [u8]
+    }
+
+    fn entries() -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut writer = TableWriter::new("Entries");
+        writer.add_column(TableColumn::row("Name", ColumnType::String));
+        writer.add_column(TableColumn::row("Size", ColumnType::UInt32));
+        writer.add_column(TableColumn::constant("Version", RowValue::UInt16(3)));
+        writer.add_column(TableColumn::zero("Unused", ColumnType::Int32));
+        writer.add_column(TableColumn::row("Id", ColumnType::Guid));
+        writer.add_column(TableColumn::row("Hash", ColumnType::Data));
+        writer.add_column(TableColumn::row("Extra", ColumnType::Single));
+        for i in 0..3u8 {
+            let name = writer.add_string(&format!("entry{}", i));
+            let hash = writer.add_data(&[i; 4]);
+            writer.add_row(vec![RowValue::String(name), RowValue::UInt32(i as u32 * 0x100), RowValue::None,
+                RowValue::None, RowValue::Guid(Guid::new([i; 16])), RowValue::Data(hash), RowValue::Single(1.0)]);
+        }
+        Ok(writer.to_bytes()?)
+    }
+
+    #[test]
+    fn deserialize_rows() -> Result<(), Box<dyn Error>> {
+        let table = entries()?;
+        let rows: Vec<Entry> = from_bytes(&table)?;
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2], Entry { Name: "entry2", Size: 0x200, Version: 3, Unused: None, Missing: 0,
+            Id: Guid::new([2; 16]), Hash: &[2; 4] });
+        // single rows, and the first row when reading a struct
+        let view = TableView::new(&table)?;
+        assert_eq!(from_row::<Entry>(&view, 1)?.Name, "entry1");
+        assert_eq!(from_table::<Entry>(&view)?.Name, "entry0");
+        assert!(matches!(from_row::<Entry>(&view, 3), Err(CriError::Deserialize(_))));
+        // maps only hold the columns that have a value
+        let row: HashMap<String, IgnoredAny> = from_row(&view, 0)?;
+        let mut columns: Vec<_> = row.keys().map(String::as_str).collect();
+        columns.sort();
+        assert_eq!(columns, ["Extra", "Hash", "Id", "Name", "Size", "Version"]);
+        // GUIDs as text
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Ids { Id: String }
+        let ids: Vec<Ids> = from_table(&view)?;
+        assert_eq!(ids[1].Id, "01010101-0101-0101-0101-010101010101");
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_invalid() -> Result<(), Box<dyn Error>> {
+        let table = entries()?;
+        #[derive(Debug, Deserialize)]
+        #[allow(non_snake_case, dead_code)]
+        struct WrongType { Name: u32 }
+        assert!(matches!(from_bytes::<Vec<WrongType>>(&table), Err(CriError::Deserialize(_))));
+        #[derive(Debug, Deserialize)]
+        #[allow(non_snake_case, dead_code)]
+        struct MissingColumn { Unused: i32 }
+        assert!(matches!(from_bytes::<Vec<MissingColumn>>(&table), Err(CriError::Deserialize(_))));
+        #[derive(Debug, Deserialize)]
+        #[allow(non_snake_case, dead_code)]
+        struct TooSmall { Size: u8 }
+        assert!(matches!(from_bytes::<Vec<TooSmall>>(&table), Err(CriError::Deserialize(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_nested_tables() -> Result<(), Box<dyn Error>> {
+        #[derive(Debug, Deserialize)]
+        #[allow(non_snake_case)]
+        struct Header<'a> {
+            #[serde(borrow)]
+            Entries: Vec<Entry<'a>>,
+            First: Entry<'a>,
+            Raw: Vec<u8>,
+            Empty: Option<Vec<Entry<'a>>>
+        }
+        let entries = entries()?;
+        let mut writer = TableWriter::new("Header");
+        for name in ["Entries", "First", "Raw", "Empty"] {
+            writer.add_column(TableColumn::row(name, ColumnType::Data));
+        }
+        let nested = writer.add_data(&entries);
+        let raw = writer.add_data(&[1, 2, 3]);
+        writer.add_row(vec![RowValue::Data(nested), RowValue::Data(nested), RowValue::Data(raw),
+            RowValue::Data(DataValue::new(0, 0))]);
+        let table = writer.to_bytes()?;
+        let header: Header = from_bytes(&table)?;
+        assert_eq!(header.Entries.iter().map(|e| e.Name).collect::<Vec<_>>(), ["entry0", "entry1", "entry2"]);
+        assert_eq!(header.First.Hash, &[0; 4]);
+        assert_eq!(header.Raw, [1, 2, 3]);
+        assert!(header.Empty.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_shift_jis() -> Result<(), Box<dyn Error>> {
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        struct Name<'a> { #[serde(borrow)] Name: Cow<'a, str> }
+        let mut writer = TableWriter::new_with_encoding("Names", StringEncoding::ShiftJIS);
+        writer.add_column(TableColumn::row("Name", ColumnType::String));
+        for name in ["フォント.fnt", "data.bin"] {
+            let name = writer.add_string(name);
+            writer.add_row(vec![RowValue::String(name)]);
+        }
+        let table = writer.to_bytes()?;
+        let names: Vec<Name> = from_bytes(&table)?;
+        assert_eq!(names[0].Name, "フォント.fnt");
+        assert_eq!(names[1].Name, "data.bin");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "cpk")]
+    fn deserialize_cpk_toc() -> Result<(), Box<dyn Error>> {
+        use std::io::{Cursor, Seek, SeekFrom};
+        use crate::cpk::header::TableContainer;
+        use crate::cpk::toc::CpkToc;
+        #[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+        #[allow(non_snake_case)]
+        struct TocEntry {
+            DirName: String,
+            FileName: String,
+            FileSize: u32,
+            ExtractSize: u32,
+            FileOffset: u64,
+            UserString: Option<String>
+        }
+        let mut handle = Cursor::new(crate::fixtures::shift_jis_cpk()?);
+        let cpk_header = CpkToc::read_header(&mut handle, 0)?;
+        handle.seek(SeekFrom::Start(cpk_header.toc_offset().unwrap()))?;
+        let toc = TableContainer::new(&mut handle)?;
+        let mut entries: Vec<TocEntry> = from_bytes(&toc)?;
+        entries.sort();
+        let mut files = crate::fixtures::shift_jis_cpk_files();
+        files.sort();
+        assert_eq!(entries.len(), files.len());
+        for (entry, (directory, file_name, data)) in entries.iter().zip(files) {
+            assert_eq!((entry.DirName.as_str(), entry.FileName.as_str()), (directory, file_name));
+            assert_eq!(entry.FileSize, data.len() as u32);
+            assert_eq!(entry.ExtractSize, data.len() as u32);
+        }
+        Ok(())
+    }
+}
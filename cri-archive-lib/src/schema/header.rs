@@ -13,28 +13,66 @@
 //! - u16 RowSize: 0x1a,
 //! - u32 RowCount: 0x1c,
 
+use std::borrow::Cow;
 use std::fmt::{Debug, Formatter};
 use std::ptr::NonNull;
+use encoding_rs::SHIFT_JIS;
 use crate::from_slice;
 use crate::utils::endianness::BigEndian;
 use crate::utils::slice::FromSlice;
 
-#[derive(Debug)]
-#[repr(u8)]
+/// Encoding of the strings in a table's string pool, EncodingType in the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StringEncoding {
+    /// 0, used by older games such as Persona 4 Golden
     ShiftJIS,
-    UTF8
+    /// 1
+    UTF8,
+    /// Any other value, read as UTF-8
+    Other(u8)
 }
 
 impl From<u8> for StringEncoding {
     fn from(value: u8) -> Self {
         match value {
             0 => Self::ShiftJIS,
-            _ => Self::UTF8
+            1 => Self::UTF8,
+            v => Self::Other(v)
         }
     }
 }
 
+impl StringEncoding {
+    /// Value stored in the table header
+    pub fn raw(&self) -> u8 {
+        match self {
+            Self::ShiftJIS => 0,
+            Self::UTF8 => 1,
+            Self::Other(v) => *v
+        }
+    }
+
+    /// Decode a string without its null terminator. Strings that are already valid UTF-8 (or
+    /// ASCII for Shift-JIS) are borrowed, and invalid sequences are replaced.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match self {
+            Self::ShiftJIS => SHIFT_JIS.decode_without_bom_handling(bytes).0,
+            Self::UTF8 | Self::Other(_) => String::from_utf8_lossy(bytes)
+        }
+    }
+
+    /// Encode a string, without a null terminator. None if the string has characters that
+    /// can't be represented in this encoding
+    pub fn encode<'a>(&self, value: &'a str) -> Option<Cow<'a, [u8]>> {
+        match self {
+            Self::ShiftJIS => match SHIFT_JIS.encode(value) {
+                (_, _, true) => None,
+                (bytes, _, false) => Some(bytes)
+            },
+            Self::UTF8 | Self::Other(_) => Some(Cow::Borrowed(value.as_bytes()))
+        }
+    }
+}
 
 pub(crate) static HEADER_OFFSET: u32 = 0x8;
 pub static HEADER_SIZE: usize = 0x20;
@@ -96,64 +134,47 @@ impl Debug for TableHeader {
 pub mod tests {
     use super::*;
     use std::error::Error;
-    use std::fs::File;
-    use std::io::SeekFrom;
-    use std::mem::MaybeUninit;
-    use std::io::{Read, Seek};
+    use crate::fixtures::{sample_acb, sample_acf};
 
     #[test]
     fn read_header_acb() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/Metaphor/base_cpk/COMMON/sound/bgm.acb";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let file = std::fs::read(target_table)?;
+        let file = sample_acb()?;
         let header = TableHeader::new(&file);
-        assert_eq!(512, header.rows_offset());
-        assert_eq!(951, header.string_pool_offset());
-        assert_eq!(2112, header.data_pool_offset());
-        assert_eq!(96, header.column_count());
-        assert_eq!(415, header.row_size());
+        assert_eq!(StringEncoding::UTF8, header.encoding());
+        assert_eq!(file.len() as u32 - HEADER_OFFSET, header.size());
+        // columns are a u8 type and u32 name each
+        assert_eq!(HEADER_SIZE as u16 + 16 * 5, header.rows_offset());
+        assert_eq!(16, header.column_count());
+        // 3 u32, 3 u8, 9 data, 1 string
+        assert_eq!(3 * 4 + 3 + 9 * 8 + 4, header.row_size());
         assert_eq!(1, header.row_count());
+        assert_eq!(header.rows_offset() as u32 + header.row_size() as u32, header.string_pool_offset());
+        assert!(header.string_pool_offset() < header.data_pool_offset());
+        assert_eq!(0, header.data_pool_offset() % 8);
         Ok(())
     }
 
     #[test]
     fn read_header_acf() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/Metaphor/base_cpk/COMMON/sound/sound.acf";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let file = std::fs::read(target_table)?;
+        let file = sample_acf()?;
         let header = TableHeader::new(&file);
-        assert_eq!(352, header.rows_offset());
-        assert_eq!(674, header.string_pool_offset());
-        assert_eq!(1568, header.data_pool_offset());
-        assert_eq!(64, header.column_count());
-        assert_eq!(322, header.row_size());
+        assert_eq!(HEADER_SIZE as u16 + 7 * 5, header.rows_offset());
+        assert_eq!(7, header.column_count());
+        assert_eq!(4 + 4 + 5 * 8, header.row_size());
         assert_eq!(1, header.row_count());
         Ok(())
     }
 
     #[test]
+    #[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
     fn read_header_cpk() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/SteamLibrary/steamapps/common/METAPHOR/base.cpk";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let mut handle = File::open(target_table)?;
-        handle.seek(SeekFrom::Start(0x10))?; // go to first table
+        let file = crate::fixtures::sample_cpk(false)?;
         // this CPK table is not encrypted so we can immediately use TableHeader
-        let mut first_header: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
-        handle.read_exact(unsafe { first_header.assume_init_mut() })?;
-        let first_header = unsafe { first_header.assume_init() };
-        let header = TableHeader::new(&first_header);
-        assert_eq!(252, header.rows_offset());
-        assert_eq!(378, header.string_pool_offset());
-        assert_eq!(848, header.data_pool_offset());
+        let header = TableHeader::new(&file[0x10..]);
         assert_eq!(44, header.column_count());
-        assert_eq!(126, header.row_size());
         assert_eq!(1, header.row_count());
+        assert_eq!(HEADER_SIZE as u16 + 44 * 5, header.rows_offset());
+        assert!(header.data_pool_offset() <= header.size() + HEADER_OFFSET);
         Ok(())
     }
-}
\ No newline at end of file
+}
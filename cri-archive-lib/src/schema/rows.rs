@@ -1,14 +1,15 @@
-use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::io::{Read, Seek, SeekFrom};
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut, Index};
+use crate::error::{CriError, Result};
 use crate::schema::columns::{Column, ColumnFlag, ColumnType};
 use crate::schema::header::TableHeader;
 use crate::utils::endianness::BigEndian;
 use crate::utils::slice::FromSlice;
 use crate::from_slice;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RowValue {
     None,
     Byte(u8),
@@ -23,17 +24,21 @@ pub enum RowValue {
     Double(f64),
     String(u32),
     Data(DataValue),
-    Guid([u32; 4])
+    Guid(Guid)
 }
 
 #[repr(C)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DataValue {
     offset: u32,
     length: u32
 }
 
 impl DataValue {
+    pub fn new(offset: u32, length: u32) -> Self {
+        Self { offset, length }
+    }
+
     pub fn is_none(&self) -> bool {
         self.length == 0
     }
@@ -47,6 +52,58 @@ impl DataValue {
     }
 }
 
+/// 16 byte GUID. Tables store the fields big-endian like the rest of their values, so the bytes
+/// are kept in the order they appear in the table.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const fn new(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Guid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct GuidVisitor;
+        impl serde::de::Visitor<'_> for GuidVisitor {
+            type Value = Guid;
+            fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                f.write_str("16 bytes")
+            }
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Guid, E> {
+                v.try_into().map(Guid).map_err(|_| E::invalid_length(v.len(), &self))
+            }
+        }
+        deserializer.deserialize_bytes(GuidVisitor)
+    }
+}
+
+impl From<[u8; 16]> for Guid {
+    fn from(value: [u8; 16]) -> Self {
+        Self(value)
+    }
+}
+
+impl Display for Guid {
+    /// 8-4-4-4-12 form, e.g. 00112233-4455-6677-8899-aabbccddeeff
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Row(Vec<RowValue>);
 
@@ -66,19 +123,21 @@ impl DerefMut for Row {
 
 impl Row {
     pub fn new_list<C: Read + Seek>(handle: &mut C, header: &TableHeader, column_def: &[Column])
-        -> Result<Vec<Self>, Box<dyn Error>> {
+        -> Result<Vec<Self>> {
         handle.seek(SeekFrom::Start(header.rows_offset() as u64))?;
         let mut rows = Vec::with_capacity(header.row_count() as usize);
+        let mut offset = header.rows_offset() as u64;
         for _ in 0..header.row_count() {
-            rows.push(Self::create_row(handle, column_def)?);
+            rows.push(Self::create_row(handle, column_def, &mut offset)?);
         }
         Ok(rows)
     }
 
-    fn create_row<C: Read + Seek>(handle: &mut C, column_def: &[Column]) -> Result<Self, Box<dyn Error>> {
+    /// Offset is the position of the row within the table, and is moved past the row
+    fn create_row<C: Read + Seek>(handle: &mut C, column_def: &[Column], offset: &mut u64) -> Result<Self> {
         let mut column_data = vec![];
         let mut field: MaybeUninit<[u8; 0x10]> = MaybeUninit::uninit();
-        for c in column_def {
+        for (i, c) in column_def.iter().enumerate() {
             let ctype = c.get_value().get_type();
             // Handle null objects
             if !c.get_value().get_flags().contains(ColumnFlag::ROW_STORAGE) {
@@ -87,7 +146,9 @@ impl Row {
             }
             let slice = unsafe { std::slice::from_raw_parts_mut(
                 field.as_mut_ptr() as *mut u8, ctype.get_size() as usize) };
-            handle.read_exact(slice)?;
+            handle.read_exact(slice)
+                .map_err(|e| CriError::table_read(e, *offset, Some(i), "row extends past the end of the table"))?;
+            *offset += slice.len() as u64;
             column_data.push(Self::row_value(ctype, unsafe { field.assume_init_ref() }));
         }
         Ok(Self(column_data))
@@ -110,7 +171,53 @@ impl Row {
                 offset: from_slice!(&slice[0..4], u32),
                 length: from_slice!(&slice[4..8], u32),
             }),
-            ColumnType::Guid => todo!()
+            ColumnType::Guid => RowValue::Guid(Guid(slice[0..16].try_into().unwrap()))
+        }
+    }
+}
+
+impl RowValue {
+    /// The column type that stores this value, or None for RowValue::None
+    pub fn get_type(&self) -> Option<ColumnType> {
+        Some(match self {
+            Self::None => return None,
+            Self::Byte(_) => ColumnType::Byte,
+            Self::SByte(_) => ColumnType::SByte,
+            Self::UInt16(_) => ColumnType::UInt16,
+            Self::Int16(_) => ColumnType::Int16,
+            Self::UInt32(_) => ColumnType::UInt32,
+            Self::Int32(_) => ColumnType::Int32,
+            Self::UInt64(_) => ColumnType::UInt64,
+            Self::Int64(_) => ColumnType::Int64,
+            Self::Single(_) => ColumnType::Single,
+            Self::Double(_) => ColumnType::Double,
+            Self::String(_) => ColumnType::String,
+            Self::Data(_) => ColumnType::Data,
+            Self::Guid(_) => ColumnType::Guid
+        })
+    }
+
+    /// Appends the big-endian representation of this value to the output, as it would appear in
+    /// a row or as a column's default value. None has no representation.
+    pub(crate) fn write_be(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::None => (),
+            Self::Byte(v) => out.push(*v),
+            Self::SByte(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::UInt16(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::Int16(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::UInt32(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::Int32(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::UInt64(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::Int64(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::Single(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::Double(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::String(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Self::Data(v) => {
+                out.extend_from_slice(&v.offset.to_be_bytes());
+                out.extend_from_slice(&v.length.to_be_bytes());
+            },
+            Self::Guid(v) => out.extend_from_slice(v.as_bytes())
         }
     }
 }
@@ -125,29 +232,33 @@ impl Index<usize> for Row {
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use std::fs::File;
-    use std::io::{BufReader, Read, Seek, SeekFrom};
+    use std::io::{Cursor, Read, Seek, SeekFrom};
     use std::mem::MaybeUninit;
+    use crate::fixtures::{sample_acb, SAMPLE_ACB_VERSION, SAMPLE_ACF_MD5};
     use crate::schema::columns::Column;
     use crate::schema::header::{TableHeader, HEADER_SIZE};
     use crate::schema::rows::{DataValue, Row, RowValue};
     use crate::schema::strings::{ StringPool, StringPoolImpl };
 
-    struct OffsetedLowCpkReader(File);
+    #[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
+    struct OffsetedLowCpkReader<R: Read + Seek>(R);
 
-    impl OffsetedLowCpkReader {
-        fn new(file: File) -> Self {
-            Self(file)
+    #[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
+    impl<R: Read + Seek> OffsetedLowCpkReader<R> {
+        fn new(stream: R) -> Self {
+            Self(stream)
         }
     }
 
-    impl Read for OffsetedLowCpkReader {
+    #[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
+    impl<R: Read + Seek> Read for OffsetedLowCpkReader<R> {
         fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
             self.0.read(buf)
         }
     }
 
-    impl Seek for OffsetedLowCpkReader {
+    #[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
+    impl<R: Read + Seek> Seek for OffsetedLowCpkReader<R> {
         fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
             // Add + 0x10 to everything
             let pos = match pos {
@@ -160,11 +271,7 @@ pub mod tests {
 
     #[test]
     fn read_rows_acb() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/Metaphor/base_cpk/COMMON/sound/bgm.acb";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let mut handle = BufReader::new(File::open(target_table)?);
+        let mut handle = Cursor::new(sample_acb()?);
         let mut header_serial: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
         handle.read_exact(unsafe { header_serial.assume_init_mut() })?;
         let header_serial = unsafe { header_serial.assume_init() };
@@ -174,19 +281,16 @@ pub mod tests {
         let rows =  Row::new_list(&mut handle, &header, &columns)?;
         let acb_row = &rows[0];
         assert_eq!(RowValue::UInt32(0), acb_row[0]); // FileIdentifier
-        assert_eq!(RowValue::UInt32(20382208), acb_row[2]); // Version
+        assert_eq!(RowValue::UInt32(SAMPLE_ACB_VERSION), acb_row[2]); // Version
         assert_eq!(RowValue::Data(DataValue { offset: 0, length: 16 }), acb_row[5]); // AcfMd5Hash
-        assert_eq!(RowValue::Data(DataValue { offset: 32, length: 1704 }), acb_row[7]); // CueTable
+        assert_eq!(RowValue::Data(DataValue { offset: 16, length: 120 }), acb_row[7]); // CueTable
         Ok(())
     }
 
     #[test]
+    #[cfg(all(feature = "cpk_compression_layla", feature = "cpk_encryption_table"))]
     fn read_rows_cpk() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/PersonaMultiplayer/CriFsV2Lib/CriFsV2Lib.Tests/Assets/SampleData.cpk";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let mut handle = OffsetedLowCpkReader::new(File::open(target_table)?);
+        let mut handle = OffsetedLowCpkReader::new(Cursor::new(crate::fixtures::sample_cpk(false)?));
         handle.seek(SeekFrom::Start(0))?; // go to first table (this will actually go to 0x10)
         let mut first_header: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
         handle.read_exact(unsafe { first_header.assume_init_mut() })?;
@@ -197,16 +301,16 @@ pub mod tests {
         let cpk_row = &rows[0];
         assert_eq!(RowValue::UInt64(1), cpk_row[0]); // UpdateDateTime
         assert_eq!(RowValue::None, cpk_row[1]); // FileSize
-        assert_eq!(RowValue::UInt64(0x800), cpk_row[2]); // ContentOffset
-        assert_eq!(RowValue::UInt64(0x2a000), cpk_row[3]); // ContentSize
+        assert!(matches!(cpk_row[2], RowValue::UInt64(ofs) if ofs > 0x800 && ofs % 0x800 == 0)); // ContentOffset
+        assert_eq!(RowValue::UInt64(0x800), cpk_row[4]); // TocOffset
+        assert_eq!(RowValue::UInt32(3), cpk_row[23]); // Files
         assert_eq!(RowValue::UInt32(1), cpk_row[35]); // CpkMode
         Ok(())
     }
 
     #[test]
     fn readme_example() -> Result<(), Box<dyn Error>> {
-        let path = "E:/Metaphor/base_cpk/COMMON/sound/bgm.acb";
-        let mut handle = BufReader::new(File::open(path)?);
+        let mut handle = Cursor::new(sample_acb()?);
         let mut header_serial: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
         // Read the table header at 0x0 (ACB, ACF, AWB)
         handle.read_exact(unsafe { header_serial.assume_init_mut() })?;
@@ -220,23 +324,75 @@ pub mod tests {
         // find the column for "AcfMd5Hash"
         let mut acf_md5_hash: Option<usize> = None;
         for (i, c) in columns.iter().enumerate() {
-            if let Some(str) = string_pool.get_string(c.get_string_offset()) {
-                if str == "AcfMd5Hash" {
-                    acf_md5_hash = Some(i);
-                    break;
-                }
+            if let Some(str) = string_pool.get_string(c.get_string_offset()) && str == "AcfMd5Hash" {
+                acf_md5_hash = Some(i);
+                break;
             }
         }
-        if let Some(acf_col) = acf_md5_hash {
-            if let RowValue::Data(hash) = &acb_row[acf_col] {
-                // read the ACF MD5 hash
-                handle.seek(SeekFrom::Start((header.data_pool_offset() + hash.offset) as u64))?;
-                let mut acf_md5 = Vec::with_capacity(hash.length as usize);
-                unsafe { acf_md5.set_len(acf_md5.capacity()) };
-                handle.read_exact(&mut acf_md5)?;
-                assert_eq!(acf_md5, &[236, 103, 97, 106, 90, 25, 172, 164, 161, 234, 209, 75, 242, 34, 227, 209]);
+        assert_eq!(acf_md5_hash, Some(5));
+        if let Some(acf_col) = acf_md5_hash && let RowValue::Data(hash) = &acb_row[acf_col] {
+            // read the ACF MD5 hash
+            handle.seek(SeekFrom::Start((header.data_pool_offset() + hash.offset) as u64))?;
+            let mut acf_md5 = vec![0; hash.length as usize];
+            handle.read_exact(&mut acf_md5)?;
+            assert_eq!(acf_md5, &SAMPLE_ACF_MD5);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "cpk")]
+    fn readme_example_shift_jis() -> Result<(), Box<dyn Error>> {
+        use crate::cpk::header::TableContainer;
+        use crate::cpk::toc::CpkToc;
+        let mut handle = Cursor::new(crate::fixtures::shift_jis_cpk()?);
+        // Find the TOC from the CPK header, then read its table (decrypting it if needed)
+        let cpk_header = CpkToc::read_header(&mut handle, 0)?;
+        handle.seek(SeekFrom::Start(cpk_header.toc_offset().unwrap()))?;
+        let toc = TableContainer::new(&mut handle)?;
+        let header = TableHeader::new(&toc);
+        let mut toc_handle = Cursor::new(toc.as_slice());
+        let columns = Column::new_list(&mut toc_handle, &header)?;
+        let string_pool = StringPoolImpl::new(&mut toc_handle, &header)?;
+        let rows = Row::new_list(&mut toc_handle, &header, &columns)?;
+        let file_name = columns.iter()
+            .position(|c| string_pool.get_string(c.get_string_offset()) == Some("FileName")).unwrap();
+        let mut names = vec![];
+        for row in &rows {
+            if let RowValue::String(name) = row[file_name] {
+                names.push(string_pool.get_string(name).unwrap());
             }
         }
+        assert_eq!(names, ["フォント.fnt", "テスト.bin"]);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn read_rows_guid() -> Result<(), Box<dyn Error>> {
+        use crate::schema::columns::ColumnType;
+        use crate::schema::rows::Guid;
+        use crate::schema::writer::{TableColumn, TableWriter};
+        let id = Guid::new(std::array::from_fn(|i| i as u8 * 0x11));
+        let default = Guid::new([0xa5; 16]);
+        let mut writer = TableWriter::new("Guids");
+        writer.add_column(TableColumn::row("Id", ColumnType::Guid));
+        writer.add_column(TableColumn::constant("Default", RowValue::Guid(default)));
+        writer.add_column(TableColumn::row("Value", ColumnType::UInt32));
+        writer.add_row(vec![RowValue::Guid(id), RowValue::None, RowValue::UInt32(7)]);
+        let table = writer.to_bytes()?;
+        let header = TableHeader::new(&table);
+        // stored in the same byte order
+        let rows_offset = header.rows_offset() as usize;
+        assert_eq!(&table[rows_offset..rows_offset + 16], id.as_bytes());
+        let mut handle = Cursor::new(table.as_slice());
+        let columns = Column::new_list(&mut handle, &header)?;
+        assert_eq!(columns[1].get_default_value(), Some(&RowValue::Guid(default)));
+        let rows = Row::new_list(&mut handle, &header, &columns)?;
+        assert_eq!(rows[0][0], RowValue::Guid(id));
+        assert_eq!(rows[0][1], RowValue::None);
+        assert_eq!(rows[0][2], RowValue::UInt32(7));
+        assert_eq!(id.to_string(), "00112233-4455-6677-8899-aabbccddeeff");
+        assert_eq!(Guid::default().to_string(), "00000000-0000-0000-0000-000000000000");
+        Ok(())
+    }
+}
@@ -1,32 +1,52 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::error::Error;
+use std::collections::hash_map::Entry;
 use std::ffi::CStr;
 use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
 // use std::ptr::NonNull;
-use encoding_rs::SHIFT_JIS;
+use crate::error::{CriError, Result};
 use crate::schema::header::{StringEncoding, TableHeader};
 
 pub trait StringPool {
     fn get_string(&self, offset: u32) -> Option<&str>;
 }
 
+/// String starting at offset, up to the null terminator or the end of the pool
+pub(crate) fn pool_string(stream: &[u8], offset: usize) -> &[u8] {
+    let rest = &stream[offset..];
+    CStr::from_bytes_until_nul(rest).map_or(rest, |s| s.to_bytes())
+}
+
+/// Copy of a table's string pool. Strings are decoded when they're first requested: UTF-8 and
+/// ASCII strings are borrowed from the pool, others are decoded once and cached.
 #[derive(Debug)]
 pub struct StringPoolImpl {
     alloc: Vec<u8>,
-    encoding: StringEncoding
+    encoding: StringEncoding,
+    /// Decoded strings by offset. Entries are never removed or replaced, so they live as long
+    /// as the pool does
+    decoded: Mutex<HashMap<u32, Box<str>>>
 }
 
 impl StringPoolImpl {
     pub fn new<C: Read + Seek>(handle: &mut C, header: &TableHeader)
-        -> Result<Self, Box<dyn Error>> {
+        -> Result<Self> {
         let string_pool_offset = header.string_pool_offset();
         handle.seek(SeekFrom::Start(string_pool_offset as u64))?;
-        let pool_length = (header.data_pool_offset() - string_pool_offset) as usize;
-        let mut alloc = Vec::with_capacity(pool_length);
-        unsafe { alloc.set_len(pool_length) };
+        let pool_length = header.data_pool_offset().checked_sub(string_pool_offset)
+            .ok_or(CriError::malformed(string_pool_offset as u64, "data pool is before the string pool"))? as usize;
+        let mut alloc = vec![0; pool_length];
         handle.read_exact(&mut alloc)?;
-        Ok(Self { alloc, encoding: header.encoding() })
+        Ok(Self::new_with_encoding(alloc, header.encoding()))
+    }
+
+    /// Pool from a slice starting at string_pool_offset
+    pub fn new_with_encoding(alloc: Vec<u8>, encoding: StringEncoding) -> Self {
+        Self { alloc, encoding, decoded: Mutex::new(HashMap::new()) }
     }
+
+    pub fn encoding(&self) -> StringEncoding { self.encoding }
 }
 
 impl StringPool for StringPoolImpl {
@@ -34,11 +54,16 @@ impl StringPool for StringPoolImpl {
         if (offset as usize) >= self.alloc.len() {
             return None;
         }
-        match self.encoding {
-            StringEncoding::ShiftJIS => panic!("StringPoolImpl does not support SHIFT-JIS"),
-            StringEncoding::UTF8 => Some(unsafe { CStr::from_ptr(
-                self.alloc.as_ptr().add(offset as usize) as _).to_str().unwrap() })
-        }
+        let mut decoded = self.decoded.lock().unwrap_or_else(|e| e.into_inner());
+        let value: *const str = match decoded.entry(offset) {
+            Entry::Occupied(entry) => &**entry.into_mut(),
+            Entry::Vacant(entry) => match self.encoding.decode(pool_string(&self.alloc, offset as usize)) {
+                Cow::Borrowed(s) => return Some(s),
+                Cow::Owned(s) => &**entry.insert(s.into_boxed_str())
+            }
+        };
+        // The boxed string doesn't move when the map grows, and is only dropped with the pool
+        Some(unsafe { &*value })
     }
 }
 
@@ -51,43 +76,42 @@ pub struct StringPoolFast(HashMap<usize, String>);
 
 impl StringPoolFast {
     pub fn new<C: Read + Seek>(handle: &mut C, header: &TableHeader)
-        -> Result<Self, Box<dyn Error>> {
+        -> Result<Self> {
         let string_pool_offset = header.string_pool_offset();
         handle.seek(SeekFrom::Start(string_pool_offset as u64))?;
-        let pool_length = (header.data_pool_offset() - string_pool_offset) as usize;
-        let mut alloc = Vec::with_capacity(pool_length);
-        unsafe { alloc.set_len(pool_length) };
+        let pool_length = header.data_pool_offset().checked_sub(string_pool_offset)
+            .ok_or(CriError::malformed(string_pool_offset as u64, "data pool is before the string pool"))? as usize;
+        let mut alloc = vec![0; pool_length];
         handle.read_exact(&mut alloc)?;
         unsafe { Self::new_borrowed(&alloc, header) }
     }
 
     // Assumes that this slice begins at string_pool_offset
-    pub(crate) unsafe fn new_borrowed(stream: &[u8], header: &TableHeader) -> Result<Self, Box<dyn Error>> {
+    pub(crate) unsafe fn new_borrowed(stream: &[u8], header: &TableHeader) -> Result<Self> {
+        let base = header.string_pool_offset() as u64;
         match header.encoding() {
-            StringEncoding::ShiftJIS => Self::new_borrowed_shift_jis(stream),
-            StringEncoding::UTF8 => Self::new_borrowed_utf8(stream),
+            StringEncoding::UTF8 => Self::new_borrowed_utf8(stream, base),
+            encoding => Ok(Self::new_borrowed_decoded(stream, encoding)),
         }
     }
 
-    fn new_borrowed_shift_jis(stream: &[u8]) -> Result<Self, Box<dyn Error>> {
+    fn new_borrowed_decoded(stream: &[u8], encoding: StringEncoding) -> Self {
         let mut offset = 0;
         let mut pointers = HashMap::new();
         while offset < stream.len() {
-            let bytes = unsafe { CStr::from_ptr(stream.as_ptr().add(offset) as _).to_bytes() };
-            let (new, _, _) = SHIFT_JIS.decode(bytes);
-            pointers.insert(offset, new.to_string());
+            let bytes = pool_string(stream, offset);
+            pointers.insert(offset, encoding.decode(bytes).into_owned());
             offset += bytes.len() + 1;
         }
-        Ok(Self(pointers))
+        Self(pointers)
     }
 
-    fn new_borrowed_utf8(stream: &[u8]) -> Result<Self, Box<dyn Error>> {
+    fn new_borrowed_utf8(stream: &[u8], base: u64) -> Result<Self> {
         let mut offset = 0;
         let mut pointers = HashMap::new();
         while offset < stream.len() {
-            // CStr::from_ptr precalculates strlen, so cache the result for each string
-            // Calls to StringPoolFast::get_string() will use O(1) CStr::to_str()
-            let new = unsafe { CStr::from_ptr(stream.as_ptr().add(offset) as _).to_str().unwrap() };
+            let new = std::str::from_utf8(pool_string(stream, offset))
+                .map_err(|_| CriError::malformed(base + offset as u64, "string isn't valid UTF-8"))?;
             pointers.insert(offset, new.to_string());
             offset += new.len() + 1;
         }
@@ -104,69 +128,113 @@ impl StringPool for StringPoolFast {
 #[cfg(test)]
 pub mod tests {
     use std::error::Error;
-    use std::fs::File;
-    use std::io::{BufReader, Read/* , Seek, SeekFrom*/};
+    use std::io::{Cursor, Read};
     use std::mem::MaybeUninit;
-    use crate::schema::header::{TableHeader, HEADER_SIZE};
-    use crate::schema::strings::{StringPoolFast, StringPoolImpl};
+    use crate::fixtures::sample_acb;
+    use crate::schema::columns::ColumnType;
+    use crate::schema::header::{StringEncoding, TableHeader, HEADER_SIZE};
+    use crate::schema::rows::RowValue;
+    use crate::schema::strings::{StringPool, StringPoolFast, StringPoolImpl};
+    use crate::schema::writer::{TableColumn, TableWriter};
+
+    /// Offset of a string in the table's string pool
+    fn string_offset(table: &[u8], header: &TableHeader, value: &str) -> u32 {
+        let pool = &table[header.string_pool_offset() as usize..header.data_pool_offset() as usize];
+        pool.split(|b| *b == 0).scan(0, |ofs, s| {
+            let current = *ofs;
+            *ofs += s.len() as u32 + 1;
+            Some((current, s))
+        }).find(|(_, s)| *s == value.as_bytes()).unwrap().0
+    }
 
     #[test]
     fn parse_strings_fastpool_utf8() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/Metaphor/base_cpk/COMMON/sound/bgm.acb";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let mut handle = BufReader::new(File::open(target_table)?);
+        let table = sample_acb()?;
+        let mut handle = Cursor::new(table.as_slice());
         let mut header_serial: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
         handle.read_exact(unsafe { header_serial.assume_init_mut() })?;
         let header_serial = unsafe { header_serial.assume_init() };
         let header = TableHeader::new(&header_serial);
-        let _ = StringPoolFast::new(&mut handle, &header)?;
+        let strings = StringPoolFast::new(&mut handle, &header)?;
+        let full_header = TableHeader::new(&table);
+        assert_eq!(strings.get_string(0), Some("<NULL>"));
+        assert_eq!(strings.get_string(string_offset(&table, &full_header, "Header")), Some("Header"));
+        assert_eq!(strings.get_string(string_offset(&table, &full_header, "AcfMd5Hash")), Some("AcfMd5Hash"));
         Ok(())
     }
 
     #[test]
     fn parse_strings_standard_utf8() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/Metaphor/base_cpk/COMMON/sound/bgm.acb";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let mut handle = BufReader::new(File::open(target_table)?);
+        let table = sample_acb()?;
+        let mut handle = Cursor::new(table.as_slice());
         let mut header_serial: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
         handle.read_exact(unsafe { header_serial.assume_init_mut() })?;
         let header_serial = unsafe { header_serial.assume_init() };
         let header = TableHeader::new(&header_serial);
-        let _ = StringPoolImpl::new(&mut handle, &header)?;
+        let strings = StringPoolImpl::new(&mut handle, &header)?;
+        let full_header = TableHeader::new(&table);
+        assert_eq!(strings.get_string(0), Some("<NULL>"));
+        assert_eq!(strings.get_string(string_offset(&table, &full_header, "sample")), Some("sample"));
         Ok(())
     }
 
-    /*
     #[test]
     fn parse_strings_fastpool_shiftjis() -> Result<(), Box<dyn Error>> {
-        let target_table = "E:/SteamLibrary/steamapps/common/Persona 4 Golden/data.cpk";
-        if !std::fs::exists(target_table)? {
-            return Ok(());
-        }
-        let mut handle = BufReader::new(File::open(target_table)?);
-        handle.seek(SeekFrom::Start(0x810))?;
-        let mut header_serial: MaybeUninit<[u8; HEADER_SIZE]> = MaybeUninit::uninit();
-        handle.read_exact(unsafe { header_serial.assume_init_mut() })?;
-        let header_serial = unsafe { header_serial.assume_init() };
-        let header = TableHeader::new(&header_serial);
-        let mut table = Vec::with_capacity(header.size() as usize + 8);
-        unsafe { table.set_len(table.capacity()) };
-        handle.read_exact(&mut table)?;
-        let str_raw = &table[header.string_pool_offset() as usize..header.data_pool_offset() as usize];
-        let _ = unsafe { StringPoolFast::new_borrowed(str_raw, &header)? };
-        // let _ = unsafe { StringPoolFast::new(&mut handle, &header)? };
-        /*
-        let mut table = Vec::with_capacity(header.size() as usize);
-        unsafe { table.set_len(table.capacity()) };
-        handle.read_exact(&mut table)?;
+        let mut writer = TableWriter::new_with_encoding("ファイル", StringEncoding::ShiftJIS);
+        writer.add_column(TableColumn::row("名前", ColumnType::String));
+        let name = writer.add_string("データ.bin");
+        writer.add_row(vec![RowValue::String(name)]);
+        let table = writer.to_bytes()?;
+        let header = TableHeader::new(&table);
+        assert_eq!(header.encoding(), StringEncoding::ShiftJIS);
         let str_raw = &table[header.string_pool_offset() as usize..header.data_pool_offset() as usize];
-        let _ = unsafe { StringPoolFast::new_borrowed(str_raw, &header)? };
-        */
+        let strings = unsafe { StringPoolFast::new_borrowed(str_raw, &header)? };
+        // strings are stored after "<NULL>"
+        assert_eq!(strings.get_string(7), Some("ファイル"));
+        assert_eq!(strings.get_string(writer.add_string("名前")), Some("名前"));
+        assert_eq!(strings.get_string(name), Some("データ.bin"));
         Ok(())
     }
-    */
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_strings_standard_shiftjis() -> Result<(), Box<dyn Error>> {
+        let mut writer = TableWriter::new_with_encoding("ファイル", StringEncoding::ShiftJIS);
+        writer.add_column(TableColumn::row("Name", ColumnType::String));
+        let name = writer.add_string("データ.bin");
+        writer.add_row(vec![RowValue::String(name)]);
+        let table = writer.to_bytes()?;
+        let header = TableHeader::new(&table);
+        let strings = StringPoolImpl::new(&mut Cursor::new(table.as_slice()), &header)?;
+        assert_eq!(strings.encoding(), StringEncoding::ShiftJIS);
+        assert_eq!(strings.get_string(7), Some("ファイル"));
+        assert_eq!(strings.get_string(writer.add_string("Name")), Some("Name"));
+        assert_eq!(strings.get_string(table.len() as u32), None);
+        // decoded once, then returned from the cache even though the pool changed
+        let mut strings = StringPoolImpl::new(&mut Cursor::new(table.as_slice()), &header)?;
+        let first: *const str = strings.get_string(name).unwrap();
+        strings.alloc[name as usize..name as usize + 4].copy_from_slice(b"abcd");
+        let second = strings.get_string(name).unwrap();
+        assert_eq!(second, "データ.bin");
+        assert!(std::ptr::eq(first, second));
+        Ok(())
+    }
+
+    #[test]
+    fn string_encoding_values() -> Result<(), Box<dyn Error>> {
+        assert_eq!(StringEncoding::from(0), StringEncoding::ShiftJIS);
+        assert_eq!(StringEncoding::from(1), StringEncoding::UTF8);
+        assert_eq!(StringEncoding::from(2), StringEncoding::Other(2));
+        let mut writer = TableWriter::new_with_encoding("Other", StringEncoding::Other(2));
+        writer.add_column(TableColumn::row("Name", ColumnType::String));
+        writer.add_row(vec![RowValue::String(0)]);
+        let table = writer.to_bytes()?;
+        let header = TableHeader::new(&table);
+        assert_eq!(header.encoding().raw(), 2);
+        let strings = StringPoolImpl::new(&mut Cursor::new(table.as_slice()), &header)?;
+        assert_eq!(strings.get_string(7), Some("Other"));
+        // invalid sequences are replaced rather than failing
+        let pool = StringPoolImpl::new_with_encoding(b"a\xffb\0".to_vec(), StringEncoding::UTF8);
+        assert_eq!(pool.get_string(0), Some("a\u{fffd}b"));
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+//! # Table View
+//!
+//! Borrowed view over a table's bytes. Column offsets are worked out once when the view is
+//! created, and cells and strings are only decoded when they're requested, so opening a table
+//! doesn't allocate per row. Prefer this over `Row::new_list` for large tables such as CPK TOCs.
+
+use std::borrow::Cow;
+use crate::error::{CriError, Result};
+use crate::from_slice;
+use crate::schema::columns::{ColumnFlag, ColumnType};
+use crate::schema::header::{StringEncoding, TableHeader, HEADER_SIZE};
+use crate::schema::rows::{DataValue, Guid, Row, RowValue};
+use crate::schema::strings::pool_string;
+use crate::utils::endianness::BigEndian;
+use crate::utils::slice::FromSlice;
+
+/// Where a column's value is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ViewStorage {
+    /// Column has no value
+    None,
+    /// Offset of the default value from the start of the table
+    Default(usize),
+    /// Offset of the value from the start of each row
+    Row(usize)
+}
+
+#[derive(Debug)]
+struct ViewColumn<'a> {
+    name: Cow<'a, str>,
+    ctype: ColumnType,
+    storage: ViewStorage
+}
+
+/// Table borrowed from a byte slice starting at the table's header
+#[derive(Debug)]
+pub struct TableView<'a> {
+    table: &'a [u8],
+    encoding: StringEncoding,
+    columns: Vec<ViewColumn<'a>>,
+    rows_offset: usize,
+    row_size: usize,
+    row_count: usize,
+    string_pool: &'a [u8],
+    data_pool_offset: usize
+}
+
+impl<'a> TableView<'a> {
+    pub fn new(table: &'a [u8]) -> Result<Self> {
+        if table.len() < HEADER_SIZE {
+            return Err(CriError::malformed(table.len() as u64, "table is smaller than its header"));
+        }
+        let header = TableHeader::new(table);
+        let string_pool_offset = header.string_pool_offset() as usize;
+        let data_pool_offset = header.data_pool_offset() as usize;
+        let string_pool = table.get(string_pool_offset..data_pool_offset)
+            .ok_or(CriError::malformed(string_pool_offset as u64, "string pool lies outside the table"))?;
+        let encoding = header.encoding();
+
+        let mut columns = Vec::with_capacity(header.column_count() as usize);
+        let mut offset = HEADER_SIZE;
+        let mut row_offset = 0;
+        for i in 0..header.column_count() as usize {
+            let column = table.get(offset..offset + 5).ok_or(CriError::MalformedTable {
+                offset: offset as u64, column: Some(i), reason: "column extends past the end of the table" })?;
+            let flag = from_slice!(column, u8);
+            let ctype = ColumnType::try_from(flag & 0xf)
+                .map_err(|value| CriError::UnsupportedColumnType { offset: offset as u64, value })?;
+            let flags = ColumnFlag::from_bits_retain(flag & !0xf);
+            let name = pool_string_checked(string_pool, from_slice!(column, u32, 0x1))
+                .map_or(Cow::Borrowed(""), |s| encoding.decode(s));
+            offset += 5;
+            let size = ctype.get_size() as usize;
+            let default = match flags.contains(ColumnFlag::DEFAULT_VALUE) {
+                true => {
+                    if offset + size > table.len() {
+                        return Err(CriError::MalformedTable { offset: offset as u64, column: Some(i),
+                            reason: "default value extends past the end of the table" });
+                    }
+                    offset += size;
+                    Some(offset - size)
+                },
+                false => None
+            };
+            // same as Row::new_list, values stored in rows take priority over the default
+            let storage = match (flags.contains(ColumnFlag::ROW_STORAGE), default) {
+                (true, _) => {
+                    row_offset += size;
+                    ViewStorage::Row(row_offset - size)
+                },
+                (false, Some(default)) => ViewStorage::Default(default),
+                (false, None) => ViewStorage::None
+            };
+            columns.push(ViewColumn { name, ctype, storage });
+        }
+
+        let rows_offset = header.rows_offset() as usize;
+        let row_size = header.row_size() as usize;
+        let row_count = header.row_count() as usize;
+        if row_offset > row_size {
+            return Err(CriError::malformed(rows_offset as u64, "row values are larger than the row size"));
+        }
+        let rows_end = row_size.checked_mul(row_count).and_then(|s| s.checked_add(rows_offset));
+        if rows_end.is_none_or(|end| end > table.len()) {
+            return Err(CriError::malformed(rows_offset as u64, "row extends past the end of the table"));
+        }
+        Ok(Self { table, encoding, columns, rows_offset, row_size, row_count, string_pool, data_pool_offset })
+    }
+
+    /// Table name from the header
+    pub fn name(&self) -> Cow<'a, str> {
+        self.get_pool_string(from_slice!(self.table, u32, 0x14)).unwrap_or_default()
+    }
+    pub fn encoding(&self) -> StringEncoding { self.encoding }
+    pub fn row_count(&self) -> usize { self.row_count }
+    pub fn column_count(&self) -> usize { self.columns.len() }
+    pub fn column_name(&self, column: usize) -> Option<&str> {
+        self.columns.get(column).map(|c| c.name.as_ref())
+    }
+    pub fn column_type(&self, column: usize) -> Option<ColumnType> {
+        self.columns.get(column).map(|c| c.ctype)
+    }
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    /// Value of a cell by column index, falling back to the column's default. None if the row or
+    /// column doesn't exist or the column has no value
+    pub fn get_value_at(&self, row: usize, column: usize) -> Option<RowValue> {
+        if row >= self.row_count {
+            return None;
+        }
+        let column = self.columns.get(column)?;
+        let offset = match column.storage {
+            ViewStorage::None => return None,
+            ViewStorage::Default(offset) => offset,
+            ViewStorage::Row(offset) => self.rows_offset + row * self.row_size + offset
+        };
+        Some(Row::row_value(column.ctype, &self.table[offset..offset + column.ctype.get_size() as usize]))
+    }
+
+    /// Value of a cell by column name, falling back to the column's default
+    pub fn get_value(&self, row: usize, column: &str) -> Option<RowValue> {
+        self.get_value_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_u8_at(&self, row: usize, column: usize) -> Option<u8> {
+        match self.get_value_at(row, column)? {
+            RowValue::Byte(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Unsigned integer value of up to 16 bits
+    pub fn get_u16_at(&self, row: usize, column: usize) -> Option<u16> {
+        match self.get_value_at(row, column)? {
+            RowValue::Byte(v) => Some(v as u16),
+            RowValue::UInt16(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Unsigned integer value of up to 32 bits
+    pub fn get_u32_at(&self, row: usize, column: usize) -> Option<u32> {
+        match self.get_value_at(row, column)? {
+            RowValue::Byte(v) => Some(v as u32),
+            RowValue::UInt16(v) => Some(v as u32),
+            RowValue::UInt32(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Unsigned integer value of any width
+    pub fn get_u64_at(&self, row: usize, column: usize) -> Option<u64> {
+        match self.get_value_at(row, column)? {
+            RowValue::Byte(v) => Some(v as u64),
+            RowValue::UInt16(v) => Some(v as u64),
+            RowValue::UInt32(v) => Some(v as u64),
+            RowValue::UInt64(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Integer value of any width, sign extended
+    pub fn get_i64_at(&self, row: usize, column: usize) -> Option<i64> {
+        Some(match self.get_value_at(row, column)? {
+            RowValue::Byte(v) => v as i64,
+            RowValue::SByte(v) => v as i64,
+            RowValue::UInt16(v) => v as i64,
+            RowValue::Int16(v) => v as i64,
+            RowValue::UInt32(v) => v as i64,
+            RowValue::Int32(v) => v as i64,
+            RowValue::UInt64(v) => v as i64,
+            RowValue::Int64(v) => v,
+            _ => return None
+        })
+    }
+
+    /// Floating point value of either width
+    pub fn get_f64_at(&self, row: usize, column: usize) -> Option<f64> {
+        match self.get_value_at(row, column)? {
+            RowValue::Single(v) => Some(v as f64),
+            RowValue::Double(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// String decoded with the table's encoding. Strings that are already valid UTF-8 are borrowed
+    pub fn get_str_at(&self, row: usize, column: usize) -> Option<Cow<'a, str>> {
+        match self.get_value_at(row, column)? {
+            RowValue::String(offset) => self.get_pool_string(offset),
+            _ => None
+        }
+    }
+
+    /// Contents of a data column, None if it's empty or the data lies outside the table
+    pub fn get_data_at(&self, row: usize, column: usize) -> Option<&'a [u8]> {
+        match self.get_value_at(row, column)? {
+            RowValue::Data(data) => self.get_pool_data(&data),
+            _ => None
+        }
+    }
+
+    pub fn get_guid_at(&self, row: usize, column: usize) -> Option<Guid> {
+        match self.get_value_at(row, column)? {
+            RowValue::Guid(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Nested table stored in a data column
+    pub fn get_table_at(&self, row: usize, column: usize) -> Result<Option<TableView<'a>>> {
+        self.get_data_at(row, column).map(TableView::new).transpose()
+    }
+
+    pub fn get_u8(&self, row: usize, column: &str) -> Option<u8> {
+        self.get_u8_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_u16(&self, row: usize, column: &str) -> Option<u16> {
+        self.get_u16_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_u32(&self, row: usize, column: &str) -> Option<u32> {
+        self.get_u32_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_u64(&self, row: usize, column: &str) -> Option<u64> {
+        self.get_u64_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_i64(&self, row: usize, column: &str) -> Option<i64> {
+        self.get_i64_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_f64(&self, row: usize, column: &str) -> Option<f64> {
+        self.get_f64_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_str(&self, row: usize, column: &str) -> Option<Cow<'a, str>> {
+        self.get_str_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_data(&self, row: usize, column: &str) -> Option<&'a [u8]> {
+        self.get_data_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_guid(&self, row: usize, column: &str) -> Option<Guid> {
+        self.get_guid_at(row, self.column_index(column)?)
+    }
+
+    pub fn get_table(&self, row: usize, column: &str) -> Result<Option<TableView<'a>>> {
+        match self.column_index(column) {
+            Some(column) => self.get_table_at(row, column),
+            None => Ok(None)
+        }
+    }
+
+    /// Slice of the data pool referenced by a Data value
+    pub fn get_pool_data(&self, data: &DataValue) -> Option<&'a [u8]> {
+        if data.is_none() {
+            return None;
+        }
+        let start = self.data_pool_offset.checked_add(data.get_offset() as usize)?;
+        self.table.get(start..start.checked_add(data.get_length() as usize)?)
+    }
+
+    /// String at an offset into the string pool
+    pub fn get_pool_string(&self, offset: u32) -> Option<Cow<'a, str>> {
+        pool_string_checked(self.string_pool, offset).map(|s| self.encoding.decode(s))
+    }
+}
+
+fn pool_string_checked(pool: &[u8], offset: u32) -> Option<&[u8]> {
+    match (offset as usize) < pool.len() {
+        true => Some(pool_string(pool, offset as usize)),
+        false => None
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use std::io::Cursor;
+    use crate::error::CriError;
+    use crate::schema::columns::{Column, ColumnType};
+    use crate::schema::header::{StringEncoding, TableHeader, HEADER_SIZE};
+    use crate::schema::rows::{Guid, Row, RowValue};
+    use crate::schema::view::TableView;
+    use crate::schema::writer::{TableColumn, TableWriter};
+
+    fn sample_table(encoding: StringEncoding) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut writer = TableWriter::new_with_encoding("Files", encoding);
+        writer.add_column(TableColumn::row("FileName", ColumnType::String));
+        writer.add_column(TableColumn::row("FileSize", ColumnType::UInt32));
+        writer.add_column(TableColumn::row("FileOffset", ColumnType::UInt64));
+        writer.add_column(TableColumn::constant("Version", RowValue::UInt16(7)));
+        writer.add_column(TableColumn::zero("Unused", ColumnType::Int32));
+        writer.add_column(TableColumn::row("Scale", ColumnType::Single));
+        writer.add_column(TableColumn::row("Id", ColumnType::Guid));
+        writer.add_column(TableColumn::row("Data", ColumnType::Data));
+        for (i, name) in ["フォント.fnt", "data.bin", "テスト.bin"].into_iter().enumerate() {
+            let name = writer.add_string(name);
+            let data = writer.add_data(&vec![i as u8; i * 3]);
+            writer.add_row(vec![RowValue::String(name), RowValue::UInt32(i as u32 * 0x100),
+                RowValue::UInt64(0x800 << i), RowValue::None, RowValue::None, RowValue::Single(i as f32 / 2.0),
+                RowValue::Guid(Guid::new([i as u8; 16])), RowValue::Data(data)]);
+        }
+        Ok(writer.to_bytes()?)
+    }
+
+    #[test]
+    fn view_matches_rows() -> Result<(), Box<dyn Error>> {
+        for encoding in [StringEncoding::UTF8, StringEncoding::ShiftJIS] {
+            let table = sample_table(encoding)?;
+            let view = TableView::new(&table)?;
+            let header = TableHeader::new(&table);
+            let mut handle = Cursor::new(table.as_slice());
+            let columns = Column::new_list(&mut handle, &header)?;
+            let rows = Row::new_list(&mut handle, &header, &columns)?;
+            assert_eq!(view.name(), "Files");
+            assert_eq!(view.encoding(), encoding);
+            assert_eq!(view.row_count(), rows.len());
+            assert_eq!(view.column_count(), columns.len());
+            for (r, row) in rows.iter().enumerate() {
+                for (c, column) in columns.iter().enumerate() {
+                    let expected = match &row[c] {
+                        RowValue::None => column.get_default_value().cloned(),
+                        value => Some(value.clone())
+                    };
+                    assert_eq!(view.get_value_at(r, c), expected);
+                }
+            }
+            let names: Vec<_> = (0..view.row_count()).map(|r| view.get_str(r, "FileName").unwrap()).collect();
+            assert_eq!(names, ["フォント.fnt", "data.bin", "テスト.bin"]);
+            assert_eq!(view.get_u32(2, "FileSize"), Some(0x200));
+            assert_eq!(view.get_u64(2, "FileOffset"), Some(0x2000));
+            assert_eq!(view.get_u32(2, "FileOffset"), None);
+            assert_eq!(view.get_u32(1, "Version"), Some(7));
+            assert_eq!(view.get_value(1, "Unused"), None);
+            assert_eq!(view.get_f64(1, "Scale"), Some(0.5));
+            assert_eq!(view.get_guid(2, "Id"), Some(Guid::new([2; 16])));
+            assert_eq!(view.get_data(0, "Data"), None);
+            assert_eq!(view.get_data(2, "Data"), Some(&[2u8; 6][..]));
+            assert_eq!(view.get_value(3, "FileSize"), None);
+            assert_eq!(view.get_value(0, "Missing"), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn view_column_index_accessors() -> Result<(), Box<dyn Error>> {
+        let table = sample_table(StringEncoding::UTF8)?;
+        let view = TableView::new(&table)?;
+        for name in ["FileName", "FileSize", "FileOffset", "Version", "Scale", "Id", "Data"] {
+            let column = view.column_index(name).unwrap();
+            for row in 0..view.row_count() {
+                assert_eq!(view.get_value_at(row, column), view.get_value(row, name));
+                assert_eq!(view.get_str_at(row, column), view.get_str(row, name));
+                assert_eq!(view.get_u32_at(row, column), view.get_u32(row, name));
+                assert_eq!(view.get_u64_at(row, column), view.get_u64(row, name));
+                assert_eq!(view.get_f64_at(row, column), view.get_f64(row, name));
+                assert_eq!(view.get_guid_at(row, column), view.get_guid(row, name));
+                assert_eq!(view.get_data_at(row, column), view.get_data(row, name));
+            }
+        }
+        assert_eq!(view.get_u32_at(2, 1), Some(0x200));
+        assert_eq!(view.get_u16_at(0, 3), Some(7));
+        assert_eq!(view.get_i64_at(1, 2), Some(0x1000));
+        assert_eq!(view.get_u32_at(0, view.column_count()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn view_row_and_default() -> Result<(), Box<dyn Error>> {
+        // a column with both a default and a value in each row reads the row's value
+        let mut writer = TableWriter::new("Table");
+        writer.add_column(TableColumn::row("Value", ColumnType::UInt32).with_default(RowValue::UInt32(9)));
+        writer.add_column(TableColumn::row("Next", ColumnType::UInt16));
+        writer.add_row(vec![RowValue::UInt32(1), RowValue::UInt16(2)]);
+        writer.add_row(vec![RowValue::UInt32(3), RowValue::UInt16(4)]);
+        let table = writer.to_bytes()?;
+        let header = TableHeader::new(&table);
+        let mut handle = Cursor::new(table.as_slice());
+        let columns = Column::new_list(&mut handle, &header)?;
+        let rows = Row::new_list(&mut handle, &header, &columns)?;
+        let view = TableView::new(&table)?;
+        for (r, row) in rows.iter().enumerate() {
+            for c in 0..columns.len() {
+                assert_eq!(view.get_value_at(r, c).as_ref(), Some(&row[c]));
+            }
+        }
+        assert_eq!(view.get_u32(1, "Value"), Some(3));
+        assert_eq!(view.get_u32(1, "Next"), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn view_nested_table() -> Result<(), Box<dyn Error>> {
+        let inner = sample_table(StringEncoding::UTF8)?;
+        let mut writer = TableWriter::new("Header");
+        writer.add_column(TableColumn::row("FileTable", ColumnType::Data));
+        let data = writer.add_data(&inner);
+        writer.add_row(vec![RowValue::Data(data)]);
+        let table = writer.to_bytes()?;
+        let view = TableView::new(&table)?;
+        let inner = view.get_table(0, "FileTable")?.unwrap();
+        assert_eq!(inner.name(), "Files");
+        assert_eq!(inner.get_str(1, "FileName").as_deref(), Some("data.bin"));
+        assert!(view.get_table(0, "Missing")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "cpk")]
+    fn view_cpk_toc() -> Result<(), Box<dyn Error>> {
+        use std::borrow::Cow;
+        use std::io::{Seek, SeekFrom};
+        use crate::cpk::header::TableContainer;
+        use crate::cpk::toc::CpkToc;
+        let mut handle = Cursor::new(crate::fixtures::shift_jis_cpk()?);
+        let cpk_header = CpkToc::read_header(&mut handle, 0)?;
+        handle.seek(SeekFrom::Start(cpk_header.toc_offset().unwrap()))?;
+        let toc = TableContainer::new(&mut handle)?;
+        let view = TableView::new(&toc)?;
+        let mut files: Vec<_> = (0..view.row_count())
+            .map(|r| (view.get_str(r, "DirName").unwrap(), view.get_str(r, "FileName").unwrap(),
+                view.get_u32(r, "FileSize").unwrap()))
+            .collect();
+        files.sort();
+        let mut expected: Vec<(Cow<str>, Cow<str>, u32)> = crate::fixtures::shift_jis_cpk_files().into_iter()
+            .map(|(directory, file_name, data)| (directory.into(), file_name.into(), data.len() as u32))
+            .collect();
+        expected.sort();
+        assert_eq!(files, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn view_invalid() -> Result<(), Box<dyn Error>> {
+        let mut table = sample_table(StringEncoding::UTF8)?;
+        assert!(matches!(TableView::new(&table[..HEADER_SIZE - 1]),
+            Err(CriError::MalformedTable { .. })));
+        // rows reach past the string pool once the pools are cut off
+        let header = TableHeader::new(&table);
+        let rows_end = header.rows_offset() as usize + header.row_size() as usize * header.row_count() as usize;
+        let mut truncated = table[..rows_end - 1].to_vec();
+        truncated[0xc..0x14].copy_from_slice(&[0; 8]);
+        assert!(matches!(TableView::new(&truncated),
+            Err(CriError::MalformedTable { reason: "row extends past the end of the table", .. })));
+        assert!(matches!(TableView::new(&table[..HEADER_SIZE + 2]),
+            Err(CriError::MalformedTable { .. })));
+        // type nibble of the first column
+        table[HEADER_SIZE] |= 0xf;
+        assert!(matches!(TableView::new(&table),
+            Err(CriError::UnsupportedColumnType { offset: 0x20, value: 0xf })));
+        Ok(())
+    }
+}
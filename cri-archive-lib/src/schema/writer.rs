@@ -0,0 +1,397 @@
+//! # CRI Table Writer
+//!
+//! Serializes column definitions and rows into an @UTF table, using the same layout that
+//! `TableHeader` reads:
+//! - Table header (0x20 bytes)
+//! - Column definitions: u8 flags/type, u32 name offset, then the default value if the column has
+//!   [`ColumnFlag::DEFAULT_VALUE`]
+//! - Rows, containing values for each column with [`ColumnFlag::ROW_STORAGE`]
+//! - String pool, starting with `<NULL>` followed by the table name
+//! - Data pool
+//!
+//! All fields are big-endian. Strings and data blobs are deduplicated.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use crate::schema::columns::{ColumnFlag, ColumnType, ColumnValue};
+use crate::schema::header::{StringEncoding, HEADER_OFFSET, HEADER_SIZE};
+use crate::schema::rows::{DataValue, RowValue};
+
+#[derive(Debug)]
+pub enum TableWriterError {
+    /// Row (index) has a different number of values than there are columns
+    ColumnCountMismatch(usize),
+    /// Default value for column (name) doesn't match the column type
+    DefaultTypeMismatch(String),
+    /// Value in (row, column) doesn't match the column type
+    RowTypeMismatch(usize, String),
+    /// Default value for column (name) is RowValue::None
+    NoneDefault(String),
+    /// String can't be represented in the table's encoding
+    UnencodableString(String),
+    /// Rows are too large to fit in a u16 row size
+    RowTooLarge(usize),
+    TableTooLarge
+}
+
+impl Error for TableWriterError {}
+
+impl Display for TableWriterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Debug>::fmt(self, f)
+    }
+}
+
+/// Column definition for a table being written
+#[derive(Debug, Clone)]
+pub struct TableColumn {
+    name: String,
+    ctype: ColumnType,
+    flags: ColumnFlag,
+    default: Option<RowValue>
+}
+
+impl TableColumn {
+    /// Create a column with the given flags. [`ColumnFlag::NAME`] is always set.
+    pub fn new(name: &str, ctype: ColumnType, flags: ColumnFlag) -> Self {
+        Self { name: name.to_owned(), ctype, flags: flags | ColumnFlag::NAME, default: None }
+    }
+
+    /// Create a column that stores a value in each row
+    pub fn row(name: &str, ctype: ColumnType) -> Self {
+        Self::new(name, ctype, ColumnFlag::ROW_STORAGE)
+    }
+
+    /// Create a column with no value, which reads back as [`RowValue::None`]
+    pub fn zero(name: &str, ctype: ColumnType) -> Self {
+        Self::new(name, ctype, ColumnFlag::empty())
+    }
+
+    /// Create a column which takes the same value in every row. The column type is taken from
+    /// the value, which can't be [`RowValue::None`].
+    pub fn constant(name: &str, value: RowValue) -> Self {
+        let ctype = value.get_type().unwrap_or(ColumnType::Byte);
+        Self::new(name, ctype, ColumnFlag::empty()).with_default(value)
+    }
+
+    /// Store a default value in the column definition
+    pub fn with_default(mut self, value: RowValue) -> Self {
+        self.flags |= ColumnFlag::DEFAULT_VALUE;
+        self.default = Some(value);
+        self
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn column_type(&self) -> ColumnType { self.ctype }
+    pub fn flags(&self) -> ColumnFlag { self.flags }
+    pub fn default_value(&self) -> Option<&RowValue> { self.default.as_ref() }
+
+    fn value(&self) -> ColumnValue {
+        ColumnValue::new(self.ctype, self.flags)
+    }
+
+    fn is_row_storage(&self) -> bool {
+        self.flags.contains(ColumnFlag::ROW_STORAGE)
+    }
+}
+
+#[derive(Debug)]
+pub struct TableWriter {
+    name: u32,
+    encoding: StringEncoding,
+    columns: Vec<TableColumn>,
+    rows: Vec<Vec<RowValue>>,
+    strings: Vec<u8>,
+    string_lookup: HashMap<String, u32>,
+    data: Vec<u8>,
+    data_lookup: HashMap<Vec<u8>, DataValue>,
+    /// First string that couldn't be encoded, reported when the table is serialized
+    unencodable: Option<String>
+}
+
+impl TableWriter {
+    const DATA_ALIGNMENT: usize = 8;
+
+    pub fn new(name: &str) -> Self {
+        Self::new_with_encoding(name, StringEncoding::UTF8)
+    }
+
+    pub fn new_with_encoding(name: &str, encoding: StringEncoding) -> Self {
+        let mut table = Self { name: 0, encoding, columns: vec![], rows: vec![], strings: vec![],
+            string_lookup: HashMap::new(), data: vec![], data_lookup: HashMap::new(),
+            unencodable: None };
+        // first string is always <NULL>, which is used for empty string values
+        table.add_string("<NULL>");
+        table.name = table.add_string(name);
+        table
+    }
+
+    pub fn encoding(&self) -> StringEncoding { self.encoding }
+    pub fn columns(&self) -> &[TableColumn] { &self.columns }
+    pub fn rows(&self) -> &[Vec<RowValue>] { &self.rows }
+
+    /// Add a string to the string pool, returning its offset for use in [`RowValue::String`].
+    /// Strings that can't be encoded make `to_bytes` fail.
+    pub fn add_string(&mut self, value: &str) -> u32 {
+        if let Some(ofs) = self.string_lookup.get(value) {
+            return *ofs;
+        }
+        let ofs = self.strings.len() as u32;
+        match self.encoding.encode(value) {
+            Some(bytes) => self.strings.extend_from_slice(&bytes),
+            None => { self.unencodable.get_or_insert_with(|| value.to_owned()); }
+        }
+        self.strings.push(0);
+        self.string_lookup.insert(value.to_owned(), ofs);
+        ofs
+    }
+
+    /// Add a blob to the data pool, returning its location for use in [`RowValue::Data`]
+    pub fn add_data(&mut self, value: &[u8]) -> DataValue {
+        if value.is_empty() {
+            return DataValue::new(0, 0);
+        }
+        if let Some(ofs) = self.data_lookup.get(value) {
+            return *ofs;
+        }
+        self.data.resize(self.data.len().next_multiple_of(Self::DATA_ALIGNMENT), 0);
+        let ofs = DataValue::new(self.data.len() as u32, value.len() as u32);
+        self.data.extend_from_slice(value);
+        self.data_lookup.insert(value.to_vec(), ofs);
+        ofs
+    }
+
+    pub fn add_column(&mut self, column: TableColumn) {
+        self.add_string(&column.name);
+        self.columns.push(column);
+    }
+
+    /// Add a row. Each column must have a value, which is [`RowValue::None`] for columns
+    /// that aren't stored in rows.
+    pub fn add_row(&mut self, row: Vec<RowValue>) {
+        self.rows.push(row);
+    }
+
+    fn validate(&self) -> Result<usize, TableWriterError> {
+        if let Some(value) = &self.unencodable {
+            return Err(TableWriterError::UnencodableString(value.clone()));
+        }
+        for column in &self.columns {
+            if let Some(RowValue::None) = &column.default {
+                return Err(TableWriterError::NoneDefault(column.name.clone()));
+            }
+            if let Some(default) = &column.default
+                && default.get_type() != Some(column.ctype) {
+                return Err(TableWriterError::DefaultTypeMismatch(column.name.clone()));
+            }
+        }
+        for (i, row) in self.rows.iter().enumerate() {
+            if row.len() != self.columns.len() {
+                return Err(TableWriterError::ColumnCountMismatch(i));
+            }
+            for (column, value) in self.columns.iter().zip(row.iter()) {
+                let valid = match column.is_row_storage() {
+                    true => value.get_type() == Some(column.ctype),
+                    false => *value == RowValue::None
+                };
+                if !valid {
+                    return Err(TableWriterError::RowTypeMismatch(i, column.name.clone()));
+                }
+            }
+        }
+        let row_size = self.columns.iter()
+            .filter(|c| c.is_row_storage())
+            .map(|c| c.ctype.get_size() as usize).sum();
+        match row_size > u16::MAX as usize {
+            true => Err(TableWriterError::RowTooLarge(row_size)),
+            false => Ok(row_size)
+        }
+    }
+
+    /// Serialize the table
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TableWriterError> {
+        let row_size = self.validate()?;
+        let columns_size: usize = self.columns.iter()
+            .map(|c| 5 + c.default.as_ref().map_or(0, |_| c.ctype.get_size() as usize)).sum();
+        let rows_offset = HEADER_SIZE + columns_size;
+        let string_pool_offset = rows_offset + row_size * self.rows.len();
+        let data_pool_offset = (string_pool_offset + self.strings.len())
+            .next_multiple_of(Self::DATA_ALIGNMENT);
+        let table_size = (data_pool_offset + self.data.len()).next_multiple_of(Self::DATA_ALIGNMENT);
+        if rows_offset > u16::MAX as usize || table_size > u32::MAX as usize {
+            return Err(TableWriterError::TableTooLarge);
+        }
+        let header_offset = HEADER_OFFSET as usize;
+        let mut out = Vec::with_capacity(table_size);
+        out.extend_from_slice(b"@UTF");
+        out.extend_from_slice(&((table_size - header_offset) as u32).to_be_bytes());
+        out.push(0);
+        out.push(self.encoding.raw());
+        out.extend_from_slice(&((rows_offset - header_offset) as u16).to_be_bytes());
+        out.extend_from_slice(&((string_pool_offset - header_offset) as u32).to_be_bytes());
+        out.extend_from_slice(&((data_pool_offset - header_offset) as u32).to_be_bytes());
+        out.extend_from_slice(&self.name.to_be_bytes());
+        out.extend_from_slice(&(self.columns.len() as u16).to_be_bytes());
+        out.extend_from_slice(&(row_size as u16).to_be_bytes());
+        out.extend_from_slice(&(self.rows.len() as u32).to_be_bytes());
+        for column in &self.columns {
+            out.push(column.value().get_raw());
+            out.extend_from_slice(&self.string_lookup[&column.name].to_be_bytes());
+            if let Some(default) = &column.default {
+                default.write_be(&mut out);
+            }
+        }
+        for row in &self.rows {
+            for (column, value) in self.columns.iter().zip(row.iter()) {
+                if column.is_row_storage() {
+                    value.write_be(&mut out);
+                }
+            }
+        }
+        out.extend_from_slice(&self.strings);
+        out.resize(data_pool_offset, 0);
+        out.extend_from_slice(&self.data);
+        out.resize(table_size, 0);
+        Ok(out)
+    }
+
+    /// Serialize the table, then encrypt it with `TableEncryptor`
+    #[cfg(feature = "cpk_encryption_table")]
+    pub fn to_encrypted_bytes(&self) -> Result<Vec<u8>, TableWriterError> {
+        let mut out = self.to_bytes()?;
+        crate::cpk::encrypt::table::TableEncryptor::encrypt_utf_in_place(&mut out);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use std::io::Cursor;
+    use crate::schema::columns::{Column, ColumnFlag, ColumnType};
+    use crate::schema::header::{StringEncoding, TableHeader, HEADER_SIZE};
+    use crate::schema::rows::{Row, RowValue};
+    use crate::schema::strings::{StringPool, StringPoolFast};
+    use crate::schema::writer::{TableColumn, TableWriter, TableWriterError};
+
+    fn sample_table(encoding: StringEncoding) -> TableWriter {
+        let mut table = TableWriter::new_with_encoding("Waveform", encoding);
+        table.add_column(TableColumn::row("MemoryAwbId", ColumnType::UInt16));
+        table.add_column(TableColumn::row("Name", ColumnType::String));
+        table.add_column(TableColumn::constant("NumChannels", RowValue::Byte(2)));
+        table.add_column(TableColumn::zero("LoopFlag", ColumnType::Byte));
+        table.add_column(TableColumn::row("Payload", ColumnType::Data));
+        table.add_column(TableColumn::row("Volume", ColumnType::Single));
+        let name_a = table.add_string("バトル");
+        let name_b = table.add_string("Field");
+        let blob = table.add_data(&[1, 2, 3, 4, 5]);
+        let empty = table.add_data(&[]);
+        table.add_row(vec![RowValue::UInt16(0), RowValue::String(name_a), RowValue::None,
+            RowValue::None, RowValue::Data(blob), RowValue::Single(1.0)]);
+        table.add_row(vec![RowValue::UInt16(1), RowValue::String(name_b), RowValue::None,
+            RowValue::None, RowValue::Data(empty), RowValue::Single(0.5)]);
+        table
+    }
+
+    type TableContents = (Vec<Column>, Vec<Row>, StringPoolFast);
+
+    fn read_back(bytes: &[u8]) -> Result<TableContents, Box<dyn Error>> {
+        let header = TableHeader::new(bytes);
+        assert_eq!(header.size() as usize, bytes.len() - 8);
+        let mut cursor = Cursor::new(bytes);
+        cursor.set_position(HEADER_SIZE as u64);
+        let columns = Column::new_list(&mut cursor, &header)?;
+        let rows = Row::new_list(&mut cursor, &header, &columns)?;
+        let strings = unsafe { StringPoolFast::new_borrowed(
+            &bytes[header.string_pool_offset() as usize..header.data_pool_offset() as usize], &header)? };
+        Ok((columns, rows, strings))
+    }
+
+    #[test]
+    fn write_and_read_table() -> Result<(), Box<dyn Error>> {
+        for encoding in [StringEncoding::UTF8, StringEncoding::ShiftJIS] {
+            let bytes = sample_table(encoding).to_bytes()?;
+            let header = TableHeader::new(&bytes);
+            assert_eq!(header.encoding(), encoding);
+            assert_eq!(header.column_count(), 6);
+            assert_eq!(header.row_size(), 2 + 4 + 8 + 4);
+            assert_eq!(header.row_count(), 2);
+            let (columns, rows, strings) = read_back(&bytes)?;
+            assert_eq!(strings.get_string(0), Some("<NULL>"));
+            assert_eq!(strings.get_string(7), Some("Waveform"));
+            assert_eq!(strings.get_string(columns[0].get_string_offset()), Some("MemoryAwbId"));
+            assert_eq!(columns[2].get_value().get_flags(), ColumnFlag::NAME | ColumnFlag::DEFAULT_VALUE);
+            assert_eq!(columns[2].get_default_value(), Some(&RowValue::Byte(2)));
+            assert_eq!(columns[3].get_value().get_flags(), ColumnFlag::NAME);
+            let RowValue::String(name) = rows[0][1] else { panic!("expected string") };
+            assert_eq!(strings.get_string(name), Some("バトル"));
+            let RowValue::Data(data) = rows[0][4] else { panic!("expected data") };
+            let data_pool = header.data_pool_offset() as usize + data.get_offset() as usize;
+            assert_eq!(&bytes[data_pool..data_pool + data.get_length() as usize], &[1, 2, 3, 4, 5]);
+            assert_eq!(rows[1][0], RowValue::UInt16(1));
+            assert!(matches!(&rows[1][4], RowValue::Data(v) if v.is_none()));
+            assert_eq!(rows[1][5], RowValue::Single(0.5));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn write_table_deduplicates() -> Result<(), Box<dyn Error>> {
+        let mut table = TableWriter::new("Dedup");
+        assert_eq!(table.add_string("<NULL>"), 0);
+        let a = table.add_string("CueName");
+        assert_eq!(table.add_string("CueName"), a);
+        table.add_column(TableColumn::row("CueName", ColumnType::String));
+        let first = table.add_data(b"@UTF");
+        let second = table.add_data(b"AFS2");
+        assert_eq!(table.add_data(b"@UTF"), first);
+        assert_eq!(second.get_offset(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn write_table_rejects_bad_rows() -> Result<(), Box<dyn Error>> {
+        let mut table = TableWriter::new("Bad");
+        table.add_column(TableColumn::row("Id", ColumnType::UInt32));
+        table.add_row(vec![RowValue::UInt16(0)]);
+        assert!(matches!(table.to_bytes(), Err(TableWriterError::RowTypeMismatch(0, _))));
+        let mut table = TableWriter::new("Bad");
+        table.add_column(TableColumn::row("Id", ColumnType::UInt32));
+        table.add_row(vec![]);
+        assert!(matches!(table.to_bytes(), Err(TableWriterError::ColumnCountMismatch(0))));
+        let mut table = TableWriter::new("Bad");
+        table.add_column(TableColumn::zero("Id", ColumnType::UInt32).with_default(RowValue::Byte(0)));
+        assert!(matches!(table.to_bytes(), Err(TableWriterError::DefaultTypeMismatch(_))));
+        let mut table = TableWriter::new("Bad");
+        table.add_column(TableColumn::constant("Id", RowValue::None));
+        assert!(matches!(table.to_bytes(), Err(TableWriterError::NoneDefault(name)) if name == "Id"));
+        Ok(())
+    }
+
+    #[test]
+    fn write_table_rejects_unencodable_strings() -> Result<(), Box<dyn Error>> {
+        let mut table = TableWriter::new_with_encoding("Names", StringEncoding::ShiftJIS);
+        table.add_column(TableColumn::row("Name", ColumnType::String));
+        let name = table.add_string("🎵.hca");
+        table.add_row(vec![RowValue::String(name)]);
+        assert!(matches!(table.to_bytes(), Err(TableWriterError::UnencodableString(v)) if v == "🎵.hca"));
+        let mut table = TableWriter::new("Names");
+        table.add_string("🎵.hca");
+        table.to_bytes()?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "cpk_encryption_table")]
+    fn write_encrypted_table() -> Result<(), Box<dyn Error>> {
+        use crate::cpk::encrypt::table::TableDecryptor;
+        let table = sample_table(StringEncoding::UTF8);
+        let plain = table.to_bytes()?;
+        let mut encrypted = table.to_encrypted_bytes()?;
+        assert!(TableDecryptor::is_encrypted(&encrypted));
+        TableDecryptor::decrypt_utf_in_place(&mut encrypted);
+        assert_eq!(encrypted, plain);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! # USM Chunks
+//!
+//! A USM is a flat sequence of chunks, big endian:
+//!
+//! ```text
+//! 0x0: signature ("CRID", "@SFV", "@SFA", "@ALP", "@SBT", "@CUE")
+//! 0x4: size of the rest of the chunk (u32)
+//! 0x9: payload offset from 0x8 (u8)
+//! 0xa: padding after the payload (u16)
+//! 0xc: channel (u8)
+//! 0xf: payload type (u8)
+//! 0x10: frame time (u32), frame rate (u32)
+//! ```
+
+use crate::usm::error::UsmError;
+use crate::utils::endianness::BigEndian;
+use crate::utils::slice::FromSlice;
+
+pub(crate) const CHUNK_HEADER_SIZE: usize = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UsmChunkType {
+    /// Directory of the file's streams
+    Crid,
+    Video,
+    Audio,
+    /// Video alpha channel
+    Alpha,
+    Subtitle,
+    Cue,
+    Other([u8; 4])
+}
+
+impl UsmChunkType {
+    pub fn signature(&self) -> [u8; 4] {
+        match self {
+            Self::Crid => *b"CRID",
+            Self::Video => *b"@SFV",
+            Self::Audio => *b"@SFA",
+            Self::Alpha => *b"@ALP",
+            Self::Subtitle => *b"@SBT",
+            Self::Cue => *b"@CUE",
+            Self::Other(s) => *s
+        }
+    }
+}
+
+impl From<[u8; 4]> for UsmChunkType {
+    fn from(value: [u8; 4]) -> Self {
+        match &value {
+            b"CRID" => Self::Crid,
+            b"@SFV" => Self::Video,
+            b"@SFA" => Self::Audio,
+            b"@ALP" => Self::Alpha,
+            b"@SBT" => Self::Subtitle,
+            b"@CUE" => Self::Cue,
+            _ => Self::Other(value)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsmPayloadType {
+    /// Elementary stream data
+    Stream,
+    /// @UTF table describing the stream
+    Header,
+    /// Text marking the end of the headers or the stream, e.g. "#HEADER END"
+    SectionEnd,
+    /// @UTF table with seek or cue points
+    Metadata,
+    Other(u8)
+}
+
+impl From<u8> for UsmPayloadType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Stream,
+            1 => Self::Header,
+            2 => Self::SectionEnd,
+            3 => Self::Metadata,
+            v => Self::Other(v)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UsmChunk<'a> {
+    chunk_type: UsmChunkType,
+    channel: u8,
+    payload_type: UsmPayloadType,
+    frame_time: u32,
+    frame_rate: u32,
+    payload: &'a [u8]
+}
+
+impl<'a> UsmChunk<'a> {
+    /// Parse the chunk at the start of data, returning it and its total size
+    pub fn parse(data: &'a [u8]) -> Result<(Self, usize), UsmError> {
+        if data.len() < CHUNK_HEADER_SIZE {
+            return Err(UsmError::Truncated);
+        }
+        let size = 8 + u32::from_slice::<BigEndian>(data, 4) as usize;
+        let chunk = data.get(..size).ok_or(UsmError::Truncated)?;
+        let payload_start = 8 + data[9] as usize;
+        let payload_end = size.checked_sub(u16::from_slice::<BigEndian>(data, 0xa) as usize)
+            .ok_or(UsmError::InvalidChunk)?;
+        if payload_start < CHUNK_HEADER_SIZE || payload_start > payload_end {
+            return Err(UsmError::InvalidChunk);
+        }
+        Ok((Self {
+            chunk_type: UsmChunkType::from([data[0], data[1], data[2], data[3]]),
+            channel: data[0xc],
+            payload_type: UsmPayloadType::from(data[0xf] & 3),
+            frame_time: u32::from_slice::<BigEndian>(data, 0x10),
+            frame_rate: u32::from_slice::<BigEndian>(data, 0x14),
+            payload: &chunk[payload_start..payload_end]
+        }, size))
+    }
+
+    pub fn chunk_type(&self) -> UsmChunkType { self.chunk_type }
+    pub fn channel(&self) -> u8 { self.channel }
+    pub fn payload_type(&self) -> UsmPayloadType { self.payload_type }
+    /// Timestamp of the payload, in the time base given by frame_rate
+    pub fn frame_time(&self) -> u32 { self.frame_time }
+    /// Time base, e.g. 2997 for 29.97 fps video
+    pub fn frame_rate(&self) -> u32 { self.frame_rate }
+    pub fn payload(&self) -> &'a [u8] { self.payload }
+}
+
+/// Iterates over every chunk of a USM, stopping after the first error
+#[derive(Debug, Clone)]
+pub struct UsmChunkIter<'a> {
+    data: &'a [u8],
+    offset: usize
+}
+
+impl<'a> UsmChunkIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    /// Offset of the next chunk
+    pub fn offset(&self) -> usize { self.offset }
+}
+
+impl<'a> Iterator for UsmChunkIter<'a> {
+    type Item = Result<UsmChunk<'a>, UsmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+        match UsmChunk::parse(&self.data[self.offset..]) {
+            Ok((chunk, size)) => {
+                self.offset += size;
+                Some(Ok(chunk))
+            },
+            Err(e) => {
+                self.offset = self.data.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::usm::chunk::{UsmChunkIter, UsmChunkType, UsmPayloadType};
+    use crate::usm::error::UsmError;
+
+    /// Chunk with the payload 4 byte aligned
+    pub(crate) fn build_chunk(chunk_type: UsmChunkType, channel: u8, payload_type: u8, frame_time: u32, payload: &[u8]) -> Vec<u8> {
+        let padding = payload.len().next_multiple_of(4) - payload.len();
+        let mut out = chunk_type.signature().to_vec();
+        out.extend(((0x18 + payload.len() + padding) as u32).to_be_bytes());
+        out.extend([0, 0x18]);
+        out.extend((padding as u16).to_be_bytes());
+        out.extend([channel, 0, 0, payload_type]);
+        out.extend(frame_time.to_be_bytes());
+        out.extend(2997u32.to_be_bytes());
+        out.extend([0; 8]);
+        out.extend(payload);
+        out.resize(out.len() + padding, 0);
+        out
+    }
+
+    #[test]
+    fn iterate_chunks() -> Result<(), Box<dyn Error>> {
+        let mut data = build_chunk(UsmChunkType::Video, 0, 0, 100, b"frame");
+        data.extend(build_chunk(UsmChunkType::Audio, 1, 2, 0, b"#CONTENTS END   ===============\0"));
+        let chunks = UsmChunkIter::new(&data).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].chunk_type(), UsmChunkType::Video);
+        assert_eq!(chunks[0].payload_type(), UsmPayloadType::Stream);
+        assert_eq!((chunks[0].frame_time(), chunks[0].frame_rate()), (100, 2997));
+        assert_eq!(chunks[0].payload(), b"frame");
+        assert_eq!((chunks[1].chunk_type(), chunks[1].channel()), (UsmChunkType::Audio, 1));
+        assert_eq!(chunks[1].payload_type(), UsmPayloadType::SectionEnd);
+        let mut iter = UsmChunkIter::new(&data[..data.len() - 1]);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(matches!(iter.next(), Some(Err(UsmError::Truncated))));
+        assert!(iter.next().is_none());
+        let mut bad = data.clone();
+        bad[0xb] = 0xff;
+        assert!(matches!(UsmChunkIter::new(&bad).next(), Some(Err(UsmError::InvalidChunk))));
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+#[derive(Debug)]
+pub enum UsmError {
+    /// File doesn't start with a CRID chunk
+    InvalidMagic,
+    /// Chunk extends past the end of the data
+    Truncated,
+    /// Chunk's payload offset or padding don't fit in the chunk
+    InvalidChunk,
+    /// Header or metadata payload isn't a valid @UTF table
+    InvalidTable,
+}
+
+impl Error for UsmError {}
+impl Display for UsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Debug>::fmt(self, f)
+    }
+}
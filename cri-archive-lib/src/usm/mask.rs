@@ -0,0 +1,141 @@
+//! # USM Encryption
+//!
+//! Encrypted USMs XOR their stream payloads with masks derived from the game's 64-bit key, the
+//! same keycode used for its HCA files.
+//!
+//! - **Video**: The first 0x40 bytes are plain. If at least 0x200 bytes follow, everything after
+//!   the next 0x100 bytes is XORed with a rolling mask fed by the plaintext, then those 0x100 bytes
+//!   are XORed with a mask fed by the plaintext after them.
+//! - **Audio**: Everything from 0x140 onwards is XORed with a fixed mask. Only ADX audio uses it,
+//!   HCA audio has its own encryption.
+
+const VIDEO_PLAIN_SIZE: usize = 0x40;
+const VIDEO_HEAD_SIZE: usize = 0x100;
+const AUDIO_PLAIN_SIZE: usize = 0x140;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsmMask {
+    video1: [u8; 0x20],
+    video2: [u8; 0x20],
+    audio: [u8; 0x20]
+}
+
+impl UsmMask {
+    pub fn new(key: u64) -> Self {
+        let c = key.to_le_bytes();
+        let mut t = [0u8; 0x20];
+        t[0x00] = c[0];
+        t[0x01] = c[1];
+        t[0x02] = c[2];
+        t[0x03] = c[3].wrapping_sub(0x34);
+        t[0x04] = c[4].wrapping_add(0xf9);
+        t[0x05] = c[5] ^ 0x13;
+        t[0x06] = c[6].wrapping_add(0x61);
+        t[0x07] = t[0x00] ^ 0xff;
+        t[0x08] = t[0x01].wrapping_add(t[0x02]);
+        t[0x09] = t[0x01].wrapping_sub(t[0x07]);
+        t[0x0a] = t[0x02] ^ 0xff;
+        t[0x0b] = t[0x01] ^ 0xff;
+        t[0x0c] = t[0x0b].wrapping_add(t[0x09]);
+        t[0x0d] = t[0x08].wrapping_sub(t[0x03]);
+        t[0x0e] = t[0x0d] ^ 0xff;
+        t[0x0f] = t[0x0a].wrapping_sub(t[0x0b]);
+        t[0x10] = t[0x08].wrapping_sub(t[0x0f]);
+        t[0x11] = t[0x10] ^ t[0x07];
+        t[0x12] = t[0x0f] ^ 0xff;
+        t[0x13] = t[0x03] ^ 0x10;
+        t[0x14] = t[0x04].wrapping_sub(0x32);
+        t[0x15] = t[0x05].wrapping_add(0xed);
+        t[0x16] = t[0x06] ^ 0xf3;
+        t[0x17] = t[0x13].wrapping_sub(t[0x0f]);
+        t[0x18] = t[0x15].wrapping_add(t[0x07]);
+        t[0x19] = 0x21u8.wrapping_sub(t[0x13]);
+        t[0x1a] = t[0x14] ^ t[0x17];
+        t[0x1b] = t[0x16].wrapping_add(t[0x16]);
+        t[0x1c] = t[0x17].wrapping_add(0x44);
+        t[0x1d] = t[0x03].wrapping_add(t[0x04]);
+        t[0x1e] = t[0x05].wrapping_sub(t[0x16]);
+        t[0x1f] = t[0x1d] ^ t[0x13];
+        let video2 = t.map(|b| b ^ 0xff);
+        let audio = std::array::from_fn(|i| match i & 1 {
+            1 => b"URUC"[(i >> 1) & 3],
+            _ => video2[i]
+        });
+        Self { video1: t, video2, audio }
+    }
+
+    pub fn decrypt_video(&self, data: &mut [u8]) {
+        if data.len() < VIDEO_PLAIN_SIZE + 2 * VIDEO_HEAD_SIZE {
+            return;
+        }
+        let data = &mut data[VIDEO_PLAIN_SIZE..];
+        let mut mask = self.video2;
+        for (i, b) in data.iter_mut().enumerate().skip(VIDEO_HEAD_SIZE) {
+            *b ^= mask[i & 0x1f];
+            mask[i & 0x1f] = *b ^ self.video2[i & 0x1f];
+        }
+        let mut mask = self.video1;
+        for i in 0..VIDEO_HEAD_SIZE {
+            mask[i & 0x1f] ^= data[VIDEO_HEAD_SIZE + i];
+            data[i] ^= mask[i & 0x1f];
+        }
+    }
+
+    pub fn encrypt_video(&self, data: &mut [u8]) {
+        if data.len() < VIDEO_PLAIN_SIZE + 2 * VIDEO_HEAD_SIZE {
+            return;
+        }
+        let data = &mut data[VIDEO_PLAIN_SIZE..];
+        // the head's mask comes from the plaintext after it, so it goes first
+        let mut mask = self.video1;
+        for i in 0..VIDEO_HEAD_SIZE {
+            mask[i & 0x1f] ^= data[VIDEO_HEAD_SIZE + i];
+            data[i] ^= mask[i & 0x1f];
+        }
+        let mut mask = self.video2;
+        for (i, b) in data.iter_mut().enumerate().skip(VIDEO_HEAD_SIZE) {
+            let plain = *b;
+            *b ^= mask[i & 0x1f];
+            mask[i & 0x1f] = plain ^ self.video2[i & 0x1f];
+        }
+    }
+
+    /// XOR with the audio mask, which both decrypts and encrypts
+    pub fn decrypt_audio(&self, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate().skip(AUDIO_PLAIN_SIZE) {
+            *b ^= self.audio[i & 0x1f];
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::usm::mask::UsmMask;
+
+    #[test]
+    fn mask_round_trip() -> Result<(), Box<dyn Error>> {
+        let mask = UsmMask::new(0x0123_4567_89ab_cdef);
+        assert_eq!(&mask.audio[..4], &[mask.video1[0] ^ 0xff, b'U', mask.video1[2] ^ 0xff, b'R']);
+        let plain: Vec<u8> = (0..0x400u32).map(|i| (i * 7 + i / 3) as u8).collect();
+        let mut data = plain.clone();
+        mask.encrypt_video(&mut data);
+        assert_eq!(data[..0x40], plain[..0x40]);
+        assert_ne!(data[0x40..0x140], plain[0x40..0x140]);
+        assert_ne!(data[0x140..], plain[0x140..]);
+        mask.decrypt_video(&mut data);
+        assert_eq!(data, plain);
+        // too short to be encrypted
+        let mut short = plain[..0x23f].to_vec();
+        mask.encrypt_video(&mut short);
+        assert_eq!(short, plain[..0x23f]);
+        let mut audio = plain.clone();
+        mask.decrypt_audio(&mut audio);
+        assert_eq!(audio[..0x140], plain[..0x140]);
+        assert_ne!(audio, plain);
+        mask.decrypt_audio(&mut audio);
+        assert_eq!(audio, plain);
+        assert_ne!(UsmMask::new(1), UsmMask::new(2));
+        Ok(())
+    }
+}
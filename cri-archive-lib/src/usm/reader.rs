@@ -0,0 +1,309 @@
+//! # USM Reader
+//!
+//! Demuxes the streams of a USM (CRID) movie. The file starts with a CRID chunk whose table lists
+//! every stream, then each stream's header table, followed by interleaved stream payloads. Each
+//! stream is identified by its chunk type and channel.
+
+use crate::error::Result;
+use crate::usm::chunk::{UsmChunkIter, UsmChunkType, UsmPayloadType};
+use crate::usm::error::UsmError;
+use crate::usm::mask::UsmMask;
+use crate::usm::table::UsmTable;
+
+/// Codec of a video stream, mpeg_codec in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsmVideoCodec {
+    /// MPEG-1 or MPEG-2 video
+    Mpeg,
+    H264,
+    Vp9,
+    Other(i64)
+}
+
+impl From<i64> for UsmVideoCodec {
+    fn from(value: i64) -> Self {
+        match value {
+            1 => Self::Mpeg,
+            5 => Self::H264,
+            9 => Self::Vp9,
+            v => Self::Other(v)
+        }
+    }
+}
+
+impl UsmVideoCodec {
+    /// Usual extension of the demuxed elementary stream
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mpeg => "m2v",
+            Self::H264 => "h264",
+            // stored as IVF frames
+            Self::Vp9 => "ivf",
+            Self::Other(_) => "bin"
+        }
+    }
+}
+
+/// Codec of an audio stream, audio_codec in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsmAudioCodec {
+    Adx,
+    Hca,
+    Other(i64)
+}
+
+impl From<i64> for UsmAudioCodec {
+    fn from(value: i64) -> Self {
+        match value {
+            2 => Self::Adx,
+            4 => Self::Hca,
+            v => Self::Other(v)
+        }
+    }
+}
+
+impl UsmAudioCodec {
+    /// Usual extension of the demuxed elementary stream
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Adx => "adx",
+            Self::Hca => "hca",
+            Self::Other(_) => "bin"
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UsmStream {
+    chunk_type: UsmChunkType,
+    channel: u8,
+    filename: Option<String>,
+    header: Option<UsmTable>,
+    metadata: Vec<UsmTable>
+}
+
+impl UsmStream {
+    pub fn chunk_type(&self) -> UsmChunkType { self.chunk_type }
+    pub fn channel(&self) -> u8 { self.channel }
+    /// Name of the stream's source file, from the CRID table
+    pub fn filename(&self) -> Option<&str> { self.filename.as_deref() }
+    /// VIDEO_HDRINFO, AUDIO_HDRINFO or similar table
+    pub fn header(&self) -> Option<&UsmTable> { self.header.as_ref() }
+    /// Seek and cue point tables
+    pub fn metadata(&self) -> &[UsmTable] { &self.metadata }
+
+    pub fn video_codec(&self) -> Option<UsmVideoCodec> {
+        match self.chunk_type {
+            UsmChunkType::Video | UsmChunkType::Alpha => self.header()?.get_int(0, "mpeg_codec").map(UsmVideoCodec::from),
+            _ => None
+        }
+    }
+
+    pub fn audio_codec(&self) -> Option<UsmAudioCodec> {
+        match self.chunk_type {
+            UsmChunkType::Audio => self.header()?.get_int(0, "audio_codec").map(UsmAudioCodec::from),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UsmReader<'a> {
+    data: &'a [u8],
+    crid: UsmTable,
+    streams: Vec<UsmStream>,
+    mask: Option<UsmMask>
+}
+
+impl<'a> UsmReader<'a> {
+    /// Read an unencrypted USM
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        let mut chunks = UsmChunkIter::new(data);
+        let crid = match chunks.next() {
+            Some(Ok(chunk)) if chunk.chunk_type() == UsmChunkType::Crid => UsmTable::new(chunk.payload())?,
+            Some(Err(e)) => return Err(e.into()),
+            _ => return Err(UsmError::InvalidMagic.into())
+        };
+        let mut streams: Vec<UsmStream> = vec![];
+        for chunk in chunks {
+            let chunk = chunk?;
+            if matches!(chunk.chunk_type(), UsmChunkType::Crid) {
+                continue;
+            }
+            let stream = match streams.iter().position(|s| s.chunk_type == chunk.chunk_type() && s.channel == chunk.channel()) {
+                Some(i) => &mut streams[i],
+                None => {
+                    let filename = Self::find_filename(&crid, chunk.chunk_type(), chunk.channel());
+                    streams.push(UsmStream { chunk_type: chunk.chunk_type(), channel: chunk.channel(), filename,
+                        header: None, metadata: vec![] });
+                    streams.last_mut().unwrap()
+                }
+            };
+            match chunk.payload_type() {
+                UsmPayloadType::Header if stream.header.is_none() => stream.header = Some(UsmTable::new(chunk.payload())?),
+                UsmPayloadType::Metadata => stream.metadata.push(UsmTable::new(chunk.payload())?),
+                _ => ()
+            }
+        }
+        Ok(Self { data, crid, streams, mask: None })
+    }
+
+    /// Read a USM, decrypting its streams with the game's key
+    pub fn new_with_key(data: &'a [u8], key: u64) -> Result<Self> {
+        let mut reader = Self::new(data)?;
+        reader.mask = Some(UsmMask::new(key));
+        Ok(reader)
+    }
+
+    fn find_filename(crid: &UsmTable, chunk_type: UsmChunkType, channel: u8) -> Option<String> {
+        // the first row describes the whole file
+        let stream_id = u32::from_be_bytes(chunk_type.signature()) as i64;
+        (1..crid.row_count())
+            .find(|r| crid.get_int(*r, "stmid") == Some(stream_id) && crid.get_int(*r, "chno") == Some(channel as i64))
+            .and_then(|r| crid.get_string(r, "filename"))
+            .map(str::to_owned)
+    }
+
+    /// CRIUSF_DIR_STREAM table listing the file and its streams
+    pub fn crid(&self) -> &UsmTable { &self.crid }
+    pub fn streams(&self) -> &[UsmStream] { &self.streams }
+
+    pub fn get_stream(&self, chunk_type: UsmChunkType, channel: u8) -> Option<&UsmStream> {
+        self.streams.iter().find(|s| s.chunk_type == chunk_type && s.channel == channel)
+    }
+
+    /// Concatenate a stream's payloads into its elementary stream, decrypting them if the reader
+    /// has a key
+    pub fn demux(&self, chunk_type: UsmChunkType, channel: u8) -> Result<Vec<u8>> {
+        let stream = self.get_stream(chunk_type, channel);
+        let audio_mask = stream.and_then(|s| s.audio_codec()) == Some(UsmAudioCodec::Adx);
+        let mut out = vec![];
+        for chunk in UsmChunkIter::new(self.data) {
+            let chunk = chunk?;
+            if chunk.chunk_type() != chunk_type || chunk.channel() != channel
+                || chunk.payload_type() != UsmPayloadType::Stream {
+                continue;
+            }
+            let start = out.len();
+            out.extend_from_slice(chunk.payload());
+            let payload = &mut out[start..];
+            match (&self.mask, chunk_type) {
+                (Some(mask), UsmChunkType::Video | UsmChunkType::Alpha) => mask.decrypt_video(payload),
+                (Some(mask), UsmChunkType::Audio) if audio_mask => mask.decrypt_audio(payload),
+                _ => ()
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::error::CriError;
+    use crate::fixtures::{build_table, TableValue};
+    use crate::schema::columns::ColumnType;
+    use crate::schema::rows::RowValue;
+    use crate::usm::chunk::tests::build_chunk;
+    use crate::usm::chunk::{UsmChunk, UsmChunkType};
+    use crate::usm::error::UsmError;
+    use crate::usm::mask::UsmMask;
+    use crate::usm::reader::{UsmAudioCodec, UsmReader, UsmVideoCodec};
+
+    const KEY: u64 = 0x0000_0000_00ab_cdef;
+
+    fn build_crid() -> Result<Vec<u8>, Box<dyn Error>> {
+        let rows = [("movie.usm", [0; 4], 0xffff), ("movie.m2v", *b"@SFV", 0), ("movie.adx", *b"@SFA", 0)]
+            .into_iter()
+            .map(|(name, id, channel)| vec![TableValue::String(name.to_owned()),
+                TableValue::Value(RowValue::UInt32(u32::from_be_bytes(id))), TableValue::Value(RowValue::UInt16(channel))])
+            .collect();
+        build_table("CRIUSF_DIR_STREAM",
+            &[("filename", ColumnType::String), ("stmid", ColumnType::UInt32), ("chno", ColumnType::UInt16)], rows)
+    }
+
+    /// Table with a single row of values
+    fn build_info(name: &str, columns: &[(&str, RowValue)]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let types: Vec<_> = columns.iter().map(|(name, value)| (*name, value.get_type().unwrap())).collect();
+        build_table(name, &types, vec![columns.iter().map(|(_, v)| TableValue::Value(v.clone())).collect()])
+    }
+
+    /// Video stream with frames of the given sizes and ADX audio, encrypted with KEY
+    fn build_usm(video_frames: &[Vec<u8>], audio: &[Vec<u8>]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mask = UsmMask::new(KEY);
+        let mut out = build_chunk(UsmChunkType::Crid, 0, 1, 0, &build_crid()?);
+        out.extend(build_chunk(UsmChunkType::Video, 0, 1, 0, &build_info("VIDEO_HDRINFO",
+            &[("width", RowValue::UInt32(640)), ("height", RowValue::UInt32(480)), ("mpeg_codec", RowValue::Byte(1))])?));
+        out.extend(build_chunk(UsmChunkType::Audio, 0, 1, 0, &build_info("AUDIO_HDRINFO",
+            &[("audio_codec", RowValue::Byte(2)), ("sampling_rate", RowValue::UInt32(44100))])?));
+        for chunk_type in [UsmChunkType::Video, UsmChunkType::Audio] {
+            out.extend(build_chunk(chunk_type, 0, 2, 0, b"#HEADER END     ===============\0"));
+        }
+        out.extend(build_chunk(UsmChunkType::Video, 0, 3, 0, &build_info("VIDEO_SEEKINFO",
+            &[("ofs_byte", RowValue::UInt64(0x800)), ("ofs_frmid", RowValue::Int32(0))])?));
+        for (i, frame) in video_frames.iter().enumerate() {
+            let mut frame = frame.clone();
+            mask.encrypt_video(&mut frame);
+            out.extend(build_chunk(UsmChunkType::Video, 0, 0, i as u32 * 100, &frame));
+            if let Some(audio) = audio.get(i) {
+                let mut audio = audio.clone();
+                mask.decrypt_audio(&mut audio);
+                out.extend(build_chunk(UsmChunkType::Audio, 0, 0, i as u32 * 100, &audio));
+            }
+        }
+        for chunk_type in [UsmChunkType::Video, UsmChunkType::Audio] {
+            out.extend(build_chunk(chunk_type, 0, 2, 0, b"#CONTENTS END   ===============\0"));
+        }
+        Ok(out)
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn demux_streams() -> Result<(), Box<dyn Error>> {
+        let video = vec![pattern(0x400, 1), pattern(0x30, 2), pattern(0x801, 3)];
+        let audio = vec![pattern(0x200, 4), pattern(0x100, 5)];
+        let usm = build_usm(&video, &audio)?;
+        let reader = UsmReader::new_with_key(&usm, KEY)?;
+        assert_eq!(reader.crid().name(), "CRIUSF_DIR_STREAM");
+        assert_eq!(reader.streams().len(), 2);
+        let stream = reader.get_stream(UsmChunkType::Video, 0).unwrap();
+        assert_eq!(stream.filename(), Some("movie.m2v"));
+        assert_eq!(stream.video_codec(), Some(UsmVideoCodec::Mpeg));
+        assert_eq!(stream.header().unwrap().get_int(0, "width"), Some(640));
+        assert_eq!(stream.metadata()[0].get_int(0, "ofs_byte"), Some(0x800));
+        let stream = reader.get_stream(UsmChunkType::Audio, 0).unwrap();
+        assert_eq!(stream.filename(), Some("movie.adx"));
+        assert_eq!(stream.audio_codec(), Some(UsmAudioCodec::Adx));
+        assert_eq!(stream.audio_codec().unwrap().extension(), "adx");
+        assert_eq!(reader.demux(UsmChunkType::Video, 0)?, video.concat());
+        assert_eq!(reader.demux(UsmChunkType::Audio, 0)?, audio.concat());
+        assert!(reader.demux(UsmChunkType::Video, 1)?.is_empty());
+        // without the key, the payloads are still encrypted
+        let reader = UsmReader::new(&usm)?;
+        let encrypted = reader.demux(UsmChunkType::Video, 0)?;
+        assert_eq!(encrypted.len(), 0x400 + 0x30 + 0x801);
+        assert_ne!(encrypted, video.concat());
+        assert_eq!(encrypted[0x400..0x430], video[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid() -> Result<(), Box<dyn Error>> {
+        let usm = build_usm(&[pattern(0x10, 0)], &[])?;
+        let (_, crid_size) = UsmChunk::parse(&usm)?;
+        let error = UsmReader::new(&usm[crid_size..]).unwrap_err();
+        assert!(matches!(error, CriError::Usm(UsmError::InvalidMagic)));
+        let error = UsmReader::new(&usm[..usm.len() - 1]).unwrap_err();
+        assert!(matches!(error, CriError::Usm(UsmError::Truncated)));
+        let mut bad = usm.clone();
+        // corrupt the video header's @UTF magic, after the CRID's
+        let header = bad.windows(4).enumerate().filter(|(_, w)| w == b"@UTF").nth(1).unwrap().0;
+        bad[header] = b'X';
+        let error = UsmReader::new(&bad).unwrap_err();
+        assert!(matches!(error, CriError::Usm(UsmError::InvalidTable)));
+        Ok(())
+    }
+}
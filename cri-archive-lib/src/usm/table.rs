@@ -0,0 +1,134 @@
+//! # USM Tables
+//!
+//! Stream directories, headers and seek points are stored as @UTF tables in chunk payloads.
+
+use crate::error::Result;
+use std::io::Cursor;
+use crate::schema::columns::Column;
+use crate::schema::header::{TableHeader, HEADER_SIZE};
+use crate::schema::rows::{Row, RowValue};
+use crate::schema::strings::{StringPool, StringPoolFast};
+use crate::usm::error::UsmError;
+use crate::utils::endianness::BigEndian;
+use crate::utils::slice::FromSlice;
+
+/// Owned @UTF table with values looked up by column name
+#[derive(Debug)]
+pub struct UsmTable {
+    alloc: Vec<u8>,
+    name: String,
+    columns: Vec<Column>,
+    column_names: Vec<String>,
+    strings: StringPoolFast,
+    rows: Vec<Row>,
+    data_pool_offset: usize
+}
+
+impl UsmTable {
+    pub fn new(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE || &data[..4] != b"@UTF" {
+            return Err(UsmError::InvalidTable.into());
+        }
+        let alloc = data.get(..8 + u32::from_slice::<BigEndian>(data, 4) as usize)
+            .ok_or(UsmError::InvalidTable)?.to_vec();
+        let header = TableHeader::new(&alloc);
+        let string_pool_offset = header.string_pool_offset() as usize;
+        let data_pool_offset = header.data_pool_offset() as usize;
+        if string_pool_offset > data_pool_offset || data_pool_offset > alloc.len() {
+            return Err(UsmError::InvalidTable.into());
+        }
+        let mut cursor = Cursor::new(alloc.as_slice());
+        let columns = Column::new_list(&mut cursor, &header)?;
+        let rows = Row::new_list(&mut cursor, &header, &columns)?;
+        let strings = unsafe { StringPoolFast::new_borrowed(&alloc[string_pool_offset..data_pool_offset], &header)? };
+        let name = strings.get_string(u32::from_slice::<BigEndian>(&alloc, 0x14)).unwrap_or_default().to_owned();
+        let column_names = columns.iter()
+            .map(|c| strings.get_string(c.get_string_offset()).unwrap_or_default().to_owned())
+            .collect();
+        Ok(Self { alloc, name, columns, column_names, strings, rows, data_pool_offset })
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn column_names(&self) -> &[String] { &self.column_names }
+    pub fn row_count(&self) -> usize { self.rows.len() }
+
+    /// Value of a column, falling back to the column's default
+    pub fn get_value(&self, row: usize, column: &str) -> Option<&RowValue> {
+        let index = self.column_names.iter().position(|n| n == column)?;
+        match &self.rows.get(row)?[index] {
+            RowValue::None => self.columns[index].get_default_value(),
+            value => Some(value)
+        }
+    }
+
+    /// Integer value of any width, sign extended
+    pub fn get_int(&self, row: usize, column: &str) -> Option<i64> {
+        Some(match self.get_value(row, column)? {
+            RowValue::Byte(v) => *v as i64,
+            RowValue::SByte(v) => *v as i64,
+            RowValue::UInt16(v) => *v as i64,
+            RowValue::Int16(v) => *v as i64,
+            RowValue::UInt32(v) => *v as i64,
+            RowValue::Int32(v) => *v as i64,
+            RowValue::UInt64(v) => *v as i64,
+            RowValue::Int64(v) => *v,
+            _ => return None
+        })
+    }
+
+    pub fn get_string(&self, row: usize, column: &str) -> Option<&str> {
+        match self.get_value(row, column)? {
+            RowValue::String(offset) => self.strings.get_string(*offset),
+            _ => None
+        }
+    }
+
+    /// Contents of a data column, None if the data lies outside the table
+    pub fn get_data(&self, row: usize, column: &str) -> Option<&[u8]> {
+        match self.get_value(row, column)? {
+            RowValue::Data(data) if !data.is_none() => {
+                let start = self.data_pool_offset + data.get_offset() as usize;
+                self.alloc.get(start..start + data.get_length() as usize)
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::error::Error;
+    use crate::schema::columns::ColumnType;
+    use crate::schema::rows::RowValue;
+    use crate::schema::writer::{TableColumn, TableWriter};
+    use crate::usm::table::UsmTable;
+
+    #[test]
+    fn read_table() -> Result<(), Box<dyn Error>> {
+        let mut table = TableWriter::new("CRIUSF_DIR_STREAM");
+        table.add_column(TableColumn::row("filename", ColumnType::String));
+        table.add_column(TableColumn::row("filesize", ColumnType::UInt32));
+        table.add_column(TableColumn::constant("fmtver", RowValue::UInt32(0x01000300)));
+        table.add_column(TableColumn::row("chno", ColumnType::Int16));
+        for (name, size, channel) in [("movie.usm", 0x1000, -1), ("movie.m2v", 0x800, 0)] {
+            let row = vec![RowValue::String(table.add_string(name)), RowValue::UInt32(size), RowValue::None,
+                RowValue::Int16(channel)];
+            table.add_row(row);
+        }
+        let mut bytes = table.to_bytes()?;
+        bytes.extend([0xff; 4]);
+        let table = UsmTable::new(&bytes)?;
+        assert_eq!(table.name(), "CRIUSF_DIR_STREAM");
+        assert_eq!(table.column_names(), ["filename", "filesize", "fmtver", "chno"]);
+        assert_eq!(table.row_count(), 2);
+        assert_eq!(table.get_string(1, "filename"), Some("movie.m2v"));
+        assert_eq!(table.get_int(0, "filesize"), Some(0x1000));
+        assert_eq!(table.get_int(1, "fmtver"), Some(0x01000300));
+        assert_eq!(table.get_int(0, "chno"), Some(-1));
+        assert_eq!(table.get_int(0, "missing"), None);
+        assert_eq!(table.get_int(2, "chno"), None);
+        assert!(UsmTable::new(b"@UTF\0\0\0\x10").is_err());
+        assert!(UsmTable::new(&bytes[4..]).is_err());
+        Ok(())
+    }
+}
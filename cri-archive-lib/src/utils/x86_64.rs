@@ -5,6 +5,10 @@
 use core::arch::x86_64::{__m128i, __m256i, _mm256_and_si256, _mm256_mullo_epi16, _mm256_or_si256, _mm256_set1_epi16, _mm256_slli_epi16, _mm256_srli_epi16, _mm_and_si128, _mm_mullo_epi16, _mm_or_si128, _mm_set1_epi16, _mm_slli_epi16, _mm_srli_epi16};
 
 /// Multiplies individual bytes for AVX registers.
+///
+/// # Safety
+///
+/// The caller must ensure that the CPU supports AVX2.
 pub unsafe fn multiply_bytes_avx(a: __m256i, b: __m256i) -> __m256i {
     // Derived from https://stackoverflow.com/questions/8193601/sse-multiplication-16-x-uint8-t
     unsafe {
@@ -14,7 +18,11 @@ pub unsafe fn multiply_bytes_avx(a: __m256i, b: __m256i) -> __m256i {
     }
 }
 
-// Multiplies individual bytes for SSE registers.
+/// Multiplies individual bytes for SSE registers.
+///
+/// # Safety
+///
+/// The caller must ensure that the CPU supports SSE2.
 pub unsafe fn multiply_bytes_sse(a: __m128i, b: __m128i) -> __m128i {
     // unpack and multiply
     unsafe {
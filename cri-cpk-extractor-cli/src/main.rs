@@ -1,24 +1,40 @@
-pub mod error_wrapper;
 pub mod progress;
 pub mod printerr;
 
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::time::Instant;
 use console::Term;
-use cri_archive_lib::cpk::encrypt::p5r::P5RDecryptor;
-use cri_archive_lib::cpk::reader::CpkReader;
+use cri_archive_lib::cpk::encrypt::data::DecryptorChain;
+use cri_archive_lib::cpk::positional::CpkPositionalReader;
+use cri_archive_lib::error::CriError;
 use crate::progress::Progress;
 
 use rayon::prelude::*;
-use crate::error_wrapper::ErrorWrapper;
 use crate::printerr::PrintErr;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let (flags, args): (Vec<String>, Vec<String>) = std::env::args().partition(|a| a.starts_with("--"));
     let stdout = Term::stdout();
+    let mut decryptor = "p5r";
+    for flag in &flags {
+        match flag.split_once('=') {
+            Some(("--decrypt", names)) => decryptor = names,
+            _ => {
+                PrintErr::print_to(&stdout, "Unknown option:", flag);
+                PrintErr::wait_for_key(&stdout);
+                return;
+            }
+        }
+    }
+    let Some(decryptor) = DecryptorChain::from_name(decryptor) else {
+        PrintErr::print_to(&stdout, "Unknown decryptor.", &format!("Expected a comma separated list of: {}",
+            DecryptorChain::names().join(", ")));
+        PrintErr::wait_for_key(&stdout);
+        return;
+    };
     if args.len() < 2 {
         PrintErr::print_to(&stdout, "Missing a path to the CPK to extract.", "Drag the CPK onto the executable or add the path after the executable's name from the terminal.");
         PrintErr::wait_for_key(&stdout);
@@ -37,13 +53,21 @@ fn main() {
             Path::new(&args[1]).parent().unwrap().join(inner)
         }
     };
-    if let Err(e) = extract(Path::new(&args[1]), out_folder) {
-        PrintErr::print_to(&stdout, "Error while extracting:", &e.to_string());
+    if let Err(e) = extract(Path::new(&args[1]), out_folder, decryptor) {
+        // CriError leaves the wrapped error to source, so print the whole chain
+        let mut message = e.to_string();
+        let mut source = e.source();
+        while let Some(inner) = source {
+            message.push_str(&format!(": {inner}"));
+            source = inner.source();
+        }
+        PrintErr::print_to(&stdout, "Error while extracting:", &message);
         PrintErr::wait_for_key(&stdout);
     }
 }
 
-fn extract<P0: AsRef<Path>, P1: AsRef<Path> + Send + Sync>(input: P0, output: P1) -> Result<(), Box<dyn Error>> {
+fn extract<P0: AsRef<Path>, P1: AsRef<Path> + Send + Sync>(input: P0, output: P1, decryptor: DecryptorChain)
+    -> Result<(), Box<dyn Error>> {
     let stdout = Term::stdout();
 
     let col_lightblue = match stdout.features().true_colors_supported() {
@@ -57,34 +81,33 @@ fn extract<P0: AsRef<Path>, P1: AsRef<Path> + Send + Sync>(input: P0, output: P1
     println!("Input file: {}", col_lightblue.apply_to(input.as_ref().to_str().unwrap()));
     println!("Output directory: {}", col_orchid.apply_to(output.as_ref().to_str().unwrap()));
     std::fs::create_dir_all(output.as_ref())?;
-    let mut cpk = CpkReader::<_, P5RDecryptor>::new_with_encryption(
-        BufReader::new(File::open(input)?))?;
-    let mut files = cpk.get_files()?;
+    let cpk = CpkPositionalReader::new_with_decryptor(File::open(input)?, decryptor)?;
+    let mut files = cpk.get_files().to_vec();
     files.sort_by(|a, b| a.directory().cmp(b.directory()));
     let mut last_dir_created = None;
     let dir_start = Instant::now();
     for file in &files {
-        if let Some(last) = last_dir_created {
-            if last == file.directory() { continue; }
-        }
+        if let Some(last) = last_dir_created && last == file.directory() { continue; }
         std::fs::create_dir_all(output.as_ref().join(file.directory()))?;
         last_dir_created = Some(file.directory());
     }
     let dir_end = Instant::now().duration_since(dir_start).as_micros() as f64 / 1000.;
     println!("Created directories in {} ms", dir_end);
-    files.sort_by(|a, b| b.file_size().cmp(&a.file_size()));
+    files.sort_by_key(|f| std::cmp::Reverse(f.file_size()));
     let progress = Progress::new(&files);
     files.into_par_iter().try_for_each(|f| {
         progress.set_current_file(&f);
-        let bytes = cpk.extract_file(&f).map_err(|e| ErrorWrapper::new(e))?;
-        let path = match f.directory() {
-            "" => f.file_name().to_owned(),
-            _ => format!("{}/{}", f.directory(), f.file_name())
+        let path = match (f.directory(), f.file_name(), f.id()) {
+            // ID addressed CPKs have no file names
+            ("", "", Some(id)) => format!("{:05}.bin", id),
+            ("", name, _) => name.to_owned(),
+            (dir, name, _) => format!("{}/{}", dir, name)
         };
-        std::fs::write(output.as_ref().join(path), bytes)
-            .map_err(|e| ErrorWrapper::new(Box::new(e)))?;
+        let mut out = BufWriter::new(File::create(output.as_ref().join(path))?);
+        cpk.extract_to(&f, &mut out)?;
+        out.flush()?;
         progress.read_one();
-        Ok::<(), ErrorWrapper>(())
+        Ok::<(), CriError>(())
     })?;
     let extract_time = progress.get_duration().as_secs_f64();
     let (ex_min, ex_sec) = ((extract_time / 60.).floor(), extract_time % 60.);